@@ -0,0 +1,51 @@
+// Compares the two ways of executing a program: Program::step, which matches on each op, and
+// Program::step_threaded, which Execution uses and which calls a handler chosen when the
+// program was compiled.  Run it with
+//
+//     cargo run --release --example dispatch
+//
+// Each program is run a few times with each and the fastest time is reported.
+
+extern crate brainfuck;
+
+use std::time::{Duration, Instant};
+
+use brainfuck::{Program, State, Step};
+
+// Busy programs with no input.  The first is five levels of nested counting loops; in the
+// second, the innermost loop walks to the end of a run of cells and back.
+const PROGRAMS: [(&str, &str); 2] = [
+    ("nested loops", "++++++++++++++++[>++++++++++++++++[>++++++++++++++++[>++++++++++++++++[>++++++++++++++++[>+>++<<-]<-]<-]<-]<-]"),
+    ("scans", ">>>>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+><<<<<<<<<<<<<<<<<<<<++++++++++++++++[>++++++++++++++++[>++++++++++++++++[>>[>]<[<]<-]<-]<-]")
+];
+
+const RUNS: usize = 5;
+
+fn time<F: Fn(&Program, &mut State) -> Step>(program: &Program, step: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut steps = 0;
+    for _ in 0..RUNS {
+        let mut state = State::new(&[]);
+        steps = 0;
+        let start = Instant::now();
+        while !program.finished(&state) {
+            step(program, &mut state);
+            steps += 1;
+        }
+        best = best.min(start.elapsed());
+    }
+    (best, steps)
+}
+
+fn main() {
+    for &(name, source) in PROGRAMS.iter() {
+        for level in 0..3 {
+            let program = Program::with_opt_level(source, level);
+            let (matched, steps) = time(&program, |program, state| program.step(state));
+            let (threaded, _) = time(&program, |program, state| program.step_threaded(state));
+            let per_step = |time: Duration| time.as_nanos() as f64 / steps.max(1) as f64;
+            println!("{:12} -O{}  {:11} steps   match {:8.2?} ({:.2} ns/step)   threaded {:8.2?} ({:.2} ns/step)",
+                     name, level, steps, matched, per_step(matched), threaded, per_step(threaded));
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::sync::mpsc::{Receiver, Sender};
+
+use read_stdin_byte;
+
+// Where Program::run gets ',' input from and sends '.' output to, so that anything embedding the
+// interpreter (a game, a visualizer, a test) can intercept the program's I/O
+pub trait BfIo {
+    // The next byte of input, or None once the input has ended, after which every ',' leaves
+    // the current cell unchanged
+    fn read(&mut self) -> Option<u8>;
+
+    fn write(&mut self, byte: u8);
+}
+
+// Reads stdin and writes stdout
+pub struct Stdio;
+
+impl BfIo for Stdio {
+    fn read(&mut self) -> Option<u8> {
+        read_stdin_byte()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[byte]).expect("Could not write to stdout");
+        stdout.flush().expect("Could not flush stdout");
+    }
+}
+
+// Reads from a fixed input and collects the output in memory
+pub struct Buffer {
+    input: VecDeque<u8>,
+    output: Vec<u8>
+}
+
+impl Buffer {
+    pub fn new(input: &[u8]) -> Buffer {
+        Buffer { input: input.iter().cloned().collect(), output: Vec::new() }
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+impl BfIo for Buffer {
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
+
+// Reads from and writes to channels, for a program running on a different thread than whatever
+// it's talking to.  Reading waits for the next byte, and the input ends when its sender is
+// dropped.  Output sent after the receiver is dropped is discarded.
+pub struct Channels {
+    input: Receiver<u8>,
+    output: Sender<u8>
+}
+
+impl Channels {
+    pub fn new(input: Receiver<u8>, output: Sender<u8>) -> Channels {
+        Channels { input, output }
+    }
+}
+
+impl BfIo for Channels {
+    fn read(&mut self) -> Option<u8> {
+        self.input.recv().ok()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let _ = self.output.send(byte);
+    }
+}
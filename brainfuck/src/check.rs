@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use ir;
+use ir::{Op, SourceMap, Span};
+
+// What check found in a program
+pub struct Report {
+    // How many of each command the program has, and how its loops nest
+    pub summary: Vec<String>,
+    // Each with the line and column it's about, like the errors
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>
+}
+
+// Check the source of the program called name
+pub fn check(name: &str, source: &str) -> Report {
+    let source_map = SourceMap::new(source);
+    let ops = ir::parse(source);
+    let at = |span: Span| {
+        let (line, col) = source_map.location(span.start);
+        format!("line {}, col {}", line, col)
+    };
+
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in source.chars().filter(|c| "+-<>.,[]".contains(*c)) {
+        *counts.entry(c).or_insert(0) += 1;
+    }
+
+    // Match the brackets, finding how deeply they nest
+    let mut errors = Vec::new();
+    let mut open = Vec::new();
+    let mut loops = Vec::new();
+    let mut max_depth = 0;
+    for (i, &(op, span)) in ops.iter().enumerate() {
+        match op {
+            Op::Open(_)  => {
+                open.push(i);
+                max_depth = max_depth.max(open.len());
+            },
+            Op::Close(_) => match open.pop() {
+                Some(start) => loops.push((start, i)),
+                None        => errors.push(format!("{}: unmatched ']'", at(span)))
+            },
+            _ => ()
+        }
+    }
+    errors.extend(open.iter().map(|&i| format!("{}: unmatched '['", at(ops[i].1))));
+    loops.sort();
+
+    let counts: Vec<String> = "+-<>.,[]".chars().map(|c| format!("{} {}", c, counts.get(&c).unwrap_or(&0))).collect();
+    let summary = vec![
+        format!("{}: {} instructions", name, ops.len()),
+        format!("  {}", counts.join("  ")),
+        format!("  {} loops, nested at most {} deep", loops.len(), max_depth)
+    ];
+    let warnings = warnings(&ops, &loops).into_iter().map(|(span, message)| format!("{}: {}", at(span), message)).collect();
+    Report { summary, warnings, errors }
+}
+
+// Loops (given as the positions of their brackets, in order) that obviously never run, and code
+// after a loop that obviously never finishes
+fn warnings(ops: &[(Op, Span)], loops: &[(usize, usize)]) -> Vec<(Span, String)> {
+    let mut warnings = Vec::new();
+    let close_of: HashMap<usize, usize> = loops.iter().cloned().collect();
+
+    // Until the first loop or ',' the value of every cell is known, so a loop reached then
+    // either never runs or, if it's empty, never ends
+    let mut tape: HashMap<isize, isize> = HashMap::new();
+    let mut pos = 0;
+    for (i, &(op, span)) in ops.iter().enumerate() {
+        match op {
+            Op::Add(n)  => *tape.entry(pos).or_insert(0) += n,
+            Op::Move(n) => pos = (pos + n).max(0),
+            Op::Output  => (),
+            Op::Open(_) => {
+                let val = tape.get(&pos).cloned().unwrap_or(0);
+                match close_of.get(&i) {
+                    Some(_) if val == 0 => warnings.push((span, "loop never runs, since the current cell is always zero here".to_string())),
+                    Some(&close) if close == i + 1 && close + 1 < ops.len() => {
+                        warnings.push((ops[close + 1].1, "unreachable, since the empty loop before it never finishes".to_string()));
+                        return warnings;
+                    },
+                    _ => ()
+                }
+                break;
+            },
+            _ => break
+        }
+    }
+
+    for &(start, close) in loops {
+        // A loop only ends when the current cell is zero, so one straight after it never runs
+        if start > 0 && matches!(ops[start - 1].0, Op::Close(_)) {
+            warnings.push((ops[start].1, "loop never runs, since the loop before it leaves the current cell zero".to_string()));
+        } else if close == start + 1 && !warnings.iter().any(|&(span, _)| span == ops[start].1) {
+            warnings.push((ops[start].1, "empty loop never finishes if the current cell isn't zero".to_string()));
+        }
+    }
+    warnings.sort_by_key(|&(span, _)| span.start);
+    warnings
+}
@@ -0,0 +1,150 @@
+// Argument parsing for the command line, which reports bad arguments and exits, so it stays out
+// of the library
+
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process;
+
+use brainfuck::Program;
+use brainfuck::{check, equiv, optimizer};
+
+const DEFAULT_MAX_STEPS: usize = 10_000_000;
+
+// brainfuck equiv a.bf b.bf --inputs dir/ [--max-steps n] [-O<level>]
+// Run both programs on every file in the inputs directory and report the first input where
+// their output differs.  Exits with 1 if they diverge, and 0 if they agree on every input.
+pub fn equiv<I: Iterator<Item=String>>(mut argv: I) {
+    let mut paths = Vec::new();
+    let mut inputs = None;
+    let mut max_steps = DEFAULT_MAX_STEPS;
+    let mut opt_level = optimizer::DEFAULT_LEVEL;
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--inputs"    => inputs = argv.next(),
+            "--max-steps" => max_steps = flag_value(&arg, argv.next()),
+            _ if arg.starts_with("-O") => opt_level = opt_level_value(&arg),
+            _             => paths.push(arg)
+        }
+    }
+
+    let dir = match (paths.len(), inputs) {
+        (2, Some(dir)) => dir,
+        _              => {
+            eprintln!("Usage: brainfuck equiv <a.bf> <b.bf> --inputs <dir> [--max-steps <n>]");
+            process::exit(2);
+        }
+    };
+
+    let programs: Vec<Program> = paths.iter().map(|path| Program::with_opt_level(&read(&PathBuf::from(path)), opt_level)).collect();
+
+    let entries = fs::read_dir(&dir).and_then(|entries| entries.collect::<Result<Vec<_>, _>>());
+    let mut files: Vec<PathBuf> = match entries {
+        Ok(entries) => entries.iter().map(|entry| entry.path()).filter(|path| path.is_file()).collect(),
+        Err(err)    => {
+            eprintln!("Error: could not read {}: {}", dir, err);
+            process::exit(2);
+        }
+    };
+    files.sort();
+
+    let mut inconclusive = 0;
+    for file in &files {
+        match equiv::compare(&programs[0], &programs[1], &read_bytes(file), max_steps) {
+            equiv::Comparison::Same => (),
+            equiv::Comparison::Differ(pos, out_a, out_b) => {
+                println!("{}: outputs differ at byte {}", file.display(), pos);
+                println!("  {}: {}", paths[0], describe(&out_a, pos));
+                println!("  {}: {}", paths[1], describe(&out_b, pos));
+                process::exit(1);
+            },
+            equiv::Comparison::Inconclusive => {
+                println!("{}: inconclusive, no difference before the step limit of {}", file.display(), max_steps);
+                inconclusive += 1;
+            }
+        }
+    }
+
+    println!("No differences on {} inputs ({} inconclusive)", files.len(), inconclusive);
+}
+
+// brainfuck check file.bf
+// Check a program without running it: report unmatched brackets as errors, print how many of
+// each command it has and how deeply its loops nest, and warn about loops that can never run
+// and code that can never be reached.  Exits with 1 if there are errors.
+pub fn check<I: Iterator<Item=String>>(mut argv: I) {
+    let path = match (argv.next(), argv.next()) {
+        (Some(path), None) => path,
+        _                  => {
+            eprintln!("Usage: brainfuck check <file.bf>");
+            process::exit(2);
+        }
+    };
+    let mut source = String::new();
+    if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_string(&mut source)) {
+        eprintln!("Error: could not read {}: {}", path, err);
+        process::exit(2);
+    }
+
+    let report = check::check(&path, &source);
+    for line in &report.summary {
+        println!("{}", line);
+    }
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+    for error in &report.errors {
+        println!("error: {}", error);
+    }
+    if !report.errors.is_empty() {
+        process::exit(1);
+    }
+}
+
+// Parse the value following a flag like --max-output
+pub fn flag_value(flag: &str, value: Option<String>) -> usize {
+    match value.map(|val| val.parse::<usize>()) {
+        Some(Ok(val)) => val,
+        _             => {
+            eprintln!("Error: {} requires a non-negative integer", flag);
+            process::exit(2);
+        }
+    }
+}
+
+// Parse a flag like -O2
+pub fn opt_level_value(flag: &str) -> u32 {
+    match flag[2..].parse::<u32>() {
+        Ok(level) if level <= 2 => level,
+        _                       => {
+            eprintln!("Error: unknown optimization level '{}' (expected -O0, -O1 or -O2)", flag);
+            process::exit(2);
+        }
+    }
+}
+
+// Show the byte at a position and the output around it, e.g. 'W' in "Hello World"
+fn describe(output: &[u8], pos: usize) -> String {
+    if pos >= output.len() {
+        return format!("output ended after {} bytes", output.len());
+    }
+    let start = pos.saturating_sub(16);
+    let end = (pos + 16).min(output.len());
+    format!("{:?} in {:?}", output[pos] as char, String::from_utf8_lossy(&output[start..end]))
+}
+
+fn read(path: &PathBuf) -> String {
+    String::from_utf8_lossy(&read_bytes(path)).into_owned()
+}
+
+fn read_bytes(path: &PathBuf) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+        Ok(_)    => bytes,
+        Err(err) => {
+            eprintln!("Error: could not read {}: {}", path.display(), err);
+            process::exit(2);
+        }
+    }
+}
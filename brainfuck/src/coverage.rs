@@ -0,0 +1,42 @@
+use brainfuck::Program;
+
+// Print the source to stderr with a line of '^' markers under every command that never ran,
+// given how many times each instruction executed
+pub fn report(program: &Program, source: &str, counts: &[usize]) {
+    let chars: Vec<char> = source.chars().collect();
+    let mut covered = vec![false; chars.len()];
+    for (span, _) in program.spans().iter().zip(counts).filter(|&(_, &count)| count > 0) {
+        for c in &mut covered[span.start..span.end] {
+            *c = true;
+        }
+    }
+
+    let mut total = 0;
+    let mut missed = 0;
+    let mut pos = 0;
+    for line in chars.split(|&c| c == '\n') {
+        let mut markers = String::new();
+        for (&c, &hit) in line.iter().zip(&covered[pos..]) {
+            if is_command(c) {
+                total += 1;
+                if !hit {
+                    missed += 1;
+                }
+                markers.push(if hit { ' ' } else { '^' });
+            } else {
+                markers.push(if c == '\t' { '\t' } else { ' ' });
+            }
+        }
+        eprintln!("{}", line.iter().collect::<String>().trim_end_matches('\r'));
+        if markers.contains('^') {
+            eprintln!("{}", markers.trim_end());
+        }
+        pos += line.len() + 1;
+    }
+
+    eprintln!("Coverage: {} of {} instructions executed", total - missed, total);
+}
+
+fn is_command(c: char) -> bool {
+    "+-<>.,[]".contains(c)
+}
@@ -0,0 +1,185 @@
+use std::io;
+use std::io::prelude::*;
+use std::collections::BTreeSet;
+
+use {Program, State, Step};
+
+const HELP: &str = "\
+commands:
+  step [n]      execute n instructions (default 1)
+  continue      run until a breakpoint, a watchpoint, or the end of the program
+  break <pc>    pause before executing the instruction at pc
+  break <line>:<col>
+                pause before the first instruction at or after a source location
+  watch <cell>  pause whenever the value of the given cell changes
+  unwatch <cell>
+  tape          print the tape and pointer
+  quit";
+
+pub struct Debugger<'a> {
+    program: &'a Program,
+    state: State,
+    breakpoints: BTreeSet<usize>,
+    watches: BTreeSet<usize>,
+    // Called whenever ',' needs a byte, like Interpreter's input hook
+    input_fn: Box<dyn FnMut() -> Option<u8> + 'a>,
+    // Where both the program's output and the debugger's messages go; stdout by default
+    output: Box<dyn Write + 'a>
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(program: &'a Program, input_fn: Box<dyn FnMut() -> Option<u8> + 'a>) -> Debugger<'a> {
+        Debugger {
+            program,
+            state: State::new_streaming(),
+            breakpoints: BTreeSet::new(),
+            watches: BTreeSet::new(),
+            input_fn,
+            output: Box::new(io::stdout())
+        }
+    }
+
+    pub fn set_output<W: Write + 'a>(&mut self, output: W) {
+        self.output = Box::new(output);
+    }
+
+    // Read commands from stdin until the input ends or one of them is quit
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+
+        self.show_position();
+        loop {
+            self.print("bf> ");
+            let mut line = String::new();
+            match stdin.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_)          => ()
+            }
+            if !self.command(&line) {
+                return;
+            }
+        }
+    }
+
+    // Carry out one command, returning false if it's quit
+    pub fn command(&mut self, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = words.get(1).map(|word| word.parse::<usize>());
+        match (words.first().cloned(), arg) {
+            (None, _)                                  => (),
+            (Some("step"), None) | (Some("s"), None)   => self.resume(Some(1)),
+            (Some("step"), Some(Ok(0)))
+                | (Some("s"), Some(Ok(0)))             => self.print("Invalid step count (expected at least 1)\n"),
+            (Some("step"), Some(Ok(n)))
+                | (Some("s"), Some(Ok(n)))             => self.resume(Some(n)),
+            (Some("continue"), _) | (Some("c"), _)     => self.resume(None),
+            (Some("break"), _) | (Some("b"), _)        => self.add_breakpoint(words.get(1).cloned()),
+            (Some("watch"), Some(Ok(cell)))
+                | (Some("w"), Some(Ok(cell)))          => { self.watches.insert(cell); },
+            (Some("unwatch"), Some(Ok(cell)))          => { self.watches.remove(&cell); },
+            (Some("tape"), _) | (Some("t"), _)         => self.show_tape(),
+            (Some("quit"), _) | (Some("q"), _)         => return false,
+            (Some("help"), _)                          => self.print(&format!("{}\n", HELP)),
+            _                                          => self.print(&format!("Invalid command '{}' (try 'help')\n", line.trim()))
+        }
+        true
+    }
+
+    fn print(&mut self, text: &str) {
+        self.output.write_all(text.as_bytes()).expect("Could not write to stdout");
+        self.output.flush().expect("Could not flush stdout");
+    }
+
+    // Add a breakpoint at a pc, or at the instruction for a line:col in the source
+    fn add_breakpoint(&mut self, target: Option<&str>) {
+        let pc = match target.map(|target| (target, target.split_once(':'))) {
+            Some((_, Some((line, col)))) => match (line.parse(), col.parse()) {
+                (Ok(line), Ok(col)) => self.program.pc_at(line, col),
+                _                   => None
+            },
+            Some((target, None)) => target.parse().ok(),
+            None                 => None
+        };
+        match pc {
+            Some(pc) if pc >= self.program.code().len() => {
+                let message = format!("Invalid breakpoint: the program ends at pc {}\n", self.program.code().len());
+                self.print(&message);
+            },
+            Some(pc) => {
+                self.breakpoints.insert(pc);
+                let message = format!("Breakpoint at {}\n", self.describe(pc));
+                self.print(&message);
+            },
+            None => self.print("Invalid breakpoint (expected a pc or line:col)\n")
+        }
+    }
+
+    // Run until the step limit, a breakpoint, a watchpoint, or the end of the program
+    fn resume(&mut self, limit: Option<usize>) {
+        let mut steps = 0;
+
+        loop {
+            if self.program.finished(&self.state) {
+                self.print("Program finished\n");
+                return;
+            }
+
+            let pc = self.state.pc();
+            let before: Vec<(usize, isize)> = self.watches.iter().map(|&cell| (cell, self.state.tape().get_at(cell))).collect();
+            match self.program.step(&mut self.state) {
+                Step::Output(byte) => self.output.write_all(&[byte]).expect("Could not write to stdout"),
+                // The ',' hasn't executed yet, and is retried once there's input for it
+                Step::NeedInput    => {
+                    self.output.flush().expect("Could not flush stdout");
+                    match (self.input_fn)() {
+                        Some(byte) => self.state.feed(&[byte]),
+                        None       => self.state.close_input()
+                    }
+                    continue;
+                },
+                Step::Continue     => ()
+            }
+            steps += 1;
+
+            let mut paused = false;
+            for (cell, old) in before {
+                let new = self.state.tape().get_at(cell);
+                if new != old {
+                    let message = format!("Watchpoint: cell {} changed from {} to {} at {}\n", cell, old, new, self.describe(pc));
+                    self.print(&message);
+                    paused = true;
+                }
+            }
+
+            if paused || self.breakpoints.contains(&self.state.pc()) || Some(steps) == limit {
+                self.show_position();
+                return;
+            }
+        }
+    }
+
+    fn show_position(&mut self) {
+        let message = match self.program.code().get(self.state.pc()) {
+            Some(op) => format!("{}: '{}'\n", self.describe(self.state.pc()), op),
+            None     => format!("pc {}: end of program\n", self.state.pc())
+        };
+        self.print(&message);
+    }
+
+    // e.g. "pc 12 (line 1, col 15)"
+    fn describe(&self, pc: usize) -> String {
+        match self.program.location(pc) {
+            Some((line, col)) => format!("pc {} (line {}, col {})", pc, line, col),
+            None              => format!("pc {}", pc)
+        }
+    }
+
+    fn show_tape(&mut self) {
+        let tape = self.state.tape();
+        let cells: Vec<String> = tape.cells().iter().enumerate().map(|(i, val)| {
+            if i == tape.pos() { format!("[{}]", val) } else { val.to_string() }
+        }).collect();
+        let message = format!("ptr {}: {}\n", tape.pos(), cells.join(" "));
+        self.print(&message);
+    }
+}
@@ -0,0 +1,97 @@
+use ir::Op;
+use {State, Step};
+
+type Handler = fn(&mut State, isize, isize) -> Step;
+
+// The threaded form of a program, which Execution runs.  Every op is turned into a handler and
+// its operands when the program is compiled, so the hot loop is a single indirect call per
+// instruction instead of a match on the op.  Each handler does exactly what Program::step does
+// for its op, including moving the pc.
+pub struct Instr {
+    exec: Handler,
+    a: isize,
+    b: isize
+}
+
+impl Instr {
+    pub fn exec(&self, state: &mut State) -> Step {
+        (self.exec)(state, self.a, self.b)
+    }
+}
+
+pub fn compile(code: &[Op]) -> Vec<Instr> {
+    code.iter().map(|&op| {
+        let (exec, a, b): (Handler, isize, isize) = match op {
+            Op::Add(n)                 => (add, n, 0),
+            Op::Move(n)                => (shift, n, 0),
+            Op::Output                 => (output, 0, 0),
+            Op::Input                  => (input, 0, 0),
+            Op::Open(close)            => (open, close as isize, 0),
+            Op::Close(open)            => (close, open as isize, 0),
+            Op::Scan(n)                => (scan, n, 0),
+            Op::Clear(n)               => (clear, n, 0),
+            Op::MulAdd(offset, factor) => (mul_add, offset, factor)
+        };
+        Instr { exec, a, b }
+    }).collect()
+}
+
+fn add(state: &mut State, n: isize, _: isize) -> Step {
+    state.tape.add(n);
+    state.pc += 1;
+    Step::Continue
+}
+
+fn shift(state: &mut State, n: isize, _: isize) -> Step {
+    state.tape.shift(n);
+    state.pc += 1;
+    Step::Continue
+}
+
+fn output(state: &mut State, _: isize, _: isize) -> Step {
+    state.pc += 1;
+    Step::Output(state.tape.getb())
+}
+
+fn input(state: &mut State, _: isize, _: isize) -> Step {
+    match state.input.pop_front() {
+        Some(byte)                  => state.tape.setb(byte),
+        None if !state.input_closed => return Step::NeedInput,
+        None                        => ()
+    }
+    state.pc += 1;
+    Step::Continue
+}
+
+fn open(state: &mut State, close: isize, _: isize) -> Step {
+    state.pc = if state.tape.get() == 0 { close as usize + 1 } else { state.pc + 1 };
+    Step::Continue
+}
+
+fn close(state: &mut State, open: isize, _: isize) -> Step {
+    state.pc = if state.tape.get() != 0 { open as usize + 1 } else { state.pc + 1 };
+    Step::Continue
+}
+
+fn scan(state: &mut State, n: isize, _: isize) -> Step {
+    if state.tape.scan(n) {
+        state.pc += 1;
+    }
+    Step::Continue
+}
+
+fn clear(state: &mut State, n: isize, _: isize) -> Step {
+    let val = state.tape.get();
+    if val == 0 || (val > 0) != (n > 0) {
+        state.tape.add(-val);
+        state.pc += 1;
+    }
+    Step::Continue
+}
+
+fn mul_add(state: &mut State, offset: isize, factor: isize) -> Step {
+    let val = state.tape.get();
+    state.tape.add_at(offset, val * factor);
+    state.pc += 1;
+    Step::Continue
+}
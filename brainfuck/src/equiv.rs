@@ -0,0 +1,56 @@
+use {Program, Event};
+
+// How two programs' outputs compare on one input
+#[derive(PartialEq, Debug)]
+pub enum Comparison {
+    Same,
+    // The outputs differ at this byte; these are what each program wrote
+    Differ(usize, Vec<u8>, Vec<u8>),
+    // At least one program hit the step limit before the outputs differed
+    Inconclusive
+}
+
+pub fn compare(a: &Program, b: &Program, input: &[u8], max_steps: usize) -> Comparison {
+    let (out_a, end_a) = run(a, input, max_steps);
+    let (out_b, end_b) = run(b, input, max_steps);
+
+    if let Some(pos) = first_difference(&out_a, &out_b) {
+        // Output that stopped short because of the step limit might still have continued
+        let a_short = pos == out_a.len() && end_a == Event::StepLimitExceeded;
+        let b_short = pos == out_b.len() && end_b == Event::StepLimitExceeded;
+        if !a_short && !b_short {
+            return Comparison::Differ(pos, out_a, out_b);
+        }
+    }
+
+    if end_a == Event::StepLimitExceeded || end_b == Event::StepLimitExceeded {
+        Comparison::Inconclusive
+    } else {
+        Comparison::Same
+    }
+}
+
+// Run a program to completion or the step limit, collecting its output
+fn run(program: &Program, input: &[u8], max_steps: usize) -> (Vec<u8>, Event) {
+    let mut execution = program.execute();
+    execution.feed(input);
+    execution.close_input();
+    execution.set_max_steps(max_steps);
+
+    let mut output = Vec::new();
+    loop {
+        match execution.resume() {
+            Event::Output(byte) => output.push(byte),
+            event               => return (output, event)
+        }
+    }
+}
+
+// The position of the first byte where the outputs differ, if they aren't identical
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(pos)                 => Some(pos),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None                      => None
+    }
+}
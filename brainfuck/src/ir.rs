@@ -0,0 +1,150 @@
+use std::fmt;
+
+// A single instruction of the intermediate representation that the interpreter executes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Op {
+    // Add to the value of the current cell
+    Add(isize),
+    // Move the pointer right (positive) or left (negative)
+    Move(isize),
+    Output,
+    Input,
+    // Jump to the matching Close if the current cell is zero
+    Open(usize),
+    // Jump back to the matching Open if the current cell is non-zero
+    Close(usize),
+    // Move the pointer in steps of the given size until it reaches a zero cell, i.e. [>] or [<]
+    Scan(isize),
+    // Add 1 (positive) or -1 (negative) until the current cell is zero, i.e. [+] or [-]
+    Clear(isize),
+    // Add the current cell times a factor to the cell at an offset, i.e. one target of a
+    // multiply loop like [->++<].  The loop itself becomes a series of these and a Clear.
+    MulAdd(isize, isize)
+}
+
+// The range of characters in the source that an op was compiled from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+impl Span {
+    // The span covering both this span and another, plus anything between them
+    pub fn join(self, other: Span) -> Span {
+        Span { start: self.start.min(other.start), end: self.end.max(other.end) }
+    }
+}
+
+// Maps character offsets in the source to lines and columns, so that anything reported about an
+// op can refer to where it came from in the original file rather than to its position in the IR
+pub struct SourceMap {
+    // The offset of the first character of every line
+    line_starts: Vec<usize>
+}
+
+impl SourceMap {
+    pub fn new(source: &str) -> SourceMap {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.chars().enumerate().filter(|&(_, c)| c == '\n').map(|(i, _)| i + 1));
+        SourceMap { line_starts }
+    }
+
+    // The line and column (both starting at 1) of a character offset
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line)  => line,
+            Err(next) => next - 1
+        };
+        (line + 1, offset - self.line_starts[line] + 1)
+    }
+
+    // The character offset of a line and column, if the line exists
+    pub fn offset(&self, line: usize, col: usize) -> Option<usize> {
+        match (line, col) {
+            (0, _) | (_, 0) => None,
+            _               => self.line_starts.get(line - 1).map(|start| start + col - 1)
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::Add(1)      => write!(f, "+"),
+            Op::Add(-1)     => write!(f, "-"),
+            Op::Add(n)      => write!(f, "add {}", n),
+            Op::Move(1)     => write!(f, ">"),
+            Op::Move(-1)    => write!(f, "<"),
+            Op::Move(n)     => write!(f, "move {}", n),
+            Op::Output      => write!(f, "."),
+            Op::Input       => write!(f, ","),
+            Op::Open(_)     => write!(f, "["),
+            Op::Close(_)    => write!(f, "]"),
+            Op::Scan(1)     => write!(f, "[>]"),
+            Op::Scan(-1)    => write!(f, "[<]"),
+            Op::Scan(n)     => write!(f, "scan {}", n),
+            Op::Clear(1)    => write!(f, "[+]"),
+            Op::Clear(_)    => write!(f, "[-]"),
+            Op::MulAdd(offset, factor) => write!(f, "muladd {} {}", offset, factor)
+        }
+    }
+}
+
+// Translate source into one op per command, ignoring every other character.
+// Jump targets are left unset until the ops are linked.
+pub fn parse(source: &str) -> Vec<(Op, Span)> {
+    source.chars().enumerate().filter_map(|(i, c)| {
+        let op = match c {
+            '+' => Op::Add(1),
+            '-' => Op::Add(-1),
+            '>' => Op::Move(1),
+            '<' => Op::Move(-1),
+            '.' => Op::Output,
+            ',' => Op::Input,
+            '[' => Op::Open(0),
+            ']' => Op::Close(0),
+            _   => return None
+        };
+        Some((op, Span { start: i, end: i + 1 }))
+    }).collect()
+}
+
+// Fill in the jump targets of every bracket.  Unmatched brackets are dropped.
+pub fn link(ops: Vec<(Op, Span)>) -> Vec<(Op, Span)> {
+    let mut leftstack = Vec::new(); // A stack of positions of Opens
+    let mut matched = vec![false; ops.len()];
+    for (i, &(op, _)) in ops.iter().enumerate() {
+        match op {
+            Op::Open(_)  => leftstack.push(i),
+            Op::Close(_) => if let Some(left) = leftstack.pop() {
+                matched[left] = true;
+                matched[i] = true;
+            },
+            _ => ()
+        }
+    }
+
+    let mut linked: Vec<(Op, Span)> = ops.into_iter().enumerate()
+        .filter(|&(i, (op, _))| matched[i] || !is_bracket(op))
+        .map(|(_, instr)| instr)
+        .collect();
+
+    for i in 0..linked.len() {
+        match linked[i].0 {
+            Op::Open(_)  => leftstack.push(i),
+            Op::Close(_) => {
+                // left is the position of the corresponding Open, i is the current position
+                let left = leftstack.pop().unwrap();
+                linked[left].0 = Op::Open(i);
+                linked[i].0 = Op::Close(left);
+            },
+            _ => ()
+        }
+    }
+    linked
+}
+
+fn is_bracket(op: Op) -> bool {
+    matches!(op, Op::Open(_) | Op::Close(_))
+}
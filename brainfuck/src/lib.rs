@@ -0,0 +1,408 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+
+pub mod bfio;
+pub mod check;
+pub mod debugger;
+mod dispatch;
+pub mod equiv;
+pub mod ir;
+pub mod optimizer;
+pub mod profile;
+mod tape;
+mod tests;
+
+use ir::{Op, SourceMap, Span};
+pub use tape::Tape;
+pub use bfio::BfIo;
+
+// The mutable state of a running program
+pub struct State {
+    pc: usize,
+    tape: Tape,
+    input: VecDeque<u8>,
+    // Once input is closed, ',' leaves the current cell unchanged instead of waiting for more
+    input_closed: bool
+}
+
+impl State {
+    // Start a program with all of its input available up front
+    pub fn new(input: &[u8]) -> State {
+        State { pc: 0, tape: Tape::new(), input: input.iter().cloned().collect(), input_closed: true }
+    }
+
+    // Start a program whose input will be provided as it runs
+    pub fn new_streaming() -> State {
+        State { pc: 0, tape: Tape::new(), input: VecDeque::new(), input_closed: false }
+    }
+
+    // Make more input available to ','
+    pub fn feed(&mut self, input: &[u8]) {
+        self.input.extend(input.iter().cloned());
+    }
+
+    // Signal that no more input is coming, so ',' on exhausted input leaves the cell unchanged
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn tape(&self) -> &Tape {
+        &self.tape
+    }
+}
+
+// What happened when a single instruction was executed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Step {
+    Continue,
+    // '.' wrote a byte
+    Output(u8),
+    // ',' found no input; the instruction will be retried on the next step
+    NeedInput
+}
+
+pub struct Program {
+    code: Vec<Op>,
+    // The source span each op was compiled from, indexed by pc
+    spans: Vec<Span>,
+    source_map: SourceMap,
+    threaded: Vec<dispatch::Instr>
+}
+
+impl Program {
+    pub fn new(source: &str) -> Program {
+        Program::with_opt_level(source, optimizer::DEFAULT_LEVEL)
+    }
+
+    // Compile the source, running the optimization passes selected by the level (see optimizer::optimize)
+    pub fn with_opt_level(source: &str, level: u32) -> Program {
+        let (code, spans): (Vec<Op>, Vec<Span>) = optimizer::optimize(ir::parse(source), level).into_iter().unzip();
+        let threaded = dispatch::compile(&code);
+        Program { code, spans, source_map: SourceMap::new(source), threaded }
+    }
+
+    pub fn code(&self) -> &[Op] {
+        &self.code
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    // The line and column in the source of the start of the op at pc
+    pub fn location(&self, pc: usize) -> Option<(usize, usize)> {
+        self.spans.get(pc).map(|span| self.source_map.location(span.start))
+    }
+
+    // The pc of the first op compiled from the given line and column or anything after it
+    pub fn pc_at(&self, line: usize, col: usize) -> Option<usize> {
+        let offset = self.source_map.offset(line, col)?;
+        self.spans.iter().position(|span| span.end > offset)
+    }
+
+    // Whether the program has run off the end of its code
+    pub fn finished(&self, state: &State) -> bool {
+        state.pc >= self.code.len()
+    }
+
+    // Execute the single instruction at the current pc
+    pub fn step(&self, state: &mut State) -> Step {
+        let mut step = Step::Continue;
+        let tape = &mut state.tape;
+        match self.code[state.pc] {
+            Op::Add(n)  => tape.add(n),
+            Op::Move(n) => tape.shift(n),
+            Op::Input   => match state.input.pop_front() {
+                Some(byte)                    => tape.setb(byte),
+                None if !state.input_closed   => return Step::NeedInput,
+                None                          => ()
+            },
+            // Skip to the corresponding closing bracket
+            Op::Open(close) if tape.get() == 0 => state.pc = close,
+            // Go back to the corresponding opening bracket
+            Op::Close(open) if tape.get() != 0 => state.pc = open,
+            // A scan that never finds a zero cell spins forever, just like the loop it replaced
+            Op::Scan(n) if !tape.scan(n) => return step,
+            // Likewise a clear loop on a cell that's moving away from zero
+            Op::Clear(n) if tape.get() != 0 && (tape.get() > 0) == (n > 0) => return step,
+            Op::Clear(_) => { let val = tape.get(); tape.add(-val) },
+            Op::MulAdd(offset, factor) => { let val = tape.get(); tape.add_at(offset, val * factor) },
+            Op::Output => step = Step::Output(tape.getb()),
+            _ => ()
+        }
+        state.pc += 1;
+        step
+    }
+
+    // The same as step, but through the handlers the program was compiled to rather than a
+    // match on the op.  This is what Execution runs; see examples/dispatch.rs for how they compare.
+    pub fn step_threaded(&self, state: &mut State) -> Step {
+        self.threaded[state.pc].exec(state)
+    }
+
+    // Run the program to completion, with its input and output going through io
+    pub fn run<T: BfIo>(&self, io: &mut T) {
+        self.execute().run(io);
+    }
+
+    // Run the program to completion on the given input, returning everything it wrote and what
+    // the run took.  Nothing goes through stdin or stdout, so a test can check the output
+    // directly.  Like run, this never returns if the program doesn't finish.
+    pub fn run_captured(&self, input: &[u8]) -> (Vec<u8>, RunStats) {
+        let mut execution = self.execute();
+        execution.feed(input);
+        execution.close_input();
+        let mut output = Vec::new();
+        while let Event::Output(byte) = execution.resume() {
+            output.push(byte);
+        }
+        let stats = RunStats { steps: execution.steps, input_read: input.len() - execution.state.input.len() };
+        (output, stats)
+    }
+
+    // Start running the program without any input, to be driven with Execution::resume
+    pub fn execute(&self) -> Execution<'_> {
+        Execution { program: self, state: State::new_streaming(), output_len: 0, max_output: None, steps: 0, max_steps: None, counts: None }
+    }
+}
+
+// What a run of a program took, from Program::run_captured
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RunStats {
+    // The number of instructions executed
+    pub steps: usize,
+    // How many bytes of the input ',' read
+    pub input_read: usize
+}
+
+// Why a streaming execution handed control back to its host
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    Output(u8),
+    // The program is waiting on ','; call feed (or close_input) and resume again
+    NeedInput,
+    Finished,
+    // The program tried to write more than the maximum output; it can't be resumed
+    OutputLimitExceeded,
+    // The program ran for the maximum number of steps without finishing; it can't be resumed
+    StepLimitExceeded
+}
+
+// A running program that a host application drives like a coroutine, rather than blocking
+// until it terminates: each call to resume runs until the program writes a byte, needs input
+// that hasn't been fed yet, or finishes.
+pub struct Execution<'a> {
+    program: &'a Program,
+    state: State,
+    output_len: usize,
+    max_output: Option<usize>,
+    steps: usize,
+    max_steps: Option<usize>,
+    counts: Option<Vec<usize>>
+}
+
+impl<'a> Execution<'a> {
+    // Make more input available to ','
+    pub fn feed(&mut self, input: &[u8]) {
+        self.state.feed(input);
+    }
+
+    // Signal that no more input is coming, so ',' on exhausted input leaves the cell unchanged
+    pub fn close_input(&mut self) {
+        self.state.close_input();
+    }
+
+    // Count how many times each instruction executes from now on, for coverage and profiling
+    pub fn count_steps(&mut self) {
+        self.counts = Some(vec![0; self.program.code().len()]);
+    }
+
+    // How many times each instruction has executed, indexed by pc, if count_steps was called
+    pub fn counts(&self) -> Option<&[usize]> {
+        self.counts.as_deref()
+    }
+
+    // Abort the program once it has written the given number of bytes and tries to write another.
+    // This makes it safe to run random or adversarial programs that would otherwise flood the host.
+    pub fn set_max_output(&mut self, max_output: usize) {
+        self.max_output = Some(max_output);
+    }
+
+    // Abort the program once it has executed the given number of instructions without finishing
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = Some(max_steps);
+    }
+
+    pub fn resume(&mut self) -> Event {
+        loop {
+            if let Some(event) = self.resume_for(usize::MAX) {
+                return event;
+            }
+        }
+    }
+
+    // Like resume, but hand control back after executing at most budget instructions, returning
+    // None if the program was still running then.  A host with its own event loop (a GUI, say)
+    // can call this repeatedly to run a long program without becoming unresponsive.
+    pub fn resume_for(&mut self, budget: usize) -> Option<Event> {
+        if self.max_output.is_some_and(|max| self.output_len > max) {
+            return Some(Event::OutputLimitExceeded);
+        }
+
+        for _ in 0..budget {
+            if self.program.finished(&self.state) {
+                return Some(Event::Finished);
+            }
+            if self.max_steps.is_some_and(|max| self.steps >= max) {
+                return Some(Event::StepLimitExceeded);
+            }
+            self.steps += 1;
+            if let Some(ref mut counts) = self.counts {
+                counts[self.state.pc] += 1;
+            }
+            match self.program.step_threaded(&mut self.state) {
+                Step::Continue     => (),
+                Step::Output(byte) => {
+                    self.output_len += 1;
+                    if self.max_output.is_some_and(|max| self.output_len > max) {
+                        return Some(Event::OutputLimitExceeded);
+                    }
+                    return Some(Event::Output(byte));
+                },
+                Step::NeedInput    => {
+                    // The ',' will be retried, so it hasn't executed yet
+                    self.steps -= 1;
+                    if let Some(ref mut counts) = self.counts {
+                        counts[self.state.pc] -= 1;
+                    }
+                    return Some(Event::NeedInput);
+                }
+            }
+        }
+        if self.program.finished(&self.state) {
+            Some(Event::Finished)
+        } else {
+            None
+        }
+    }
+
+    // Resume until the program finishes or exceeds a limit, connecting its I/O to io
+    fn run<T: BfIo + ?Sized>(&mut self, io: &mut T) -> Event {
+        loop {
+            match self.resume() {
+                Event::Output(byte) => io.write(byte),
+                Event::NeedInput    => match io.read() {
+                    Some(byte) => self.feed(&[byte]),
+                    None       => self.close_input()
+                },
+                event               => return event
+            }
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+}
+
+// Runs a program to completion, reading ',' input from one hook and sending '.' output to
+// another, so embedders can connect a GUI, a socket, or a test harness instead of OS stdio.
+// By default input comes from stdin and output goes to stdout.
+pub struct Interpreter<'a> {
+    execution: Execution<'a>,
+    hooks: Hooks<'a>
+}
+
+struct Hooks<'a> {
+    input_fn: Box<dyn FnMut() -> Option<u8> + 'a>,
+    output_fn: Box<dyn FnMut(u8) + 'a>
+}
+
+impl<'a> BfIo for Hooks<'a> {
+    fn read(&mut self) -> Option<u8> {
+        (self.input_fn)()
+    }
+
+    fn write(&mut self, byte: u8) {
+        (self.output_fn)(byte)
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(program: &'a Program) -> Interpreter<'a> {
+        Interpreter {
+            execution: program.execute(),
+            hooks: Hooks {
+                input_fn: Box::new(read_stdin_byte),
+                output_fn: Box::new(|byte| io::stdout().write_all(&[byte]).expect("Could not write to stdout"))
+            }
+        }
+    }
+
+    // Called whenever ',' needs a byte; None means the input has ended, and every later ','
+    // leaves the current cell unchanged
+    pub fn set_input_fn<F: FnMut() -> Option<u8> + 'a>(&mut self, input_fn: F) {
+        self.hooks.input_fn = Box::new(input_fn);
+    }
+
+    // Called with every byte written by '.'
+    pub fn set_output_fn<F: FnMut(u8) + 'a>(&mut self, output_fn: F) {
+        self.hooks.output_fn = Box::new(output_fn);
+    }
+
+    pub fn set_max_output(&mut self, max_output: usize) {
+        self.execution.set_max_output(max_output);
+    }
+
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.execution.set_max_steps(max_steps);
+    }
+
+    pub fn count_steps(&mut self) {
+        self.execution.count_steps();
+    }
+
+    pub fn counts(&self) -> Option<&[usize]> {
+        self.execution.counts()
+    }
+
+    // Run until the program finishes (Event::Finished) or exceeds one of its limits
+    // (Event::OutputLimitExceeded or Event::StepLimitExceeded)
+    pub fn run(&mut self) -> Event {
+        self.execution.run(&mut self.hooks)
+    }
+
+    pub fn state(&self) -> &State {
+        self.execution.state()
+    }
+}
+
+// Wrap an input hook so that every CR LF pair it reads becomes a single LF, for programs that
+// expect Unix newlines but are given input with Windows ones
+pub fn translate_crlf<F: FnMut() -> Option<u8>>(mut read: F) -> impl FnMut() -> Option<u8> {
+    let mut pending = None;
+    move || {
+        let byte = pending.take().or_else(&mut read);
+        if byte == Some(b'\r') {
+            match read() {
+                Some(b'\n') => return Some(b'\n'),
+                next         => pending = next
+            }
+        }
+        byte
+    }
+}
+
+// The default input hook: read one byte from stdin, or None at the end of stdin
+pub fn read_stdin_byte() -> Option<u8> {
+    let mut byte = [0];
+    match io::stdin().read(&mut byte) {
+        Ok(1) => Some(byte[0]),
+        _     => None
+    }
+}
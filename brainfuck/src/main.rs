@@ -1,146 +1,229 @@
 use std::fs::File;
 use std::path::Path;
+use std::io;
 use std::io::prelude::*;
 use std::vec::Vec;
 use std::env;
-use std::collections::BTreeMap;
+use std::process;
+use std::process::Command;
+use std::io::IsTerminal;
 
-struct Tape {
-    pos: usize,
-    tape: Vec<isize>
+extern crate brainfuck;
+
+mod cli;
+mod coverage;
+
+use brainfuck::{Program, Interpreter, Event};
+use brainfuck::{debugger, optimizer, profile};
+use cli::{flag_value, opt_level_value};
+
+struct Options {
+    max_output: Option<usize>,
+    max_steps: Option<usize>,
+    // A file to write every byte consumed by ',' to, so the run can be replayed later
+    record: Option<String>,
+    // Whether CR LF in the input is read as a single LF
+    translate_crlf: bool,
+    // Whether to count how many times each instruction executes, for --coverage and --profile
+    count_steps: bool
 }
 
-impl Tape {
-    fn new() -> Tape {
-        Tape { pos: 0, tape: vec![0] }
-    }
-    
-    // Get the value at the current position
-    fn get(&self) -> isize {
-        self.tape[self.pos]
-    }
-    
-    // Get the character value at the current position
-    fn getc(&self) -> char {
-        self.tape[self.pos] as u8 as char
+// Where ',' reads from
+enum Input {
+    // Exactly these bytes, followed by the end of the input
+    Bytes(Vec<u8>),
+    // These bytes, followed by whatever is read from stdin as the program asks for it
+    Stdin(Vec<u8>)
+}
+
+// The hook that ',' reads the input through, translating CR LF and recording every byte it
+// reads if the options say to
+fn input_fn(input: Input, options: &Options) -> Box<dyn FnMut() -> Option<u8>> {
+    let mut read: Box<dyn FnMut() -> Option<u8>> = match input {
+        Input::Bytes(bytes) => {
+            let mut bytes = bytes.into_iter();
+            Box::new(move || bytes.next())
+        },
+        Input::Stdin(prefix) => {
+            let mut prefix = prefix.into_iter();
+            Box::new(move || prefix.next().or_else(brainfuck::read_stdin_byte))
+        }
+    };
+    if options.translate_crlf {
+        read = Box::new(brainfuck::translate_crlf(read));
     }
-    
-    // Set the current position to the given character
-    fn setc(&mut self, char: char) {
-        self.tape[self.pos] = char as isize;
+    let mut log = options.record.as_ref().map(|path| File::create(path).unwrap_or_else(|err| {
+        eprintln!("Error: could not create {}: {}", path, err);
+        process::exit(2);
+    }));
+
+    Box::new(move || {
+        let byte = read();
+        if let (Some(byte), Some(log)) = (byte, log.as_mut()) {
+            log.write_all(&[byte]).expect("Could not write to the record file");
+        }
+        byte
+    })
+}
+
+// Run the program, writing its output to stdout as it's produced.  If the options count steps,
+// this also gives how many times each instruction executed, even when a limit stopped the run.
+fn run(program: &Program, input: Input, options: &Options) -> (Result<(), String>, Option<Vec<usize>>) {
+    let Options { max_output, max_steps, count_steps, .. } = *options;
+
+    let mut interpreter = Interpreter::new(program);
+    interpreter.set_input_fn(input_fn(input, options));
+    if count_steps {
+        interpreter.count_steps();
     }
-    
-    // Increment the current position
-    fn inc(&mut self) {
-        self.tape[self.pos] += 1;
+    if let Some(max_output) = max_output {
+        interpreter.set_max_output(max_output);
     }
-    
-    // Decrement the current position
-    fn dec(&mut self) {
-        self.tape[self.pos] -= 1;
+    if let Some(max_steps) = max_steps {
+        interpreter.set_max_steps(max_steps);
     }
-    
-    // Advance the pointer
-    fn adv(&mut self) {
-        self.pos += 1;
-        if self.tape.len() <= self.pos {
-            self.tape.push(0);
+
+    let event = interpreter.run();
+    io::stdout().flush().expect("Could not flush stdout");
+    // After the output limit, pc has already moved past the '.' that exceeded it
+    let pc = match event {
+        Event::OutputLimitExceeded => interpreter.state().pc() - 1,
+        _                          => interpreter.state().pc()
+    };
+    let location = match program.location(pc) {
+        Some((line, col)) => format!("line {}, col {}", line, col),
+        None              => "end of program".to_string()
+    };
+    let result = match event {
+        Event::OutputLimitExceeded => Err(format!("output exceeded the limit of {} bytes at {}", max_output.unwrap_or(0), location)),
+        Event::StepLimitExceeded   => Err(format!("program didn't finish within {} steps, stopped at {}", max_steps.unwrap_or(0), location)),
+        _                          => Ok(())
+    };
+    (result, interpreter.counts().map(|counts| counts.to_vec()))
+}
+
+// While this is alive, a terminal on stdin sends each key as soon as it's pressed rather than
+// a line at a time.  The terminal's previous settings are restored when it's dropped.
+struct RawInput {
+    saved: Option<String>
+}
+
+impl RawInput {
+    fn new() -> RawInput {
+        if !io::stdin().is_terminal() {
+            return RawInput { saved: None };
         }
+        let saved = Command::new("stty").arg("-g").output().ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        if saved.is_some() {
+            let _ = Command::new("stty").args(["-icanon", "min", "1"]).status();
+        }
+        RawInput { saved }
     }
-    
-    // Devance the pointer
-    fn dev(&mut self) {
-        if self.pos > 0 {
-            self.pos -= 1;
+}
+
+impl Drop for RawInput {
+    fn drop(&mut self) {
+        if let Some(ref saved) = self.saved {
+            let _ = Command::new("stty").arg(saved).status();
         }
     }
 }
 
-struct Program {
-    code: Vec<char>,
-    loop_map: BTreeMap<usize, usize>,
-    stdin: Vec<char>
+// Get the file name or text following a flag like --record
+fn path_value(flag: &str, value: Option<String>) -> String {
+    value.unwrap_or_else(|| {
+        eprintln!("Error: {} requires a value", flag);
+        process::exit(2);
+    })
 }
 
-impl Program {
-    fn new(source: String, stdin: String) -> Program {
-        let mut code: Vec<char> = Vec::new();
-        let mut loop_map = BTreeMap::new();
-        let mut leftstack = Vec::new(); // A stack of positions of left brackets '['
-        let mut pc = 0;
-        
-        for c in source.chars() {
-            match c {
-                '+' | '-' | '.' | ',' | '<' | '>' => (),
-                '[' => { leftstack.push(pc); },
-                ']' => match leftstack.pop() {
-                    Some(left) => {
-                        // left is the position of the corresponding left bracket, pc is the current position
-                        loop_map.insert(left, pc);
-                        loop_map.insert(pc, left);
-                    }
-                    None => {}
-                },
-                _ => { continue; }
-            }
-            code.push(c);
-            pc += 1;
-        }
-        Program{ code: code, loop_map: loop_map, stdin: stdin.chars().collect() }
+fn main() {
+    match env::args().nth(1).as_deref() {
+        Some("equiv") => { cli::equiv(env::args().skip(2)); return; },
+        Some("check") => { cli::check(env::args().skip(2)); return; },
+        _             => ()
     }
-    
-    fn run(&self) {
-        let mut pc: usize = 0;
-        let mut spc: usize = 0;
-        let len = self.code.len();
-        let mut tape = Tape::new();
-        
-        while pc < len {
-            match self.code[pc] {
-                '+' => tape.inc(),
-                '-' => tape.dec(),
-                '>' => tape.adv(),
-                '<' => tape.dev(),
-                ',' => {
-                    if spc < self.stdin.len() {
-                        tape.setc(self.stdin[spc]);
-                        spc += 1;
-                    }
-                },
-                '[' => {
-                    if tape.get() == 0 {
-                        // Skip to the corresponding closing bracket
-                        pc = self.loop_map[&pc];
-                    }
-                },
-                ']' => {
-                    if tape.get() != 0 {
-                        // Go back to the corresponding opening bracket
-                        pc = self.loop_map[&pc]
-                    }
-                },
-                '.' => { print!("{}", tape.getc()); },
-                _ => ()
-            }
-            pc += 1;
+
+    let mut debug = false;
+    let mut coverage = false;
+    let mut profile = false;
+    let mut dump_ir = false;
+    let mut opt_level = optimizer::DEFAULT_LEVEL;
+    let mut max_output = None;
+    let mut max_steps = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut translate_crlf = false;
+    let mut raw_input = false;
+    let mut prefix = None;
+    let mut args: Vec<String> = Vec::new();
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--debug"      => debug = true,
+            "--coverage"   => coverage = true,
+            "--profile"    => profile = true,
+            "--dump-ir"    => dump_ir = true,
+            "--max-output" => max_output = Some(flag_value(&arg, argv.next())),
+            "--max-steps"  => max_steps = Some(flag_value(&arg, argv.next())),
+            "--record"     => record = Some(path_value(&arg, argv.next())),
+            "--replay"     => replay = Some(path_value(&arg, argv.next())),
+            "--input"      => prefix = Some(path_value(&arg, argv.next()).into_bytes()),
+            "--crlf"       => translate_crlf = true,
+            "--raw"        => raw_input = true,
+            _ if arg.starts_with("-O") => opt_level = opt_level_value(&arg),
+            _              => args.push(arg)
         }
     }
-}
 
-fn main() {
-    let arg1 = env::args().nth(1).unwrap();
-    let arg2 = env::args().nth(2);
-    
-    let path = Path::new(&arg1);
+    let path = Path::new(&args[0]);
     let mut source = String::new();
-    let mut file = File::open(&path).unwrap();
+    let mut file = File::open(path).unwrap();
     file.read_to_string(&mut source).unwrap();
-    
-    let mut stdin: String = String::new();
-    match arg2 {
-        Some(s) => stdin = s,
-        None => ()
+
+    // Input comes from a replayed session, the second argument, or (when running normally) the
+    // --input text followed by stdin
+    let input = match (replay, args.get(1)) {
+        (Some(path), _) => {
+            let mut bytes = Vec::new();
+            if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+                eprintln!("Error: could not read {}: {}", path, err);
+                process::exit(2);
+            }
+            Input::Bytes(bytes)
+        },
+        (None, Some(arg)) => Input::Bytes(arg.clone().into_bytes()),
+        (None, None)      => Input::Stdin(prefix.unwrap_or_default())
+    };
+    let options = Options { max_output, max_steps, record, translate_crlf, count_steps: coverage || profile };
+
+    let program = Program::with_opt_level(&source, opt_level);
+    if dump_ir {
+        for (pc, op) in program.code().iter().enumerate() {
+            println!("{:5}  {}", pc, op);
+        }
+    } else if debug {
+        debugger::Debugger::new(&program, input_fn(input, &options)).run();
+    } else {
+        let (result, counts) = {
+            let _raw = match input {
+                Input::Stdin(_) if raw_input => Some(RawInput::new()),
+                _                            => None
+            };
+            run(&program, input, &options)
+        };
+        if let Some(counts) = counts {
+            if profile {
+                profile::report(&program, &counts);
+            } else {
+                coverage::report(&program, &source, &counts);
+            }
+        }
+        if let Err(err) = result {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
     }
-    
-    Program::new(source, stdin).run();
-}
\ No newline at end of file
+}
@@ -0,0 +1,132 @@
+use ir;
+use ir::{Op, Span};
+
+// The level used when none is given: every pass that exactly preserves the program's behavior
+pub const DEFAULT_LEVEL: u32 = 1;
+
+// Run the optimization passes selected by the level over the (unlinked) ops and link the result:
+//   0: none, one op per command
+//   1: run-length encoding, clear loops and scan loops
+//   2: also multiply loops, which assume the loop never moves left of the first cell
+// Each pass keeps track of the source span that every op it produces came from.
+pub fn optimize(ops: Vec<(Op, Span)>, level: u32) -> Vec<(Op, Span)> {
+    let ops = match level {
+        0 => ops,
+        1 => scan_loops(clear_loops(run_lengths(ops))),
+        _ => scan_loops(multiply_loops(clear_loops(run_lengths(ops))))
+    };
+    ir::link(ops)
+}
+
+// Merge runs of + and - into a single Add, and runs of > or of < into a single Move.
+// Moves in opposite directions aren't merged because moving left stops at the end of the tape.
+pub fn run_lengths(ops: Vec<(Op, Span)>) -> Vec<(Op, Span)> {
+    let mut result: Vec<(Op, Span)> = Vec::with_capacity(ops.len());
+    for (op, span) in ops {
+        let merged = match (result.last().map(|&(last, _)| last), op) {
+            (Some(Op::Add(a)), Op::Add(b))                        => Some(Op::Add(a + b)),
+            (Some(Op::Move(a)), Op::Move(b)) if (a > 0) == (b > 0) => Some(Op::Move(a + b)),
+            _                                                     => None
+        };
+        match merged {
+            Some(merged) => {
+                let last = result.last_mut().unwrap();
+                *last = (merged, last.1.join(span));
+            },
+            None => result.push((op, span))
+        }
+    }
+    result
+}
+
+// Replace [-] and [+] with a single Clear
+pub fn clear_loops(ops: Vec<(Op, Span)>) -> Vec<(Op, Span)> {
+    let mut result = Vec::with_capacity(ops.len());
+    let mut i = 0;
+    while i < ops.len() {
+        match (ops[i], ops.get(i + 1), ops.get(i + 2)) {
+            ((Op::Open(_), open), Some(&(Op::Add(n), _)), Some(&(Op::Close(_), close))) if n == 1 || n == -1 => {
+                result.push((Op::Clear(n), open.join(close)));
+                i += 3;
+            },
+            (instr, _, _) => {
+                result.push(instr);
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+// Replace loops like [->+>++<<], which only add and move, end where they started and decrement
+// the current cell once per iteration, with a MulAdd for every other cell they change and a Clear
+pub fn multiply_loops(ops: Vec<(Op, Span)>) -> Vec<(Op, Span)> {
+    let mut result = Vec::with_capacity(ops.len());
+    let mut i = 0;
+    while i < ops.len() {
+        match multiply_loop(&ops[i..]) {
+            Some((len, targets)) => {
+                let span = ops[i].1.join(ops[i + len - 1].1);
+                for (offset, factor) in targets {
+                    result.push((Op::MulAdd(offset, factor), span));
+                }
+                result.push((Op::Clear(-1), span));
+                i += len;
+            },
+            None => {
+                result.push(ops[i]);
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+// If the ops start with a multiply loop, get its length and the (offset, factor) of every target
+fn multiply_loop(ops: &[(Op, Span)]) -> Option<(usize, Vec<(isize, isize)>)> {
+    match ops.first() {
+        Some(&(Op::Open(_), _)) => (),
+        _                       => return None
+    }
+
+    let mut offset = 0;
+    let mut targets: Vec<(isize, isize)> = Vec::new();
+    for (i, &(op, _)) in ops.iter().enumerate().skip(1) {
+        match op {
+            Op::Move(n) => offset += n,
+            Op::Add(n)  => match targets.iter_mut().find(|&&mut (target, _)| target == offset) {
+                Some(target) => target.1 += n,
+                None         => targets.push((offset, n))
+            },
+            Op::Close(_) => {
+                if offset != 0 || !targets.contains(&(0, -1)) {
+                    return None;
+                }
+                targets.retain(|&(target, factor)| target != 0 && factor != 0);
+                return Some((i + 1, targets));
+            },
+            _ => return None
+        }
+    }
+    None
+}
+
+// Replace [>] and [<] (and wider strides like [>>>]) with a single Scan, which searches the
+// tape directly instead of executing the loop one instruction at a time
+pub fn scan_loops(ops: Vec<(Op, Span)>) -> Vec<(Op, Span)> {
+    let mut result = Vec::with_capacity(ops.len());
+    let mut i = 0;
+    while i < ops.len() {
+        match (ops[i], ops.get(i + 1), ops.get(i + 2)) {
+            ((Op::Open(_), open), Some(&(Op::Move(n), _)), Some(&(Op::Close(_), close))) if n != 0 => {
+                result.push((Op::Scan(n), open.join(close)));
+                i += 3;
+            },
+            (instr, _, _) => {
+                result.push(instr);
+                i += 1;
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,39 @@
+use Program;
+use ir::Op;
+
+// The widest bar in the histogram
+const BAR_WIDTH: usize = 40;
+
+// Print the histogram to stderr
+pub fn report(program: &Program, counts: &[usize]) {
+    for line in histogram(program, counts) {
+        eprintln!("{}", line);
+    }
+}
+
+// A histogram of how many iterations each loop ran, busiest first, with the share of all the
+// steps that were spent inside it (including any loops nested in it), given how many times each
+// instruction executed
+pub fn histogram(program: &Program, counts: &[usize]) -> Vec<String> {
+    let total: usize = counts.iter().sum();
+    // Every iteration of a loop ends at its ']', so that's run once per iteration
+    let mut loops: Vec<(usize, usize, usize, usize)> = program.code().iter().enumerate().filter_map(|(pc, op)| match *op {
+        Op::Open(close) => Some((pc, close, counts[close], counts[pc..=close].iter().sum())),
+        _               => None
+    }).collect();
+    loops.sort_by(|a, b| b.2.cmp(&a.2).then(b.3.cmp(&a.3)).then(a.0.cmp(&b.0)));
+
+    let mut lines = vec![format!("Loop profile: {} steps, {} loops", total, loops.len())];
+    let max = loops.first().map_or(0, |&(_, _, iterations, _)| iterations);
+    for (open, close, iterations, steps) in loops {
+        // From the '[' to the ']', which the optimizer always leaves as ops of their own
+        let (start_line, start_col) = program.location(open).unwrap();
+        let (end_line, end_col) = program.location(close).unwrap();
+        let span = format!("{}:{}-{}:{}", start_line, start_col, end_line, end_col);
+        let percent = if total == 0 { 0.0 } else { 100.0 * steps as f64 / total as f64 };
+        let bar = if max == 0 { 0 } else { (iterations * BAR_WIDTH).div_ceil(max) };
+        let line = format!("{:<16} {:>10} iterations {:>12} steps {:>5.1}%  {}", span, iterations, steps, percent, "#".repeat(bar));
+        lines.push(line.trim_end().to_string());
+    }
+    lines
+}
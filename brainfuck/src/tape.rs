@@ -0,0 +1,104 @@
+pub struct Tape {
+    pos: usize,
+    tape: Vec<isize>
+}
+
+impl Default for Tape {
+    fn default() -> Tape {
+        Tape::new()
+    }
+}
+
+impl Tape {
+    pub fn new() -> Tape {
+        Tape { pos: 0, tape: vec![0] }
+    }
+
+    // Get the value at the current position
+    pub fn get(&self) -> isize {
+        self.tape[self.pos]
+    }
+
+    // Get the value at an arbitrary position (cells that haven't been reached yet are 0)
+    pub fn get_at(&self, pos: usize) -> isize {
+        if pos < self.tape.len() { self.tape[pos] } else { 0 }
+    }
+
+    // Get the position of the pointer
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    // Get every cell that has been reached so far
+    pub fn cells(&self) -> &[isize] {
+        &self.tape
+    }
+
+    // Get the byte value at the current position
+    pub fn getb(&self) -> u8 {
+        self.tape[self.pos] as u8
+    }
+
+    // Set the current position to the given byte
+    pub fn setb(&mut self, byte: u8) {
+        self.tape[self.pos] = byte as isize;
+    }
+
+    // Add to the value at the current position
+    pub fn add(&mut self, n: isize) {
+        self.tape[self.pos] += n;
+    }
+
+    // Add to the value at an offset from the current position, without moving the pointer.
+    // Like shift, offsets past the left end of the tape stop at the first cell.
+    pub fn add_at(&mut self, offset: isize, n: isize) {
+        let pos = if offset >= 0 {
+            self.pos + offset as usize
+        } else {
+            self.pos.saturating_sub(-offset as usize)
+        };
+        if self.tape.len() <= pos {
+            self.tape.resize(pos + 1, 0);
+        }
+        self.tape[pos] += n;
+    }
+
+    // Move the pointer right (positive) or left (negative), stopping at the left end of the tape
+    pub fn shift(&mut self, n: isize) {
+        if n >= 0 {
+            self.pos += n as usize;
+            if self.tape.len() <= self.pos {
+                self.tape.resize(self.pos + 1, 0);
+            }
+        } else {
+            self.pos = self.pos.saturating_sub(-n as usize);
+        }
+    }
+
+    // Move the pointer in steps of the given size until it reaches a zero cell.
+    // Returns false if there is no such cell, in which case the equivalent loop never terminates.
+    pub fn scan(&mut self, step: isize) -> bool {
+        if step > 0 {
+            let step = step as usize;
+            match self.tape[self.pos..].iter().step_by(step).position(|&val| val == 0) {
+                Some(n) => self.pos += n * step,
+                None    => {
+                    // Every cell past the end of the tape is zero
+                    let remaining = self.tape.len() - self.pos;
+                    self.shift((remaining.div_ceil(step) * step) as isize);
+                }
+            }
+            true
+        } else {
+            let step = -step as usize;
+            match self.tape[..=self.pos].iter().rev().step_by(step).position(|&val| val == 0) {
+                Some(n) => { self.pos -= n * step; true },
+                None    => {
+                    // The last step runs into the left end of the tape
+                    self.pos = 0;
+                    self.get() == 0
+                }
+            }
+        }
+    }
+}
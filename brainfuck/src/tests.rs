@@ -0,0 +1,223 @@
+#![cfg(test)]
+use {Program, Event, RunStats, Interpreter};
+use bfio::Buffer;
+use equiv::{self, Comparison};
+use debugger::Debugger;
+use check;
+use profile;
+
+const HELLO_WORLD: &str = include_str!("../hello_world.bf");
+const REVERSE: &str = include_str!("../reverse_stdin.bf");
+
+#[test]
+fn test_hello_world() {
+    let (output, stats) = Program::new(HELLO_WORLD).run_captured(&[]);
+    assert_eq!(output, b"Hello World!\n");
+    assert_eq!(stats.input_read, 0);
+    assert!(stats.steps > 0);
+}
+
+#[test]
+fn test_input() {
+    let (output, stats) = Program::new(REVERSE).run_captured(b"abc");
+    assert_eq!(output, b"cba");
+    assert_eq!(stats.input_read, 3);
+
+    // ',' at the end of the input leaves the cell alone
+    let (output, stats) = Program::new(",.,.").run_captured(b"x");
+    assert_eq!(output, b"xx");
+    assert_eq!(stats, RunStats { steps: 4, input_read: 1 });
+}
+
+// Every optimization level gives the same output, in no more steps
+#[test]
+fn test_opt_levels() {
+    let programs = [HELLO_WORLD, "++++[>++++[>+++<-]<-]>>.", "+++++[>+>++<<-]>>[<+>-]<.", ">>+++[<]+."];
+    for source in &programs {
+        let (unoptimized, unoptimized_stats) = Program::with_opt_level(source, 0).run_captured(&[]);
+        for level in 1..=2 {
+            let (output, stats) = Program::with_opt_level(source, level).run_captured(&[]);
+            assert_eq!(output, unoptimized, "-O{} on {}", level, source);
+            assert!(stats.steps <= unoptimized_stats.steps);
+        }
+    }
+}
+
+#[test]
+fn test_unmatched_brackets() {
+    // Unmatched brackets are dropped rather than being errors
+    let (output, _) = Program::new("+++[.-]]").run_captured(&[]);
+    assert_eq!(output, [3, 2, 1]);
+}
+
+#[test]
+fn test_limits() {
+    let program = Program::new("+[.]");
+    let mut execution = program.execute();
+    execution.set_max_output(3);
+    for _ in 0..3 {
+        assert_eq!(execution.resume(), Event::Output(1));
+    }
+    assert_eq!(execution.resume(), Event::OutputLimitExceeded);
+
+    let program = Program::new("+[]");
+    let mut execution = program.execute();
+    execution.set_max_steps(100);
+    assert_eq!(execution.resume(), Event::StepLimitExceeded);
+
+    // A limit of exactly the steps a program takes lets it finish
+    let program = Program::new(HELLO_WORLD);
+    let (_, stats) = program.run_captured(&[]);
+    let mut execution = program.execute();
+    execution.set_max_steps(stats.steps);
+    while let Event::Output(_) = execution.resume() {}
+    assert!(program.finished(execution.state()));
+    let mut execution = program.execute();
+    execution.set_max_steps(stats.steps - 1);
+    while let Event::Output(_) = execution.resume() {}
+    assert_eq!(execution.resume(), Event::StepLimitExceeded);
+}
+
+#[test]
+fn test_equiv() {
+    let reverse = Program::new(REVERSE);
+    let cat = Program::new(",[.[-],]");
+    assert_eq!(equiv::compare(&reverse, &Program::with_opt_level(REVERSE, 0), b"abc", 1000), Comparison::Same);
+    assert_eq!(equiv::compare(&reverse, &cat, b"ab", 1000), Comparison::Differ(0, b"ba".to_vec(), b"ab".to_vec()));
+    // One output being a prefix of the other is a difference too
+    assert_eq!(equiv::compare(&cat, &Program::new(",[.[-],]+."), b"a", 1000), Comparison::Differ(1, b"a".to_vec(), b"a\x01".to_vec()));
+
+    // A program that hits the step limit might still have gone on to match,
+    let forever = Program::new("+[]");
+    assert_eq!(equiv::compare(&cat, &forever, b"", 1000), Comparison::Inconclusive);
+    // unless it had already written something different
+    assert_eq!(equiv::compare(&cat, &Program::new("+.[]"), b"a", 1000), Comparison::Differ(0, b"a".to_vec(), b"\x01".to_vec()));
+}
+
+#[test]
+fn test_streaming_input() {
+    let program = Program::new(",[.,]");
+    let mut execution = program.execute();
+    assert_eq!(execution.resume(), Event::NeedInput);
+    execution.feed(b"hi");
+    assert_eq!(execution.resume(), Event::Output(b'h'));
+    assert_eq!(execution.resume(), Event::Output(b'i'));
+    assert_eq!(execution.resume(), Event::NeedInput);
+    execution.feed(&[0]);
+    assert_eq!(execution.resume(), Event::Finished);
+}
+
+#[test]
+fn test_io() {
+    let program = Program::new(">,[.>,]");
+    let mut io = Buffer::new(b"abc");
+    program.run(&mut io);
+    assert_eq!(io.output(), b"abc");
+
+    let mut output = Vec::new();
+    let mut input = b"xyz".iter().cloned();
+    {
+        let mut interpreter = Interpreter::new(&program);
+        interpreter.set_input_fn(move || input.next());
+        interpreter.set_output_fn(|byte| output.push(byte));
+        assert_eq!(interpreter.run(), Event::Finished);
+    }
+    assert_eq!(output, b"xyz");
+}
+
+#[test]
+fn test_debugger() {
+    let program = Program::new(HELLO_WORLD);
+    let mut output = Vec::new();
+    {
+        let mut debugger = Debugger::new(&program, Box::new(|| None));
+        debugger.set_output(&mut output);
+        assert!(debugger.command("watch 1"));
+        assert!(debugger.command("continue"));
+        assert!(debugger.command("unwatch 1"));
+        assert!(debugger.command("break 2:1"));
+        assert!(debugger.command("continue"));
+        assert!(debugger.command("break 999"));
+        assert!(debugger.command("step 0"));
+        assert!(debugger.command("step 2"));
+        assert!(debugger.command("break nowhere"));
+        assert!(!debugger.command("quit"));
+    }
+    // The program's output ("He", then "l") is interleaved with the debugger's
+    assert_eq!(String::from_utf8(output).unwrap(), "\
+Watchpoint: cell 1 changed from 0 to 8 at pc 1 (line 1, col 2)
+pc 2 (line 1, col 10): '['
+Breakpoint at pc 38 (line 2, col 1)
+Hepc 38 (line 2, col 1): 'add 3'
+Invalid breakpoint: the program ends at pc 74
+Invalid step count (expected at least 1)
+lpc 40 (line 2, col 5): '.'
+Invalid breakpoint (expected a pc or line:col)
+");
+
+    // ',' reads through the input hook as it's reached
+    let program = Program::new(",>,.");
+    let mut input = b"A".iter().cloned();
+    let mut output = Vec::new();
+    {
+        let mut debugger = Debugger::new(&program, Box::new(move || input.next()));
+        debugger.set_output(&mut output);
+        assert!(debugger.command("step 2"));
+        assert!(debugger.command("tape"));
+        assert!(debugger.command("continue"));
+    }
+    assert_eq!(String::from_utf8(output).unwrap(), "\
+pc 2 (line 1, col 3): ','
+ptr 1: 65 [0]
+\0Program finished
+");
+}
+
+#[test]
+fn test_check() {
+    let report = check::check("dead.bf", "[+]++[-][]>");
+    assert_eq!(report.summary, [
+        "dead.bf: 11 instructions",
+        "  + 3  - 1  < 0  > 1  . 0  , 0  [ 3  ] 3",
+        "  3 loops, nested at most 1 deep"
+    ]);
+    assert_eq!(report.warnings, [
+        "line 1, col 1: loop never runs, since the current cell is always zero here",
+        "line 1, col 9: loop never runs, since the loop before it leaves the current cell zero"
+    ]);
+    assert!(report.errors.is_empty());
+
+    // An empty loop that's entered makes the rest unreachable
+    let report = check::check("spin.bf", "+[]>+.");
+    assert_eq!(report.warnings, ["line 1, col 4: unreachable, since the empty loop before it never finishes"]);
+
+    let report = check::check("unmatched.bf", "+[[-]\n]]");
+    assert_eq!(report.errors, ["line 2, col 2: unmatched ']'"]);
+    let report = check::check("unmatched.bf", "+[\n[-]");
+    assert_eq!(report.errors, ["line 1, col 2: unmatched '['"]);
+}
+
+#[test]
+fn test_profile() {
+    let program = Program::new(HELLO_WORLD);
+    let mut execution = program.execute();
+    execution.count_steps();
+    while let Event::Output(_) = execution.resume() {}
+    assert_eq!(profile::histogram(&program, execution.counts().unwrap()), [
+        "Loop profile: 449 steps, 5 loops",
+        "1:45-1:57                49 iterations          347 steps  77.3%  ########################################",
+        "1:10-1:23                 8 iterations           41 steps   9.1%  #######",
+        "1:43-1:60                 4 iterations          360 steps  80.2%  ####",
+        "2:16-2:25                 0 iterations            1 steps   0.2%",
+        "2:18-2:22                 0 iterations            0 steps   0.0%"
+    ]);
+
+    // Waiting for input doesn't count as running the ','
+    let program = Program::new(",[.,]");
+    let mut execution = program.execute();
+    execution.count_steps();
+    assert_eq!(execution.resume(), Event::NeedInput);
+    execution.feed(b"ab\0");
+    while let Event::Output(_) = execution.resume() {}
+    assert_eq!(execution.counts().unwrap(), [1, 1, 2, 2, 2]);
+}
@@ -0,0 +1,256 @@
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use interpreter::Value as Value;
+use parser::{Node, Location};
+
+// The forms read from library files are kept in a cache directory, so a file that hasn't changed
+// since it was last read doesn't have to be parsed again.  Each source's forms go in a file named
+// by a hash of the source, in a binary form that's much quicker to read back than the source.
+// The cache only ever saves time: a file that's missing, unreadable or in an older form is just
+// parsed again, and one that can't be written isn't cached.
+
+// Starts every cache file, and changes whenever the form does
+const MAGIC: &[u8] = b"rscheme-forms-1\n";
+
+// The forms cached in dir for source, if they're there
+pub fn load(dir: &str, source: &str) -> Option<Vec<Node>> {
+    let bytes = fs::read(path(dir, source)).ok()?;
+    let mut reader = Reader { bytes: bytes.strip_prefix(MAGIC)?, pos: 0 };
+    // The length guards against another source with the same hash
+    if reader.uint()? != source.len() as u64 {
+        return None;
+    }
+    let forms = reader.nodes()?;
+    if reader.pos != reader.bytes.len() {
+        return None;
+    }
+    Some(forms)
+}
+
+// Cache the forms read from source in dir, unless one of them holds a value that can't be written
+pub fn store(dir: &str, source: &str, forms: &[Node]) {
+    let mut bytes = MAGIC.to_vec();
+    put_uint(&mut bytes, source.len() as u64);
+    if put_nodes(&mut bytes, forms).is_none() || fs::create_dir_all(dir).is_err() {
+        return;
+    }
+    // Written under another name first, so nothing ever reads half a file
+    let path = path(dir, source);
+    let partial = path.with_extension(format!("{}.partial", process::id()));
+    if fs::write(&partial, bytes).is_err() || fs::rename(&partial, &path).is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+}
+
+fn path(dir: &str, source: &str) -> std::path::PathBuf {
+    Path::new(dir).join(format!("{:016x}.forms", hash(source)))
+}
+
+// 64-bit FNV-1a, which unlike the standard library's hashers is the same in every build
+fn hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+fn put_u64(bytes: &mut Vec<u8>, n: u64) {
+    bytes.extend_from_slice(&n.to_le_bytes());
+}
+
+// Lengths, locations and characters, which are nearly always small, take seven bits a byte, with
+// the top bit set in every byte but the last
+fn put_uint(bytes: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        bytes.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+}
+
+fn put_str(bytes: &mut Vec<u8>, text: &str) {
+    put_uint(bytes, text.len() as u64);
+    bytes.extend_from_slice(text.as_bytes());
+}
+
+fn put_nodes(bytes: &mut Vec<u8>, nodes: &[Node]) -> Option<()> {
+    put_uint(bytes, nodes.len() as u64);
+    nodes.iter().try_for_each(|node| put_node(bytes, node))
+}
+
+fn put_node(bytes: &mut Vec<u8>, node: &Node) -> Option<()> {
+    match *node {
+        Node::Symbol(ref name)       => { bytes.push(0); put_str(bytes, name); },
+        Node::List(ref nodes, location) => {
+            bytes.push(1);
+            match location {
+                Some(Location { line, col }) => { bytes.push(1); put_uint(bytes, line as u64); put_uint(bytes, col as u64); },
+                None                         => bytes.push(0)
+            }
+            put_nodes(bytes, nodes)?;
+        },
+        Node::Vector(ref nodes)      => { bytes.push(2); put_nodes(bytes, nodes)?; },
+        Node::Pair(ref nodes, ref tail) => { bytes.push(3); put_nodes(bytes, nodes)?; put_node(bytes, tail)?; },
+        Node::Int(n)                 => { bytes.push(4); bytes.extend_from_slice(&n.to_le_bytes()); },
+        Node::Float(x)               => { bytes.push(5); put_u64(bytes, x.to_bits()); },
+        Node::Complex(real, im)      => { bytes.push(6); put_u64(bytes, real.to_bits()); put_u64(bytes, im.to_bits()); },
+        Node::Rational(num, den)     => { bytes.push(7); bytes.extend_from_slice(&num.to_le_bytes()); bytes.extend_from_slice(&den.to_le_bytes()); },
+        Node::Bool(b)                => { bytes.push(8); bytes.push(b as u8); },
+        Node::Char(c)                => { bytes.push(9); put_uint(bytes, u64::from(c)); },
+        Node::String(ref text)       => { bytes.push(10); put_str(bytes, text); },
+        Node::Keyword(ref name)      => { bytes.push(11); put_str(bytes, name); },
+        Node::Quoted(ref val)        => { bytes.push(12); put_value(bytes, val)?; },
+        Node::ValueWrapper(_)        => return None
+    }
+    Some(())
+}
+
+// A quoted datum's value.  Only data can be quoted in a source, and only a circular list (from
+// datum labels) can't be written this way.
+fn put_value(bytes: &mut Vec<u8>, val: &Value) -> Option<()> {
+    match *val {
+        Value::Int(n)              => { bytes.push(0); bytes.extend_from_slice(&n.to_le_bytes()); },
+        Value::Float(x)            => { bytes.push(1); put_u64(bytes, x.to_bits()); },
+        Value::Complex(real, im)   => { bytes.push(2); put_u64(bytes, real.to_bits()); put_u64(bytes, im.to_bits()); },
+        Value::Rational(num, den)  => { bytes.push(3); bytes.extend_from_slice(&num.to_le_bytes()); bytes.extend_from_slice(&den.to_le_bytes()); },
+        Value::Bool(b)             => { bytes.push(4); bytes.push(b as u8); },
+        Value::Char(c)             => { bytes.push(5); put_uint(bytes, u64::from(c)); },
+        Value::Keyword(ref name)   => { bytes.push(6); put_str(bytes, name); },
+        Value::String(ref text)    => { bytes.push(7); put_str(bytes, text); },
+        Value::Literal(ref name)   => { bytes.push(8); put_str(bytes, name); },
+        Value::Nil                 => bytes.push(9),
+        Value::Vector(ref vals)    => { bytes.push(10); put_values(bytes, vals)?; },
+        Value::Pair(_)             => {
+            let (vals, tail) = val.list_parts()?;
+            bytes.push(11);
+            put_values(bytes, &vals)?;
+            put_value(bytes, &tail)?;
+        },
+        _ => return None
+    }
+    Some(())
+}
+
+fn put_values(bytes: &mut Vec<u8>, vals: &[Value]) -> Option<()> {
+    put_uint(bytes, vals.len() as u64);
+    vals.iter().try_for_each(|val| put_value(bytes, val))
+}
+
+// Reads back what the put_ functions wrote, giving None for anything else
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut n = [0; 8];
+        n.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(n))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        let mut n = [0; 4];
+        n.copy_from_slice(self.take(4)?);
+        Some(i32::from_le_bytes(n))
+    }
+
+    fn uint(&mut self) -> Option<u64> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f).checked_shl(shift)?;
+            if byte < 0x80 {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        self.u64().map(f64::from_bits)
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        self.uint().map(|n| n as usize)
+    }
+
+    fn char(&mut self) -> Option<char> {
+        self.uint().and_then(|n| char::from_u32(n as u32))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    // A count, which can't be more than the bytes that are left, since every item takes at least one
+    fn count(&mut self) -> Option<usize> {
+        self.usize().filter(|&n| n <= self.bytes.len() - self.pos)
+    }
+
+    fn nodes(&mut self) -> Option<Vec<Node>> {
+        let n = self.count()?;
+        (0..n).map(|_| self.node()).collect()
+    }
+
+    fn node(&mut self) -> Option<Node> {
+        Some(match self.byte()? {
+            0  => Node::Symbol(self.string()?),
+            1  => {
+                let location = match self.byte()? {
+                    0 => None,
+                    _ => Some(Location { line: self.usize()?, col: self.usize()? })
+                };
+                Node::List(self.nodes()?, location)
+            },
+            2  => Node::Vector(self.nodes()?),
+            3  => Node::Pair(self.nodes()?, Box::new(self.node()?)),
+            4  => Node::Int(self.i32()?),
+            5  => Node::Float(self.f64()?),
+            6  => Node::Complex(self.f64()?, self.f64()?),
+            7  => Node::Rational(self.i32()?, self.i32()?),
+            8  => Node::Bool(self.byte()? != 0),
+            9  => Node::Char(self.char()?),
+            10 => Node::String(self.string()?),
+            11 => Node::Keyword(self.string()?),
+            12 => Node::Quoted(Box::new(self.value()?)),
+            _  => return None
+        })
+    }
+
+    fn values(&mut self) -> Option<Vec<Value>> {
+        let n = self.count()?;
+        (0..n).map(|_| self.value()).collect()
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        Some(match self.byte()? {
+            0  => Value::Int(self.i32()?),
+            1  => Value::Float(self.f64()?),
+            2  => Value::Complex(self.f64()?, self.f64()?),
+            3  => Value::Rational(self.i32()?, self.i32()?),
+            4  => Value::Bool(self.byte()? != 0),
+            5  => Value::Char(self.char()?),
+            6  => Value::Keyword(self.string()?),
+            7  => Value::String(self.string()?),
+            8  => Value::Literal(self.string()?),
+            9  => Value::Nil,
+            10 => Value::Vector(self.values()?),
+            11 => {
+                let vals = self.values()?;
+                Value::dotted(vals, self.value()?)
+            },
+            _  => return None
+        })
+    }
+}
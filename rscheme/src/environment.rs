@@ -1,104 +1,498 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::iter;
 use std::f64::consts as consts;
-use std::rc::Rc as Rc;
+use shared::{self, Shared, Locked};
+use std::time::Instant;
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
 
-use interpreter;
-use interpreter::Interpreter as Interpreter;
+use interpreter::{self, Interpreter};
 use interpreter::Value as Value;
 use interpreter::EvalError as EvalError;
-use parser::Node as Node;
+use interpreter::{is_procedure, arity, EngineConfig, Builtin, Syntax};
+use parser::{self, Node, Location};
+use random;
+use port;
+use printer;
+use macros::{self, Macro};
+use generator::{self, Generator};
 
 #[derive(Clone)]
 pub struct Lambda {
+    // The positional parameters
     pub params: Vec<Node>,
-    pub body: Node
+    // The positional parameters after #:optional, which can be left out, and their defaults
+    pub optionals: Vec<(String, Node)>,
+    pub keywords: Vec<Keyword>,
+    pub body: Node,
+    // The parameter that gets a list of any positional arguments after the others
+    pub rest: Option<String>,
+    // The name it was first defined with, for errors to mention
+    pub name: Option<String>,
+    // The scope it was made in, whose bindings its body sees wherever it's called from
+    pub scope: Option<Environment>
 }
 
 impl Lambda {
-    pub fn new(params: Vec<Node>, body: Node) -> Lambda {
-        Lambda { params: params, body: body }
+    // Split a parameter list into positional parameters and keyword parameters.  A keyword
+    // parameter is written #:name param, or #:name (param default) to make it optional.  The
+    // positional parameters after #:optional can be left out of a call: each is written param,
+    // which defaults to #f, or (param default).
+    pub fn new(nodes: Vec<Node>, body: Node) -> Result<Lambda, EvalError> {
+        let mut params = Vec::new();
+        let mut optionals = Vec::new();
+        let mut keywords = Vec::new();
+        let mut optional = false;
+        let mut nodes = nodes.into_iter();
+        while let Some(node) = nodes.next() {
+            let name = match node {
+                Node::Keyword(ref name) if name == "optional" => {
+                    if optional || !keywords.is_empty() {
+                        return Err(EvalError { location: None, message: "#:optional has to come once, before any keyword parameters".to_string() })
+                    }
+                    optional = true;
+                    continue;
+                },
+                Node::Keyword(name) => {
+                    optional = false;
+                    name
+                },
+                node if optional    => {
+                    let (param, default) = match node {
+                        Node::Symbol(param)                        => (param, Node::Bool(false)),
+                        Node::List(ref spec, _) if spec.len() == 2 => match spec[0] {
+                            Node::Symbol(ref param) => (param.clone(), spec[1].clone()),
+                            _                       => return Err(EvalError { location: None, message: format!("Invalid optional parameter {}", node) })
+                        },
+                        node                                       => return Err(EvalError { location: None, message: format!("Invalid optional parameter {}", node) })
+                    };
+                    check_binding(&param, "lambda")?;
+                    optionals.push((param, default));
+                    continue;
+                },
+                node                => {
+                    if let Node::Symbol(ref param) = node {
+                        check_binding(param, "lambda")?;
+                    }
+                    params.push(node);
+                    continue;
+                }
+            };
+            let (param, default) = match nodes.next() {
+                Some(Node::Symbol(param))      => (param, None),
+                Some(Node::List(ref spec, _)) if spec.len() == 2 => match spec[0] {
+                    Node::Symbol(ref param) => (param.clone(), Some(spec[1].clone())),
+                    _                       => return Err(EvalError { location: None, message: format!("Invalid parameter for keyword #:{}", name) })
+                },
+                _                              => return Err(EvalError { location: None, message: format!("Invalid parameter for keyword #:{}", name) })
+            };
+            check_binding(&param, "lambda")?;
+            keywords.push(Keyword { name, param, default });
+        }
+        Ok(Lambda { params, optionals, keywords, body, rest: None, name: None, scope: None })
+    }
+
+    // A lambda from its formals: a list of parameters, a dotted list (a b . rest) whose last
+    // parameter takes the rest of the arguments, or a single name that takes all of them
+    pub fn from_formals(formals: &Node, body: Node) -> Result<Lambda, EvalError> {
+        let (params, rest) = match *formals {
+            Node::List(ref params, _)        => (params.clone(), None),
+            Node::Pair(ref params, ref rest) => (params.clone(), Some(&**rest)),
+            Node::Symbol(_)                  => (Vec::new(), Some(formals)),
+            _                                => return Err(EvalError { location: None, message: "lambda should provide a param list".to_string() })
+        };
+        let mut lambda = Lambda::new(params, body)?;
+        lambda.rest = match rest {
+            Some(Node::Symbol(rest)) => {
+                check_binding(rest, "lambda")?;
+                Some(rest.clone())
+            },
+            Some(rest)               => return Err(EvalError { location: None, message: format!("Invalid parameter {}", rest) }),
+            None                     => None
+        };
+        Ok(lambda)
+    }
+}
+
+// A named parameter: passed as #:name value, and bound to param in the body
+#[derive(Clone)]
+pub struct Keyword {
+    pub name: String,
+    pub param: String,
+    // Evaluated when the keyword isn't passed; a keyword without one is required
+    pub default: Option<Node>
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.default {
+            Some(ref default) => write!(f, "#:{} ({} {})", self.name, self.param, default),
+            None              => write!(f, "#:{} {}", self.name, self.param)
+        }
+    }
+}
+
+// What a promise from delay or delay-force holds: the expression to evaluate, in the scope it
+// was made in, until it's forced, and the value it came to after that
+pub enum Promise {
+    // The expression, and whether it's from delay-force, so its value is another promise to force
+    Pending(Node, Environment, bool),
+    Done(Value)
+}
+
+// A parameter object from make-parameter: the value it has outside any parameterize, and the
+// procedure that parameterize passes new values through, if it was given one
+pub struct Parameter {
+    pub value: Value,
+    pub converter: Option<Value>
+}
+
+// What error makes, and what guard and with-exception-handler give their handlers for an error
+// from a builtin (which only has a message)
+pub struct ErrorObject {
+    pub message: String,
+    pub irritants: Vec<Value>
+}
+
+impl ErrorObject {
+    // The message followed by the irritants, as the error is reported when nothing catches it
+    pub fn text(&self) -> String {
+        iter::once(self.message.clone()).chain(self.irritants.iter().map(|val| val.to_string())).collect::<Vec<String>>().join(" ")
+    }
+}
+
+// The names of the builtin libraries, which (import (toys math)) and the like bind
+const LIBRARIES: &[&str] = &["scheme base", "toys math", "toys random", "toys vector", "toys string", "toys functional", "toys io"];
+
+// The builtins that reach outside the interpreter, which a sandboxed one leaves out
+const SANDBOXED: &[&str] = &["print", "write", "write-shared", "bench"];
+
+// The builtins a configuration file evaluated by eval_config can use, which can't define
+// anything, have side effects or loop
+const CONFIG: &[&str] = &[
+    "quote", "if", "and", "or", "let", "let*", "let-list", "error",
+    "+", "-", "*", "/", "pow", "expt", "sqrt", "pi", "e",
+    ">", ">=", "<", "<=", "=", "equal?", "not",
+    "list", "cons", "car", "cdr", "append", "length", "empty?", "null?",
+    "vector", "vector-ref", "vector-length", "vector->list", "list->vector",
+    "->string", "keyword?", "keyword->string", "string->keyword"
+];
+
+// The names of the core syntax, which no binding form can rebind, since a program that did would
+// silently change the meaning of every later use of them
+const RESERVED: &[&str] = &[
+    "begin", "import", "define", "set!", "define-values", "let", "let*", "letrec", "let-list", "do",
+    "if", "cond", "case", "else", "=>", "try", "and", "or", "quote", "quasiquote", "unquote",
+    "unquote-splicing", "lambda", "define-syntax", "syntax-rules", "delay", "delay-force",
+    "the-environment", "guard", "parameterize", "define-library"
+];
+
+pub fn is_reserved(name: &str) -> bool {
+    RESERVED.contains(&name)
+}
+
+// An error if name is a reserved word, which form is trying to bind
+fn check_binding(name: &str, form: &str) -> Result<(), EvalError> {
+    if is_reserved(name) {
+        Err(EvalError { location: None, message: format!("'{}' can't bind {}, which is a reserved word", form, name) })
+    } else {
+        Ok(())
     }
 }
 
+fn procedure(name: &'static str, func: Builtin) -> (&'static str, Value) {
+    (name, Value::Function(name, Shared::new(func)))
+}
+
+fn syntax(name: &'static str, form: Syntax) -> (&'static str, Value) {
+    (name, Value::SpecialForm(name, Shared::new(form)))
+}
+
+// The bindings in a builtin library, given its name as a space-separated list like "toys math".
+// (scheme base) has the special forms, including import, and the procedures everything else is
+// built from.
+fn library(name: &str, config: &EngineConfig) -> Option<Vec<(&'static str, Value)>> {
+    let bindings = match name {
+        "scheme base" => vec![
+            syntax("import", import),
+            syntax("define-library", define_library),
+            syntax("begin", begin_form),
+            procedure("+", add),
+            procedure("-", sub),
+            procedure("*", mul),
+            procedure("/", div),
+            syntax("define", def),
+            syntax("define-syntax", define_syntax),
+            syntax("set!", set),
+            procedure("values", values),
+            syntax("define-values", define_values),
+            syntax("let", let_form),
+            syntax("let*", let_star),
+            syntax("letrec", letrec),
+            syntax("do", do_fn),
+            syntax("while", while_fn),
+            syntax("until", until_fn),
+            syntax("let-list", let_list),
+            procedure(">", gt),
+            procedure(">=", gte),
+            procedure("<", lt),
+            procedure("<=", lte),
+            procedure("=", eq),
+            procedure("eqv?", eqv),
+            procedure("equal?", equal),
+            procedure("not", not),
+            syntax("and", and),
+            syntax("or", or),
+            procedure("list", list),
+            procedure("car", car),
+            procedure("cdr", cdr),
+            procedure("cons", cons),
+            procedure("set-car!", set_car),
+            procedure("set-cdr!", set_cdr),
+            procedure("append", append),
+            procedure("empty?", emptyq),
+            procedure("null?", emptyq),
+            procedure("void", void),
+            procedure("void?", voidq),
+            procedure("length", length),
+            procedure("symbol?", symbolq),
+            procedure("gensym", gensym),
+            procedure("generate-uninterned-symbol", gensym),
+            procedure("number?", numberq),
+            procedure("list?", listq),
+            procedure("pair?", pairq),
+            syntax("if", if_fn),
+            syntax("cond", cond),
+            syntax("case", case),
+            syntax("try", try_fn),
+            procedure("error", error),
+            procedure("raise", raise),
+            procedure("raise-continuable", raise_continuable),
+            procedure("with-exception-handler", with_exception_handler),
+            syntax("guard", guard),
+            procedure("make-parameter", make_parameter),
+            syntax("parameterize", parameterize),
+            procedure("error-object?", error_objectq),
+            procedure("error-object-message", error_object_message),
+            procedure("error-object-irritants", error_object_irritants),
+            procedure("map", map),
+            procedure("call-with-current-continuation", call_cc),
+            procedure("call/cc", call_cc),
+            procedure("make-generator", make_generator),
+            procedure("yield", yield_fn),
+            procedure("for-each", for_each),
+            syntax("quote", quote),
+            syntax("quasiquote", quasiquote),
+            syntax("delay", delay),
+            syntax("delay-force", delay_force),
+            procedure("make-promise", make_promise),
+            procedure("promise?", promiseq),
+            procedure("force", force),
+            syntax("lambda", def_lambda),
+            procedure("eval", eval_fn),
+            procedure("interaction-environment", interaction_environment),
+            syntax("the-environment", the_environment),
+        ],
+        "toys math" => vec![
+            procedure("pow", pow),
+            procedure("expt", pow),
+            procedure("sin", sin),
+            procedure("cos", cos),
+            procedure("tan", tan),
+            procedure("asin", asin),
+            procedure("acos", acos),
+            procedure("atan", atan),
+            procedure("exp", exp),
+            procedure("log", log),
+            procedure("log10", log10),
+            procedure("sqrt", sqrt),
+            ("pi", Value::Float(consts::PI)),
+            ("e", Value::Float(consts::E)),
+        ],
+        "toys random" => vec![
+            procedure("random", random_fn),
+            procedure("random-seed!", random_seed),
+            procedure("shuffle", shuffle),
+            procedure("sample", sample),
+        ],
+        "toys vector" => vec![
+            procedure("vector", vector),
+            procedure("vector-ref", vector_ref),
+            procedure("vector-length", vector_length),
+            procedure("vector->list", vector_to_list),
+            procedure("list->vector", list_to_vector),
+        ],
+        "toys string" => vec![
+            procedure("->string", to_string),
+            procedure("make-string-builder", make_string_builder),
+            procedure("sb-add!", sb_add),
+            procedure("sb->string", sb_to_string),
+            procedure("keyword?", keywordq),
+            procedure("keyword->string", keyword_to_string),
+            procedure("string->keyword", string_to_keyword),
+        ],
+        "toys functional" => vec![
+            procedure("partial", partial),
+            procedure("curry", curry),
+            procedure("compose", compose),
+            procedure("identity", identity),
+            procedure("const", const_fn),
+        ],
+        "toys io" => vec![
+            procedure("print", print),
+            procedure("write", write),
+            procedure("write-shared", write_shared),
+            procedure("print-length", print_length),
+            procedure("print-depth", print_depth),
+            procedure("with-output-to-string", with_output_to_string),
+            procedure("open-input-string", open_input_string),
+            procedure("read-char", read_char),
+            procedure("peek-char", peek_char),
+            procedure("read", read),
+            procedure("define-reader-syntax", define_reader_syntax),
+            procedure("bench", bench),
+        ],
+        _ => return None
+    };
+    Some(bindings.into_iter().filter(|&(label, _)| !(config.sandbox && SANDBOXED.contains(&label))).collect())
+}
+
+// Each label that was set, with the value it had before
+type Journal = Vec<(String, Option<Value>)>;
+
 #[derive(Clone)]
 pub struct Environment {
-    pub env: HashMap<String, Value>,
-    pub outer: Option<Box<Environment>>
+    // The bindings made in this scope.  Entering a lambda or a begin copies the current environment
+    // as the outer one, and the copies share their bindings, so set! changes them for everyone.
+    pub env: Shared<Locked<HashMap<String, Value>>>,
+    pub outer: Option<Box<Environment>>,
+    // How many environments there are outside this one
+    depth: usize,
+    // When it's being kept, every label set here along with the value it had before (None if
+    // it wasn't bound), so the changes can be rolled back.  Like the bindings, it's shared between
+    // copies, so a set! made through one is journalled too.
+    journal: Option<Shared<Locked<Journal>>>
 }
 
 impl Environment {
-    pub fn new(outer: Option<Box<Environment>>) -> Environment {
-        let mut env = Environment { env: HashMap::new(), outer: outer };
+    pub fn new(outer: Option<Box<Environment>>, config: &EngineConfig) -> Environment {
+        let mut env = Environment::new_empty(outer);
         
-        env.initialize();
+        env.initialize(config);
 
         env
     }
     
     pub fn new_empty(outer: Option<Box<Environment>>) -> Environment {
-        Environment { env: HashMap::new(), outer: outer }
+        let depth = outer.as_ref().map_or(0, |outer| outer.depth + 1);
+        Environment { env: Shared::new(Locked::new(HashMap::new())), outer, depth, journal: None }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
     }
     
-    pub fn initialize(&mut self) {
+    pub fn initialize(&mut self, config: &EngineConfig) {
+        // Without import_all, a program starts out with just (scheme base) and imports the rest
+        let names = if config.import_all { LIBRARIES } else { &LIBRARIES[..1] };
         let mut env = HashMap::new();
-        env.insert("begin".to_string(),  Value::Function("begin", Rc::new(begin)));
-        env.insert("+".to_string(),      Value::Function("+", Rc::new(add)));
-        env.insert("-".to_string(),      Value::Function("-", Rc::new(sub)));
-        env.insert("*".to_string(),      Value::Function("*", Rc::new(mul)));
-        env.insert("/".to_string(),      Value::Function("/", Rc::new(div)));
-        env.insert("pow".to_string(),    Value::Function("pow", Rc::new(pow)));
-        env.insert("expt".to_string(),   Value::Function("expt", Rc::new(pow)));
-        env.insert("define".to_string(), Value::Function("define", Rc::new(def)));
-        env.insert("set!".to_string(),   Value::Function("set!", Rc::new(def)));
-        env.insert(">".to_string(),      Value::Function(">", Rc::new(gt)));
-        env.insert(">=".to_string(),     Value::Function(">=", Rc::new(gte)));
-        env.insert("<".to_string(),      Value::Function("<", Rc::new(lt)));
-        env.insert("<=".to_string(),     Value::Function("<=", Rc::new(lte)));
-        env.insert("=".to_string(),      Value::Function("=", Rc::new(eq)));
-        env.insert("equal?".to_string(), Value::Function("equal?", Rc::new(eq)));
-        env.insert("not".to_string(),    Value::Function("not", Rc::new(not)));
-        env.insert("and".to_string(),    Value::Function("and", Rc::new(and)));
-        env.insert("or".to_string(),     Value::Function("or", Rc::new(or)));
-        env.insert("list".to_string(),   Value::Function("list", Rc::new(list)));
-        env.insert("car".to_string(),    Value::Function("car", Rc::new(car)));
-        env.insert("cdr".to_string(),    Value::Function("cdr", Rc::new(cdr)));
-        env.insert("cons".to_string(),   Value::Function("cons", Rc::new(cons)));
-        env.insert("append".to_string(), Value::Function("append", Rc::new(append)));
-        env.insert("empty?".to_string(), Value::Function("empty?", Rc::new(emptyq))); 
-        env.insert("null?".to_string(),  Value::Function("null?", Rc::new(emptyq)));
-        env.insert("length".to_string(), Value::Function("length", Rc::new(length)));
-        env.insert("if".to_string(),     Value::Function("if", Rc::new(if_fn)));
-        env.insert("map".to_string(),    Value::Function("map", Rc::new(map)));
-        env.insert("sin".to_string(),    Value::Function("sin", Rc::new(sin)));
-        env.insert("cos".to_string(),    Value::Function("cos", Rc::new(cos)));
-        env.insert("tan".to_string(),    Value::Function("tan", Rc::new(tan)));
-        env.insert("asin".to_string(),   Value::Function("asin", Rc::new(asin)));
-        env.insert("acos".to_string(),   Value::Function("acos", Rc::new(acos)));
-        env.insert("atan".to_string(),   Value::Function("atan", Rc::new(atan)));
-        env.insert("exp".to_string(),    Value::Function("exp", Rc::new(exp)));
-        env.insert("log".to_string(),    Value::Function("log", Rc::new(log)));
-        env.insert("log10".to_string(),  Value::Function("log10", Rc::new(log10)));
-        env.insert("sqrt".to_string(),   Value::Function("sqrt", Rc::new(sqrt)));
-        env.insert("quote".to_string(),  Value::Function("quote", Rc::new(quote)));
-        env.insert("lambda".to_string(), Value::Function("lambda", Rc::new(def_lambda)));
-        env.insert("pi".to_string(),     Value::Float(consts::PI));
-        env.insert("e".to_string(),      Value::Float(consts::E));
+        for name in names {
+            for (label, val) in library(name, config).unwrap_or_default() {
+                env.insert(label.to_string(), val);
+            }
+        }
         
-        self.env = env;
+        self.env = Shared::new(Locked::new(env));
     }
     
-    pub fn get(&self, label: &String) -> Option<&Value> {
-        match self.env.get(label) {
-            Some(val) => Some(val),
-            None => match self.outer {
-                Some(ref outer) => outer.get(label),
-                None => None
+    // An environment with only the builtins in CONFIG
+    pub fn new_config(config: &EngineConfig) -> Environment {
+        let env = Environment::new_empty(None);
+        for name in LIBRARIES {
+            for (label, val) in library(name, config).unwrap_or_default() {
+                if CONFIG.contains(&label) {
+                    shared::lock(&env.env).insert(label.to_string(), val);
+                }
             }
         }
+        env
+    }
+    
+    // The global scope, outside every other one
+    pub fn outermost(&self) -> &Environment {
+        match self.outer {
+            Some(ref outer) => outer.outermost(),
+            None            => self
+        }
+    }
+
+    pub fn get(&self, label: &String) -> Option<Value> {
+        if let Some(val) = shared::lock(&self.env).get(label) {
+            return Some(val.clone());
+        }
+        match self.outer {
+            Some(ref outer) => outer.get(label),
+            None => None
+        }
     }
     
     pub fn set(&mut self, label: String, value: Value) {
-        self.env.insert(label, value);
+        let old = shared::lock(&self.env).insert(label.clone(), value);
+        if let Some(ref journal) = self.journal {
+            shared::lock(journal).push((label, old));
+        }
+    }
+
+    // Change the nearest binding of label, here or in an outer scope, returning false if there
+    // isn't one
+    pub fn assign(&mut self, label: &String, value: Value) -> bool {
+        if shared::lock(&self.env).contains_key(label) {
+            self.set(label.clone(), value);
+            return true;
+        }
+        match self.outer {
+            Some(ref mut outer) => outer.assign(label, value),
+            None => false
+        }
+    }
+
+    // Make the lambdas bound here that don't have a scope yet see this one, wherever they're called
+    pub fn enclose(&self) {
+        for val in shared::lock(&self.env).values_mut() {
+            if let Value::Lambda(ref mut lambda) = *val {
+                if lambda.scope.is_none() {
+                    lambda.scope = Some(self.clone());
+                }
+            }
+        }
+    }
+
+    // Start keeping a journal of the changes made here, so they can be undone
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Shared::new(Locked::new(Vec::new())));
+    }
+
+    // A point in the journal that rollback can return to
+    pub fn mark(&self) -> usize {
+        self.journal.as_ref().map_or(0, |journal| shared::lock(journal).len())
+    }
+
+    // Undo every change made since the given mark, most recent first
+    pub fn rollback(&mut self, mark: usize) {
+        let mut journal = match self.journal {
+            Some(ref journal) => shared::lock(journal),
+            None              => return
+        };
+        let mut env = shared::lock(&self.env);
+        while journal.len() > mark {
+            match journal.pop() {
+                Some((label, Some(old))) => { env.insert(label, old); },
+                Some((label, None))      => { env.remove(&label); },
+                None                     => break
+            }
+        }
     }
 }
 
@@ -110,34 +504,176 @@ impl Environment {
 // with a closure, but closures and fn pointers are not interchangeable.  I'm sure there's a
 // better way to handle this (possible by moving the whole thing to closures), but for the 
 // moment I'm stymied.
-fn begin(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    let mut val: Result<Value, EvalError> = Ok(Value::Void);
-    let env = Environment::new_empty(Some(Box::new(interpreter.env.clone())));
-    let mut interpreter = Interpreter::new_with_env(env.clone());
-    
-    for node in xs {
-        val = interpreter.eval_node(&node);
+fn begin(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    interpreter.eval_body(xs)
+}
+
+// begin, with its tail call evaluated here, for forms that have to see the body's value before
+// they return
+fn begin_value(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    begin(interpreter, xs).and_then(|val| match val {
+        Value::ScopedNode(node, env) => interpreter.with_env(env).eval_node(&node),
+        val                          => Ok(val)
+    })
+}
+
+// (begin form ...) as it's written in a program.  One that doesn't define anything has no need
+// for a scope of its own, so its last form is left as a tail call.
+fn begin_form(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    if xs.iter().any(interpreter::is_definition) {
+        return begin(interpreter, xs);
+    }
+    match xs.split_last() {
+        Some((last, init)) => {
+            for node in init {
+                interpreter.eval_node(node)?;
+            }
+            Ok(Value::NodeWrapper(last.clone()))
+        },
+        None => Ok(Value::Void)
     }
-    
-    val
 }
 
-fn add(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() == 0 {
+// (import (toys math) ...) binds everything in each of the named libraries in the current scope.
+// Nothing is bound if any of them doesn't exist.
+fn import(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let mut bindings = Vec::new();
+    for spec in xs {
+        let name = match library_name(spec) {
+            Some(name) => name,
+            None       => return Err(EvalError { location: None, message: format!("Unknown library {}", spec) })
+        };
+        if let Some(library) = library(&name, &interpreter.config) {
+            bindings.extend(library.into_iter().map(|(label, val)| (label.to_string(), val)));
+            continue;
+        }
+        if interpreter.library(&name).is_none() {
+            load_library(interpreter, &name)?;
+        }
+        match interpreter.library(&name) {
+            Some(exports) => bindings.extend(exports),
+            None          => return Err(EvalError { location: None, message: format!("Unknown library {}", spec) })
+        }
+    }
+    for (label, val) in bindings {
+        interpreter.env.set(label, val);
+    }
+    Ok(Value::Void)
+}
+
+// The name of a library, like (toys math), as a space-separated string
+fn library_name(spec: &Node) -> Option<String> {
+    match *spec {
+        Node::List(ref parts, _) if !parts.is_empty() => parts.iter().map(|part| match *part {
+            Node::Symbol(ref part) => Some(part.clone()),
+            Node::Int(n)           => Some(n.to_string()),
+            _                      => None
+        }).collect::<Option<Vec<String>>>().map(|name| name.join(" ")),
+        _ => None
+    }
+}
+
+// The libraries whose files are being evaluated, so one that imports itself is an error rather
+// than a stack overflow
+thread_local! {
+    static LOADING: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+// Evaluate the file for a library that hasn't been defined yet, if there's one on the library
+// path: (my utils) is defined by my/utils.sld.  A sandboxed interpreter doesn't read files.
+fn load_library(interpreter: &mut Interpreter, name: &str) -> Result<(), EvalError> {
+    if interpreter.config.sandbox {
+        return Ok(());
+    }
+    let file = format!("{}.sld", name.replace(' ', "/"));
+    let path = match interpreter.config.library_path.iter().map(|dir| Path::new(dir).join(&file)).find(|path| path.is_file()) {
+        Some(path) => path,
+        None       => return Ok(())
+    };
+    if LOADING.with(|loading| loading.borrow().iter().any(|loading| loading == name)) {
+        return Err(EvalError { location: None, message: format!("The library ({}) imports itself", name) });
+    }
+    let source = fs::read_to_string(&path)
+        .map_err(|err| EvalError { location: None, message: format!("Could not read {}: {}", path.display(), err) })?;
+
+    // The file is evaluated in a global scope of its own, so only the libraries it defines are seen
+    let mut loader = interpreter.with_env(Interpreter::new((*interpreter.config).clone()).env);
+    LOADING.with(|loading| loading.borrow_mut().push(name.to_string()));
+    let result = loader.eval_cached(&source);
+    LOADING.with(|loading| loading.borrow_mut().pop());
+    match result {
+        Ok(_)                                      => Ok(()),
+        Err(interpreter::ScriptError::Eval(err))   => Err(EvalError { location: None, message: format!("In {}: {}", path.display(), err) }),
+        Err(interpreter::ScriptError::Parse(errs)) => Err(EvalError { location: None, message: format!("In {}: {}", path.display(), errs[0].describe(&source)) })
+    }
+}
+
+// (define-library (name ...) declaration ...) defines a library.  (begin form ...) declarations
+// are evaluated in a scope of the library's own, (import ...) makes other libraries available to
+// them, and (export name ... (rename internal external) ...) says what importing the library binds.
+fn define_library(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let name = match xs.first().and_then(library_name) {
+        Some(name) => name,
+        None       => return Err(EvalError { location: None, message: "'define-library' needs a name like (my utils)".to_string() })
+    };
+    // What the library imports goes outside the scope its definitions are made in, so its
+    // procedures only have to carry its own definitions with them
+    let base = Interpreter::new((*interpreter.config).clone()).env;
+    let mut lib = interpreter.with_env(Environment::new_empty(Some(Box::new(base.clone()))));
+    let mut exports = Vec::new();
+    for decl in &xs[1..] {
+        let nodes = match *decl {
+            Node::List(ref nodes, _) if !nodes.is_empty() => nodes,
+            _                                             => return Err(EvalError { location: None, message: format!("Invalid library declaration {}", decl) })
+        };
+        match nodes[0] {
+            Node::Symbol(ref head) if head == "export" => for spec in &nodes[1..] {
+                exports.push(match *spec {
+                    Node::Symbol(ref label) => (label.clone(), label.clone()),
+                    Node::List(ref rename, _) if rename.len() == 3 => match (&rename[0], &rename[1], &rename[2]) {
+                        (Node::Symbol(head), Node::Symbol(internal), Node::Symbol(external)) if head == "rename" => (internal.clone(), external.clone()),
+                        _ => return Err(EvalError { location: None, message: format!("Invalid export {}", spec) })
+                    },
+                    _ => return Err(EvalError { location: None, message: format!("Invalid export {}", spec) })
+                });
+            },
+            Node::Symbol(ref head) if head == "import" => { import(&mut interpreter.with_env(base.clone()), &nodes[1..])?; },
+            Node::Symbol(ref head) if head == "begin"  => { lib.eval_script(nodes[1..].to_vec())?; },
+            _ => return Err(EvalError { location: None, message: format!("Invalid library declaration {}", decl) })
+        }
+    }
+
+    // The library's procedures see its definitions wherever they're called
+    lib.env.enclose();
+    let mut bindings = Vec::new();
+    for (internal, external) in exports {
+        match lib.env.get(&internal) {
+            Some(val) => bindings.push((external, val)),
+            None      => return Err(EvalError { location: None, message: format!("({}) exports {}, which it doesn't define", name, internal) })
+        }
+    }
+    interpreter.define_library(name, bindings);
+    Ok(Value::Void)
+}
+
+// Integers are i32s, and arithmetic whose result doesn't fit in one is an error rather than
+// wrapping around
+fn overflow(name: &str) -> EvalError {
+    EvalError { location: None, message: format!("Integer overflow in '{}'", name) }
+}
+
+fn add(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.is_empty() {
         return Ok(Value::Int(0));
     }
 
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => match val {
-            Value::Bool(true)  => Value::Int(1),
-            Value::Bool(false) => Value::Int(0),
-            val                => val
-        },
-        err      => return err
+    let x = match xs[0].clone() {
+        Value::Bool(true)  => Value::Int(1),
+        Value::Bool(false) => Value::Int(0),
+        val            => val
     };
     
-    let ys = xs[1..].to_vec();
-    let y = match add(interpreter, &ys) {
+    let y = match add(_interpreter, &xs[1..]) {
         Ok(val) => match val {
             Value::Bool(true)  => Value::Int(1),
             Value::Bool(false) => Value::Int(0),
@@ -147,7 +683,7 @@ fn add(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError
     };
 
     match (x, y) {
-        (Value::Int(x), Value::Int(y))                 => Ok(Value::Int(x + y)),
+        (Value::Int(x), Value::Int(y))                 => x.checked_add(y).map(Value::Int).ok_or_else(|| overflow("+")),
         (Value::Float(x), Value::Int(y))               => Ok(Value::Float(x + y as f64)),
         (Value::Int(x), Value::Float(y))               => Ok(Value::Float(x as f64 + y)),
         (Value::Float(x), Value::Float(y))             => Ok(Value::Float(x + y)),
@@ -156,36 +692,29 @@ fn add(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError
         (Value::Int(x), Value::Complex(yr,yi))         => Ok(Value::Complex(x as f64+yr,yi)),
         (Value::Float(x), Value::Complex(yr,yi))       => Ok(Value::Complex(x+yr,yi)),
         (Value::Complex(xr,xi), Value::Complex(yr,yi)) => Ok(Value::Complex(xr+yr,xi+yi)),
-        (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-        _                                  => Err(EvalError { message: "Invalid types for '+'".to_string() })
+        _                                  => Err(EvalError { location: None, message: "Invalid types for '+'".to_string() })
     }
 }
 
-fn sub(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() == 0 {
+fn sub(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.is_empty() {
         return Ok(Value::Int(0));
     }
 
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => match val {
-            Value::Bool(true)  => Value::Int(1),
-            Value::Bool(false) => Value::Int(0),
-            val                => val
-        },
-        err      => return err
+    let x = match xs[0].clone() {
+        Value::Bool(true)  => Value::Int(1),
+        Value::Bool(false) => Value::Int(0),
+        val            => val
     };
-    let y = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => match val {
-            Value::Bool(true)  => Value::Int(1),
-            Value::Bool(false) => Value::Int(0),
-            val                => val
-        },
-        err      => return err
+    let y = match xs[1].clone() {
+        Value::Bool(true)  => Value::Int(1),
+        Value::Bool(false) => Value::Int(0),
+        val            => val
     };
     
     if xs.len() < 3 {
         match (x, y) {
-            (Value::Int(x), Value::Int(y))                 => Ok(Value::Int(x - y)),
+            (Value::Int(x), Value::Int(y))                 => x.checked_sub(y).map(Value::Int).ok_or_else(|| overflow("-")),
             (Value::Float(x), Value::Int(y))               => Ok(Value::Float(x - y as f64)),
             (Value::Int(x), Value::Float(y))               => Ok(Value::Float(x as f64 - y)),
             (Value::Float(x), Value::Float(y))             => Ok(Value::Float(x - y)),
@@ -194,48 +723,42 @@ fn sub(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError
             (Value::Int(x), Value::Complex(yr,yi))         => Ok(Value::Complex(x as f64-yr,yi)),
             (Value::Float(x), Value::Complex(yr,yi))       => Ok(Value::Complex(x-yr,yi)),
             (Value::Complex(xr,xi), Value::Complex(yr,yi)) => Ok(Value::Complex(xr-yr,xi-yi)),
-            (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-            _                                  => Err(EvalError { message: "Invalid types for '-'".to_string() })
+            _                                  => Err(EvalError { location: None, message: "Invalid types for '-'".to_string() })
         }
     } else {
         let x_sub_y = match (x, y) {
-            (Value::Int(x), Value::Int(y))     => Node::Int(x - y),
-            (Value::Float(x), Value::Int(y))   => Node::Float(x - y as f64),
-            (Value::Int(x), Value::Float(y))   => Node::Float(x as f64 - y),
-            (Value::Float(x), Value::Float(y)) => Node::Float(x - y),
-            (Value::Complex(xr,xi), Value::Int(y))         => Node::Complex(xr-y as f64,xi),
-            (Value::Complex(xr,xi), Value::Float(y))       => Node::Complex(xr-y,xi),
-            (Value::Int(x), Value::Complex(yr,yi))         => Node::Complex(x as f64-yr,yi),
-            (Value::Float(x), Value::Complex(yr,yi))       => Node::Complex(x-yr,yi),
-            (Value::Complex(xr,xi), Value::Complex(yr,yi)) => Node::Complex(xr-yr,xi-yi),
-            (Value::Symbol(val), _) | (_, Value::Symbol(val)) => return Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-            _                                  => return Err(EvalError { message: "Invalid types for '-'".to_string() })
+            (Value::Int(x), Value::Int(y))     => Value::Int(x.checked_sub(y).ok_or_else(|| overflow("-"))?),
+            (Value::Float(x), Value::Int(y))   => Value::Float(x - y as f64),
+            (Value::Int(x), Value::Float(y))   => Value::Float(x as f64 - y),
+            (Value::Float(x), Value::Float(y)) => Value::Float(x - y),
+            (Value::Complex(xr,xi), Value::Int(y))         => Value::Complex(xr-y as f64,xi),
+            (Value::Complex(xr,xi), Value::Float(y))       => Value::Complex(xr-y,xi),
+            (Value::Int(x), Value::Complex(yr,yi))         => Value::Complex(x as f64-yr,yi),
+            (Value::Float(x), Value::Complex(yr,yi))       => Value::Complex(x-yr,yi),
+            (Value::Complex(xr,xi), Value::Complex(yr,yi)) => Value::Complex(xr-yr,xi-yi),
+            _                                  => return Err(EvalError { location: None, message: "Invalid types for '-'".to_string() })
         };
     
-        let mut xs = xs.clone();
+        let mut xs = xs.to_vec();
         xs.remove(0);
         xs.remove(0);
         xs.insert(0, x_sub_y);
-        sub(interpreter, &xs)
+        sub(_interpreter, &xs)
     }
 }
 
-fn mul(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() == 0 {
+fn mul(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.is_empty() {
         return Ok(Value::Int(1));
     }
 
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => match val {
-            Value::Bool(true)  => Value::Int(1),
-            Value::Bool(false) => Value::Int(0),
-            val                => val
-        },
-        err      => return err
+    let x = match xs[0].clone() {
+        Value::Bool(true)  => Value::Int(1),
+        Value::Bool(false) => Value::Int(0),
+        val            => val
     };
     
-    let ys = xs[1..].to_vec();
-    let y = match mul(interpreter, &ys) {
+    let y = match mul(_interpreter, &xs[1..]) {
         Ok(val) => match val {
             Value::Bool(true)  => Value::Int(1),
             Value::Bool(false) => Value::Int(0),
@@ -244,7 +767,7 @@ fn mul(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError
         err      => return err
     };
     match (x, y) {
-        (Value::Int(x), Value::Int(y))                 => Ok(Value::Int(x * y)),
+        (Value::Int(x), Value::Int(y))                 => x.checked_mul(y).map(Value::Int).ok_or_else(|| overflow("*")),
         (Value::Float(x), Value::Int(y))               => Ok(Value::Float(x * y as f64)),
         (Value::Int(x), Value::Float(y))               => Ok(Value::Float(x as f64 * y)),
         (Value::Float(x), Value::Float(y))             => Ok(Value::Float(x * y)),
@@ -253,688 +776,1992 @@ fn mul(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError
         (Value::Int(x), Value::Complex(yr,yi))         => Ok(Value::Complex(x as f64*yr, x as f64*yi)),
         (Value::Float(x), Value::Complex(yr,yi))       => Ok(Value::Complex(x*yr, x*yi)),
         (Value::Complex(xr,xi), Value::Complex(yr,yi)) => Ok(Value::Complex(xr*yr - xi*yi, xr*yi + yr*xi)),
-        (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-        _                                  => Err(EvalError { message: "Invalid types for '*'".to_string() })
+        _                                  => Err(EvalError { location: None, message: "Invalid types for '*'".to_string() })
     }
 }
 
-fn div(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn div(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() == 1 {
         return Ok(Value::Int(1));
     }
 
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => match val {
-            Value::Bool(true)  => Value::Int(1),
-            Value::Bool(false) => Value::Int(0),
-            val                => val
-        },
-        err      => return err
+    let x = match xs[0].clone() {
+        Value::Bool(true)  => Value::Int(1),
+        Value::Bool(false) => Value::Int(0),
+        val            => val
     };
-    let y = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => match val {
-            Value::Bool(true)  => Value::Int(1),
-            Value::Bool(false) => Value::Int(0),
-            val                => val
-        },
-        err      => return err
+    let y = match xs[1].clone() {
+        Value::Bool(true)  => Value::Int(1),
+        Value::Bool(false) => Value::Int(0),
+        val            => val
     };
     
     if xs.len() < 3 {
         match (x, y) {
-            (_, Value::Int(0)) | (_, Value::Float(0.0)) => Err(EvalError { message: "Invalid division by zero".to_string() }),
-            (Value::Int(x), Value::Int(y))                    => Ok(Value::Int(x / y)),
+            (_, Value::Int(0)) | (_, Value::Float(0.0)) => Err(EvalError { location: None, message: "Invalid division by zero".to_string() }),
+            (Value::Int(x), Value::Int(y))                    => x.checked_div(y).map(Value::Int).ok_or_else(|| overflow("/")),
             (Value::Float(x), Value::Int(y))                  => Ok(Value::Float(x / y as f64)),
             (Value::Int(x), Value::Float(y))                  => Ok(Value::Float(x as f64 / y)),
             (Value::Float(x), Value::Float(y))                => Ok(Value::Float(x / y)),
             (Value::Complex(xr,xi), Value::Int(y))            => Ok(Value::Complex(xr/y as f64,xi/y as f64)),
             (Value::Complex(xr,xi), Value::Float(y))          => Ok(Value::Complex(xr/y,xi/y)),
-            (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
             (x, Value::Complex(yr, yi))                       => {
                 let den = yr*yr + yi*yi;
                 match x {
                     Value::Int(x)         => Ok(Value::Complex(x as f64*yr/den, -x as f64*yi/den)),
                     Value::Float(x)       => Ok(Value::Complex(x*yr/den, -x*yi/den)),
                     Value::Complex(xr,xi) => Ok(Value::Complex((xr*yr + xi*yi)/den, (xi*yr - xr*yi)/den)),
-                    _                     => Err(EvalError { message: "Invalid types for '/'".to_string() })
+                    _                     => Err(EvalError { location: None, message: "Invalid types for '/'".to_string() })
                 }
             },
-            _                                  => Err(EvalError { message: "Invalid types for '/'".to_string() })
+            _                                  => Err(EvalError { location: None, message: "Invalid types for '/'".to_string() })
         }
     } else {
         let x_div_y = match (x, y) {
-            (_, Value::Int(0)) | (_, Value::Float(0.0)) => return Err(EvalError { message: "Invalid division by zero".to_string() }),
-            (Value::Int(x), Value::Int(y))                    => Node::Int(x / y),
-            (Value::Float(x), Value::Int(y))                  => Node::Float(x / y as f64),
-            (Value::Int(x), Value::Float(y))                  => Node::Float(x as f64 / y),
-            (Value::Float(x), Value::Float(y))                => Node::Float(x / y),
-            (Value::Symbol(val), _) | (_, Value::Symbol(val)) => return Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-            (Value::Complex(xr,xi), Value::Int(y))            => Node::Complex(xr/y as f64,xi/y as f64),
-            (Value::Complex(xr,xi), Value::Float(y))          => Node::Complex(xr/y,xi/y),
+            (_, Value::Int(0)) | (_, Value::Float(0.0)) => return Err(EvalError { location: None, message: "Invalid division by zero".to_string() }),
+            (Value::Int(x), Value::Int(y))                    => Value::Int(x.checked_div(y).ok_or_else(|| overflow("/"))?),
+            (Value::Float(x), Value::Int(y))                  => Value::Float(x / y as f64),
+            (Value::Int(x), Value::Float(y))                  => Value::Float(x as f64 / y),
+            (Value::Float(x), Value::Float(y))                => Value::Float(x / y),
+            (Value::Complex(xr,xi), Value::Int(y))            => Value::Complex(xr/y as f64,xi/y as f64),
+            (Value::Complex(xr,xi), Value::Float(y))          => Value::Complex(xr/y,xi/y),
             (x, Value::Complex(yr, yi))                       => {
                 let den = yr*yr + yi*yi;
                 match x {
-                    Value::Int(x)         => Node::Complex(x as f64*yr/den, -x as f64*yi/den),
-                    Value::Float(x)       => Node::Complex(x*yr/den, -x*yi/den),
-                    Value::Complex(xr,xi) => Node::Complex((xr*yr + xi*yi)/den, (xi*yr - xr*yi)/den),
-                    _                     => return Err(EvalError { message: "Invalid types for '/'".to_string() })
+                    Value::Int(x)         => Value::Complex(x as f64*yr/den, -x as f64*yi/den),
+                    Value::Float(x)       => Value::Complex(x*yr/den, -x*yi/den),
+                    Value::Complex(xr,xi) => Value::Complex((xr*yr + xi*yi)/den, (xi*yr - xr*yi)/den),
+                    _                     => return Err(EvalError { location: None, message: "Invalid types for '/'".to_string() })
                 }
             },
-            _                                                  => return Err(EvalError { message: "Invalid types for '/'".to_string() })
+            _                                                  => return Err(EvalError { location: None, message: "Invalid types for '/'".to_string() })
         };
     
-        let mut xs = xs.clone();
+        let mut xs = xs.to_vec();
         xs.remove(0);
         xs.remove(0);
         xs.insert(0, x_div_y);
-        div(interpreter, &xs)
+        div(_interpreter, &xs)
     }
 }
 
-fn pow(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+// (expt x y) is exact for an integer raised to a non-negative integer power, unless the result
+// doesn't fit in an integer, and a float otherwise
+fn pow(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 2 {
-        return Err(EvalError { message: "'expt' takes exactly two arguments".to_string() })
+        return Err(EvalError { location: None, message: "'expt' takes exactly two arguments".to_string() })
     }
 
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val)  => val,
-        err      => return err
-    };
-    let y = match interpreter.eval_node(&xs[1]) {
-        Ok(val)  => val,
-        err      => return err
-    };
+    let x = xs[0].clone();
+    let y = xs[1].clone();
 
     match (x, y) {
-        (Value::Int(x), Value::Int(y))           => Ok(Value::Float((x as f64).powi(y))),
+        (Value::Int(x), Value::Int(y))           => match x.checked_pow(y as u32) {
+            Some(val) if y >= 0 => Ok(Value::Int(val)),
+            _                   => Ok(Value::Float((x as f64).powi(y)))
+        },
         (Value::Float(x), Value::Int(y))         => Ok(Value::Float(x.powi(y))),
         (Value::Int(x), Value::Float(y))         => Ok(Value::Float((x as f64).powf(y))),
         (Value::Float(x), Value::Float(y))       => Ok(Value::Float(x.powf(y))),
-        (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-        _                                        => Err(EvalError { message: "Invalid types for 'pow'".to_string() })
+        _                                        => Err(EvalError { location: None, message: "Invalid types for 'pow'".to_string() })
     }
 }
 
-pub fn def(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {   
+pub fn def(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {   
+    if xs.len() < 2 {
+        return Err(EvalError { location: None, message: "'define' requires a name and a value".to_string() })
+    }
     let x = xs[0].clone();
 
+    // (define (f a . rest) ...) is read as a dotted list
+    let (x, rest) = match x {
+        Node::Pair(nodes, rest) => (Node::List(nodes, None), Some(rest)),
+        x                       => (x, None)
+    };
     match x {
-        Node::List(nodes) => {
-            let params = &nodes[1..].to_vec();
+        Node::List(nodes, location) if !nodes.is_empty() => {
+            let formals = match rest {
+                Some(rest) if nodes.len() == 1 => *rest,
+                Some(rest)                     => Node::Pair(nodes[1..].to_vec(), rest),
+                None                           => Node::List(nodes[1..].to_vec(), location)
+            };
             let body = if xs.len() > 2 {
-                let mut newbody = xs.clone();
+                let mut newbody = xs.to_vec();
                 newbody[0] = Node::Symbol("begin".to_string());
-                Node::List(newbody)
+                Node::List(newbody, location)
             } else {
                 xs[1].clone()
             };
-            let lambda = Node::ValueWrapper(Box::new(Value::Lambda(Lambda { params: params.clone(), body: body.clone() })));
-            def(interpreter, &vec![nodes[0].clone(), lambda])
+            let lambda = match nodes[0] {
+                // (define ((f a) b) ...) is (define (f a) (lambda (b) ...)), which is left as an
+                // expression so that a is replaced in it when f is called
+                Node::List(..) => Node::List(vec![Node::Symbol("lambda".to_string()), formals, body], location),
+                _              => {
+                    let mut lambda = Lambda::from_formals(&formals, macros::expand_all(&body, &interpreter.env)?)?;
+                    lambda.scope = Some(interpreter.env.clone());
+                    Node::ValueWrapper(Box::new(Value::Lambda(Box::new(lambda))))
+                }
+            };
+            def(interpreter, &[nodes[0].clone(), lambda])
         },
         Node::Symbol(label) => {
+            check_binding(&label, "define")?;
             let y = match interpreter.eval_node(&xs[1]) {
+                // A procedure takes the first name it's defined with
+                Ok(Value::Lambda(mut lambda)) => {
+                    lambda.name = lambda.name.or_else(|| Some(label.clone()));
+                    Value::Lambda(lambda)
+                },
                 Ok(val) => val,
                 err     => return err
             };
             interpreter.env.set(label, y); Ok(Value::Void)
         },
-        _ => Err(EvalError { message: format!("Can't define {}", xs[0]).to_string() })
+        _ => Err(EvalError { location: None, message: format!("Can't define {}", xs[0]).to_string() })
     }
 }
 
-fn gt(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+// (define-syntax name (syntax-rules (literal ...) (pattern template) ...)) defines a macro: see
+// macros::Macro
+fn define_syntax(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
     if xs.len() != 2 {
-        return Err(EvalError { message: "'>' takes exactly two arguments".to_string() })
+        return Err(EvalError { location: None, message: "'define-syntax' takes exactly two arguments".to_string() })
     }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    
-    let y = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => val,
-        err     => return err
+
+    let name = match xs[0] {
+        Node::Symbol(ref name) => name,
+        _                      => return Err(EvalError { location: None, message: format!("Can't define-syntax {}", xs[0]) })
     };
-    match (x, y) {
-        (Value::Int(x), Value::Int(y))     => Ok(Value::Bool(x > y)),
-        (Value::Float(x), Value::Int(y))   => Ok(Value::Bool(x > y as f64)),
-        (Value::Int(x), Value::Float(y))   => Ok(Value::Bool(x as f64 > y)),
-        (Value::Float(x), Value::Float(y)) => Ok(Value::Bool(x > y)),
-        (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-        _                                  => Err(EvalError { message: "Invalid types for '>'".to_string() })
-    }
+    check_binding(name, "define-syntax")?;
+    let val = Value::Macro(Shared::new(Macro::new(name, &xs[1])?));
+    interpreter.env.set(name.clone(), val);
+    Ok(Value::Void)
 }
-fn gte(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+
+// (set! name expr) changes the nearest binding of name to the value of expr.  Unlike define, it
+// never makes a new binding, so name has to be bound already.
+fn set(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
     if xs.len() != 2 {
-        return Err(EvalError { message: "'>=' takes exactly two arguments".to_string() })
+        return Err(EvalError { location: None, message: "'set!' takes exactly two arguments".to_string() })
     }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    
-    let y = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => val,
-        err     => return err
+
+    let label = match xs[0] {
+        Node::Symbol(ref label) => label,
+        _                       => return Err(EvalError { location: None, message: format!("Can't set! {}", xs[0]) })
     };
-    match (x, y) {
-        (Value::Int(x), Value::Int(y))     => Ok(Value::Bool(x >= y)),
-        (Value::Float(x), Value::Int(y))   => Ok(Value::Bool(x >= y as f64)),
-        (Value::Int(x), Value::Float(y))   => Ok(Value::Bool(x as f64 >= y)),
-        (Value::Float(x), Value::Float(y)) => Ok(Value::Bool(x >= y)),
-        (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-        _                                  => Err(EvalError { message: "Invalid types for '>='".to_string() })
+    check_binding(label, "set!")?;
+    let val = interpreter.eval_node(&xs[1])?;
+    if interpreter.env.assign(label, val) {
+        Ok(Value::Void)
+    } else {
+        Err(EvalError { location: None, message: format!("Can't set! {}, which isn't defined", label) })
     }
 }
 
-fn lt(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 2 {
-        return Err(EvalError { message: "'<' takes exactly two arguments".to_string() })
+// (values x ...) returns several values at once, for define-values to bind
+fn values(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() == 1 {
+        Ok(xs[0].clone())
+    } else {
+        Ok(Value::Values(xs.to_vec()))
     }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
+}
+
+// Match formals like (a b) or (a b . rest) against multiple values or a list, returning the
+// name each value is bound to.  rest gets a list of whatever's left over.
+fn destructure(formals: &Node, val: Value, name: &str) -> Result<Vec<(String, Value)>, EvalError> {
+    let (params, rest) = match *formals {
+        Node::List(ref params, _)        => (params, None),
+        Node::Pair(ref params, ref rest) => (params, Some(&**rest)),
+        _                                => return Err(EvalError { location: None, message: format!("'{}' requires a list of names", name) })
     };
-    
-    let y = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => val,
-        err     => return err
+    let vals = match val {
+        Value::Values(vals) => vals,
+        val                 => match val.list_elements() {
+            Some(vals) => vals,
+            None       => return Err(EvalError { location: None, message: format!("Invalid type for '{}'", name) })
+        }
     };
-    match (x, y) {
-        (Value::Int(x), Value::Int(y))     => Ok(Value::Bool(x < y)),
-        (Value::Float(x), Value::Int(y))   => Ok(Value::Bool(x < y as f64)),
-        (Value::Int(x), Value::Float(y))   => Ok(Value::Bool((x as f64) < y)),
-        (Value::Float(x), Value::Float(y)) => Ok(Value::Bool(x < y)),
-        (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-        _                                  => Err(EvalError { message: "Invalid types for '<'".to_string() })
+    if vals.len() < params.len() || (rest.is_none() && vals.len() > params.len()) {
+        return Err(EvalError { location: None, message: format!("'{}' expected {} values, got {}", name, params.len(), vals.len()) })
+    }
+
+    let mut bindings = Vec::new();
+    let mut vals = vals.into_iter();
+    for param in params.iter().map(Some).chain(iter::once(rest)) {
+        let label = match param {
+            Some(Node::Symbol(label)) => {
+                check_binding(label, name)?;
+                label.clone()
+            },
+            Some(param)               => return Err(EvalError { location: None, message: format!("Can't bind {}", param) }),
+            None                      => break
+        };
+        let val = if bindings.len() < params.len() {
+            vals.next().unwrap()
+        } else {
+            Value::list(vals.by_ref().collect())
+        };
+        bindings.push((label, val));
     }
+    Ok(bindings)
 }
 
-fn lte(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+// (define-values (a b . rest) expr) defines each name as one of the values expr returns
+fn define_values(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
     if xs.len() != 2 {
-        return Err(EvalError { message: "'<=' takes exactly two arguments".to_string() })
+        return Err(EvalError { location: None, message: "'define-values' takes exactly two arguments".to_string() })
     }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    
-    let y = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    match (x, y) {
-        (Value::Int(x), Value::Int(y))     => Ok(Value::Bool(x <= y)),
-        (Value::Float(x), Value::Int(y))   => Ok(Value::Bool(x <= y as f64)),
-        (Value::Int(x), Value::Float(y))   => Ok(Value::Bool(x as f64 <= y)),
-        (Value::Float(x), Value::Float(y)) => Ok(Value::Bool(x <= y)),
-        (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-        _                                  => Err(EvalError { message: "Invalid types for '<='".to_string() })
+
+    let val = interpreter.eval_node(&xs[1])?;
+    for (label, val) in destructure(&xs[0], val, "define-values")? {
+        interpreter.env.set(label, val);
     }
+    Ok(Value::Void)
 }
 
-fn eq(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 2 {
-        return Err(EvalError { message: "'=' takes exactly two arguments".to_string() })
-    }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    
-    let y = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => val,
-        err     => return err
+// The ((name init) ...) bindings of a let, let* or letrec, which has to be followed by a body
+fn let_bindings<'a>(xs: &'a [Node], form: &str) -> Result<Vec<(String, &'a Node)>, EvalError> {
+    let bindings = match xs.first() {
+        Some(Node::List(bindings, _)) => bindings.iter().map(|binding| match *binding {
+            Node::List(ref binding, _) if binding.len() == 2 => match binding[0] {
+                Node::Symbol(ref name) => Some((name.clone(), &binding[1])),
+                _                      => None
+            },
+            _ => None
+        }).collect::<Option<Vec<_>>>(),
+        _ => None
     };
-    match (x, y) {
-        (Value::Int(x), Value::Int(y))                   => Ok(Value::Bool(x == y)),
-        (Value::Float(x), Value::Int(y))                 => Ok(Value::Bool(x == y as f64)),
-        (Value::Int(x), Value::Float(y))                 => Ok(Value::Bool(x as f64 == y)),
-        (Value::Float(x), Value::Float(y))               => Ok(Value::Bool(x == y)),
-        (Value::Complex(xr, xi), Value::Int(y))          => Ok(Value::Bool(xr == y as f64 && xi == 0.0)),
-        (Value::Complex(xr, xi), Value::Float(y))        => Ok(Value::Bool(xr == y && xi == 0.0)),
-        (Value::Int(x), Value::Complex(yr, yi))          => Ok(Value::Bool(x as f64== yr && yi == 0.0)),
-        (Value::Float(x), Value::Complex(yr, yi))        => Ok(Value::Bool(x == yr && yi == 0.0)),
-        (Value::Complex(xr, xi), Value::Complex(yr, yi)) => Ok(Value::Bool(xr == yr && xi == yi)),
-        (Value::Literal(x), Value::Literal(y))           => Ok(Value::Bool(x == y)),
-        (Value::String(x), Value::String(y))             => Ok(Value::Bool(x == y)),
-        (Value::Symbol(val), _) | (_, Value::Symbol(val)) => Err(EvalError { message: format!("Unknown symbol {}", val).to_string() }),
-        _                                  => Err(EvalError { message: "Invalid types for '='".to_string() })
+    for (name, _) in bindings.iter().flatten() {
+        check_binding(name, form)?;
+    }
+    match bindings {
+        Some(_) if xs.len() < 2 => Err(EvalError { location: None, message: format!("'{}' requires a body", form) }),
+        Some(bindings)          => Ok(bindings),
+        None                    => Err(EvalError { location: None, message: format!("'{}' requires a list of (name value) bindings", form) })
     }
 }
 
-fn not(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 1 {
-        return Err(EvalError { message: "'not' takes exactly one argument".to_string() })
-    }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    match x {
-       Value::Bool(val) => Ok(Value::Bool(!val)),
-       _ => Err(EvalError { message: "Invalid type for 'not'".to_string() })
+// The body of a let-like form, evaluated with the bindings in env.  Its last form is handed back as
+// a tail call in env, unless it defines something and so needs a scope of its own.
+fn let_body(interpreter: &mut Interpreter, env: Environment, xs: &[Node]) -> Result<Value, EvalError> {
+    if xs.iter().any(interpreter::is_definition) {
+        return begin(&mut interpreter.with_env(env), xs);
+    }
+    match xs.split_last() {
+        Some((last, init)) => {
+            let mut interpreter = interpreter.with_env(env.clone());
+            for node in init {
+                interpreter.eval_node(node)?;
+            }
+            Ok(Value::ScopedNode(last.clone(), env))
+        },
+        None => Ok(Value::Void)
     }
 }
 
-fn and(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() == 0 {
-        return return Ok(Value::Bool(true));
+// (let ((name init) ...) body ...) evaluates every init in the current scope, then the body with
+// the names bound to their values
+fn let_form(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    if let Some(Node::Symbol(name)) = xs.first() {
+        return named_let(interpreter, name, &xs[1..]);
     }
-    
-    let x = interpreter.eval_node(&xs[0]);
-    let ys = xs[1..].to_vec().clone();
-    
-    if ys.len() == 0 {
-        x
-    } else {
-        match x {
-            Ok(Value::Bool(true))  => and(interpreter, &ys),
-            Ok(Value::Bool(false)) => x,
-            Ok(_)                  => Err(EvalError { message: "Invalid type for 'and'".to_string() }),
-            err                    => err
-        }
+    let mut vals = Vec::new();
+    for (name, init) in let_bindings(xs, "let")? {
+        vals.push((name, interpreter.eval_node(init)?));
     }
+    let mut env = interpreter.inner_env()?;
+    for (name, val) in vals {
+        env.set(name, val);
+    }
+    let_body(interpreter, env, &xs[1..])
 }
 
-fn or(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() == 0 {
-        return return Ok(Value::Bool(false));
-    }
-    
-    let x = interpreter.eval_node(&xs[0]);
-    let ys = xs[1..].to_vec().clone();
-    
-    if ys.len() == 0 {
-        x
+// (let loop ((name init) ...) body ...) is a let whose body can call loop with new values for the
+// names.  Since the call is a tail call, this is how to loop without deep recursion.
+fn named_let(interpreter: &mut Interpreter, name: &str, xs: &[Node]) -> Result<Value, EvalError> {
+    check_binding(name, "let")?;
+    let bindings = let_bindings(xs, "let")?;
+    let body = if xs.len() > 2 {
+        let mut newbody = xs.to_vec();
+        newbody[0] = Node::Symbol("begin".to_string());
+        Node::List(newbody, None)
     } else {
-        match x {
-            Ok(Value::Bool(false)) => or(interpreter, &ys),
-            Ok(Value::Bool(true))  => x,
-            Ok(_)                  => Err(EvalError { message: "Invalid type for 'and'".to_string() }),
-            err                    => err
-        }
-    }
+        xs[1].clone()
+    };
+    let mut params = Vec::new();
+    let mut vals = Vec::new();
+    for (param, init) in bindings {
+        params.push(Node::Symbol(param));
+        vals.push(interpreter.eval_node(init)?);
+    }
+    // The lambda's scope is the one that binds its name, so it can call itself
+    let mut env = interpreter.inner_env()?;
+    let mut lambda = Lambda::new(params, macros::expand_all(&body, &interpreter.env)?)?;
+    lambda.name = Some(name.to_string());
+    lambda.scope = Some(env.clone());
+    let lambda = Value::Lambda(Box::new(lambda));
+    env.set(name.to_string(), lambda.clone());
+    // The first call of the loop is a tail call too
+    Ok(Value::NodeWrapper(interpreter::call_node(lambda, vals, &[])))
 }
 
-fn list(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    let mut vals: Vec<Value> = Vec::new();
-    
-    for node in xs {
-        vals.push(match interpreter.eval_node(&node) {
-            Ok(val)  => val,
-            err      => return err
-        });
-    }
+// (let* ((name init) ...) body ...) is like let, but each init can use the names bound before it
+fn let_star(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let bindings = let_bindings(xs, "let*")?;
+    let mut env = interpreter.inner_env()?;
+    for (name, init) in bindings {
+        let val = interpreter.with_env(env.clone()).eval_node(init)?;
+        env.set(name, val);
+    }
+    let_body(interpreter, env, &xs[1..])
+}
 
-    Ok(Value::List(vals))
+// (letrec ((name init) ...) body ...) binds all the names before evaluating any init, so
+// procedures bound by it can call each other
+fn letrec(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let bindings = let_bindings(xs, "letrec")?;
+    let mut env = interpreter.inner_env()?;
+    for (name, _) in &bindings {
+        env.set(name.clone(), Value::Void);
+    }
+    for (name, init) in bindings {
+        let val = interpreter.with_env(env.clone()).eval_node(init)?;
+        env.set(name, val);
+    }
+    let_body(interpreter, env, &xs[1..])
 }
 
-fn emptyq(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 1 {
-        return Err(EvalError { message: "'empty?' takes exactly one argument".to_string() })
+// (do ((name init step) ...) (test result ...) command ...) binds each name to its init, then
+// until test is true evaluates the commands and gives every name the value of its step (or
+// leaves it alone if it has none).  The value is that of the last result, if there are any.
+fn do_fn(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let specs = match xs.first() {
+        Some(Node::List(specs, _)) => specs.iter().map(|spec| match *spec {
+            Node::List(ref spec, _) if spec.len() == 2 || spec.len() == 3 => match spec[0] {
+                Node::Symbol(ref name) => Some((name.clone(), &spec[1], spec.get(2))),
+                _                      => None
+            },
+            _ => None
+        }).collect::<Option<Vec<_>>>(),
+        _ => None
+    };
+    let specs = match specs {
+        Some(specs) => specs,
+        None        => return Err(EvalError { location: None, message: "'do' requires a list of (name init step) clauses".to_string() })
+    };
+    for (name, _, _) in &specs {
+        check_binding(name, "do")?;
     }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
+    let (test, results) = match xs.get(1) {
+        Some(Node::List(clause, _)) if !clause.is_empty() => (&clause[0], &clause[1..]),
+        _ => return Err(EvalError { location: None, message: "'do' requires a (test result ...) clause".to_string() })
     };
-    match x {
-        Value::List(vals) => Ok(Value::Bool(vals.len() == 0)),
-        _                 => Err(EvalError { message: "Invalid type for 'empty?'".to_string() })
+
+    let mut vals = Vec::new();
+    for &(ref name, init, _) in &specs {
+        vals.push((name.clone(), interpreter.eval_node(init)?));
+    }
+    loop {
+        // Each time round has bindings of its own, so a lambda made in one keeps its values
+        let mut env = interpreter.inner_env()?;
+        for (name, val) in vals {
+            env.set(name, val);
+        }
+        let mut interpreter = interpreter.with_env(env);
+        let val = interpreter.eval_node(test)?;
+        if interpreter.is_true(&val, "do")? {
+            let mut val = Value::Void;
+            for node in results {
+                val = interpreter.eval_node(node)?;
+            }
+            return Ok(val);
+        }
+        for node in &xs[2..] {
+            interpreter.eval_node(node)?;
+        }
+        // Every step is evaluated before any name changes
+        vals = Vec::new();
+        for &(ref name, _, step) in &specs {
+            vals.push((name.clone(), match step {
+                Some(step) => interpreter.eval_node(step)?,
+                None       => interpreter.eval_node(&Node::Symbol(name.clone()))?
+            }));
+        }
     }
 }
 
-fn length(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 1 {
-        return Err(EvalError { message: "'length' takes exactly one argument".to_string() })
-    }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+// (while test body ...) evaluates the body over and over for as long as test is true, checking
+// it before each pass.  Its value is void.
+fn while_fn(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    repeat(interpreter, xs, "while", true)
+}
+
+// (until test body ...) is while with the test the other way around
+fn until_fn(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    repeat(interpreter, xs, "until", false)
+}
+
+fn repeat(interpreter: &mut Interpreter, xs: &[Node], form: &str, keep_going: bool) -> Result<Value, EvalError> {
+    let test = match xs.first() {
+        Some(test) => test,
+        None       => return Err(EvalError { location: None, message: format!("'{}' requires a test", form) })
+    };
+    let env = interpreter.inner_env()?;
+    let mut interpreter = interpreter.with_env(env);
+    loop {
+        let val = interpreter.eval_node(test)?;
+        if interpreter.is_true(&val, form)? != keep_going {
+            return Ok(Value::Void);
+        }
+        for node in &xs[1..] {
+            interpreter.eval_node(node)?;
+        }
+    }
+}
+
+// (let-list ((a b . rest) lst) body ...) evaluates body with the names bound to the elements
+// of lst
+fn let_list(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let (formals, expr) = match xs.first() {
+        Some(Node::List(clause, _)) if clause.len() == 2 => (&clause[0], &clause[1]),
+        _ => return Err(EvalError { location: None, message: "'let-list' requires a (names list) clause".to_string() })
+    };
+
+    let val = interpreter.eval_node(expr)?;
+    let mut env = interpreter.inner_env()?;
+    for (label, val) in destructure(formals, val, "let-list")? {
+        env.set(label, val);
+    }
+    let_body(interpreter, env, &xs[1..])
+}
+
+fn gt(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'>' takes exactly two arguments".to_string() })
+    }
+    
+    let x = xs[0].clone();
+    
+    let y = xs[1].clone();
+    match (x, y) {
+        (Value::Int(x), Value::Int(y))     => Ok(Value::Bool(x > y)),
+        (Value::Float(x), Value::Int(y))   => Ok(Value::Bool(x > y as f64)),
+        (Value::Int(x), Value::Float(y))   => Ok(Value::Bool(x as f64 > y)),
+        (Value::Float(x), Value::Float(y)) => Ok(Value::Bool(x > y)),
+        _                                  => Err(EvalError { location: None, message: "Invalid types for '>'".to_string() })
+    }
+}
+fn gte(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'>=' takes exactly two arguments".to_string() })
+    }
+    
+    let x = xs[0].clone();
+    
+    let y = xs[1].clone();
+    match (x, y) {
+        (Value::Int(x), Value::Int(y))     => Ok(Value::Bool(x >= y)),
+        (Value::Float(x), Value::Int(y))   => Ok(Value::Bool(x >= y as f64)),
+        (Value::Int(x), Value::Float(y))   => Ok(Value::Bool(x as f64 >= y)),
+        (Value::Float(x), Value::Float(y)) => Ok(Value::Bool(x >= y)),
+        _                                  => Err(EvalError { location: None, message: "Invalid types for '>='".to_string() })
+    }
+}
+
+fn lt(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'<' takes exactly two arguments".to_string() })
+    }
+    
+    let x = xs[0].clone();
+    
+    let y = xs[1].clone();
+    match (x, y) {
+        (Value::Int(x), Value::Int(y))     => Ok(Value::Bool(x < y)),
+        (Value::Float(x), Value::Int(y))   => Ok(Value::Bool(x < y as f64)),
+        (Value::Int(x), Value::Float(y))   => Ok(Value::Bool((x as f64) < y)),
+        (Value::Float(x), Value::Float(y)) => Ok(Value::Bool(x < y)),
+        _                                  => Err(EvalError { location: None, message: "Invalid types for '<'".to_string() })
+    }
+}
+
+fn lte(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'<=' takes exactly two arguments".to_string() })
+    }
+    
+    let x = xs[0].clone();
+    
+    let y = xs[1].clone();
+    match (x, y) {
+        (Value::Int(x), Value::Int(y))     => Ok(Value::Bool(x <= y)),
+        (Value::Float(x), Value::Int(y))   => Ok(Value::Bool(x <= y as f64)),
+        (Value::Int(x), Value::Float(y))   => Ok(Value::Bool(x as f64 <= y)),
+        (Value::Float(x), Value::Float(y)) => Ok(Value::Bool(x <= y)),
+        _                                  => Err(EvalError { location: None, message: "Invalid types for '<='".to_string() })
+    }
+}
+
+fn eq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'=' takes exactly two arguments".to_string() })
+    }
+    
+    eq_values(&xs[0], &xs[1]).map(Value::Bool)
+}
+
+// Whether two numbers, symbols, strings, characters or keywords are the same
+fn eq_values(x: &Value, y: &Value) -> Result<bool, EvalError> {
+    match (x, y) {
+        (&Value::Int(x), &Value::Int(y))                   => Ok(x == y),
+        (&Value::Float(x), &Value::Int(y))                 => Ok(x == y as f64),
+        (&Value::Int(x), &Value::Float(y))                 => Ok(x as f64 == y),
+        (&Value::Float(x), &Value::Float(y))               => Ok(x == y),
+        (&Value::Complex(xr, xi), &Value::Int(y))          => Ok(xr == y as f64 && xi == 0.0),
+        (&Value::Complex(xr, xi), &Value::Float(y))        => Ok(xr == y && xi == 0.0),
+        (&Value::Int(x), &Value::Complex(yr, yi))          => Ok(x as f64== yr && yi == 0.0),
+        (&Value::Float(x), &Value::Complex(yr, yi))        => Ok(x == yr && yi == 0.0),
+        (&Value::Complex(xr, xi), &Value::Complex(yr, yi)) => Ok(xr == yr && xi == yi),
+        (Value::Literal(x), Value::Literal(y))             => Ok(x == y),
+        (Value::String(x), Value::String(y))               => Ok(x == y),
+        (&Value::Char(x), &Value::Char(y))                 => Ok(x == y),
+        (&Value::Rational(a, b), &Value::Rational(c, d))   => Ok(a == c && b == d),
+        (Value::Keyword(x), Value::Keyword(y))             => Ok(x == y),
+        // A keyword is never the same as the symbol with its name
+        (&Value::Keyword(_), &Value::Literal(_)) | (&Value::Literal(_), &Value::Keyword(_)) => Ok(false),
+        _                                  => Err(EvalError { location: None, message: "Invalid types for '='".to_string() })
+    }
+}
+
+// (eqv? a b) is true for numbers of the same exactness and value, for the same symbols,
+// strings, characters, keywords or booleans, and for a list and itself.  Unlike =, it's just
+// false for values of different types.
+fn eqv(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'eqv?' takes exactly two arguments".to_string() })
+    }
+
+    Ok(Value::Bool(eqv_values(&xs[0], &xs[1])))
+}
+
+fn eqv_values(x: &Value, y: &Value) -> bool {
+    match (x, y) {
+        (&Value::Int(x), &Value::Int(y))                   => x == y,
+        (&Value::Float(x), &Value::Float(y))               => x == y,
+        (&Value::Complex(xr, xi), &Value::Complex(yr, yi)) => xr == yr && xi == yi,
+        (&Value::Rational(a, b), &Value::Rational(c, d))   => a == c && b == d,
+        (&Value::Bool(x), &Value::Bool(y))                 => x == y,
+        (&Value::Char(x), &Value::Char(y))                 => x == y,
+        (Value::Literal(x), Value::Literal(y))             => x == y,
+        (Value::String(x), Value::String(y))               => x == y,
+        (Value::Keyword(x), Value::Keyword(y))             => x == y,
+        (Value::Pair(x), Value::Pair(y))                   => Shared::ptr_eq(x, y),
+        (&Value::Nil, &Value::Nil)                         => true,
+        _                                                  => false
+    }
+}
+
+// (equal? a b) compares lists, vectors and pairs element by element, and anything else the way =
+// does, except that values of different types are just unequal.  It keeps its own stack, and
+// never compares the same two lists twice, so a structure that's deep, shared or cyclic can't
+// overflow or hang it.  After max_compare comparisons it gives up with an error.
+fn equal(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'equal?' takes exactly two arguments".to_string() })
+    }
+
+    let max = interpreter.config.max_compare;
+    let mut seen = HashSet::new();
+    let mut stack = vec![(xs[0].clone(), xs[1].clone())];
+    let mut compared = 0;
+    while let Some((x, y)) = stack.pop() {
+        compared += 1;
+        if let Some(max) = max.filter(|&max| compared > max) {
+            return Err(EvalError { location: None, message: format!("'equal?' gave up after {} comparisons", max) })
+        }
+        match (&x, &y) {
+            (Value::Pair(xp), Value::Pair(yp)) => {
+                if !seen.insert((Shared::as_ptr(xp), Shared::as_ptr(yp))) {
+                    continue;
+                }
+                let (x_car, x_cdr) = x.uncons().unwrap();
+                let (y_car, y_cdr) = y.uncons().unwrap();
+                stack.push((x_cdr, y_cdr));
+                stack.push((x_car, y_car));
+            },
+            (&Value::Nil, &Value::Nil) => (),
+            (&Value::Vector(ref xs), &Value::Vector(ref ys)) | (&Value::Values(ref xs), &Value::Values(ref ys)) => {
+                if xs.len() != ys.len() {
+                    return Ok(Value::Bool(false));
+                }
+                stack.extend(xs.iter().cloned().zip(ys.iter().cloned()).rev());
+            },
+            _ => if !eq_values(&x, &y).unwrap_or(false) {
+                return Ok(Value::Bool(false));
+            }
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn not(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'not' takes exactly one argument".to_string() })
+    }
+    
+    let x = xs[0].clone();
+    Ok(Value::Bool(!interpreter.is_true(&x, "not")?))
+}
+
+// (and x ...) evaluates its operands left to right, stopping at the first false one, and gives
+// the last value it evaluated (#t if there are none).  The last operand is a tail call.
+fn and(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let (last, init) = match xs.split_last() {
+        Some(split) => split,
+        None        => return Ok(Value::Bool(true))
+    };
+    for node in init {
+        let x = interpreter.eval_node(node)?;
+        if !interpreter.is_true(&x, "and")? {
+            return Ok(x);
+        }
+    }
+    Ok(Value::NodeWrapper(last.clone()))
+}
+
+// (or x ...) evaluates its operands left to right, stopping at the first true one, and gives the
+// last value it evaluated (#f if there are none).  The last operand is a tail call.
+fn or(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let (last, init) = match xs.split_last() {
+        Some(split) => split,
+        None        => return Ok(Value::Bool(false))
+    };
+    for node in init {
+        let x = interpreter.eval_node(node)?;
+        if interpreter.is_true(&x, "or")? {
+            return Ok(x);
+        }
+    }
+    Ok(Value::NodeWrapper(last.clone()))
+}
+
+fn list(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    Ok(Value::list(xs.to_vec()))
+}
+
+fn emptyq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'empty?' takes exactly one argument".to_string() })
+    }
+    
+    let x = xs[0].clone();
     match x {
-        Value::List(vals) => Ok(Value::Int(vals.len() as i32)),
-        _                 => Err(EvalError { message: "Invalid type for 'length'".to_string() })
+        Value::Nil     => Ok(Value::Bool(true)),
+        Value::Pair(_) => Ok(Value::Bool(false)),
+        _                 => Err(EvalError { location: None, message: "Invalid type for 'empty?'".to_string() })
+    }
+}
+
+// (void args...) ignores its arguments and returns the unspecified value, which is what
+// define, set! and other procedures called only for their effects return
+fn void(_interpreter: &mut Interpreter, _xs: &[Value]) -> Result<Value, EvalError> {
+    Ok(Value::Void)
+}
+
+fn voidq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'void?' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].clone() {
+        Value::Void => Ok(Value::Bool(true)),
+        _           => Ok(Value::Bool(false))
+    }
+}
+
+fn symbolq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [val] => Ok(Value::Bool(matches!(*val, Value::Literal(_)))),
+        _     => Err(EvalError { location: None, message: "'symbol?' takes exactly one argument".to_string() })
+    }
+}
+
+// (gensym prefix) is a symbol that isn't equal to any other, whether it's been read or made by
+// gensym, for macros and code generators to bind.  The prefix, a string or symbol, is g by default.
+fn gensym(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let prefix = match xs {
+        []                                                 => "g".to_string(),
+        [Value::String(prefix)] | [Value::Literal(prefix)] => prefix.clone(),
+        _ => return Err(EvalError { location: None, message: "'gensym' takes an optional string or symbol prefix".to_string() })
+    };
+    Ok(Value::Literal(macros::gensym(&prefix)))
+}
+
+fn numberq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [val] => Ok(Value::Bool(matches!(*val, Value::Int(_) | Value::Float(_) | Value::Rational(..) | Value::Complex(..)))),
+        _     => Err(EvalError { location: None, message: "'number?' takes exactly one argument".to_string() })
+    }
+}
+
+// Whether a value is a proper list, which a dotted pair isn't
+fn listq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [val] => Ok(Value::Bool(val.list_elements().is_some())),
+        _     => Err(EvalError { location: None, message: "'list?' takes exactly one argument".to_string() })
+    }
+}
+
+fn pairq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [val] => Ok(Value::Bool(matches!(*val, Value::Pair(_)))),
+        _     => Err(EvalError { location: None, message: "'pair?' takes exactly one argument".to_string() })
+    }
+}
+
+fn keywordq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'keyword?' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].clone() {
+        Value::Keyword(_) => Ok(Value::Bool(true)),
+        _                 => Ok(Value::Bool(false))
+    }
+}
+
+// (keyword->string #:name) is "name", without the #:
+fn keyword_to_string(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'keyword->string' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].clone() {
+        Value::Keyword(name) => Ok(Value::String(name)),
+        _                    => Err(EvalError { location: None, message: "Invalid type for 'keyword->string'".to_string() })
+    }
+}
+
+fn string_to_keyword(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'string->keyword' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].clone() {
+        Value::String(ref name) if !name.is_empty() => Ok(Value::Keyword(name.clone())),
+        _                                           => Err(EvalError { location: None, message: "Invalid type for 'string->keyword'".to_string() })
+    }
+}
+
+fn length(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'length' takes exactly one argument".to_string() })
+    }
+    
+    match xs[0].list_elements() {
+        Some(vals) => Ok(Value::Int(vals.len() as i32)),
+        None       => Err(EvalError { location: None, message: "Invalid type for 'length'".to_string() })
+    }
+}
+
+fn vector(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    Ok(Value::Vector(xs.to_vec()))
+}
+
+fn vector_ref(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'vector-ref' takes exactly two arguments".to_string() })
+    }
+
+    let vector = xs[0].clone();
+    let index = xs[1].clone();
+    match (vector, index) {
+        (Value::Vector(vals), Value::Int(i)) => match vals.get(i as usize) {
+            Some(val) if i >= 0 => Ok(val.clone()),
+            _                   => Err(EvalError { location: None, message: format!("Index {} out of range for 'vector-ref'", i) })
+        },
+        _ => Err(EvalError { location: None, message: "Invalid type for 'vector-ref'".to_string() })
+    }
+}
+
+fn vector_length(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'vector-length' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].clone() {
+        Value::Vector(vals) => Ok(Value::Int(vals.len() as i32)),
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'vector-length'".to_string() })
+    }
+}
+
+fn vector_to_list(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'vector->list' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].clone() {
+        Value::Vector(vals) => Ok(Value::list(vals)),
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'vector->list'".to_string() })
+    }
+}
+
+fn list_to_vector(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'list->vector' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].list_elements() {
+        Some(vals) => Ok(Value::Vector(vals)),
+        None       => Err(EvalError { location: None, message: "Invalid type for 'list->vector'".to_string() })
+    }
+}
+
+fn car(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'car' takes exactly one argument".to_string() })
+    }
+    
+    match xs[0].uncons() {
+        Some((car, _)) => Ok(car),
+        None           => Err(EvalError { location: None, message: "Invalid type for 'car'".to_string() })
+    }
+}
+
+fn cdr(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'cdr' takes exactly one argument".to_string() })
+    }
+    
+    match xs[0].uncons() {
+        Some((_, cdr)) => Ok(cdr),
+        None           => Err(EvalError { location: None, message: "Invalid type for 'cdr'".to_string() })
+    }
+}
+
+fn cons(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'cons' takes exactly two arguments".to_string() })
+    }
+    
+    Ok(Value::cons(xs[0].clone(), xs[1].clone()))
+}
+
+// (set-car! pair x) and (set-cdr! pair x) change a pair in place, for every list that shares it
+fn set_car(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [Value::Pair(pair), val] => {
+            shared::lock(pair).car = val.clone();
+            Ok(Value::Void)
+        },
+        _ => Err(EvalError { location: None, message: "'set-car!' requires a pair and a value".to_string() })
+    }
+}
+
+fn set_cdr(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [Value::Pair(pair), val] => {
+            shared::lock(pair).cdr = val.clone();
+            Ok(Value::Void)
+        },
+        _ => Err(EvalError { location: None, message: "'set-cdr!' requires a pair and a value".to_string() })
+    }
+}
+
+fn append(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'cons' takes exactly two arguments".to_string() })
+    }
+    
+    // The result shares the second list, and copies the pairs of the first
+    match xs[0].list_elements() {
+        Some(vals) => Ok(Value::dotted(vals, xs[1].clone())),
+        None       => Err(EvalError { location: None, message: "Invalid type for 'cons'".to_string() })
+    }
+}
+
+fn if_fn(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    if xs.len() != 3 {
+        return Err(EvalError { location: None, message: "'if' takes exactly three arguments".to_string() })
+    }
+
+    let test = interpreter.eval_node(&xs[0])?;
+    let test = interpreter.is_true(&test, "if")?;
+    if test {
+        Ok(Value::NodeWrapper(xs[1].clone()))
+    } else {
+        Ok(Value::NodeWrapper(xs[2].clone()))
+    }
+}
+
+// (cond (test body ...) ... (else body ...)) evaluates the body of the first clause whose test is
+// true, and gives the value of its last form (or of the test, if it has no body).  In a
+// (test => receiver) clause, receiver is called with the value of the test, which counts as true
+// if it's anything but #f even with strict booleans.  With no matching clause, the value is void.
+fn cond(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    for (i, clause) in xs.iter().enumerate() {
+        let clause = match *clause {
+            Node::List(ref clause, _) if !clause.is_empty() => clause,
+            _ => return Err(EvalError { location: None, message: "'cond' requires (test body ...) clauses".to_string() })
+        };
+        let body = &clause[1..];
+        let test = match clause[0] {
+            Node::Symbol(ref name) if name == "else" => {
+                if i + 1 < xs.len() {
+                    return Err(EvalError { location: None, message: "'cond' requires the else clause to be last".to_string() })
+                }
+                Value::Bool(true)
+            },
+            ref test => interpreter.eval_node(test)?
+        };
+        match body.first() {
+            Some(Node::Symbol(arrow)) if arrow == "=>" => {
+                if body.len() != 2 {
+                    return Err(EvalError { location: None, message: "'cond' requires exactly one receiver after =>".to_string() })
+                }
+                if let Value::Bool(false) = test {
+                    continue;
+                }
+                let receiver = interpreter.eval_node(&body[1])?;
+                return interpreter.apply(receiver, vec![test]);
+            },
+            _ => if !interpreter.is_true(&test, "cond")? {
+                continue;
+            }
+        }
+        return match body.split_last() {
+            Some((last, init)) => {
+                for node in init {
+                    interpreter.eval_node(node)?;
+                }
+                Ok(Value::NodeWrapper(last.clone()))
+            },
+            None => Ok(test)
+        };
+    }
+    Ok(Value::Void)
+}
+
+// (call/cc f) calls f with the current continuation, a procedure that returns its argument from
+// the call to call/cc straight away, abandoning whatever f was doing.  Continuations can only
+// escape: one can't be called after its call/cc has returned.
+fn call_cc(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 || !is_procedure(&xs[0]) {
+        return Err(EvalError { location: None, message: "'call/cc' requires a procedure".to_string() })
+    }
+
+    let id = interpreter::enter_continuation();
+    let result = interpreter.apply(xs[0].clone(), vec![Value::Continuation(id)]);
+    match (interpreter::exit_continuation(id), result) {
+        (Some(val), _) => Ok(val),
+        (None, result) => result
+    }
+}
+
+// (case key ((datum ...) body ...) ... (else body ...)) evaluates the body of the first clause
+// with a datum that's eqv? to the value of key.  The datums aren't evaluated.  With no matching
+// clause, the value is void.
+fn case(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let key = match xs.first() {
+        Some(key) => interpreter.eval_node(key)?,
+        None      => return Err(EvalError { location: None, message: "'case' requires a key".to_string() })
+    };
+    for (i, clause) in xs[1..].iter().enumerate() {
+        let (datums, body) = match *clause {
+            Node::List(ref clause, _) if clause.len() >= 2 => (&clause[0], &clause[1..]),
+            _ => return Err(EvalError { location: None, message: "'case' requires ((datum ...) body ...) clauses".to_string() })
+        };
+        let matched = match *datums {
+            Node::Symbol(ref name) if name == "else" => {
+                if i + 2 < xs.len() {
+                    return Err(EvalError { location: None, message: "'case' requires the else clause to be last".to_string() })
+                }
+                true
+            },
+            Node::List(ref datums, _) => datums.iter().any(|datum| eqv_values(&quote_node(datum), &key)),
+            _ => return Err(EvalError { location: None, message: "'case' requires ((datum ...) body ...) clauses".to_string() })
+        };
+        if matched {
+            let (last, init) = body.split_last().unwrap();
+            for node in init {
+                interpreter.eval_node(node)?;
+            }
+            return Ok(Value::NodeWrapper(last.clone()));
+        }
+    }
+    Ok(Value::Void)
+}
+
+// (try expr handler) evaluates expr, and if that fails, calls handler with the error message
+// instead.  The handler isn't evaluated at all unless there's an error.
+fn try_fn(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'try' takes exactly two arguments".to_string() })
+    }
+
+    let handlers = interpreter::push_handler(None);
+    let result = interpreter.eval_node(&xs[0]);
+    interpreter::restore_handlers(handlers);
+    match result {
+        Ok(val)  => Ok(val),
+        Err(err) if interpreter::escaping() => Err(err),
+        Err(err) => {
+            interpreter::take_raised(&err.message);
+            let handler = interpreter.eval_node(&xs[1])?;
+            if !is_procedure(&handler) {
+                return Err(EvalError { location: None, message: "Invalid type for 'try'".to_string() })
+            }
+            interpreter.apply(handler, vec![Value::String(err.message)])
+        }
+    }
+}
+
+// (error message irritants...) raises an error object with the message and irritants, which
+// fails with the message followed by the irritants if nothing handles it
+fn error(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let message = match xs.first() {
+        Some(Value::String(val)) => val.clone(),
+        Some(val)                => val.to_string(),
+        None                     => return Err(EvalError { location: None, message: "'error' takes at least one argument".to_string() })
+    };
+    let obj = Value::Error(Shared::new(ErrorObject { message, irritants: xs[1..].to_vec() }));
+    raise_value(interpreter, obj, false)
+}
+
+// (raise obj) calls the innermost handler from with-exception-handler with obj, and fails if
+// the handler returns.  Inside a guard or try, or with no handler, it fails straight away, with
+// obj for guard to catch.
+fn raise(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [obj] => raise_value(interpreter, obj.clone(), false),
+        _     => Err(EvalError { location: None, message: "'raise' takes exactly one argument".to_string() })
+    }
+}
+
+// (raise-continuable obj) is like raise, except that the handler's value is returned
+fn raise_continuable(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [obj] => raise_value(interpreter, obj.clone(), true),
+        _     => Err(EvalError { location: None, message: "'raise-continuable' takes exactly one argument".to_string() })
+    }
+}
+
+fn raise_value(interpreter: &mut Interpreter, obj: Value, continuable: bool) -> Result<Value, EvalError> {
+    let message = match obj {
+        Value::Error(ref err) => err.text(),
+        ref obj               => format!("Uncaught raise of {}", obj)
+    };
+    let handler = match interpreter::pop_handler() {
+        Some(Some(handler)) => handler,
+        _                   => return Err(interpreter::raised(obj, message))
+    };
+    let result = interpreter.apply(handler.clone(), vec![obj]);
+    interpreter::push_handler(Some(handler));
+    let val = result?;
+    if continuable {
+        Ok(val)
+    } else {
+        let message = format!("The handler returned from a non-continuable raise: {}", message);
+        Err(interpreter::raised(Value::Error(Shared::new(ErrorObject { message: message.clone(), irritants: Vec::new() })), message))
+    }
+}
+
+// (with-exception-handler handler thunk) calls thunk with handler installed, so that raise and
+// error call it.  An error from a builtin can't be resumed, so handler is called with an error
+// object for it once it has unwound to here, and then the error carries on.
+fn with_exception_handler(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let (handler, thunk) = match xs {
+        [handler, thunk] if is_procedure(handler) && is_procedure(thunk) => (handler.clone(), thunk.clone()),
+        _ => return Err(EvalError { location: None, message: "'with-exception-handler' requires a handler and a thunk".to_string() })
+    };
+
+    let handlers = interpreter::push_handler(Some(handler.clone()));
+    let result = interpreter.apply(thunk, Vec::new());
+    interpreter::restore_handlers(handlers);
+    match result {
+        Err(err) if !interpreter::escaping() && !interpreter::is_raised(&err.message) => {
+            let obj = Value::Error(Shared::new(ErrorObject { message: err.message.clone(), irritants: Vec::new() }));
+            interpreter.apply(handler, vec![obj.clone()])?;
+            Err(interpreter::raised(obj, err.message))
+        },
+        result => result
     }
 }
 
-fn car(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 1 {
-        return Err(EvalError { message: "'car' takes exactly one argument".to_string() })
+// (guard (var clause ...) body ...) evaluates the body, and if it raises an object or fails,
+// binds var to the object (an error object for an error from a builtin) and chooses one of the
+// clauses, which are like cond's.  If none of them is chosen, the object is raised again.
+fn guard(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let (var, clauses) = match xs.first() {
+        Some(Node::List(ref spec, _)) if xs.len() > 1 => match spec.split_first() {
+            Some((Node::Symbol(var), clauses)) => (var.clone(), clauses.to_vec()),
+            _ => return Err(EvalError { location: None, message: "'guard' requires (var clause ...) and a body".to_string() })
+        },
+        _ => return Err(EvalError { location: None, message: "'guard' requires (var clause ...) and a body".to_string() })
+    };
+    check_binding(&var, "guard")?;
+
+    let handlers = interpreter::push_handler(None);
+    let result = begin_value(interpreter, &xs[1..]);
+    interpreter::restore_handlers(handlers);
+    let err = match result {
+        Err(err) if !interpreter::escaping() => err,
+        result                               => return result
+    };
+    let obj = interpreter::take_raised(&err.message)
+        .unwrap_or_else(|| Value::Error(Shared::new(ErrorObject { message: err.message, irritants: Vec::new() })));
+
+    let mut clauses = clauses;
+    let has_else = matches!(clauses.last(), Some(Node::List(ref clause, _)) if matches!(clause.first(), Some(Node::Symbol(ref name)) if name == "else"));
+    if !has_else {
+        let reraise = Node::List(vec![Node::ValueWrapper(Box::new(Value::Function("raise-continuable", Shared::new(raise_continuable)))), Node::ValueWrapper(Box::new(obj.clone()))], None);
+        clauses.push(Node::List(vec![Node::Symbol("else".to_string()), reraise], None));
+    }
+    let mut env = interpreter.inner_env()?;
+    env.set(var, obj);
+    let mut interpreter = interpreter.with_env(env);
+    match cond(&mut interpreter, &clauses)? {
+        Value::NodeWrapper(node) => interpreter.eval_node(&node),
+        val                      => Ok(val)
     }
-    
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
+}
+
+// (make-generator producer) makes a generator from a procedure of no arguments.  Calling the
+// generator gives the next value the producer passes to yield, or void once it's returned.
+fn make_generator(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [producer] if is_procedure(producer) => Ok(Value::Generator(Shared::new(Locked::new(Generator::new(producer.clone()))))),
+        _ => Err(EvalError { location: None, message: "'make-generator' requires a procedure".to_string() })
+    }
+}
+
+// (yield val) makes val the next value of the generator whose producer is running
+fn yield_fn(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [val] => generator::yield_value(val.clone()),
+        _     => Err(EvalError { location: None, message: "'yield' takes exactly one argument".to_string() })
+    }
+}
+
+// (make-parameter value converter) makes a parameter object, which returns value when it's called
+// with no arguments, until parameterize binds it to something else.  The optional converter is
+// applied to value and to every value parameterize binds it to.
+fn make_parameter(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let (value, converter) = match xs {
+        [value]                                       => (value.clone(), None),
+        [value, converter] if is_procedure(converter) => (interpreter.apply(converter.clone(), vec![value.clone()])?, Some(converter.clone())),
+        _ => return Err(EvalError { location: None, message: "'make-parameter' requires a value and an optional converter".to_string() })
     };
-    match x {
-        Value::List(vals) => Ok(vals[0].clone()),
-        _                 => Err(EvalError { message: "Invalid type for 'car'".to_string() })
+    Ok(Value::Parameter(Shared::new(Parameter { value, converter })))
+}
+
+// (parameterize ((param value) ...) body ...) evaluates every param and value, then the body
+// with each parameter bound to its value, for everything the body calls as well as the body
+// itself.  The bindings are undone however the body is left.
+fn parameterize(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    let bindings = match xs.first() {
+        Some(Node::List(ref bindings, _)) if xs.len() > 1 => bindings,
+        _ => return Err(EvalError { location: None, message: "'parameterize' requires a list of (parameter value) bindings and a body".to_string() })
+    };
+    let mut vals = Vec::new();
+    for binding in bindings {
+        let (param, val) = match *binding {
+            Node::List(ref binding, _) if binding.len() == 2 => (interpreter.eval_node(&binding[0])?, interpreter.eval_node(&binding[1])?),
+            _ => return Err(EvalError { location: None, message: "'parameterize' requires a list of (parameter value) bindings and a body".to_string() })
+        };
+        let param = match param {
+            Value::Parameter(param) => param,
+            _                       => return Err(EvalError { location: None, message: format!("'parameterize' requires a parameter, not {}", param) })
+        };
+        let val = match param.converter {
+            Some(ref converter) => interpreter.apply(converter.clone(), vec![val])?,
+            None                => val
+        };
+        vals.push((param, val));
+    }
+
+    let mut count = None;
+    for (param, val) in vals {
+        let before = interpreter::bind_parameter(param, val);
+        count = count.or(Some(before));
     }
+    let result = begin_value(interpreter, &xs[1..]);
+    if let Some(count) = count {
+        interpreter::unbind_parameters(count);
+    }
+    result
 }
 
-fn cdr(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 1 {
-        return Err(EvalError { message: "'cdr' takes exactly one argument".to_string() })
+fn error_object<'a>(xs: &'a [Value], name: &str) -> Result<&'a ErrorObject, EvalError> {
+    match xs {
+        [Value::Error(err)] => Ok(err),
+        _                   => Err(EvalError { location: None, message: format!("'{}' requires an error object", name) })
+    }
+}
+
+fn error_objectq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [val] => Ok(Value::Bool(matches!(*val, Value::Error(_)))),
+        _     => Err(EvalError { location: None, message: "'error-object?' takes exactly one argument".to_string() })
+    }
+}
+
+fn error_object_message(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    Ok(Value::String(error_object(xs, "error-object-message")?.message.clone()))
+}
+
+fn error_object_irritants(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    Ok(Value::list(error_object(xs, "error-object-irritants")?.irritants.clone()))
+}
+
+// map and for-each evaluate the function and then the list, and apply the function to the
+// elements strictly in order from first to last, stopping at the first error
+fn map(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError>  {
+    if xs.len() != 2 {
+        return Err(EvalError { location: None, message: "'map' takes exactly two arguments".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    match x {
-        Value::List(vals) => {
-            let mut vals = vals.clone();
-            vals.remove(0);
-            Ok(Value::List(vals))
+    let func = xs[0].clone();
+    match (func, xs[1].list_elements()) {
+        (func, Some(vals)) if is_procedure(&func) => {
+            let mut res: Vec<Value> = Vec::new();
+            for val in vals {
+                res.push(interpreter.apply(func.clone(), vec![val])?);
+            }
+            Ok(Value::list(res))
         },
-        _                 => Err(EvalError { message: "Invalid type for 'cdr'".to_string() })
+        _                 => Err(EvalError { location: None, message: "Invalid type for 'map'".to_string() })
     }
 }
 
-fn cons(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn for_each(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError>  {
     if xs.len() != 2 {
-        return Err(EvalError { message: "'cons' takes exactly two arguments".to_string() })
+        return Err(EvalError { location: None, message: "'for-each' takes exactly two arguments".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    let ys = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    match (x, ys) {
-        (v, Value::List(vals)) => {
-            let mut vals = vals.clone();
-            vals.insert(0, v);
-            Ok(Value::List(vals))
+    let func = xs[0].clone();
+    match (func, xs[1].list_elements()) {
+        (func, Some(vals)) if is_procedure(&func) => {
+            for val in vals {
+                interpreter.apply(func.clone(), vec![val])?;
+            }
+            Ok(Value::Void)
+        },
+        _                 => Err(EvalError { location: None, message: "Invalid type for 'for-each'".to_string() })
+    }
+}
+
+// (partial f args...) returns a procedure that calls f with args followed by its own arguments
+fn partial(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let (func, args) = procedure_and_args(xs, "partial")?;
+    Ok(Value::Partial(Box::new(func), args))
+}
+
+// (curry f args...) returns a procedure that keeps taking arguments until it has as many as f
+// takes, then calls f with all of them.  Procedures that take any number of arguments are called
+// the first time the curried procedure is.
+fn curry(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let (func, args) = procedure_and_args(xs, "curry")?;
+    match arity(&func) {
+        Some(n) if args.len() >= n => interpreter.apply(func, args),
+        _                          => Ok(Value::Curried(Box::new(func), args))
+    }
+}
+
+// (random n) returns an integer from 0 up to but not including n
+fn random_fn(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'random' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].clone() {
+        Value::Int(n) if n > 0 => Ok(Value::Int(random::below(n as usize) as i32)),
+        _                      => Err(EvalError { location: None, message: "Invalid type for 'random'".to_string() })
+    }
+}
+
+// (random-seed! n) restarts the random numbers used by random, shuffle and sample from a seed,
+// so a program can get a different (but still reproducible) sequence
+fn random_seed(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'random-seed!' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].clone() {
+        Value::Int(n) => {
+            random::seed(n as u64);
+            Ok(Value::Void)
+        },
+        _ => Err(EvalError { location: None, message: "Invalid type for 'random-seed!'".to_string() })
+    }
+}
+
+fn shuffle(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'shuffle' takes exactly one argument".to_string() })
+    }
+
+    match xs[0].list_elements() {
+        Some(mut vals) => {
+            let len = vals.len();
+            random::shuffle_front(&mut vals[..], len);
+            Ok(Value::list(vals))
         },
-        _ => Err(EvalError { message: "Invalid type for 'cons'".to_string() })
+        None => Err(EvalError { location: None, message: "Invalid type for 'shuffle'".to_string() })
     }
 }
 
-fn append(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+// (sample lst k) returns k different elements of lst, chosen at random
+fn sample(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 2 {
-        return Err(EvalError { message: "'cons' takes exactly two arguments".to_string() })
+        return Err(EvalError { location: None, message: "'sample' takes exactly two arguments".to_string() })
     }
-    
-    let ys = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    let zs = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    match (ys, zs) {
-        (Value::List(xs), Value::List(ys)) => {
-            let mut xs = xs.clone();
-            let mut ys = ys.clone();
-            xs.append(&mut ys);
-            Ok(Value::List(xs))
+
+    let k = xs[1].clone();
+    match (xs[0].list_elements(), k) {
+        (Some(mut vals), Value::Int(k)) if k >= 0 && k as usize <= vals.len() => {
+            random::shuffle_front(&mut vals, k as usize);
+            vals.truncate(k as usize);
+            Ok(Value::list(vals))
         },
-        _ => Err(EvalError { message: "Invalid type for 'cons'".to_string() })
+        (Some(_), Value::Int(_)) => Err(EvalError { location: None, message: "'sample' can't take more elements than the list has".to_string() }),
+        _                        => Err(EvalError { location: None, message: "Invalid type for 'sample'".to_string() })
     }
 }
 
-fn if_fn(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 3 {
-        return Err(EvalError { message: "'if' takes exactly three arguments".to_string() })
+// (compose f g ...) returns a procedure that applies the procedures right to left, so that
+// ((compose f g) x) is (f (g x))
+fn compose(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if !xs.iter().all(is_procedure) {
+        return Err(EvalError { location: None, message: "Invalid type for 'compose'".to_string() })
     }
+    let mut funcs = xs.to_vec();
 
-    let test: bool = match interpreter.eval_node(&xs[0]) {
-        Ok(Value::Bool(val)) => val,
-        Ok(_)              => return Err(EvalError { message: "'if' requires a boolean test".to_string() }),
-        Err(err)             => return Err(err)
-    };
-    if test {
-        Ok(Value::NodeWrapper(xs[1].clone()))
-    } else {
-        Ok(Value::NodeWrapper(xs[2].clone()))
+    match funcs.len() {
+        0 => Ok(Value::Function("identity", Shared::new(identity))),
+        1 => Ok(funcs.remove(0)),
+        _ => Ok(Value::Composition(funcs))
+    }
+}
+
+fn identity(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'identity' takes exactly one argument".to_string() })
+    }
+
+    Ok(xs[0].clone())
+}
+
+// (const v) returns a procedure that ignores its arguments and always returns v
+fn const_fn(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'const' takes exactly one argument".to_string() })
+    }
+
+    let val = xs[0].clone();
+    Ok(Value::Partial(Box::new(Value::Function("const", Shared::new(first_argument))), vec![val]))
+}
+
+// Return the first argument, ignoring the rest
+fn first_argument(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    Ok(xs[0].clone())
+}
+
+fn procedure_and_args(xs: &[Value], name: &str) -> Result<(Value, Vec<Value>), EvalError> {
+    if xs.is_empty() {
+        return Err(EvalError { location: None, message: format!("'{}' takes at least one argument", name) })
+    }
+
+    let func = xs[0].clone();
+    if !is_procedure(&func) {
+        return Err(EvalError { location: None, message: format!("Invalid type for '{}'", name) })
     }
+    Ok((func, xs[1..].to_vec()))
 }
 
-fn map(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError>  {
+// (bench thunk iterations) calls thunk the given number of times and returns the fastest, mean
+// and slowest time in seconds, as ((min t) (mean t) (max t))
+fn bench(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 2 {
-        return Err(EvalError { message: "'map' takes exactly two arguments".to_string() })
+        return Err(EvalError { location: None, message: "'bench' takes exactly two arguments".to_string() })
     }
-    
-    let func = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
-    let list = match interpreter.eval_node(&xs[1]) {
-        Ok(val) => val,
-        err     => return err
+
+    let thunk = xs[0].clone();
+    let iterations = xs[1].clone();
+    let iterations = match (is_procedure(&thunk), iterations) {
+        (true, Value::Int(n)) if n > 0 => n,
+        _                              => return Err(EvalError { location: None, message: "Invalid type for 'bench'".to_string() })
     };
-    match (func, list) {
-        (Value::Function(_, func), Value::List(vals)) => {
-            let res_slice = vals.iter().map(|i| func(interpreter, &vec![interpreter::convert_to_node(i.clone())]));
-            let mut res: Vec<Value> = Vec::new();
-            for r in res_slice {
-                match r {
-                    Ok(val) => res.push(val),
-                    _ => ()
-                }
-            }
-            Ok(Value::List(res))
-        },
-        (Value::Lambda(ref lambda), Value::List(ref vals)) => {
-            let mut res: Vec<Value> = Vec::new();
 
-            for val in vals.clone() {
-                match interpreter.eval_lambda(lambda.clone(), &vec![xs[0].clone(), interpreter::convert_to_node(val)]) {
-                    Ok(val) => res.push(val),
-                    err => return err 
-                }
-            }
-            Ok(Value::List(res))
-        },
-        _                 => Err(EvalError { message: "Invalid type for 'map'".to_string() })
+    let mut times = Vec::new();
+    for _ in 0..iterations {
+        let start = Instant::now();
+        interpreter.apply(thunk.clone(), Vec::new())?;
+        times.push(start.elapsed().as_secs_f64());
     }
+
+    let min = times.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = times.iter().cloned().fold(0.0, f64::max);
+    let mean = times.iter().sum::<f64>() / times.len() as f64;
+    let entry = |name: &str, time: f64| Value::list(vec![Value::Literal(name.to_string()), Value::Float(time)]);
+    Ok(Value::list(vec![entry("min", min), entry("mean", mean), entry("max", max)]))
 }
 
-fn sin(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn sin(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'sin' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'sin' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).sin())),
         Value::Float(float) => Ok(Value::Float(float.sin())),
-        _                   => Err(EvalError { message: "Invalid type for 'sin'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'sin'".to_string() })
     }
 }
 
-fn cos(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn cos(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'cos' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'cos' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).cos())),
         Value::Float(float) => Ok(Value::Float(float.cos())),
-        _                   => Err(EvalError { message: "Invalid type for 'cos'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'cos'".to_string() })
     }
 }
 
-fn tan(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn tan(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'tan' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'tan' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).tan())),
         Value::Float(float) => Ok(Value::Float(float.tan())),
-        _                   => Err(EvalError { message: "Invalid type for 'tan'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'tan'".to_string() })
     }
 }
 
-fn asin(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn asin(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'asin' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'asin' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).asin())),
         Value::Float(float) => Ok(Value::Float(float.asin())),
-        _                   => Err(EvalError { message: "Invalid type for 'asin'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'asin'".to_string() })
     }
 }
 
-fn acos(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn acos(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'acos' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'acos' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).acos())),
         Value::Float(float) => Ok(Value::Float(float.acos())),
-        _                   => Err(EvalError { message: "Invalid type for 'acos'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'acos'".to_string() })
     }
 }
 
-fn atan(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn atan(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'atan' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'atan' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).atan())),
         Value::Float(float) => Ok(Value::Float(float.atan())),
-        _                   => Err(EvalError { message: "Invalid type for 'atan'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'atan'".to_string() })
     }
 }
 
-fn exp(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn exp(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'exp' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'exp' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).exp())),
         Value::Float(float) => Ok(Value::Float(float.exp())),
-        _                   => Err(EvalError { message: "Invalid type for 'exp'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'exp'".to_string() })
     }
 }
 
-fn log(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn log(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'log' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'log' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).ln())),
         Value::Float(float) => Ok(Value::Float(float.ln())),
-        _                   => Err(EvalError { message: "Invalid type for 'log'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'log'".to_string() })
     }
 }
 
-fn log10(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn log10(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'log10' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'log10' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).log10())),
         Value::Float(float) => Ok(Value::Float(float.log10())),
-        _                   => Err(EvalError { message: "Invalid type for 'log10'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'log10'".to_string() })
     }
 }
 
-fn sqrt(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn sqrt(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'sqrt' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'sqrt' takes exactly one argument".to_string() })
     }
     
-    let x = match interpreter.eval_node(&xs[0]) {
-        Ok(val) => val,
-        err     => return err
-    };
+    let x = xs[0].clone();
     match x {
         Value::Int(int)     => Ok(Value::Float((int as f64).sqrt())),
         Value::Float(float) => Ok(Value::Float(float.sqrt())),
-        _                   => Err(EvalError { message: "Invalid type for 'sqrt'".to_string() })
+        _                   => Err(EvalError { location: None, message: "Invalid type for 'sqrt'".to_string() })
     }
 }
 
-fn quote(interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
+fn quote(_interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
     if xs.len() != 1 {
-        return Err(EvalError { message: "'quote' takes exactly one argument".to_string() })
+        return Err(EvalError { location: None, message: "'quote' takes exactly one argument".to_string() })
     }
     
-    Ok(match &xs[0] {
-        &Node::Int(int)          => Value::Literal(int.to_string()),
-        &Node::Float(float)      => Value::Literal(float.to_string()),
-        &Node::Symbol(ref value) => Value::Literal(value.clone()),
-        &Node::Bool(true)        => Value::Literal("#t".to_string()),
-        &Node::Bool(false)       => Value::Literal("#f".to_string()),
-        &Node::List(ref nodes)       => {
-            let mut vals: Vec<Value> = Vec::new();
-            for node in nodes {
-                vals.push(quote(interpreter, &vec![node.clone()]).ok().unwrap());
+    Ok(quote_node(&xs[0]))
+}
+
+// Numbers, booleans and strings quote to themselves, symbols become literals, and lists and
+// vectors are quoted element by element
+pub fn quote_node(node: &Node) -> Value {
+    match *node {
+        Node::Int(int)              => Value::Int(int),
+        Node::Float(float)          => Value::Float(float),
+        Node::Complex(real, im)     => Value::Complex(real, im),
+        Node::Rational(num, den)    => Value::Rational(num, den),
+        Node::Bool(val)             => Value::Bool(val),
+        Node::Char(c)               => Value::Char(c),
+        Node::Keyword(ref name)     => Value::Keyword(name.clone()),
+        Node::String(ref val)       => Value::String(val.clone()),
+        Node::Symbol(ref value)     => Value::Literal(value.clone()),
+        Node::List(ref nodes, _)    => Value::list(nodes.iter().map(quote_node).collect()),
+        Node::Vector(ref nodes)     => Value::Vector(nodes.iter().map(quote_node).collect()),
+        Node::Pair(ref nodes, ref tail) => Value::dotted(nodes.iter().map(quote_node).collect(), quote_node(tail)),
+        // A quote inside quoted data stays a (quote ...) list
+        Node::Quoted(ref val)       => Value::list(vec![Value::Literal("quote".to_string()), (**val).clone()]),
+        Node::ValueWrapper(ref val) => (**val).clone()
+    }
+}
+
+// The inverse of quote_node: the datum a quoted value was read from
+pub fn datum_node(val: &Value) -> Node {
+    match *val {
+        Value::Int(int)              => Node::Int(int),
+        Value::Float(float)          => Node::Float(float),
+        Value::Complex(real, im)     => Node::Complex(real, im),
+        Value::Rational(num, den)    => Node::Rational(num, den),
+        Value::Bool(val)             => Node::Bool(val),
+        Value::Char(c)               => Node::Char(c),
+        Value::Keyword(ref name)     => Node::Keyword(name.clone()),
+        Value::String(ref val)       => Node::String(val.clone()),
+        Value::Literal(ref name)     => Node::Symbol(name.clone()),
+        Value::Nil                   => Node::List(Vec::new(), None),
+        Value::Vector(ref vals)      => Node::Vector(vals.iter().map(datum_node).collect()),
+        // A circular list can't be written as a datum, so it stays a value
+        Value::Pair(_)               => match val.list_parts() {
+            Some((vals, Value::Nil)) => Node::List(vals.iter().map(datum_node).collect(), None),
+            Some((vals, tail))       => Node::Pair(vals.iter().map(datum_node).collect(), Box::new(datum_node(&tail))),
+            None                     => Node::ValueWrapper(Box::new(val.clone()))
+        },
+        ref val                      => Node::ValueWrapper(Box::new(val.clone()))
+    }
+}
+
+// (eval expr env) evaluates the datum expr as code in the environment env, or in the
+// interaction environment if env isn't given
+fn eval_fn(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let env = match xs {
+        [_]                         => interpreter.env.outermost().clone(),
+        [_, Value::Environment(env)] => env.clone(),
+        [_, _]                      => return Err(EvalError { location: None, message: "'eval' requires an environment".to_string() }),
+        _                           => return Err(EvalError { location: None, message: "'eval' takes one or two arguments".to_string() })
+    };
+    interpreter.with_env(env).eval_node(&datum_node(&xs[0]))
+}
+
+// The global environment, which the REPL and scripts evaluate their top-level forms in
+fn interaction_environment(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if !xs.is_empty() {
+        return Err(EvalError { location: None, message: "'interaction-environment' takes no arguments".to_string() })
+    }
+    Ok(Value::Environment(interpreter.env.outermost().clone()))
+}
+
+// (the-environment) is the scope it's evaluated in, so eval can see the names bound there and
+// a define evaluated in it adds to it
+fn the_environment(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    if !xs.is_empty() {
+        return Err(EvalError { location: None, message: "'the-environment' takes no arguments".to_string() })
+    }
+    Ok(Value::Environment(interpreter.env.clone()))
+}
+
+// (delay expr) makes a promise to evaluate expr, in the current scope, the first time it's forced
+fn delay(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    make_delay(interpreter, xs, "delay", false)
+}
+
+// (delay-force expr) is (delay (force expr)), except that forcing it doesn't nest a call to
+// force for expr's promise, so a stream can be made of any number of them in constant space
+fn delay_force(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    make_delay(interpreter, xs, "delay-force", true)
+}
+
+fn make_delay(interpreter: &mut Interpreter, xs: &[Node], name: &str, lazy: bool) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: format!("'{}' takes exactly one argument", name) })
+    }
+    Ok(Value::Promise(Shared::new(Locked::new(Promise::Pending(xs[0].clone(), interpreter.env.clone(), lazy)))))
+}
+
+// (make-promise val) is a promise that's already been forced to val, or val if it's a promise
+fn make_promise(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [Value::Promise(_)] => Ok(xs[0].clone()),
+        [val]               => Ok(Value::Promise(Shared::new(Locked::new(Promise::Done(val.clone()))))),
+        _                   => Err(EvalError { location: None, message: "'make-promise' takes exactly one argument".to_string() })
+    }
+}
+
+fn promiseq(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [val] => Ok(Value::Bool(matches!(*val, Value::Promise(_)))),
+        _     => Err(EvalError { location: None, message: "'promise?' takes exactly one argument".to_string() })
+    }
+}
+
+// (force promise) evaluates a promise's expression the first time, and returns the same value
+// every time after that.  Anything that isn't a promise is returned as it is.  The promises a
+// chain of delay-forces lead to are forced in a loop, and all of them get the value at the end.
+fn force(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let promise = match xs {
+        [Value::Promise(promise)] => promise.clone(),
+        [val]                     => return Ok(val.clone()),
+        _                         => return Err(EvalError { location: None, message: "'force' takes exactly one argument".to_string() })
+    };
+
+    let mut chain = vec![promise.clone()];
+    let mut current = promise;
+    let val = loop {
+        let (node, env, lazy) = match *shared::lock(&current) {
+            Promise::Done(ref val)                     => break val.clone(),
+            Promise::Pending(ref node, ref env, lazy) => (node.clone(), env.clone(), lazy)
+        };
+        let val = interpreter.with_env(env).eval_node(&node)?;
+        // Forcing the promise again while it was being evaluated may have given it a value
+        // already, which is the one it keeps
+        if let Promise::Done(ref val) = *shared::lock(&current) {
+            break val.clone();
+        }
+        match val {
+            Value::Promise(next) if lazy => {
+                chain.push(next.clone());
+                current = next;
+            },
+            _ if lazy                    => return Err(EvalError { location: None, message: "'delay-force' requires an expression that evaluates to a promise".to_string() }),
+            val                          => break val
+        }
+    };
+    for promise in chain {
+        *shared::lock(&promise) = Promise::Done(val.clone());
+    }
+    Ok(val)
+}
+
+// (quasiquote template), or `template, is like quote, except that ,expr in the template is
+// replaced by the value of expr, and ,@expr by the elements of the list it evaluates to.  A
+// quasiquote inside the template is left as it is, along with the unquotes that belong to it
+// rather than to the outer one.
+fn quasiquote(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'quasiquote' takes exactly one argument".to_string() })
+    }
+
+    quasi(interpreter, &xs[0], 1)
+}
+
+// The form and operand of (quasiquote x), (unquote x) or (unquote-splicing x)
+fn quasi_form(nodes: &[Node]) -> Option<(&str, &Node)> {
+    match nodes {
+        [Node::Symbol(ref name), x] if name == "quasiquote" || name == "unquote" || name == "unquote-splicing" => Some((name, x)),
+        _ => None
+    }
+}
+
+// The value of a template inside depth quasiquotes
+fn quasi(interpreter: &mut Interpreter, node: &Node, depth: usize) -> Result<Value, EvalError> {
+    match *node {
+        Node::List(ref nodes, _) => {
+            match quasi_form(nodes) {
+                Some(("unquote", x)) if depth == 1 => interpreter.eval_node(x),
+                Some(("unquote-splicing", _)) if depth == 1 => Err(EvalError { location: None, message: "',@' has to be inside a list".to_string() }),
+                Some((name, x)) => {
+                    let depth = if name == "quasiquote" { depth + 1 } else { depth - 1 };
+                    Ok(Value::list(vec![Value::Literal(name.to_string()), quasi(interpreter, x, depth)?]))
+                },
+                None => {
+                    let (vals, tail) = quasi_elements(interpreter, nodes, depth)?;
+                    Ok(Value::dotted(vals, tail.unwrap_or(Value::Nil)))
+                }
             }
-            Value::List(vals)
         },
-        _                       => Value::Void
-    })
+        Node::Pair(ref nodes, ref tail) => {
+            let (vals, _) = quasi_elements(interpreter, nodes, depth)?;
+            let tail = quasi(interpreter, tail, depth)?;
+            Ok(Value::dotted(vals, tail))
+        },
+        Node::Vector(ref nodes) => match quasi_elements(interpreter, nodes, depth)? {
+            (vals, None) => Ok(Value::Vector(vals)),
+            _            => Err(EvalError { location: None, message: "A vector template can't have a dotted tail".to_string() })
+        },
+        // A quote in the template can still have unquotes in it
+        Node::Quoted(ref val) => quasi(interpreter, &Node::List(vec![Node::Symbol("quote".to_string()), datum_node(val)], None), depth),
+        ref node => Ok(quote_node(node))
+    }
 }
 
-fn def_lambda(_interpreter: &mut Interpreter, xs: &Vec<Node>) -> Result<Value, EvalError> {
-    if xs.len() != 2 {
-        return Err(EvalError { message: "'lambda' takes exactly two argumenta".to_string() })
+// The values of the elements of a list template, with ,@expr spliced in, and the tail it ends
+// with if it's written (a . ,expr), which reads as (a unquote expr)
+fn quasi_elements(interpreter: &mut Interpreter, nodes: &[Node], depth: usize) -> Result<(Vec<Value>, Option<Value>), EvalError> {
+    let mut vals = Vec::new();
+    for (i, node) in nodes.iter().enumerate() {
+        match *node {
+            Node::Symbol(ref name) if name == "unquote" && depth == 1 && i + 2 == nodes.len() => {
+                return Ok((vals, Some(interpreter.eval_node(&nodes[i + 1])?)));
+            },
+            Node::List(ref inner, _) if depth == 1 => if let Some(("unquote-splicing", x)) = quasi_form(inner) {
+                match interpreter.eval_node(x)?.list_elements() {
+                    Some(spliced) => vals.extend(spliced),
+                    None          => return Err(EvalError { location: None, message: "',@' requires a list".to_string() })
+                }
+                continue;
+            },
+            _ => ()
+        }
+        vals.push(quasi(interpreter, node, depth)?);
+    }
+    Ok((vals, None))
+}
+
+// (lambda formals body ...) makes a procedure.  A body of several forms is evaluated like a begin.
+fn def_lambda(interpreter: &mut Interpreter, xs: &[Node]) -> Result<Value, EvalError> {
+    if xs.len() < 2 {
+        return Err(EvalError { location: None, message: "'lambda' requires a parameter list and a body".to_string() })
+    }
+    let body = if xs.len() > 2 {
+        let mut newbody = xs.to_vec();
+        newbody[0] = Node::Symbol("begin".to_string());
+        Node::List(newbody, None)
+    } else {
+        xs[1].clone()
+    };
+    
+    let mut lambda = Lambda::from_formals(&xs[0], macros::expand_all(&body, &interpreter.env)?)?;
+    lambda.scope = Some(interpreter.env.clone());
+    Ok(Value::Lambda(Box::new(lambda)))
+}
+// (->string v) returns v as display would show it
+fn to_string(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'->string' takes exactly one argument".to_string() })
+    }
+
+    let val = xs[0].clone();
+    Ok(Value::String(interpreter.display(&val)))
+}
+
+// (print vs...) writes each value as display would show it, separated by spaces and followed by
+// a newline, to the current output port
+fn print(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let mut vals = Vec::new();
+    for x in xs {
+        vals.push(interpreter.display(x));
+    }
+    port::write_str(&(vals.join(" ") + "\n"));
+    Ok(Value::Void)
+}
+
+// (write args...) prints each argument the way the REPL shows it, within the limits set by
+// print-length and print-depth
+fn write(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let mut vals = Vec::new();
+    for x in xs {
+        vals.push(printer::write(x, interpreter.print_limits()));
+    }
+    port::write_str(&(vals.join(" ") + "\n"));
+    Ok(Value::Void)
+}
+
+// (write-shared args...) is write, except that a list appearing more than once in an argument
+// is written out once, labelled #n=, and as #n# where it appears again
+fn write_shared(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let mut vals = Vec::new();
+    for x in xs {
+        vals.push(printer::write_shared(x, interpreter.print_limits()));
+    }
+    port::write_str(&(vals.join(" ") + "\n"));
+    Ok(Value::Void)
+}
+
+fn print_length(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    print_limit(xs, "print-length", |limits| &mut limits.length)
+}
+
+fn print_depth(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    print_limit(xs, "print-depth", |limits| &mut limits.depth)
+}
+
+// (print-length n) shows at most n elements of each list, (print-length #f) shows all of them,
+// and (print-length) returns the current limit.  print-depth works the same way for nesting.
+fn print_limit(xs: &[Value], name: &str, field: fn(&mut printer::Limits) -> &mut Option<usize>) -> Result<Value, EvalError> {
+    let mut limits = printer::limits();
+    let val = match xs.len() {
+        0 => return Ok(field(&mut limits).map_or(Value::Bool(false), |n| Value::Int(n as i32))),
+        1 => xs[0].clone(),
+        _ => return Err(EvalError { location: None, message: format!("'{}' takes at most one argument", name) })
+    };
+    *field(&mut limits) = match val {
+        Value::Int(n) if n >= 0 => Some(n as usize),
+        Value::Bool(false)      => None,
+        _                       => return Err(EvalError { location: None, message: format!("Invalid type for '{}'", name) })
+    };
+    printer::set_limits(limits);
+    Ok(Value::Void)
+}
+
+// (with-output-to-string thunk) calls thunk and returns everything it printed as a string
+fn with_output_to_string(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'with-output-to-string' takes exactly one argument".to_string() })
+    }
+
+    let thunk = xs[0].clone();
+    if !is_procedure(&thunk) {
+        return Err(EvalError { location: None, message: "Invalid type for 'with-output-to-string'".to_string() })
+    }
+    let (result, output) = port::capture(|| interpreter.apply(thunk, Vec::new()));
+    result.map(|_| Value::String(output))
+}
+
+fn open_input_string(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [Value::String(text)] => Ok(Value::InputPort(Shared::new(Locked::new(port::InputPort::new(text.clone()))))),
+        _                     => Err(EvalError { location: None, message: "'open-input-string' requires a string".to_string() })
+    }
+}
+
+fn input_port<'a>(xs: &'a [Value], name: &str) -> Result<&'a Shared<Locked<port::InputPort>>, EvalError> {
+    match xs {
+        [Value::InputPort(port)] => Ok(port),
+        _                        => Err(EvalError { location: None, message: format!("'{}' requires an input port", name) })
+    }
+}
+
+// read-char and peek-char return the next character of a port, or void at the end of it
+fn read_char(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let port = input_port(xs, "read-char")?;
+    let c = shared::lock(port).read_char();
+    Ok(c.map_or(Value::Void, Value::Char))
+}
+
+fn peek_char(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let port = input_port(xs, "peek-char")?;
+    let c = shared::lock(port).peek_char();
+    Ok(c.map_or(Value::Void, Value::Char))
+}
+
+// (read port) reads the next datum from a port, as quote would have it, with any reader syntax
+// that's been defined.  It returns void at the end of the port.
+fn read(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    let port = input_port(xs, "read")?;
+    let rest = shared::lock(port).rest().to_string();
+    match interpreter.read_datum(&rest, Location { line: 1, col: 1 }) {
+        Ok(Some((node, used, _))) => {
+            shared::lock(port).advance(used);
+            Ok(quote_node(&node))
+        },
+        Ok(None)                  => Ok(Value::Void),
+        Err(err)                  => Err(EvalError { location: None, message: format!("'read' failed: {}", err.message) })
+    }
+}
+
+// (define-reader-syntax #\$ handler) makes the reader call handler with a port on the source
+// after a $ that starts an atom, and read the datum handler returns in place of the $ and
+// whatever handler read from the port.  It applies to source read after it's evaluated.
+fn define_reader_syntax(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    match xs {
+        [Value::Char(c), handler] if is_procedure(handler) => {
+            if parser::is_delimiter(*c) || *c == '#' || c.is_ascii_digit() {
+                return Err(EvalError { location: None, message: format!("'define-reader-syntax' can't use {}, which has to be read as it is", printer::write(&xs[0], printer::Limits::default())) })
+            }
+            interpreter.define_reader_syntax(*c, handler.clone());
+            Ok(Value::Void)
+        },
+        _ => Err(EvalError { location: None, message: "'define-reader-syntax' requires a character and a procedure".to_string() })
+    }
+}
+
+fn make_string_builder(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() > 1 {
+        return Err(EvalError { location: None, message: "'make-string-builder' takes at most one argument".to_string() })
+    }
+    
+    let initial = match xs.first() {
+        Some(Value::String(val)) => val.clone(),
+        Some(_) => return Err(EvalError { location: None, message: "Invalid type for 'make-string-builder'".to_string() }),
+        None => String::new()
+    };
+    Ok(Value::StringBuilder(Shared::new(Locked::new(initial))))
+}
+
+// Append each argument to a string builder: strings are added as-is, anything else in its printed form
+fn sb_add(interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.is_empty() {
+        return Err(EvalError { location: None, message: "'sb-add!' takes at least one argument".to_string() })
     }
     
-    let params: Vec<Node> = match xs[0] {
-        Node::List(ref nodes) => nodes.clone(),
-        _                     => return Err(EvalError{ message: "lambda should provide a param list".to_string() })
+    let builder = match xs[0].clone() {
+        Value::StringBuilder(builder) => builder,
+        _                             => return Err(EvalError { location: None, message: "Invalid type for 'sb-add!'".to_string() })
     };
-    let body = xs[1].clone();
-    Ok(Value::Lambda(Lambda::new(params, body)))
-}
\ No newline at end of file
+    for val in &xs[1..] {
+        shared::lock(&builder).push_str(&interpreter.display(val));
+    }
+    Ok(Value::Void)
+}
+
+fn sb_to_string(_interpreter: &mut Interpreter, xs: &[Value]) -> Result<Value, EvalError> {
+    if xs.len() != 1 {
+        return Err(EvalError { location: None, message: "'sb->string' takes exactly one argument".to_string() })
+    }
+    
+    match xs[0].clone() {
+        Value::StringBuilder(builder) => Ok(Value::String(shared::lock(&builder).clone())),
+        _                             => Err(EvalError { location: None, message: "Invalid type for 'sb->string'".to_string() })
+    }
+}
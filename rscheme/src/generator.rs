@@ -0,0 +1,216 @@
+use std::cell::RefCell;
+use std::mem;
+use std::panic;
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use shared::{self, Shared, Locked};
+
+use interpreter::{self, Interpreter, Handoff, Extent};
+use interpreter::Value as Value;
+use interpreter::EvalError as EvalError;
+
+// A generator from make-generator.  Its producer runs on a thread of its own, which waits at each
+// yield until the generator is called again, so the producer carries on from where it left off.
+pub struct Generator {
+    state: State
+}
+
+enum State {
+    // The producer, until the first call starts it
+    Unstarted(Value),
+    // Waiting at a yield
+    Suspended(Producer),
+    Running,
+    // The producer has returned, so there are no more values
+    Done
+}
+
+// The producer's thread, and the channels to it and back
+struct Producer {
+    thread: JoinHandle<()>,
+    resume: Sender<Sendable<Resume>>,
+    handback: Receiver<Sendable<Handback>>
+}
+
+// Values are built on Rc, which can't go to another thread in general, since both threads might
+// change the same count at once.  A generator and its producer's thread can share them, because
+// only one of the two runs at a time: each hands over to the other and waits for it to hand back.
+struct Sendable<T>(T);
+unsafe impl<T> Send for Sendable<T> {}
+
+// What a call of the generator sends its producer: the Handoff (see interpreter.rs), and how many
+// steps the evaluation has left, if there's a limit
+struct Resume(Handoff, Option<usize>);
+
+// What the producer sends back when it yields or returns, with the Handoff and how many steps it
+// took
+enum Handback {
+    Yielded(Value, Handoff, usize),
+    Returned(Result<Value, EvalError>, Handoff, usize)
+}
+
+// A producer's thread counts its own steps, so if there's a step limit its deadline is put out of
+// reach of any count, and each time it's resumed its count is set to what the caller has left below
+// that
+const PRODUCER_DEADLINE: usize = usize::MAX / 2;
+
+// The producer's threads are as deep as the main thread, for producers that recurse as deeply
+const PRODUCER_STACK: usize = 8 * 1024 * 1024;
+
+// On a producer's thread, the way back to its generator
+struct Channel {
+    resume: Receiver<Sendable<Resume>>,
+    handback: Sender<Sendable<Handback>>,
+    deadline: Option<usize>,
+    // The step count when it was last resumed
+    start: usize,
+    // How many of the strings output is captured into are the caller's, and the producer's own
+    // while it's waiting at a yield
+    base: usize,
+    output: Vec<String>
+}
+
+thread_local! {
+    static GENERATOR: RefCell<Option<Channel>> = const { RefCell::new(None) };
+}
+
+impl Generator {
+    pub fn new(producer: Value) -> Generator {
+        Generator { state: State::Unstarted(producer) }
+    }
+}
+
+// A producer waiting at a yield is unwound, which ends its thread, before anything else goes on
+impl Drop for Generator {
+    fn drop(&mut self) {
+        if let State::Suspended(Producer { thread, resume, handback }) = mem::replace(&mut self.state, State::Done) {
+            drop(resume);
+            let _ = handback.recv();
+            let _ = thread.join();
+        }
+    }
+}
+
+// The next value of a generator, or void once its producer has returned
+pub fn next_value(interpreter: &mut Interpreter, generator: &Shared<Locked<Generator>>) -> Result<Value, EvalError> {
+    let state = mem::replace(&mut shared::lock(generator).state, State::Running);
+    let Producer { thread, resume, handback } = match state {
+        State::Suspended(producer) => producer,
+        State::Unstarted(producer) => start(interpreter, producer)?,
+        State::Running             => return Err(EvalError { location: None, message: "A generator can't be called by its own producer".to_string() }),
+        State::Done                => {
+            shared::lock(generator).state = State::Done;
+            return Ok(Value::Void);
+        }
+    };
+
+    let left = interpreter.deadline().map(|deadline| deadline.saturating_sub(interpreter::steps()));
+    let reply = match resume.send(Sendable(Resume(interpreter::hand_off(), left))) {
+        Ok(()) => handback.recv().ok(),
+        Err(_) => None
+    };
+    let (state, result) = match reply {
+        Some(Sendable(Handback::Yielded(val, handoff, used))) => {
+            interpreter::take_up(handoff);
+            interpreter::set_steps(interpreter::steps().wrapping_add(used));
+            (State::Suspended(Producer { thread, resume, handback }), Ok(val))
+        },
+        Some(Sendable(Handback::Returned(result, handoff, used))) => {
+            interpreter::take_up(handoff);
+            interpreter::set_steps(interpreter::steps().wrapping_add(used));
+            let _ = thread.join();
+            (State::Done, result.map(|_| Value::Void))
+        },
+        // The producer's thread panicked, so this one does too
+        None => match thread.join() {
+            Err(err) => panic::resume_unwind(err),
+            Ok(())   => (State::Done, Err(EvalError { location: None, message: "The generator's producer stopped".to_string() }))
+        }
+    };
+    shared::lock(generator).state = state;
+    result
+}
+
+// Start the producer's thread, which waits to be resumed by the first call.  It starts with the
+// continuations, handlers and parameter bindings of that call, and keeps its own after that.
+fn start(interpreter: &Interpreter, producer: Value) -> Result<Producer, EvalError> {
+    let (resume, resumed) = mpsc::channel();
+    let (handback, handed_back) = mpsc::channel();
+    let mut scope = interpreter.with_env(interpreter.env.clone());
+    scope.set_deadline(scope.deadline().map(|_| PRODUCER_DEADLINE));
+    let channel = Channel { resume: resumed, handback, deadline: scope.deadline(), start: 0, base: 0, output: Vec::new() };
+    let payload = Sendable((producer, scope, interpreter::extent(), channel));
+    let thread = thread::Builder::new().stack_size(PRODUCER_STACK).spawn(move || {
+        let Sendable((producer, scope, extent, channel)) = payload;
+        run(producer, scope, extent, channel);
+    });
+    match thread {
+        Ok(thread) => Ok(Producer { thread, resume, handback: handed_back }),
+        Err(err)   => Err(EvalError { location: None, message: format!("Couldn't start the generator's producer: {}", err) })
+    }
+}
+
+// The producer's thread
+fn run(producer: Value, mut interpreter: Interpreter, extent: Extent, mut channel: Channel) {
+    let resume = match channel.resume.recv() {
+        Ok(Sendable(resume)) => resume,
+        Err(_)               => return
+    };
+    interpreter::set_extent(extent);
+    take_over(&mut channel, resume);
+    GENERATOR.with(|generator| *generator.borrow_mut() = Some(channel));
+
+    let result = interpreter.apply(producer, Vec::new()).map(|_| Value::Void);
+
+    let mut channel = match GENERATOR.with(|generator| generator.borrow_mut().take()) {
+        Some(channel) => channel,
+        None          => return
+    };
+    let (handoff, used) = hand_back(&mut channel);
+    // Nothing of the producer's can be left to drop on this thread once the caller's carries on
+    drop(interpreter);
+    interpreter::set_extent(Extent::default());
+    let _ = channel.handback.send(Sendable(Handback::Returned(result, handoff, used)));
+}
+
+// (yield val) on a producer's thread: hand val back to the generator's caller, and wait to be
+// resumed by the next call
+pub fn yield_value(val: Value) -> Result<Value, EvalError> {
+    GENERATOR.with(|generator| {
+        let mut generator = generator.borrow_mut();
+        let channel = match generator.as_mut() {
+            Some(channel) => channel,
+            None          => return Err(EvalError { location: None, message: "'yield' can only be called by a generator's producer".to_string() })
+        };
+        let (handoff, used) = hand_back(channel);
+        if channel.handback.send(Sendable(Handback::Yielded(val, handoff, used))).is_err() {
+            return Err(interpreter::unwind());
+        }
+        match channel.resume.recv() {
+            Ok(Sendable(resume)) => {
+                take_over(channel, resume);
+                Ok(Value::Void)
+            },
+            // The generator has gone, so there's nothing to resume for
+            Err(_) => Err(interpreter::unwind())
+        }
+    })
+}
+
+// Carry on from the caller's Handoff, with the producer's own captured output on top of the
+// caller's and the steps the caller has left
+fn take_over(channel: &mut Channel, Resume(mut handoff, left): Resume) {
+    channel.base = handoff.output.len();
+    handoff.output.append(&mut channel.output);
+    interpreter::take_up(handoff);
+    if let (Some(deadline), Some(left)) = (channel.deadline, left) {
+        interpreter::set_steps(deadline.saturating_sub(left));
+    }
+    channel.start = interpreter::steps();
+}
+
+fn hand_back(channel: &mut Channel) -> (Handoff, usize) {
+    let mut handoff = interpreter::hand_off();
+    channel.output = handoff.output.split_off(channel.base.min(handoff.output.len()));
+    (handoff, interpreter::steps().wrapping_sub(channel.start))
+}
@@ -1,200 +1,1450 @@
+use std::collections::HashMap;
 use std::fmt;
-use std::rc::Rc as Rc;
+use shared::{self, Shared, Locked};
+use std::cell::{Cell, RefCell};
 
 use environment::Environment as Environment;
 use environment::Lambda as Lambda;
+use environment::{Promise, ErrorObject, Parameter};
+use generator::{self, Generator};
+use environment::{quote_node, datum_node};
+use macros::{self, Macro};
+use cache;
+use printer;
+use random;
+use port;
+use parser;
 use parser::Node as Node;
+use parser::Location as Location;
+use parser::ParseError;
+use port::InputPort;
+
+// The signature of special forms like if and define, which receive their operands unevaluated
+// and decide for themselves which of them to evaluate
+pub type Syntax = fn(&mut Interpreter, &[Node]) -> Result<Value, EvalError>;
+
+// The signature of builtin procedures, which receive their arguments already evaluated
+pub type Builtin = fn(&mut Interpreter, &[Value]) -> Result<Value, EvalError>;
 
 #[derive(Clone)]
 pub enum Value {
     Int(i32),
     Float(f64),
     Complex(f64, f64),
+    // An exact fraction, in lowest terms with a denominator greater than 1
+    Rational(i32, i32),
     Bool(bool),
-    Symbol(String),
+    Char(char),
+    Keyword(String),
     Literal(String),
     String(String),
-    List(Vec<Value>),
-    Function(&'static str, Rc<fn(&mut Interpreter, &Vec<Node>) -> Result<Value, EvalError>>),
-    Lambda(Lambda),
+    // A pair from cons, which every copy shares, so lists share their tails and set-car! and
+    // set-cdr! change a pair for everything that has it
+    Pair(Shared<Locked<Pair>>),
+    // The empty list
+    Nil,
+    Vector(Vec<Value>),
+    // Several values returned at once by (values ...)
+    Values(Vec<Value>),
+    Function(&'static str, Shared<Builtin>),
+    // if, define, lambda and the other forms that can't evaluate all their operands up front
+    SpecialForm(&'static str, Shared<Syntax>),
+    // Boxed, since a lambda is much bigger than any other value and every value would be as big
+    Lambda(Box<Lambda>),
+    // A macro from define-syntax, which rewrites the forms it's used in before they're evaluated
+    Macro(Shared<Macro>),
+    // An escape-only continuation from call/cc
+    Continuation(usize),
+    // A procedure with some of its arguments already supplied, from (partial f args...)
+    Partial(Box<Value>, Vec<Value>),
+    // A procedure that collects arguments until it has as many as it takes, from (curry f args...)
+    Curried(Box<Value>, Vec<Value>),
+    // The procedures from (compose f g ...), applied right to left
+    Composition(Vec<Value>),
+    // A mutable string that can be appended to without copying, shared between all its clones
+    StringBuilder(Shared<Locked<String>>),
+    // An environment from the-environment or interaction-environment, for eval
+    Environment(Environment),
+    // An error object, from error or from an error caught by guard or with-exception-handler
+    Error(Shared<ErrorObject>),
+    // A parameter object from make-parameter, which parameterize rebinds
+    Parameter(Shared<Parameter>),
+    // A generator from make-generator, which gives the next value its producer yields each call
+    Generator(Shared<Locked<Generator>>),
+    // A promise from delay or delay-force, shared by every copy so it's only evaluated once
+    Promise(Shared<Locked<Promise>>),
+    // A port reading from a string, for read-char, peek-char and read
+    InputPort(Shared<Locked<InputPort>>),
     // NodeWrappers are used to pass uneval'd nodes out for TCO
     NodeWrapper(Node),
+    // A node passed out of a procedure's body for TCO, which is evaluated in the procedure's
+    // environment rather than the one it's passed out to
+    ScopedNode(Node, Environment),
     Void
 }
 
+pub struct Pair {
+    pub car: Value,
+    pub cdr: Value
+}
+
+// A long list is dropped a pair at a time, rather than by each pair dropping the next one, which
+// would recurse as deep as the list is long
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let mut next = std::mem::replace(&mut self.cdr, Value::Nil);
+        while let Value::Pair(pair) = next {
+            next = match Shared::try_unwrap(pair) {
+                Ok(pair) => std::mem::replace(&mut shared::into_inner(pair).cdr, Value::Nil),
+                Err(_)   => break
+            };
+        }
+    }
+}
+
+impl Value {
+    pub fn cons(car: Value, cdr: Value) -> Value {
+        Value::Pair(Shared::new(Locked::new(Pair { car, cdr })))
+    }
+
+    pub fn list(vals: Vec<Value>) -> Value {
+        Value::dotted(vals, Value::Nil)
+    }
+
+    // The elements of vals, with tail as the cdr of the last pair
+    pub fn dotted(vals: Vec<Value>, tail: Value) -> Value {
+        vals.into_iter().rev().fold(tail, |cdr, car| Value::cons(car, cdr))
+    }
+
+    // The car and cdr of a pair
+    pub fn uncons(&self) -> Option<(Value, Value)> {
+        match *self {
+            Value::Pair(ref pair) => {
+                let pair = shared::lock(pair);
+                Some((pair.car.clone(), pair.cdr.clone()))
+            },
+            _ => None
+        }
+    }
+
+    // The elements of a list, and what ends it: the empty list for a proper list, and something
+    // else for a dotted one.  A circular list has no end, so it gives None.
+    pub fn list_parts(&self) -> Option<(Vec<Value>, Value)> {
+        let mut vals = Vec::new();
+        let mut rest = self.clone();
+        // A second walk at half the speed meets the first one if the list goes round in a circle
+        let mut slow = self.clone();
+        while let Some((car, cdr)) = rest.uncons() {
+            vals.push(car);
+            rest = cdr;
+            if vals.len() % 2 == 0 {
+                slow = slow.uncons().map_or(Value::Nil, |(_, cdr)| cdr);
+                if let (Value::Pair(ref x), Value::Pair(ref y)) = (&rest, &slow) {
+                    if Shared::ptr_eq(x, y) {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some((vals, rest))
+    }
+
+    // The elements of a proper list
+    pub fn list_elements(&self) -> Option<Vec<Value>> {
+        match self.list_parts() {
+            Some((vals, Value::Nil)) => Some(vals),
+            _                        => None
+        }
+    }
+}
+
 pub fn convert_to_node(val: Value) -> Node {
     Node::ValueWrapper(Box::new(val))
 }
 
-impl fmt::Display for Value {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+pub fn is_procedure(val: &Value) -> bool {
+    matches!(*val, Value::Function(..) | Value::Lambda(_) | Value::Partial(..) | Value::Curried(..) | Value::Composition(_) | Value::Continuation(_) | Value::Parameter(_) | Value::Generator(_))
+}
+
+// The number of arguments a procedure takes, if it's fixed
+pub fn arity(val: &Value) -> Option<usize> {
+    match *val {
+        Value::Lambda(ref lambda) if lambda.rest.is_none() && lambda.optionals.is_empty() => Some(lambda.params.len()),
+        Value::Partial(ref func, ref args)
+            | Value::Curried(ref func, ref args) => arity(func).map(|n| n.saturating_sub(args.len())),
+        Value::Composition(ref funcs)          => funcs.last().and_then(arity),
+        Value::Parameter(_) | Value::Generator(_) => Some(0),
+        _                         => None
+    }
+}
+
+// What the builtin procedure with the given name returns for two small integers, if it's one
+// of the arithmetic or comparison procedures and the result doesn't overflow.  Anything else is
+// left to the builtin itself.
+fn fixnum_op(name: &str, x: i32, y: i32) -> Option<Value> {
+    match name {
+        "+"            => x.checked_add(y).map(Value::Int),
+        "-"            => x.checked_sub(y).map(Value::Int),
+        "*"            => x.checked_mul(y).map(Value::Int),
+        "<"            => Some(Value::Bool(x < y)),
+        "<="           => Some(Value::Bool(x <= y)),
+        ">"            => Some(Value::Bool(x > y)),
+        ">="           => Some(Value::Bool(x >= y)),
+        "=" | "equal?" => Some(Value::Bool(x == y)),
+        _              => None
+    }
+}
+
+// A call to func with the given values followed by the given (unevaluated) argument nodes
+pub fn call_node(func: Value, args: Vec<Value>, nodes: &[Node]) -> Node {
+    let mut call = vec![convert_to_node(func)];
+    call.extend(args.into_iter().map(convert_to_node));
+    call.extend(nodes.iter().cloned());
+    Node::List(call, None)
+}
+
+// Accessors for applications that embed the interpreter, so that getting a number or a setting out
+// of a result doesn't take a match on every variant.  The REPL doesn't need them, so they're
+// allowed to go unused here.
+#[allow(dead_code)]
+impl Value {
+    pub fn as_int(&self) -> Option<i32> {
         match *self {
-            Value::Int(val)          => write!(f, "{}", val),
-            Value::Float(val)        => write!(f, "{}", val),
-            Value::Complex(real, 0.0)  => write!(f, "{}", real),
-            Value::Complex(real, im) if im < 0.0 => write!(f, "{}-{}i", real, -im),
-            Value::Complex(real, im) => write!(f, "{}+{}i", real, im),
-            Value::Bool(true)        => write!(f, "#t"),
-            Value::Bool(false)       => write!(f, "#f"),
-            Value::Symbol(ref val) | Value::Literal(ref val) => write!(f, "{}", val),
-            Value::String(ref val)   => write!(f, "\"{}\"", val.replace("\"","\\\"")),
-            Value::List(ref vals)    => {
-                let mut output = String::new();
-                let mut sep = String::new();
-                for val in vals {
-                    output = format!("{}{}{}",output,sep,val.clone());
-                    sep = " ".to_string();
-                }
-                write!(f, "({})", output)
-            }
-            Value::Lambda(ref lambda) => {
-                let mut params_str = String::new();
-                let mut sep = String::new();
-                for p in &lambda.params {
-                    params_str = format!("{}{}{}", params_str, sep, p);
-                    sep = " ".to_string();
-                }
-                write!(f, "(lambda ({}) ({}))", params_str, lambda.body)
-            },
-            Value::Function(name, _) => write!(f, "{}", name),
-            Value::NodeWrapper(ref node) => write!(f, "{}", node),
-            Value::Void            => write!(f, "()")
+            Value::Int(n) => Some(n),
+            _             => None
+        }
+    }
+
+    // Any real number, since an integer or a fraction is fine where a float is wanted
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            Value::Int(n)             => Some(n as f64),
+            Value::Float(x)           => Some(x),
+            Value::Rational(num, den) => Some(num as f64 / den as f64),
+            _                         => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(val) => Some(val),
+            _                => None
         }
     }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref val) => Some(val),
+            _                      => None
+        }
+    }
+
+    // The name of a quoted symbol or a keyword
+    pub fn as_symbol(&self) -> Option<&str> {
+        match *self {
+            Value::Literal(ref name) | Value::Keyword(ref name) => Some(name),
+            _                                                   => None
+        }
+    }
+
+    // The elements of a list or a vector
+    pub fn as_list(&self) -> Option<Vec<Value>> {
+        match *self {
+            Value::Vector(ref vals) => Some(vals.clone()),
+            ref val                 => val.list_elements()
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Value> {
+        self.as_list().and_then(|mut vals| if index < vals.len() { Some(vals.swap_remove(index)) } else { None })
+    }
+
+    // The value for key in a property list like (#:port 8080 #:debug #t), or an association list
+    // like ((port 8080) (debug #t)) or ((port . 8080))
+    pub fn lookup(&self, key: &str) -> Option<Value> {
+        let vals = self.as_list()?;
+        if let Some(&Value::Keyword(_)) = vals.first() {
+            return vals.chunks(2)
+                .find(|pair| pair.len() == 2 && pair[0].as_symbol() == Some(key))
+                .map(|pair| pair[1].clone());
+        }
+        vals.iter().filter_map(|entry| match entry.list_parts()? {
+            (ref entry, Value::Nil) if entry.len() == 2 && entry[0].as_symbol() == Some(key) => Some(entry[1].clone()),
+            (ref entry, tail) if entry.len() == 1 && entry[0].as_symbol() == Some(key)        => Some(tail),
+            _ => None
+        }).next()
+    }
+}
+
+// Match a value against a pattern, giving the result for a match and an error saying what was
+// expected otherwise, e.g. value_match!(val, Value::Int(n) => n as usize)?
+#[allow(unused_macros)]
+macro_rules! value_match {
+    ($val:expr, $pattern:pat => $result:expr) => {
+        match $val {
+            $pattern  => Ok($result),
+            ref other => Err($crate::interpreter::EvalError { location: None, message: format!("Expected {}, got {}", stringify!($pattern), other) })
+        }
+    };
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", printer::write(self, printer::Limits::default()))
+    }
 }
 
 pub struct EvalError {
-    pub message: String, 
+    pub location: Option<Location>,
+    pub message: String
 }
 
 impl fmt::Display for EvalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "EvalError: {}", self.message)
+        match self.location {
+            Some(location) => write!(f, "EvalError at {}: {}", location, self.message),
+            None           => write!(f, "EvalError: {}", self.message)
+        }
     }
 }
 
+// How many nodes have been evaluated so far, by any interpreter on this thread.  Every lambda call
+// gets an interpreter of its own, so this can't be a field.
+thread_local! {
+    static STEPS: Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn steps() -> usize {
+    STEPS.with(|steps| steps.get())
+}
+
+pub fn set_steps(count: usize) {
+    STEPS.with(|steps| steps.set(count));
+}
+
+// The most environments that have been nested inside each other on this thread, which generated
+// code with thousands of nested scopes can make very large
+thread_local! {
+    static DEEPEST_ENV: Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn deepest_env() -> usize {
+    DEEPEST_ENV.with(|deepest| deepest.get())
+}
+
+// Continuations are escape-only: calling one unwinds the evaluation, as an error that nothing
+// but its call/cc catches, back to where call/cc was called.  These are the continuations whose
+// call/cc hasn't returned yet, innermost last, and the continuation being escaped to along with
+// the value it was called with.
+thread_local! {
+    static CONTINUATIONS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    static NEXT_CONTINUATION: Cell<usize> = const { Cell::new(0) };
+    static ESCAPE: RefCell<Option<(usize, Value)>> = const { RefCell::new(None) };
+}
+
+// Start the extent of a new continuation, returning its id
+pub fn enter_continuation() -> usize {
+    let id = NEXT_CONTINUATION.with(|next| {
+        next.set(next.get() + 1);
+        next.get()
+    });
+    CONTINUATIONS.with(|active| active.borrow_mut().push(id));
+    id
+}
+
+// End the extent of a continuation, returning the value it was called with if the evaluation
+// was unwound by calling it
+pub fn exit_continuation(id: usize) -> Option<Value> {
+    CONTINUATIONS.with(|active| active.borrow_mut().retain(|&active| active != id));
+    ESCAPE.with(|escape| {
+        let mut escape = escape.borrow_mut();
+        match *escape {
+            Some((target, _)) if target == id => escape.take().map(|(_, val)| val),
+            _                                 => None
+        }
+    })
+}
+
+// Whether an error is really the unwinding from a continuation being called, which handlers like
+// try have to let through
+pub fn escaping() -> bool {
+    ESCAPE.with(|escape| escape.borrow().is_some())
+}
+
+// The handlers with-exception-handler has installed, innermost last.  guard and try install a
+// None, so anything raised inside them unwinds to them instead of going to a handler further out.
+// Along with them, the object being raised by the error that's unwinding, if it came from
+// raise or error, and that error's message.
+thread_local! {
+    static HANDLERS: RefCell<Vec<Option<Value>>> = const { RefCell::new(Vec::new()) };
+    static RAISED: RefCell<Option<(Value, String)>> = const { RefCell::new(None) };
+}
+
+// Install a handler, returning how many there were before, to go back to with restore_handlers
+pub fn push_handler(handler: Option<Value>) -> usize {
+    HANDLERS.with(|handlers| {
+        let mut handlers = handlers.borrow_mut();
+        handlers.push(handler);
+        handlers.len() - 1
+    })
+}
+
+pub fn restore_handlers(count: usize) {
+    HANDLERS.with(|handlers| handlers.borrow_mut().truncate(count))
+}
+
+// Take the innermost handler out while it's called, so anything it raises goes to the ones
+// outside it.  None if there are no handlers, Some(None) if the innermost is a guard or try.
+pub fn pop_handler() -> Option<Option<Value>> {
+    HANDLERS.with(|handlers| {
+        let mut handlers = handlers.borrow_mut();
+        match handlers.last() {
+            Some(&Some(_)) => handlers.pop(),
+            Some(&None)    => Some(None),
+            None           => None
+        }
+    })
+}
+
+// The error that unwinds with obj, which has already been given to any handler that should
+// see it
+pub fn raised(obj: Value, message: String) -> EvalError {
+    RAISED.with(|raised| *raised.borrow_mut() = Some((obj, message.clone())));
+    EvalError { location: None, message }
+}
+
+// Whether the error with this message is unwinding with a raised object
+pub fn is_raised(message: &str) -> bool {
+    RAISED.with(|raised| matches!(*raised.borrow(), Some((_, ref raised)) if raised == message))
+}
+
+// The object the error with this message was raised with, for guard and try to catch
+pub fn take_raised(message: &str) -> Option<Value> {
+    RAISED.with(|raised| {
+        let mut raised = raised.borrow_mut();
+        match *raised {
+            Some((_, ref other)) if other == message => raised.take().map(|(obj, _)| obj),
+            _                                        => None
+        }
+    })
+}
+
+// The bindings parameterize has made, innermost last.  Calling a parameter looks through them
+// before falling back on the value it was made with.
+thread_local! {
+    static PARAMETER_BINDINGS: RefCell<Vec<(Shared<Parameter>, Value)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Bind a parameter to a value, returning how many bindings there were before, to go back to
+// with unbind_parameters
+pub fn bind_parameter(param: Shared<Parameter>, val: Value) -> usize {
+    PARAMETER_BINDINGS.with(|bindings| {
+        let mut bindings = bindings.borrow_mut();
+        bindings.push((param, val));
+        bindings.len() - 1
+    })
+}
+
+pub fn unbind_parameters(count: usize) {
+    PARAMETER_BINDINGS.with(|bindings| bindings.borrow_mut().truncate(count))
+}
+
+pub fn parameter_value(param: &Shared<Parameter>) -> Value {
+    PARAMETER_BINDINGS.with(|bindings| {
+        match bindings.borrow().iter().rev().find(|(bound, _)| Shared::ptr_eq(bound, param)) {
+            Some((_, val)) => val.clone(),
+            None           => param.value.clone()
+        }
+    })
+}
+
+pub fn escape(id: usize, val: Value) -> Result<Value, EvalError> {
+    if !CONTINUATIONS.with(|active| active.borrow().contains(&id)) {
+        return Err(EvalError { location: None, message: "A continuation can't be called after its call/cc has returned".to_string() });
+    }
+    ESCAPE.with(|escape| *escape.borrow_mut() = Some((id, val)));
+    Err(EvalError { location: None, message: "Escaping to a continuation".to_string() })
+}
+
+// Unwind everything, as an escape to a continuation that no call/cc has, for a producer whose
+// generator has gone
+pub fn unwind() -> EvalError {
+    ESCAPE.with(|escape| *escape.borrow_mut() = Some((0, Value::Void)));
+    EvalError { location: None, message: "Escaping to a continuation".to_string() }
+}
+
+// What a thread of evaluation keeps in thread locals that has to go along with it when a
+// generator's producer takes over on a thread of its own, and come back when the producer yields:
+// the counters, the output being captured, and whatever's unwinding
+pub struct Handoff {
+    deepest_env: usize,
+    next_continuation: usize,
+    escape: Option<(usize, Value)>,
+    raised: Option<(Value, String)>,
+    macros: (usize, usize),
+    random: u64,
+    limits: printer::Limits,
+    pub output: Vec<String>
+}
+
+// Take this thread's Handoff, for another thread to carry on with
+pub fn hand_off() -> Handoff {
+    Handoff {
+        deepest_env: deepest_env(),
+        next_continuation: NEXT_CONTINUATION.with(|next| next.get()),
+        escape: ESCAPE.with(|escape| escape.borrow_mut().take()),
+        raised: RAISED.with(|raised| raised.borrow_mut().take()),
+        macros: macros::counters(),
+        random: random::state(),
+        limits: printer::limits(),
+        output: port::take_captures()
+    }
+}
+
+pub fn take_up(handoff: Handoff) {
+    let Handoff { deepest_env, next_continuation, escape, raised, macros: counters, random: seed, limits, output } = handoff;
+    DEEPEST_ENV.with(|deepest| deepest.set(deepest_env));
+    NEXT_CONTINUATION.with(|next| next.set(next_continuation));
+    ESCAPE.with(|cell| *cell.borrow_mut() = escape);
+    RAISED.with(|cell| *cell.borrow_mut() = raised);
+    macros::set_counters(counters);
+    random::seed(seed);
+    printer::set_limits(limits);
+    port::set_captures(output);
+}
+
+// The continuations, handlers and parameter bindings in effect, which a producer starts with a
+// copy of and then keeps for itself
+#[derive(Default)]
+pub struct Extent {
+    continuations: Vec<usize>,
+    handlers: Vec<Option<Value>>,
+    parameter_bindings: Vec<(Shared<Parameter>, Value)>
+}
+
+pub fn extent() -> Extent {
+    Extent {
+        continuations: CONTINUATIONS.with(|active| active.borrow().clone()),
+        handlers: HANDLERS.with(|handlers| handlers.borrow().clone()),
+        parameter_bindings: PARAMETER_BINDINGS.with(|bindings| bindings.borrow().clone())
+    }
+}
+
+pub fn set_extent(extent: Extent) {
+    let Extent { continuations, handlers, parameter_bindings } = extent;
+    CONTINUATIONS.with(|active| *active.borrow_mut() = continuations);
+    HANDLERS.with(|cell| *cell.borrow_mut() = handlers);
+    PARAMETER_BINDINGS.with(|bindings| *bindings.borrow_mut() = parameter_bindings);
+}
+
+// The result of run_budgeted: either the form finished, or the budget ran out first and the
+// evaluation can be picked up again with resume_budgeted.  Embedders that can't block, like a GUI
+// event loop, use this; the REPL and scripts don't, so it's allowed to go unused here.
+#[allow(dead_code)]
+pub enum Budgeted {
+    Done(Result<Value, EvalError>),
+    Pending(Pending)
+}
+
+// An evaluation that ran out of budget.  Dropping it cancels the evaluation.
+pub struct Pending {
+    node: Node,
+    // The environment of the procedure the node was handed out of, if it was
+    env: Option<Environment>
+}
+
+// What went wrong with a script given as source: it couldn't be read, or evaluating it failed
+pub enum ScriptError {
+    Parse(Vec<ParseError>),
+    Eval(EvalError)
+}
+
+// How an interpreter behaves, which is fixed when it's created by Interpreter::new
+#[derive(Clone)]
+pub struct EngineConfig {
+    // Whether if, and, or and not require booleans, rather than treating anything but #f as true
+    pub strict_booleans: bool,
+    // How many steps (nodes evaluated) one eval or eval_script can take before it's stopped
+    pub max_steps: Option<usize>,
+    // How many digits after the point floats are printed with, instead of as many as it takes
+    // to read them back exactly
+    pub float_precision: Option<usize>,
+    // Whether to define the procedures in prelude.ss, which are written in Scheme
+    pub load_prelude: bool,
+    // Whether to leave out the builtins that reach outside the interpreter, like print
+    pub sandbox: bool,
+    // How deeply the scopes made by begin, the let forms and lambda calls can be nested before
+    // evaluation is stopped
+    pub max_env_depth: Option<usize>,
+    // How many pairs of values one equal? can compare before it's stopped
+    pub max_compare: Option<usize>,
+    // Whether every builtin library is imported from the start, as before there were libraries.
+    // Otherwise only (scheme base) is, and programs import the others they use.
+    pub import_all: bool,
+    // The directories import looks in, in order, for a library that hasn't been defined: the
+    // file for (my utils) is my/utils.sld in one of them
+    pub library_path: Vec<String>,
+    // Where the forms read from library files are cached, if anywhere (see cache.rs)
+    pub cache_dir: Option<String>
+}
+
+impl Default for EngineConfig {
+    fn default() -> EngineConfig {
+        EngineConfig { strict_booleans: false, max_steps: None, float_precision: None, load_prelude: true, sandbox: false, max_env_depth: None, max_compare: None, import_all: true, library_path: vec![".".to_string()], cache_dir: None }
+    }
+}
+
+const PRELUDE: &str = include_str!("prelude.ss");
+
+// How many steps eval_config lets a configuration take, which is far more than any real one needs
+const CONFIG_STEPS: usize = 1_000_000;
+
+// The bindings importing a library makes: each name it exports, with its value
+pub type Exports = Vec<(String, Value)>;
+
 #[derive(Clone)]
 pub struct Interpreter {
-    pub env: Environment
+    pub env: Environment,
+    pub config: Shared<EngineConfig>,
+    // The handlers from define-reader-syntax, by the character that starts the syntax they read.
+    // Like the environment, they're shared with the interpreters for nested scopes.
+    reader_syntax: Shared<Locked<HashMap<char, Value>>>,
+    // The bindings each library made by define-library exports, by its name, e.g. "my utils"
+    libraries: Shared<Locked<HashMap<String, Exports>>>,
+    // The step count at which max_steps runs out for the current evaluation
+    deadline: Option<usize>
 }
 
 impl Interpreter {
-    pub fn new() -> Interpreter {
-        Interpreter { env: Environment::new(None) }
+    pub fn new(config: EngineConfig) -> Interpreter {
+        let load_prelude = config.load_prelude;
+        let mut interpreter = Interpreter { env: Environment::new(None, &config), config: Shared::new(config), reader_syntax: Shared::new(Locked::new(HashMap::new())), libraries: Shared::new(Locked::new(HashMap::new())), deadline: None };
+        if load_prelude {
+            let forms = parser::parse_source(PRELUDE.to_string()).unwrap_or_else(|_| panic!("Could not parse the prelude"));
+            if let Err(err) = interpreter.eval_script(forms) {
+                panic!("Could not evaluate the prelude: {}", err);
+            }
+        }
+        interpreter
     }
-    
-    pub fn new_with_env(env: Environment) -> Interpreter{
-        Interpreter { env: env }
+
+    // An interpreter for a nested scope, with the same configuration as this one and counting
+    // towards the same step limit
+    pub fn with_env(&self, env: Environment) -> Interpreter {
+        Interpreter { env, config: self.config.clone(), reader_syntax: self.reader_syntax.clone(), libraries: self.libraries.clone(), deadline: self.deadline }
+    }
+
+    // Evaluate a configuration file and return its value.  Only a side-effect-free part of the
+    // language is available: literals, quote, if, and, or, let, let*, let-list, arithmetic, and
+    // building lists, vectors and strings.  There's no define, lambda or print, and a step limit,
+    // so an application can read untrusted configuration safely.  The REPL and scripts don't use
+    // this, so it's allowed to go unused here.
+    #[allow(dead_code)]
+    pub fn eval_config(source: &str) -> Result<Value, EvalError> {
+        let forms = parser::parse_source(source.to_string()).map_err(|mut errors| {
+            let err = errors.remove(0);
+            EvalError { location: err.location, message: err.message }
+        })?;
+        let config = EngineConfig { max_steps: Some(CONFIG_STEPS), load_prelude: false, sandbox: true, ..EngineConfig::default() };
+        let mut interpreter = Interpreter { env: Environment::new_config(&config), config: Shared::new(config), reader_syntax: Shared::new(Locked::new(HashMap::new())), libraries: Shared::new(Locked::new(HashMap::new())), deadline: None };
+        interpreter.start_deadline();
+        let mut val = Value::Void;
+        for form in forms {
+            val = interpreter.eval_node(&form)?;
+        }
+        Ok(val)
+    }
+
+    // A new scope inside the current one, for a lambda, begin or let form to bind names in
+    pub fn inner_env(&self) -> Result<Environment, EvalError> {
+        let env = Environment::new_empty(Some(Box::new(self.env.clone())));
+        let depth = env.depth();
+        DEEPEST_ENV.with(|deepest| deepest.set(deepest.get().max(depth)));
+        match self.config.max_env_depth {
+            Some(max) if depth > max => Err(EvalError { location: None, message: format!("Exceeded the limit of {} nested environments", max) }),
+            _                        => Ok(env)
+        }
     }
 
     pub fn eval(&mut self, tree: Node) -> Result<Value, EvalError> {
+        self.start_deadline();
         self.eval_node(&tree)
     }
+
+    // The step count at which this evaluation runs out of steps, if there's a limit
+    pub fn deadline(&self) -> Option<usize> {
+        self.deadline
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<usize>) {
+        self.deadline = deadline;
+    }
+
+    fn start_deadline(&mut self) {
+        self.deadline = self.config.max_steps.map(|max| steps().saturating_add(max));
+    }
+
+    // Whether a value counts as true for the conditional form named form
+    pub fn is_true(&self, val: &Value, form: &str) -> Result<bool, EvalError> {
+        match *val {
+            Value::Bool(val)                   => Ok(val),
+            _ if self.config.strict_booleans => Err(EvalError { location: None, message: format!("'{}' requires a boolean test", form) }),
+            _                                  => Ok(true)
+        }
+    }
+
+    // The limits the REPL and write print values within
+    pub fn print_limits(&self) -> printer::Limits {
+        printer::Limits { precision: self.config.float_precision, ..printer::limits() }
+    }
+
+    // The form display shows a value in: strings and characters as their contents, anything
+    // else as it's printed
+    pub fn display(&self, val: &Value) -> String {
+        match *val {
+            Value::String(ref val) => val.clone(),
+            Value::Char(c)         => c.to_string(),
+            ref val                => printer::write(val, printer::Limits { precision: self.config.float_precision, ..printer::Limits::default() })
+        }
+    }
+
+    // Read every form in source, which starts at location, with the reader syntax defined so far
+    pub fn read(&mut self, source: &str, location: Location) -> Result<Vec<Node>, Vec<ParseError>> {
+        parser::parse_source_with(source, location, &mut |c: char, rest: &str| self.read_syntax(c, rest))
+    }
+
+    // Read input as the REPL is given it, with the reader syntax defined so far
+    pub fn read_incremental(&mut self, input: &str) -> parser::Incremental {
+        parser::incremental(self.read(input, Location { line: 1, col: 1 }))
+    }
+
+    // Read the first datum in text, with the reader syntax defined so far
+    pub fn read_datum(&mut self, text: &str, location: Location) -> Result<Option<(Node, usize, Location)>, ParseError> {
+        parser::read_datum(text, location, &mut |c: char, rest: &str| self.read_syntax(c, rest))
+    }
+
+    // From now on, read c and what comes after it by calling handler with a port on the rest of
+    // the source, and using the datum it returns
+    pub fn define_reader_syntax(&mut self, c: char, handler: Value) {
+        shared::lock(&self.reader_syntax).insert(c, handler);
+    }
+
+    // What the library named name (like "my utils") exports, if define-library has defined it
+    pub fn library(&self, name: &str) -> Option<Exports> {
+        shared::lock(&self.libraries).get(name).cloned()
+    }
+
+    pub fn define_library(&mut self, name: String, exports: Exports) {
+        shared::lock(&self.libraries).insert(name, exports);
+    }
+
+    fn read_syntax(&mut self, c: char, rest: &str) -> Result<Option<(Node, usize)>, String> {
+        let handler = match shared::lock(&self.reader_syntax).get(&c) {
+            Some(handler) => handler.clone(),
+            None          => return Ok(None)
+        };
+        let port = Shared::new(Locked::new(InputPort::new(rest.to_string())));
+        let datum = self.apply(handler, vec![Value::InputPort(port.clone())])
+            .map_err(|err| format!("Error reading the syntax for {}: {}", c, err.message))?;
+        let used = shared::lock(&port).pos();
+        Ok(Some((datum_node(&datum), used)))
+    }
+
+    // Read and evaluate a whole script.  Reader syntax defined at the top level applies to the
+    // rest of the script, so after each define-reader-syntax, what follows it is read again.
+    pub fn eval_source(&mut self, source: &str) -> Result<Value, ScriptError> {
+        let mut rest = source;
+        let mut location = Location { line: 1, col: 1 };
+        loop {
+            let forms = self.read(rest, location).map_err(ScriptError::Parse)?;
+            let defines = forms.iter().position(defines_reader_syntax);
+            let count = match defines {
+                Some(i) if i + 1 < forms.len() => i + 1,
+                _                              => return self.eval_script(forms).map_err(ScriptError::Eval)
+            };
+            self.eval_script(forms[..count].to_vec()).map_err(ScriptError::Eval)?;
+            // Skip past the forms that have been evaluated, reading them the same way again
+            for _ in 0..count {
+                if let Some((_, used, after)) = self.read_datum(rest, location).map_err(|err| ScriptError::Parse(vec![err]))? {
+                    rest = &rest[used..];
+                    location = after;
+                }
+            }
+        }
+    }
+
+    // eval_source for a library file, with its forms from the cache in cache_dir if the same
+    // source has been read before.  Reader syntax changes how a source reads, so a file is only
+    // cached if there's none defined and the file doesn't define any.
+    pub fn eval_cached(&mut self, source: &str) -> Result<Value, ScriptError> {
+        let dir = match self.config.cache_dir {
+            Some(ref dir) if shared::lock(&self.reader_syntax).is_empty() => dir.clone(),
+            _                                                             => return self.eval_source(source)
+        };
+        if let Some(forms) = cache::load(&dir, source) {
+            return self.eval_script(forms).map_err(ScriptError::Eval);
+        }
+        let forms = self.read(source, Location { line: 1, col: 1 }).map_err(ScriptError::Parse)?;
+        if forms.iter().any(defines_reader_syntax) {
+            return self.eval_source(source);
+        }
+        cache::store(&dir, source, &forms);
+        self.eval_script(forms).map_err(ScriptError::Eval)
+    }
+
+    // Evaluate a whole script, one top-level form after another, directly in this interpreter's
+    // environment.  A script that's a single begin is treated as the sequence of forms in it.
+    // Each run of consecutive defines is evaluated in two passes: procedure definitions first,
+    // then everything else in order.  That way a define can refer to a procedure defined later
+    // in the same run, as in letrec.
+    pub fn eval_script(&mut self, forms: Vec<Node>) -> Result<Value, EvalError> {
+        self.start_deadline();
+        let forms = match forms.as_slice() {
+            [Node::List(ref nodes, _)] if is_form(nodes, "begin") => nodes[1..].to_vec(),
+            _                                                     => forms
+        };
+
+        let mut val = Value::Void;
+        for node in hoisted(&forms) {
+            val = self.eval_node(node)?;
+        }
+        Ok(val)
+    }
     
+    // Evaluate a form, but give up after about budget steps (nodes evaluated) and return what's
+    // left to do instead.  The budget is only checked between tail calls, since that's where
+    // the rest of the evaluation is a node that can be kept; a single call that recurses
+    // deeply without a tail call can go over it by any amount.
+    #[allow(dead_code)]
+    pub fn run_budgeted(&mut self, form: Node, budget: usize) -> Budgeted {
+        self.run_pending(Pending { node: form, env: None }, budget)
+    }
+
+    // Carry on with an evaluation that ran out of budget, with a new budget
+    #[allow(dead_code)]
+    pub fn resume_budgeted(&mut self, pending: Pending, budget: usize) -> Budgeted {
+        self.run_pending(pending, budget)
+    }
+
+    fn run_pending(&mut self, pending: Pending, budget: usize) -> Budgeted {
+        let start = steps();
+        let Pending { mut node, env } = pending;
+        let mut scope = env.map(|env| self.with_env(env));
+        loop {
+            if steps().wrapping_sub(start) >= budget {
+                return Budgeted::Pending(Pending { node, env: scope.map(|scope| scope.env) });
+            }
+            let interpreter = match scope {
+                Some(ref mut scope) => scope,
+                None                => &mut *self
+            };
+            match interpreter.eval_node_wrapped(&node) {
+                Ok(Value::NodeWrapper(node_cont))     => node = node_cont,
+                Ok(Value::ScopedNode(node_cont, env)) => {
+                    node = node_cont;
+                    scope = Some(self.with_env(env));
+                },
+                val                                   => return Budgeted::Done(val)
+            }
+        }
+    }
+
     pub fn eval_node(&mut self, node: &Node) -> Result<Value, EvalError> {
         let mut node = node.clone();
+        // Once a procedure's body hands a tail call out, it goes on in the procedure's environment
+        let mut scope: Option<Interpreter> = None;
         loop {
-            match self.eval_node_wrapped(&node) {
-                Ok(Value::NodeWrapper(node_cont)) => node = node_cont,
-                val                               => return val
+            let interpreter = match scope {
+                Some(ref mut scope) => scope,
+                None                => &mut *self
+            };
+            match interpreter.eval_node_wrapped(&node) {
+                Ok(Value::NodeWrapper(node_cont))     => node = node_cont,
+                Ok(Value::ScopedNode(node_cont, env)) => {
+                    node = node_cont;
+                    scope = Some(self.with_env(env));
+                },
+                val                                   => return val
             }
         }
     }
     
     pub fn eval_node_wrapped(&mut self, node: &Node) -> Result<Value, EvalError> {
-        match node {
-            &Node::ValueWrapper(ref val)  => Ok((**val).clone()),
-            &Node::Int(val)               => Ok(Value::Int(val)),
-            &Node::Float(val)             => Ok(Value::Float(val)),
-            &Node::Complex(real, im)      => Ok(Value::Complex(real, im)),
-            &Node::Bool(val)              => Ok(Value::Bool(val)),
-            &Node::Symbol(ref val)        => {
-                match self.env.get(&val) {
-                    Some(res) => Ok(res.clone()),
-                    None => Ok(Value::Symbol(val.clone()))
+        STEPS.with(|steps| steps.set(steps.get().wrapping_add(1)));
+        if let Some(deadline) = self.deadline {
+            if steps() >= deadline {
+                return Err(EvalError { location: None, message: format!("Exceeded the limit of {} steps", self.config.max_steps.unwrap_or(0)) });
+            }
+        }
+        match *node {
+            Node::ValueWrapper(ref val) | Node::Quoted(ref val) => Ok((**val).clone()),
+            Node::Int(val)               => Ok(Value::Int(val)),
+            Node::Float(val)             => Ok(Value::Float(val)),
+            Node::Complex(real, im)      => Ok(Value::Complex(real, im)),
+            Node::Rational(num, den)     => Ok(Value::Rational(num, den)),
+            Node::Bool(val)              => Ok(Value::Bool(val)),
+            Node::Char(c)                => Ok(Value::Char(c)),
+            Node::Keyword(ref name)      => Ok(Value::Keyword(name.clone())),
+            // Vector literals evaluate to themselves, like quoted lists
+            Node::Vector(ref nodes)      => Ok(Value::Vector(nodes.iter().map(quote_node).collect())),
+            Node::Pair(..)               => Err(EvalError { location: None, message: "Can't evaluate a dotted pair".to_string() }),
+            Node::Symbol(ref val)        => {
+                match self.env.get(val) {
+                    Some(res) => Ok(res),
+                    None      => Err(EvalError { location: None, message: format!("Unbound variable {}", val) })
                 }
             },
-            &Node::String(ref val)        => Ok(Value::String(val.clone())),
-            &Node::List(ref nodes)        => {
-                let func_result = self.eval_node(&nodes[0]);
-                match func_result {
-                    Ok(func_val) => {
-                        match func_val {
-                            Value::Symbol(val) => Err(EvalError { message: format!("Unknown function {}", val).to_string() }),
-                            Value::Function(_, func) => {
-                                let args = nodes[1..].to_vec();
-                                match func(self, &args) {
-                                    Ok(val) => Ok(val),
-                                    Err(err) => Err(err)
-                                 }
-                            },
-                            Value::Lambda(lambda)    => self.eval_lambda(lambda, nodes),
-                            Value::NodeWrapper(node) => {
-                                let mut node_vec: Vec<Node> = nodes.clone();
-                                node_vec[0] = node.clone();
-                                self.eval_node(&Node::List(node_vec))
-                            },
-                            _ => Err(EvalError { message: "Invalid function call".to_string() })
+            Node::String(ref val)        => Ok(Value::String(val.clone())),
+            // The innermost list that fails is where the error is reported
+            Node::List(ref nodes, location) => self.eval_list(nodes).map_err(|mut err| {
+                if err.location.is_none() {
+                    err.location = location;
+                }
+                err
+            })
+        }
+    }
+
+    fn eval_list(&mut self, nodes: &[Node]) -> Result<Value, EvalError> {
+        let func_result = self.eval_node(&nodes[0]);
+        match func_result {
+            Ok(func_val) => {
+                match func_val {
+                    Value::SpecialForm(_, form) => form(self, &nodes[1..]),
+                    Value::Macro(mac)           => Ok(Value::NodeWrapper(mac.expand(nodes, &self.env)?)),
+                    Value::Function(name, func) => {
+                        // Two-argument calls like (+ i 1) and (< i n), which loops are full of,
+                        // don't need an argument vector, and on small integers don't need the
+                        // builtin at all
+                        if nodes.len() == 3 {
+                            let x = self.eval_node(&nodes[1])?;
+                            let y = self.eval_node(&nodes[2])?;
+                            if let (&Value::Int(a), &Value::Int(b)) = (&x, &y) {
+                                if let Some(val) = fixnum_op(name, a, b) {
+                                    return Ok(val);
+                                }
+                            }
+                            return func(self, &[x, y]);
+                        }
+                        // The arguments are evaluated left to right before the procedure gets them
+                        let mut args = Vec::new();
+                        for node in &nodes[1..] {
+                            args.push(self.eval_node(node)?);
+                        }
+                        func(self, &args)
+                    },
+                    Value::Lambda(lambda)    => self.eval_lambda(*lambda, nodes),
+                    Value::Parameter(param)  => {
+                        if nodes.len() > 1 {
+                            return Err(EvalError { location: None, message: "A parameter takes no arguments".to_string() });
+                        }
+                        Ok(parameter_value(&param))
+                    },
+                    Value::Generator(gen)    => {
+                        if nodes.len() > 1 {
+                            return Err(EvalError { location: None, message: "A generator takes no arguments".to_string() });
+                        }
+                        generator::next_value(self, &gen)
+                    },
+                    Value::Continuation(id)  => {
+                        let mut args = Vec::new();
+                        for node in &nodes[1..] {
+                            args.push(self.eval_node(node)?);
+                        }
+                        escape(id, if args.len() == 1 { args.remove(0) } else { Value::Values(args) })
+                    },
+                    Value::Partial(func, args) => Ok(Value::NodeWrapper(call_node(*func, args, &nodes[1..]))),
+                    Value::Curried(func, mut args) => {
+                        for node in &nodes[1..] {
+                            args.push(self.eval_node(node)?);
+                        }
+                        match arity(&func) {
+                            Some(n) if args.len() < n => Ok(Value::Curried(func, args)),
+                            _                         => Ok(Value::NodeWrapper(call_node(*func, args, &[])))
+                        }
+                    },
+                    Value::Composition(funcs) => {
+                        // The innermost procedure gets all the arguments, and each one
+                        // after it gets the result of the one before; the last is a tail call
+                        let (outer, inner) = match funcs.split_first() {
+                            Some((outer, inner)) => (outer.clone(), inner),
+                            None                 => return Err(EvalError { location: None, message: "Invalid function call".to_string() })
+                        };
+                        let mut args = Vec::new();
+                        for node in &nodes[1..] {
+                            args.push(self.eval_node(node)?);
+                        }
+                        for func in inner.iter().rev() {
+                            args = vec![self.apply(func.clone(), args)?];
                         }
+                        Ok(Value::NodeWrapper(call_node(outer, args, &[])))
                     },
-                    Err(err) => Err(err)
+                    Value::NodeWrapper(node) => {
+                        let mut node_vec: Vec<Node> = nodes.to_vec();
+                        node_vec[0] = node.clone();
+                        self.eval_node(&Node::List(node_vec, None))
+                    },
+                    _ => Err(EvalError { location: None, message: "Invalid function call".to_string() })
                 }
-            }
+            },
+            Err(err) => Err(err)
         }
     }
     
-    pub fn eval_lambda(&mut self, lambda: Lambda, nodes: &Vec<Node>) -> Result<Value, EvalError> {
-        let mut env = Environment::new_empty(Some(Box::new(self.env.clone())));
-        let params = lambda.params;
-        let body = lambda.body;
+    pub fn eval_lambda(&mut self, lambda: Lambda, nodes: &[Node]) -> Result<Value, EvalError> {
+        let Lambda { mut params, optionals, keywords, body, rest, name, scope } = lambda;
+        // A procedure is called in a scope inside the one it was made in, rather than the caller's
+        let mut env = match scope {
+            Some(ref scope) => self.with_env(scope.clone()).inner_env()?,
+            None            => self.inner_env()?
+        };
+        // Errors mention the procedure by name if it has one, and otherwise as it was called
+        let caller = name.unwrap_or_else(|| nodes[0].to_string());
 
-        if nodes.len() - 1 != params.len() {
-            return Err(EvalError { message: format!("{} expects {} params, got {}", nodes[0], params.len(), nodes.len() - 1).to_string() })
+        // Keyword arguments, in the order they were passed, are pulled out of the call first
+        let (positional, named) = split_keyword_args(&nodes[1..])?;
+
+        if rest.is_none() && optionals.is_empty() && positional.len() != params.len() {
+            return Err(EvalError { location: None, message: format!("{} expects {}, got {}", caller, arguments(params.len()), positional.len()) })
+        }
+        if rest.is_none() && !optionals.is_empty() && (positional.len() < params.len() || positional.len() > params.len() + optionals.len()) {
+            return Err(EvalError { location: None, message: format!("{} expects {} to {} arguments, got {}", caller, params.len(), params.len() + optionals.len(), positional.len()) })
+        }
+        if rest.is_some() && positional.len() < params.len() {
+            return Err(EvalError { location: None, message: format!("{} expects at least {}, got {}", caller, arguments(params.len()), positional.len()) })
+        }
+        if let Some((name, _)) = named.iter().find(|&(name, _)| !keywords.iter().any(|keyword| keyword.name == *name)) {
+            return Err(EvalError { location: None, message: format!("{} has no keyword #:{}", caller, name) })
         }
 
+        // Each argument is evaluated exactly once, left to right, before the body runs
+        let mut args: Vec<Value> = Vec::new();
         for (i, p) in params.iter().enumerate() {
             match *p {
                 Node::Symbol(ref val) => {
-                    match self.eval_node(&nodes[i + 1]) {
-                        Ok(res) => env.set(val.clone(), res),
-                        Err(err) => return Err(err)
-                    }
+                    let arg = self.eval_node(positional[i])?;
+                    env.set(val.clone(), arg.clone());
+                    args.push(arg);
                 },
-                _ => return Err(EvalError { message: format!("Invalid parameter {}", params[i]).to_string() })
+                _ => return Err(EvalError { location: None, message: format!("Invalid parameter {}", params[i]).to_string() })
+            }
+        }
+        // Optional parameters that weren't passed get their defaults, which are evaluated in the
+        // lambda's scope, so they can use the parameters before them
+        let required = params.len();
+        for (i, (param, default)) in optionals.into_iter().enumerate() {
+            let arg = match positional.get(required + i) {
+                Some(node) => self.eval_node(node)?,
+                None       => self.with_env(env.clone()).eval_node(&default)?
+            };
+            env.set(param.clone(), arg.clone());
+            params.push(Node::Symbol(param));
+            args.push(arg);
+        }
+        if let Some(rest) = rest {
+            let mut vals = Vec::new();
+            for node in positional.iter().skip(params.len()) {
+                vals.push(self.eval_node(node)?);
             }
+            let arg = Value::list(vals);
+            env.set(rest.clone(), arg.clone());
+            params.push(Node::Symbol(rest));
+            args.push(arg);
         }
-        // Make a new interpreter, with the current interpreter as its outer scope
-        let mut interpreter = Interpreter::new_with_env(env.clone());
-        let body = interpreter.inline_lambda_nodes(body, params, &nodes[1..]);
-        
-        interpreter.eval_node_wrapped(&body)
+        // Keywords that weren't passed get their defaults, which are evaluated in the lambda's
+        // scope like the optional parameters' are
+        for keyword in &keywords {
+            let arg = match (named.iter().find(|&(name, _)| *name == keyword.name), keyword.default.as_ref()) {
+                (Some(&(_, node)), _) => self.eval_node(node)?,
+                (None, Some(node))    => self.with_env(env.clone()).eval_node(node)?,
+                (None, None)          => return Err(EvalError { location: None, message: format!("{} requires keyword #:{}", caller, keyword.name) })
+            };
+            env.set(keyword.param.clone(), arg.clone());
+            params.push(Node::Symbol(keyword.param.clone()));
+            args.push(arg);
+        }
+        // A parameter that the body set!s stays a name, looked up in (and changed in) the new
+        // environment, and the rest are replaced by their values
+        let (params, args): (Vec<Node>, Vec<Value>) = params.into_iter().zip(args)
+            .filter(|(param, _)| !matches!(*param, Node::Symbol(ref param) if assigns(&body, param)))
+            .unzip();
+        let body = inline_lambda_nodes(body, &params, &args);
+
+        // The body is handed back to the caller as a tail call, which evaluates it in this
+        // environment rather than its own, so a call there doesn't nest in this one
+        Ok(Value::ScopedNode(body, env))
     }
 
-    fn inline_lambda_nodes(&mut self, node: Node, params: Vec<Node>, values: &[Node]) -> Node {
-        match node.clone() {
-            Node::List(nodes) => {
-                let mut temp_list: Vec<Node> = Vec::new();
-                for node in nodes {
-                    temp_list.push(self.inline_lambda_nodes(node, params.clone(), values));
-                }
-                Node::List(temp_list)
+
+    // Evaluate the forms of a body, like begin's, in a scope of their own.  A define makes a binding
+    // there that the forms after it see, as in letrec*, and procedure definitions are hoisted
+    // the same way as in a script.  The procedures defined there see the scope wherever they're
+    // called from, so they can be mutually recursive, and the other forms get them by value, so a
+    // lambda made in the body can still call them once it's returned.  The last form is handed
+    // back as a tail call in the scope, unless it's a define.
+    pub fn eval_body(&mut self, forms: &[Node]) -> Result<Value, EvalError> {
+        let env = self.inner_env()?;
+        let mut interpreter = self.with_env(env.clone());
+        let with_procs = |form: &Node| -> Node {
+            let (names, procs): (Vec<Node>, Vec<Value>) = shared::lock(&env.env).iter()
+                .filter(|&(label, val)| matches!(*val, Value::Lambda(_)) && !assigns(form, label))
+                .map(|(label, val)| (Node::Symbol(label.clone()), val.clone()))
+                .unzip();
+            inline_lambda_nodes(form.clone(), &names, &procs)
+        };
+        let forms = hoisted(forms);
+        let (last, init) = match forms.split_last() {
+            Some(split) => split,
+            None        => return Ok(Value::Void)
+        };
+        for form in init {
+            if is_definition(form) {
+                interpreter.eval_node(form)?;
+                env.enclose();
+            } else {
+                interpreter.eval_node(&with_procs(form))?;
+            }
+        }
+        if is_definition(last) {
+            let val = interpreter.eval_node(last);
+            env.enclose();
+            return val;
+        }
+        Ok(Value::ScopedNode(with_procs(last), env.clone()))
+    }
+
+    // Apply a function to already-evaluated arguments
+    pub fn apply(&mut self, func: Value, args: Vec<Value>) -> Result<Value, EvalError> {
+        self.eval_node(&call_node(func, args, &[]))
+    }
+}
+
+// The arguments of a call, split into positional ones and #:name value pairs
+pub type KeywordArgs<'a> = (Vec<&'a Node>, Vec<(String, &'a Node)>);
+
+// Split the arguments of a call into positional ones and #:name value pairs, keeping the order of
+// each.  Builtins that take options as keywords can use this too.
+pub fn split_keyword_args(nodes: &[Node]) -> Result<KeywordArgs<'_>, EvalError> {
+    let mut positional = Vec::new();
+    let mut named = Vec::new();
+    let mut rest = nodes.iter();
+    while let Some(node) = rest.next() {
+        match *node {
+            Node::Keyword(ref name) => match rest.next() {
+                Some(val) => named.push((name.clone(), val)),
+                None      => return Err(EvalError { location: None, message: format!("Missing value for keyword #:{}", name) })
             },
-            Node::Symbol(label) => {
-                for (i,p) in params.iter().enumerate() {
-                    match *p {
-                        Node::Symbol(ref param) => {
-                            if label == *param {
-                                if let Ok(val) = self.eval_node(&values[i]) {
-                                    return Node::ValueWrapper(Box::new(val));
-                                }
-                            }
-                        },
-                        _ => ()
+            ref node => positional.push(node)
+        }
+    }
+    Ok((positional, named))
+}
+
+// "1 argument" or "n arguments", for arity errors
+fn arguments(n: usize) -> String {
+    if n == 1 { "1 argument".to_string() } else { format!("{} arguments", n) }
+}
+
+// Replace the parameters of a lambda in its body with the values they were bound to.  A lambda,
+// let or do inside the body that binds one of the names again keeps it as a name where its own
+// binding is the one that's seen.
+fn inline_lambda_nodes(node: Node, params: &[Node], values: &[Value]) -> Node {
+    match node {
+        Node::List(mut nodes, location) => {
+            if let [Node::Symbol(ref head), _] = nodes[..] {
+                if head == "quasiquote" {
+                    let template = nodes.pop().unwrap();
+                    nodes.push(inline_template(template, 1, params, values));
+                    return Node::List(nodes, location);
+                }
+            }
+            // A macro's patterns and templates are rewritten when it's used, not evaluated here
+            if matches!(nodes.first(), Some(Node::Symbol(head)) if head == "define-syntax" || head == "syntax-rules") {
+                return Node::List(nodes, location);
+            }
+            // A case clause's datums (or else) aren't evaluated, so they're left as they are
+            if matches!(nodes.first(), Some(Node::Symbol(head)) if head == "case") {
+                return Node::List(nodes.into_iter().enumerate().map(|(i, node)| match node {
+                    Node::List(clause, location) if i > 1 && !clause.is_empty() => {
+                        let mut clause = clause.into_iter();
+                        let datums = clause.next().unwrap();
+                        Node::List(std::iter::once(datums).chain(clause.map(|node| inline_lambda_nodes(node, params, values))).collect(), location)
+                    },
+                    node => inline_lambda_nodes(node, params, values)
+                }).collect(), location);
+            }
+            if matches!(nodes.first(), Some(Node::Symbol(head)) if BINDING_FORMS.contains(&&**head)) {
+                if let Some(nodes) = inline_binding_form(&nodes, params, values) {
+                    return Node::List(nodes, location);
+                }
+            }
+            Node::List(nodes.into_iter().map(|node| inline_lambda_nodes(node, params, values)).collect(), location)
+        },
+        Node::Symbol(label) => {
+            for (i, p) in params.iter().enumerate() {
+                if let Node::Symbol(ref param) = *p {
+                    if label == *param {
+                        return Node::ValueWrapper(Box::new(values[i].clone()));
                     }
                 }
-                node
-            },
-            _ => node
+            }
+            Node::Symbol(label)
+        },
+        _ => node
+    }
+}
+
+// inline_lambda_nodes for a quasiquote template inside depth quasiquotes, which only replaces
+// the params in the parts that are unquoted back out to depth 0
+fn inline_template(node: Node, depth: usize, params: &[Node], values: &[Value]) -> Node {
+    let inline_all = |nodes: Vec<Node>| -> Vec<Node> {
+        let len = nodes.len();
+        let mut unquoted = false;
+        nodes.into_iter().enumerate().map(|(i, node)| {
+            // (a . ,expr) reads as (a unquote expr)
+            let node = if unquoted { inline_lambda_nodes(node, params, values) } else { inline_template(node, depth, params, values) };
+            unquoted = depth == 1 && i + 2 == len && matches!(node, Node::Symbol(ref name) if name == "unquote");
+            node
+        }).collect()
+    };
+    match node {
+        Node::List(mut nodes, location) => {
+            let depth = match nodes[..] {
+                [Node::Symbol(ref name), _] if name == "quasiquote"                               => Some(depth + 1),
+                [Node::Symbol(ref name), _] if name == "unquote" || name == "unquote-splicing" => Some(depth - 1),
+                _                                                                               => None
+            };
+            match depth {
+                Some(0)     => {
+                    let x = nodes.pop().unwrap();
+                    nodes.push(inline_lambda_nodes(x, params, values));
+                    Node::List(nodes, location)
+                },
+                Some(depth) => {
+                    let x = nodes.pop().unwrap();
+                    nodes.push(inline_template(x, depth, params, values));
+                    Node::List(nodes, location)
+                },
+                None        => Node::List(inline_all(nodes), location)
+            }
+        },
+        Node::Pair(nodes, tail) => Node::Pair(inline_all(nodes), Box::new(inline_template(*tail, depth, params, values))),
+        Node::Vector(nodes)     => Node::Vector(inline_all(nodes)),
+        node                    => node
+    }
+}
+
+// The forms inline_binding_form knows the bindings of
+const BINDING_FORMS: [&str; 8] = ["lambda", "let", "let*", "letrec", "letrec*", "do", "let-list", "guard"];
+
+// inline_lambda_nodes for a lambda, let, let*, letrec, named let, do, let-list or guard, or None
+// if it doesn't bind any of the params
+fn inline_binding_form(nodes: &[Node], params: &[Node], values: &[Value]) -> Option<Vec<Node>> {
+    let form = match nodes.first() {
+        Some(Node::Symbol(head)) if nodes.len() > 1 => &**head,
+        _                                          => return None
+    };
+    let binds = |names: &[String]| params.iter().any(|param| matches!(*param, Node::Symbol(ref param) if names.contains(param)));
+    // params and values without the given names
+    let without = |names: &[String]| -> (Vec<Node>, Vec<Value>) {
+        params.iter().cloned().zip(values.iter().cloned())
+            .filter(|(param, _)| !matches!(*param, Node::Symbol(ref param) if names.contains(param)))
+            .unzip()
+    };
+    let inline_all = |nodes: &[Node], (params, values): &(Vec<Node>, Vec<Value>)| -> Vec<Node> {
+        nodes.iter().map(|node| inline_lambda_nodes(node.clone(), params, values)).collect()
+    };
+    // The names in a list of (name ...) clauses, and the clauses themselves
+    let clauses = |node: &Node| -> Option<(Vec<String>, Vec<Vec<Node>>)> {
+        match *node {
+            Node::List(ref clauses, _) => clauses.iter().map(|clause| match *clause {
+                Node::List(ref clause, _) => match clause.first() {
+                    Some(Node::Symbol(name)) => Some((name.clone(), clause.clone())),
+                    _                        => None
+                },
+                _ => None
+            }).collect::<Option<Vec<_>>>().map(|clauses| clauses.into_iter().unzip()),
+            _ => None
         }
+    };
+    // (name init rest ...), with init inlined with one set of params and the rest with another
+    let clause = |clause: &[Node], init: &(Vec<Node>, Vec<Value>), rest: &(Vec<Node>, Vec<Value>)| -> Node {
+        let mut nodes = vec![clause[0].clone()];
+        nodes.extend(inline_all(&clause[1..clause.len().min(2)], init));
+        nodes.extend(inline_all(&clause[clause.len().min(2)..], rest));
+        Node::List(nodes, None)
+    };
+
+    match form {
+        "lambda" => {
+            let names = formal_names(&nodes[1])?;
+            if !binds(&names) {
+                return None;
+            }
+            let mut inlined = vec![nodes[0].clone()];
+            inlined.extend(inline_all(&nodes[1..], &without(&names)));
+            Some(inlined)
+        },
+        // (let-list (formals expr) body ...), where only the body sees the names
+        "let-list" => {
+            let (names, clause) = match nodes[1] {
+                Node::List(ref clause, _) if clause.len() == 2 => (formal_names(&clause[0])?, clause),
+                _                                              => return None
+            };
+            if !binds(&names) {
+                return None;
+            }
+            let mut inlined = vec![nodes[0].clone(), Node::List(vec![clause[0].clone(), inline_lambda_nodes(clause[1].clone(), params, values)], None)];
+            inlined.extend(inline_all(&nodes[2..], &without(&names)));
+            Some(inlined)
+        },
+        // (guard (var clause ...) body ...), where only the clauses see var
+        "guard" => {
+            let (name, spec) = match nodes[1] {
+                Node::List(ref spec, _) => match spec.first() {
+                    Some(Node::Symbol(name)) => (vec![name.clone()], spec),
+                    _                        => return None
+                },
+                _ => return None
+            };
+            if !binds(&name) {
+                return None;
+            }
+            let mut clauses = vec![spec[0].clone()];
+            clauses.extend(inline_all(&spec[1..], &without(&name)));
+            let mut inlined = vec![nodes[0].clone(), Node::List(clauses, None)];
+            inlined.extend(inline_all(&nodes[2..], &(params.to_vec(), values.to_vec())));
+            Some(inlined)
+        },
+        "let" | "let*" | "letrec" | "letrec*" | "do" => {
+            // A named let's name is bound in its body, but not in its inits
+            let (name, at) = match nodes[1] {
+                Node::Symbol(ref name) if form == "let" => (Some(name.clone()), 2),
+                _                                       => (None, 1)
+            };
+            let (names, bindings) = clauses(nodes.get(at)?)?;
+            let bound: Vec<String> = names.iter().cloned().chain(name).collect();
+            if !binds(&bound) {
+                return None;
+            }
+            let (all, inner) = ((params.to_vec(), values.to_vec()), without(&bound));
+            let bindings = match form {
+                // Each init of a let* sees the names bound before it
+                "let*"               => bindings.iter().enumerate().map(|(i, binding)| clause(binding, &without(&names[..i]), &inner)).collect(),
+                "letrec" | "letrec*" => bindings.iter().map(|binding| clause(binding, &inner, &inner)).collect(),
+                // A do's steps, like its body, see its names
+                _                    => bindings.iter().map(|binding| clause(binding, &all, &inner)).collect()
+            };
+            let mut inlined = nodes[..at].to_vec();
+            inlined.push(Node::List(bindings, None));
+            inlined.extend(inline_all(&nodes[at + 1..], &inner));
+            Some(inlined)
+        },
+        _ => None
+    }
+}
+
+// The names a lambda's formals or a let-list's (a b . rest) bind
+fn formal_names(formals: &Node) -> Option<Vec<String>> {
+    match *formals {
+        Node::Symbol(ref rest)                => Some(vec![rest.clone()]),
+        Node::List(ref names, _)
+            | Node::Pair(ref names, _)        => {
+            let mut names: Vec<String> = names.iter().filter_map(|formal| match *formal {
+                Node::Symbol(ref name)    => Some(name.clone()),
+                Node::List(ref spec, _)   => match spec.first() {
+                    Some(Node::Symbol(name)) => Some(name.clone()),
+                    _                        => None
+                },
+                _                         => None
+            }).collect();
+            if let Node::Pair(_, ref rest) = *formals {
+                if let Node::Symbol(ref rest) = **rest {
+                    names.push(rest.clone());
+                }
+            }
+            Some(names)
+        },
+        _                                     => None
+    }
+}
+
+// Whether there's a (set! param ...) anywhere in node, or an internal define or define-values of
+// param, which binds it to something else too
+fn assigns(node: &Node, param: &str) -> bool {
+    match *node {
+        Node::List(ref nodes, _) => {
+            let assigned = match nodes.get(1) {
+                Some(Node::Symbol(label)) => (is_form(nodes, "set!") || is_form(nodes, "define")) && label == param,
+                Some(formals @ Node::List(..)) | Some(formals @ Node::Pair(..)) if is_form(nodes, "define-values") =>
+                    formal_names(formals).is_some_and(|names| names.iter().any(|name| name == param)),
+                Some(Node::List(ref signature, _)) => is_form(nodes, "define") && is_form(signature, param),
+                _ => false
+            };
+            assigned || nodes.iter().any(|node| assigns(node, param))
+        },
+        _ => false
     }
-}
\ No newline at end of file
+}
+
+fn defines_reader_syntax(form: &Node) -> bool {
+    match *form {
+        Node::List(ref nodes, _) => is_form(nodes, "define-reader-syntax"),
+        _                        => false
+    }
+}
+
+// Whether the given list is a call to the named form, e.g. (begin ...)
+fn is_form(nodes: &[Node], name: &str) -> bool {
+    match nodes.first() {
+        Some(Node::Symbol(head)) => head == name,
+        _                        => false
+    }
+}
+
+pub fn is_definition(node: &Node) -> bool {
+    match *node {
+        Node::List(ref nodes, _) => is_form(nodes, "define"),
+        _                     => false
+    }
+}
+
+// The forms in the order they're evaluated in: each run of consecutive defines has its procedure
+// definitions moved to the front, so a define can use a procedure defined later in the same run
+fn hoisted(forms: &[Node]) -> Vec<&Node> {
+    let mut order = Vec::new();
+    let mut i = 0;
+    while i < forms.len() {
+        let mut end = i;
+        while end < forms.len() && is_definition(&forms[end]) {
+            end += 1;
+        }
+        if end == i {
+            order.push(&forms[i]);
+            i += 1;
+            continue;
+        }
+        let (procedures, others): (Vec<&Node>, Vec<&Node>) = forms[i..end].iter().partition(|node| is_procedure_definition(node));
+        order.extend(procedures);
+        order.extend(others);
+        i = end;
+    }
+    order
+}
+
+// Whether the given define form binds a procedure, either (define (f x) ...) or (define f (lambda ...))
+fn is_procedure_definition(node: &Node) -> bool {
+    match *node {
+        Node::List(ref nodes, _) if is_form(nodes, "define") && nodes.len() > 2 => {
+            match nodes[1] {
+                Node::List(..) => true,
+                _             => match nodes[2] {
+                    Node::List(ref value, _) => is_form(value, "lambda"),
+                    _                     => false
+                }
+            }
+        },
+        _ => false
+    }
+}
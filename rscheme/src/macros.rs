@@ -0,0 +1,375 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+
+use environment::{Environment, quote_node, datum_node, is_reserved};
+use interpreter::EvalError as EvalError;
+use interpreter::Value as Value;
+use parser::Node as Node;
+
+// A macro defined with (define-syntax name (syntax-rules (literal ...) (pattern template) ...)).
+// A use of it is rewritten by the template of the first rule whose pattern matches it, and the
+// result is evaluated in its place.
+pub struct Macro {
+    pub name: String,
+    // Names that only match themselves in a pattern, like else
+    literals: Vec<String>,
+    // Each pattern, with its template and the names the template binds
+    rules: Vec<(Node, Node, HashSet<String>)>
+}
+
+// Expand every use in node of a macro that's already defined, rather than waiting until each one
+// is evaluated.  This is done to the body of a lambda when it's made, so that a set! a macro
+// expands into stops the parameter it assigns from being inlined.  Quoted data is left alone.
+pub fn expand_all(node: &Node, env: &Environment) -> Result<Node, EvalError> {
+    match *node {
+        Node::List(ref nodes, location) => {
+            if let Some(Node::Symbol(ref head)) = nodes.first() {
+                if head == "quote" || head == "quasiquote" {
+                    return Ok(node.clone());
+                }
+                if let Some(Value::Macro(mac)) = env.get(head) {
+                    return expand_all(&mac.expand(nodes, env)?, env);
+                }
+            }
+            Ok(Node::List(nodes.iter().map(|node| expand_all(node, env)).collect::<Result<_, _>>()?, location))
+        },
+        ref node => Ok(node.clone())
+    }
+}
+
+// What a pattern variable matched: one form, or for a variable followed by ..., one binding for
+// every form the repetition matched
+#[derive(Clone)]
+enum Binding {
+    One(Node),
+    Many(Vec<Binding>)
+}
+
+type Bindings = HashMap<String, Binding>;
+
+// Counts expansions, so every one can rename the names its template introduces differently
+thread_local! {
+    static EXPANSIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+// Counts the symbols gensym has made
+thread_local! {
+    static GENSYMS: Cell<usize> = const { Cell::new(0) };
+}
+
+// A fresh name for gensym, written #[prefixN].  The reader splits #[ into # and a bracket, so no
+// symbol that's read can have the same name, and it prints as what it is.
+pub fn gensym(prefix: &str) -> String {
+    let n = GENSYMS.with(|count| {
+        count.set(count.get() + 1);
+        count.get()
+    });
+    format!("#[{}{}]", prefix, n)
+}
+
+// Both counts, which a generator's producer takes to the thread it runs on and brings back
+pub fn counters() -> (usize, usize) {
+    (EXPANSIONS.with(|count| count.get()), GENSYMS.with(|count| count.get()))
+}
+
+pub fn set_counters((expansions, gensyms): (usize, usize)) {
+    EXPANSIONS.with(|count| count.set(expansions));
+    GENSYMS.with(|count| count.set(gensyms));
+}
+
+fn is_ellipsis(node: Option<&Node>) -> bool {
+    matches!(node, Some(Node::Symbol(ref name)) if name == "...")
+}
+
+impl Macro {
+    // The macro described by a (syntax-rules ...) form
+    pub fn new(name: &str, spec: &Node) -> Result<Macro, EvalError> {
+        let invalid = || EvalError { location: None, message: format!("'define-syntax' requires (syntax-rules (literal ...) (pattern template) ...) for {}", name) };
+        let spec = match *spec {
+            Node::List(ref spec, _) if spec.len() >= 2 && matches!(spec[0], Node::Symbol(ref head) if head == "syntax-rules") => spec,
+            _ => return Err(invalid())
+        };
+        let literals = match spec[1] {
+            Node::List(ref literals, _) => literals.iter().map(|literal| match *literal {
+                Node::Symbol(ref literal) => Some(literal.clone()),
+                _                         => None
+            }).collect::<Option<Vec<String>>>().ok_or_else(invalid)?,
+            _ => return Err(invalid())
+        };
+        let rules = spec[2..].iter().map(|rule| match *rule {
+            Node::List(ref rule, _) if rule.len() == 2 && matches!(rule[0], Node::List(..) | Node::Pair(..)) => {
+                let mut binds = HashSet::new();
+                binders(&rule[1], &mut binds);
+                Some((rule[0].clone(), rule[1].clone(), binds))
+            },
+            _ => None
+        }).collect::<Option<Vec<_>>>().ok_or_else(invalid)?;
+        Ok(Macro { name: name.to_string(), literals, rules })
+    }
+
+    // Rewrite a use of the macro (its whole form, including the name).  A name the template
+    // binds, or uses without it being defined globally, is renamed, so that it can't capture a
+    // variable of the same name in the forms that were passed to the macro.
+    pub fn expand(&self, form: &[Node], env: &Environment) -> Result<Node, EvalError> {
+        for (pattern, template, binds) in &self.rules {
+            let (patterns, tail) = match *pattern {
+                Node::List(ref patterns, _)        => (&patterns[1..], None),
+                Node::Pair(ref patterns, ref tail) => (&patterns[1..], Some(&**tail)),
+                _                                  => continue
+            };
+            let mut bindings = HashMap::new();
+            if self.match_list(patterns, tail, &form[1..], &mut bindings) {
+                let expansion = EXPANSIONS.with(|count| {
+                    count.set(count.get() + 1);
+                    count.get()
+                });
+                return Expander { bindings: &bindings, literals: &self.literals, binds, env, expansion }.expand(template, false);
+            }
+        }
+        let form: Vec<String> = form.iter().map(|node| node.to_string()).collect();
+        Err(EvalError { location: None, message: format!("No syntax-rules pattern of '{}' matches ({})", self.name, form.join(" ")) })
+    }
+
+    fn match_node(&self, pattern: &Node, form: &Node, bindings: &mut Bindings) -> bool {
+        match *pattern {
+            Node::Symbol(ref name) if name == "_" => true,
+            Node::Symbol(ref name) if self.literals.contains(name) => matches!(*form, Node::Symbol(ref form) if form == name),
+            Node::Symbol(ref name) => {
+                bindings.insert(name.clone(), Binding::One(form.clone()));
+                true
+            },
+            Node::List(ref patterns, _) => match *form {
+                Node::List(ref forms, _) => self.match_list(patterns, None, forms, bindings),
+                _                        => false
+            },
+            Node::Pair(ref patterns, ref tail) => match *form {
+                Node::List(ref forms, _) => self.match_list(patterns, Some(tail), forms, bindings),
+                _                        => false
+            },
+            Node::Vector(ref patterns) => match *form {
+                Node::Vector(ref forms) => self.match_list(patterns, None, forms, bindings),
+                _                       => false
+            },
+            // Anything else is a constant, which has to be the same as the form
+            ref pattern => same_constant(pattern, form)
+        }
+    }
+
+    // Match the elements of a list pattern, one of which can be followed by ..., and the tail of
+    // a dotted one, which matches the list of whatever elements are left
+    fn match_list(&self, patterns: &[Node], tail: Option<&Node>, forms: &[Node], bindings: &mut Bindings) -> bool {
+        let ellipsis = (0..patterns.len()).find(|&i| is_ellipsis(patterns.get(i + 1)));
+        let (before, repeated, after) = match ellipsis {
+            Some(i) => (&patterns[..i], Some(&patterns[i]), &patterns[i + 2..]),
+            None    => (patterns, None, &patterns[patterns.len()..])
+        };
+        let fixed = before.len() + after.len();
+        let enough = match (repeated, tail) {
+            (None, None) => forms.len() == fixed,
+            _            => forms.len() >= fixed
+        };
+        if !enough {
+            return false;
+        }
+
+        if !before.iter().zip(forms).all(|(pattern, form)| self.match_node(pattern, form, bindings)) {
+            return false;
+        }
+        let rest = &forms[before.len()..];
+        let (middle, rest) = match repeated {
+            // The repetition takes everything but what the patterns after it need
+            Some(_) => rest.split_at(rest.len() - after.len()),
+            None    => rest.split_at(0)
+        };
+        if let Some(repeated) = repeated {
+            let mut matches = Vec::new();
+            for form in middle {
+                let mut inner = HashMap::new();
+                if !self.match_node(repeated, form, &mut inner) {
+                    return false;
+                }
+                matches.push(inner);
+            }
+            for name in self.pattern_vars(repeated) {
+                let each = matches.iter_mut().map(|inner| inner.remove(&name).unwrap()).collect();
+                bindings.insert(name, Binding::Many(each));
+            }
+        }
+        let (rest, left) = rest.split_at(after.len().min(rest.len()));
+        if !after.iter().zip(rest).all(|(pattern, form)| self.match_node(pattern, form, bindings)) {
+            return false;
+        }
+        match tail {
+            Some(tail) => self.match_node(tail, &Node::List(left.to_vec(), None), bindings),
+            None       => left.is_empty()
+        }
+    }
+
+    // The pattern variables in a pattern
+    fn pattern_vars(&self, pattern: &Node) -> Vec<String> {
+        match *pattern {
+            Node::Symbol(ref name) if name == "_" || name == "..." || self.literals.contains(name) => Vec::new(),
+            Node::Symbol(ref name) => vec![name.clone()],
+            Node::List(ref patterns, _) | Node::Vector(ref patterns) => patterns.iter().flat_map(|pattern| self.pattern_vars(pattern)).collect(),
+            Node::Pair(ref patterns, ref tail) => patterns.iter().chain(Some(&**tail)).flat_map(|pattern| self.pattern_vars(pattern)).collect(),
+            _ => Vec::new()
+        }
+    }
+}
+
+fn same_constant(pattern: &Node, form: &Node) -> bool {
+    match (pattern, form) {
+        (Node::Int(x), Node::Int(y))         => x == y,
+        (Node::Float(x), Node::Float(y))     => x == y,
+        (Node::String(x), Node::String(y))   => x == y,
+        (Node::Bool(x), Node::Bool(y))       => x == y,
+        (Node::Char(x), Node::Char(y))       => x == y,
+        (Node::Keyword(x), Node::Keyword(y)) => x == y,
+        _                                    => false
+    }
+}
+
+// Fills in a template with what the pattern variables matched
+struct Expander<'a> {
+    bindings: &'a Bindings,
+    literals: &'a [String],
+    binds: &'a HashSet<String>,
+    env: &'a Environment,
+    // Which expansion this is, which the names the template introduces are renamed after
+    expansion: usize
+}
+
+impl<'a> Expander<'a> {
+    // Names in quoted data are left alone, since they aren't variables
+    fn expand(&self, template: &Node, quoted: bool) -> Result<Node, EvalError> {
+        match *template {
+            Node::Symbol(ref name) => match self.bindings.get(name) {
+                Some(Binding::One(node)) => Ok(node.clone()),
+                Some(Binding::Many(_))   => Err(EvalError { location: None, message: format!("Pattern variable {} is used without ...", name) }),
+                None                     => Ok(Node::Symbol(self.rename(name, quoted)))
+            },
+            Node::List(ref templates, location) => Ok(Node::List(self.expand_list(templates, quoted)?, location)),
+            Node::Vector(ref templates) => Ok(Node::Vector(self.expand_list(templates, quoted)?)),
+            Node::Pair(ref templates, ref tail) => {
+                let mut nodes = self.expand_list(templates, quoted)?;
+                match self.expand(tail, quoted)? {
+                    Node::List(rest, location) => {
+                        nodes.extend(rest);
+                        Ok(Node::List(nodes, location))
+                    },
+                    tail => Ok(Node::Pair(nodes, Box::new(tail)))
+                }
+            },
+            // The parser has already turned 'datum into a value, which might have pattern
+            // variables in it
+            Node::Quoted(ref val) => Ok(Node::Quoted(Box::new(quote_node(&self.expand(&datum_node(val), true)?)))),
+            ref node => Ok(node.clone())
+        }
+    }
+
+    // Expand the elements of a list template, repeating any element followed by ... once for
+    // every form its pattern variables matched
+    fn expand_list(&self, templates: &[Node], quoted: bool) -> Result<Vec<Node>, EvalError> {
+        let mut nodes = Vec::new();
+        let mut i = 0;
+        while i < templates.len() {
+            let template = &templates[i];
+            if !is_ellipsis(templates.get(i + 1)) {
+                nodes.push(self.expand(template, quoted)?);
+                i += 1;
+                continue;
+            }
+            let names: Vec<String> = template_names(template).into_iter()
+                .filter(|name| matches!(self.bindings.get(name), Some(Binding::Many(_))))
+                .collect();
+            let counts: Vec<usize> = names.iter().map(|name| match self.bindings[name] {
+                Binding::Many(ref each) => each.len(),
+                Binding::One(_)         => 0
+            }).collect();
+            if counts.is_empty() {
+                return Err(EvalError { location: None, message: format!("... follows {}, which has no repeated pattern variables", template) });
+            }
+            if counts.iter().any(|&count| count != counts[0]) {
+                return Err(EvalError { location: None, message: format!("The pattern variables in {} matched different numbers of forms", template) });
+            }
+            for k in 0..counts[0] {
+                let mut bindings = self.bindings.clone();
+                for name in &names {
+                    if let Binding::Many(ref each) = self.bindings[name] {
+                        bindings.insert(name.clone(), each[k].clone());
+                    }
+                }
+                let inner = Expander { bindings: &bindings, ..*self };
+                nodes.push(inner.expand(template, quoted)?);
+            }
+            i += 2;
+        }
+        Ok(nodes)
+    }
+
+    // The same name is renamed the same way throughout one expansion
+    fn rename(&self, name: &str, quoted: bool) -> String {
+        let keep = quoted || name == "..." || is_reserved(name) || self.literals.iter().any(|literal| literal == name)
+            || (!self.binds.contains(name) && self.env.outermost().get(&name.to_string()).is_some());
+        if keep {
+            name.to_string()
+        } else {
+            format!("{}#{}", name, self.expansion)
+        }
+    }
+}
+
+// Every name in a template
+fn template_names(template: &Node) -> Vec<String> {
+    match *template {
+        Node::Symbol(ref name) => vec![name.clone()],
+        Node::List(ref templates, _) | Node::Vector(ref templates) => templates.iter().flat_map(template_names).collect(),
+        Node::Pair(ref templates, ref tail) => templates.iter().chain(Some(&**tail)).flat_map(template_names).collect(),
+        Node::Quoted(ref val) => template_names(&datum_node(val)),
+        _ => Vec::new()
+    }
+}
+
+// Collect the names that the binding forms in a template bind: the variables of let, let*,
+// letrec and do, the name of a named let, the parameters of a lambda, and what define,
+// define-values and let-list define
+fn binders(template: &Node, binds: &mut HashSet<String>) {
+    let nodes = match *template {
+        Node::List(ref nodes, _) | Node::Vector(ref nodes) | Node::Pair(ref nodes, _) => nodes,
+        _ => return
+    };
+    let mut formals = |node: &Node| for name in template_names(node) {
+        if name != "..." {
+            binds.insert(name);
+        }
+    };
+    match (nodes.first(), nodes.get(1)) {
+        (Some(Node::Symbol(ref head)), Some(clauses)) if head == "let" || head == "let*" || head == "letrec" || head == "do" => {
+            let clauses = match (clauses, nodes.get(2)) {
+                (Node::Symbol(_), Some(clauses)) => {
+                    formals(&nodes[1]);
+                    clauses
+                },
+                _ => clauses
+            };
+            if let Node::List(ref clauses, _) = *clauses {
+                for clause in clauses {
+                    if let Node::List(ref clause, _) = *clause {
+                        if let Some(name) = clause.first() {
+                            formals(name);
+                        }
+                    }
+                }
+            }
+        },
+        (Some(Node::Symbol(ref head)), Some(names)) if head == "lambda" || head == "define" || head == "define-values" => formals(names),
+        (Some(Node::Symbol(ref head)), Some(Node::List(ref clause, _))) if head == "let-list" && !clause.is_empty() => formals(&clause[0]),
+        _ => ()
+    }
+    for node in nodes {
+        binders(node, binds);
+    }
+    if let Node::Pair(_, ref tail) = *template {
+        binders(tail, binds);
+    }
+}
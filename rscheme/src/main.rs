@@ -1,61 +1,220 @@
 use std::fs::File;
 use std::path::Path;
 use std::io::prelude::*;
-use std::io;
+use std::io::{self, IsTerminal};
 use std::env;
+use std::process;
 
+mod cache;
 mod parser;
 mod environment;
+mod generator;
+#[macro_use]
 mod interpreter;
+mod macros;
+mod port;
+mod printer;
+mod random;
+mod shared;
 mod tests;
 
 use interpreter::Interpreter as Interpreter;
+use interpreter::EngineConfig;
+
+const USAGE: &str = "Usage: rscheme [--max-steps <n>] [--precision <n>] [--max-env-depth <n>] [--strict-bool] [--no-prelude] [--sandbox] [--explicit-imports] [--library-path <dir>] [--no-cache] [script]";
 
 fn main() {
-    match env::args().nth(1) {
-        Some(val) => run_script(val),
-        None => repl()
+    let (config, script) = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            process::exit(1);
+        }
+    };
+    match script {
+        Some(val) => run_script(val, config),
+        None => repl(config)
     }    
 }
 
-fn run_script(file_name: String) {
+// The options, which come before the script if there is one
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<(EngineConfig, Option<String>), String> {
+    let mut config = EngineConfig { cache_dir: default_cache_dir(), ..EngineConfig::default() };
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--max-steps" => config.max_steps = Some(number_arg(&arg, args.next())?),
+            "--precision" => config.float_precision = Some(number_arg(&arg, args.next())?),
+            "--max-env-depth" => config.max_env_depth = Some(number_arg(&arg, args.next())?),
+            "--strict-bool" => config.strict_booleans = true,
+            "--no-prelude" => config.load_prelude = false,
+            "--sandbox"    => config.sandbox = true,
+            "--explicit-imports" => config.import_all = false,
+            "--library-path" => match args.next() {
+                Some(dir) => config.library_path.push(dir),
+                None      => return Err(format!("{} requires a directory", arg))
+            },
+            "--no-cache"   => config.cache_dir = None,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _              => return Ok((config, Some(arg)))
+        }
+    }
+    Ok((config, None))
+}
+
+// Where library files' forms are cached: rscheme in the user's cache directory
+fn default_cache_dir() -> Option<String> {
+    let dir = match env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => Path::new(&dir).to_path_buf(),
+        _                          => Path::new(&env::var("HOME").ok()?).join(".cache")
+    };
+    Some(dir.join("rscheme").to_string_lossy().to_string())
+}
+
+fn number_arg(option: &str, val: Option<String>) -> Result<usize, String> {
+    match val.as_ref().and_then(|val| val.parse::<usize>().ok()) {
+        Some(n) => Ok(n),
+        None    => Err(format!("{} requires a number", option))
+    }
+}
+
+fn run_script(file_name: String, config: EngineConfig) {
     let path = Path::new(&file_name);
     let mut source = String::new();
-    let mut file = File::open(&path).unwrap();
+    let mut file = File::open(path).unwrap();
     file.read_to_string(&mut source).unwrap();
     
-     match parser::parse(parser::tokenize(source)) {
-        Ok(val) => { 
-            match Interpreter::new().eval(val) {
-                Ok(val) => { println!("{}", val); },
-                Err(err) => { println!("{}", err); }
+     match Interpreter::new(config).eval_source(&source) {
+        Ok(val) => { println!("{}", val); },
+        Err(interpreter::ScriptError::Eval(err)) => { println!("{}", err); },
+        Err(interpreter::ScriptError::Parse(errors)) => {
+            for err in errors {
+                println!("{}", err.describe(&source));
             }
-        },
-        Err(err) => { println!("{}", err); }
+        }
      }
 }
 
-fn repl() {
+// What a terminal in bracketed paste mode sends before and after pasted text
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+fn repl(config: EngineConfig) {
     let stdin = io::stdin();
-    let mut interpreter = Interpreter::new();
+    let mut interpreter = Interpreter::new(config);
+    interpreter.env.start_journal();
+    // Where the journal was before each input that changed the environment, for :undo
+    let mut history: Vec<usize> = Vec::new();
     
+    // What's been entered of the current input, which can span several lines until its
+    // parentheses balance
+    let mut input = String::new();
+
+    // With bracketed paste, a block of several forms pasted at once is read in full before any
+    // of it is evaluated, rather than line by line
+    let terminal = stdin.is_terminal();
+    if terminal {
+        print!("\x1b[?2004h");
+    }
+    let mut pasting = false;
+
     loop {
-        print!("rscheme> ");
-        io::stdout().flush().ok().expect("Could not flush stdout");
+        if !pasting {
+            print!("{}", if input.is_empty() { "rscheme> " } else { "    ...> " });
+        }
+        io::stdout().flush().expect("Could not flush stdout");
         let mut line = String::new();
-        let _res = stdin.read_line(&mut line);
-        match parser::parse(parser::tokenize(line)) {
-            Ok(node) => {
-                match interpreter.eval(node) {
-                    Ok(val)  => match val {
-                        interpreter::Value::Void => (),
-                        _           => println!("{}", val)
-                    },
-                    Err(err) => println!("{}", err)
-                }
-             },
-             Err(err) => println!("{}", err)
+        match stdin.read_line(&mut line) {
+            Ok(0) | Err(_) => { println!(); break; },
+            Ok(_)          => ()
+        }
+        if line.contains(PASTE_START) {
+            pasting = true;
+            line = line.replace(PASTE_START, "");
+        }
+        if line.contains(PASTE_END) {
+            pasting = false;
+            line = line.replace(PASTE_END, "");
+        }
+        if pasting {
+            input.push_str(&line);
+            continue;
+        }
+        if input.is_empty() && line.trim() == ":undo" {
+            match history.pop() {
+                Some(mark) => interpreter.env.rollback(mark),
+                None       => println!("Nothing to undo")
+            }
+            continue;
+        }
+        if input.is_empty() && line.trim() == ":stats" {
+            println!("Deepest environment chain: {}", interpreter::deepest_env());
+            continue;
+        }
+        // :load-string "(define x 1) (+ x 1)" evaluates the forms in the string, as if they'd
+        // been entered
+        if input.is_empty() && line.trim_start().starts_with(":load-string") {
+            let source = match parser::parse_source(line.trim_start()[":load-string".len()..].to_string()) {
+                Ok(ref nodes) if nodes.len() == 1 => match nodes[0] {
+                    parser::Node::String(ref source) => Some(source.clone()),
+                    _                                => None
+                },
+                _ => None
+            };
+            match source {
+                Some(source) => match interpreter.read(&source, parser::Location { line: 1, col: 1 }) {
+                    Ok(nodes)   => eval_input(&mut interpreter, nodes, &mut history),
+                    Err(errors) => for err in errors {
+                        println!("{}", err.describe(&source));
+                    }
+                },
+                None => println!("Usage: :load-string \"<forms>\"")
+            }
+            continue;
         }
+        input.push_str(&line);
+        let nodes = match interpreter.read_incremental(&input) {
+            parser::Incremental::Complete(nodes) => nodes,
+            parser::Incremental::NeedMoreInput   => {
+                // A line with nothing but whitespace or comments on it doesn't start an input
+                if let Ok(ref tokens) = parser::tokenize(input.clone()) {
+                    if tokens.is_empty() {
+                        input.clear();
+                    }
+                }
+                continue;
+            },
+            parser::Incremental::Errors(errors)  => {
+                for err in errors {
+                    println!("{}", err.describe(&input));
+                }
+                input.clear();
+                continue;
+            }
+        };
+        input.clear();
+        eval_input(&mut interpreter, nodes, &mut history);
+    }
+    if terminal {
+        print!("\x1b[?2004l");
+        io::stdout().flush().expect("Could not flush stdout");
     }
 }
 
+// Evaluate the forms of one input in order, printing each result as soon as it's ready and
+// stopping at the first error
+fn eval_input(interpreter: &mut Interpreter, nodes: Vec<parser::Node>, history: &mut Vec<usize>) {
+    let mark = interpreter.env.mark();
+    for node in nodes {
+        match interpreter.eval(node) {
+            Ok(val)  => match val {
+                interpreter::Value::Void => (),
+                _           => println!("{}", printer::write(&val, interpreter.print_limits()))
+            },
+            Err(err) => { println!("{}", err); break; }
+        }
+        io::stdout().flush().expect("Could not flush stdout");
+    }
+    if interpreter.env.mark() > mark {
+        history.push(mark);
+    }
+}
@@ -1,20 +1,37 @@
 extern crate regex;
 
+use std::collections::HashMap;
 use std::fmt;
 use std::slice;
+use std::str;
+use std::convert::TryFrom;
 use self::regex::Regex as Regex;
 
 use interpreter::Value as Value;
+use environment::quote_node;
 
 #[derive(Clone)]
 pub enum Node {
     Symbol(String),
-    List(Vec<Node>),
+    // Lists read from the source know where they started, so errors in them can say where they are
+    List(Vec<Node>, Option<Location>),
+    // A #(...) vector literal, whose elements aren't evaluated
+    Vector(Vec<Node>),
+    // A dotted list (a b . c): the elements before the dot and the datum after it
+    Pair(Vec<Node>, Box<Node>),
     Int(i32),
     Float(f64),
     Complex(f64, f64),
+    // An exact fraction, always in lowest terms with a positive denominator greater than 1
+    Rational(i32, i32),
     Bool(bool),
+    Char(char),
     String(String),
+    // #:name, which names an argument in a call or a parameter in a lambda list
+    Keyword(String),
+    // A quoted datum, converted to its value once when it's read so evaluating it again (in a
+    // loop, say) doesn't rebuild it
+    Quoted(Box<Value>),
     // ValueWrapper is for occasions when a value needs to be treated as a Node
     ValueWrapper(Box<Value>)
 }
@@ -24,20 +41,38 @@ impl fmt::Display for Node {
         match self.clone() {
             Node::Symbol(val)       => write!(f, "{}", val),
             Node::Int(val)          => write!(f, "{}", val),
-            Node::Float(val)        => write!(f, "{}", val),
+            Node::Float(val)        => write!(f, "{:?}", val),
             Node::Complex(real, im) => write!(f, "{}+{}i", real, im),
+            Node::Rational(num, den) => write!(f, "{}/{}", num, den),
             Node::Bool(true)        => write!(f, "#t"),
             Node::Bool(false)       => write!(f, "#f"),
-            Node::String(ref val)   => write!(f, "\"{}\"", val.replace("\"","\\\"")),
-            Node::List(vals)        => { 
-                let mut output = String::new();
-                let mut sep = String::new();
-                for val in vals {
-                    output = format!("{}{}{}",output,sep,val.clone());
-                    sep = " ".to_string();
+            Node::Char(c)           => write!(f, "#\\{}", char_name(c)),
+            Node::String(ref val)   => write!(f, "\"{}\"", escape_string(val)),
+            Node::Keyword(ref name) => write!(f, "#:{}", name),
+            Node::List(vals, _)     => {
+                write!(f, "(")?;
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 { write!(f, " ")?; }
+                    write!(f, "{}", val)?;
                 }
-                write!(f, "({})", output)
+                write!(f, ")")
             },
+            Node::Vector(vals)      => {
+                write!(f, "#(")?;
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 { write!(f, " ")?; }
+                    write!(f, "{}", val)?;
+                }
+                write!(f, ")")
+            },
+            Node::Pair(vals, tail)  => {
+                write!(f, "(")?;
+                for val in vals.iter() {
+                    write!(f, "{} ", val)?;
+                }
+                write!(f, ". {})", tail)
+            },
+            Node::Quoted(ref val)   => write!(f, "'{}", val),
             Node::ValueWrapper(ref val) => write!(f, "{}", val)
         }
     }
@@ -45,116 +80,676 @@ impl fmt::Display for Node {
 
 pub enum Token {
     OpenParen,
+    // #(, which starts a vector literal
+    OpenVector,
     CloseParen,
+    // [ and ], which work like parentheses but have to match each other
+    OpenBracket,
+    CloseBracket,
     String(String),
+    // ', which quotes the datum after it
+    Quote,
+    // `, , and ,@, which read as (quasiquote datum), (unquote datum) and (unquote-splicing datum)
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    // #;, which comments out the datum after it
+    DatumComment,
+    // #n=, which labels the datum after it, and #n#, which stands for the datum labelled n
+    Label(u32),
+    LabelRef(u32),
+    // A datum read by a handler from define-reader-syntax
+    Datum(Node),
     NonParen(String)
 }
 
+// A position in the source, counting lines and columns from 1
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Location {
+    pub line: usize,
+    pub col: usize
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, col {}", self.line, self.col)
+    }
+}
+
 pub struct ParseError {
     pub message: String,
+    pub location: Option<Location>,
+    // Whether the error is only that the input ended too soon, so more of it could fix it
+    pub incomplete: bool
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ParseError: {}", self.message)
-    }
-}
-
-pub fn tokenize(program: String) -> Vec<Token> {
-    let mut tokens: Vec<Token> = Vec::new();
-
-    let program_spread = program.replace("(", " ( ").replace(")", " ) ").replace("\"", " \" ").replace("\\ \" ","\\\"");
-    let split_whitespace = program_spread.split_whitespace();
-    
-    let mut in_quote = false;
-    let mut quote_level = 0;
-    let mut in_string = false;
-    let mut string: Vec<String> = Vec::new();
-    for token in split_whitespace {
-        if !in_string {
-            if token == "(" {
-                tokens.push(Token::OpenParen);
-                if in_quote { quote_level += 1; }
-            } else if token == ")" {
-                tokens.push(Token::CloseParen); 
-                if in_quote { 
-                    quote_level -= 1; 
-                    if quote_level == 0 {
-                        tokens.push(Token::CloseParen);
-                    }
+        match self.location {
+            Some(location) => write!(f, "ParseError at {}: {}", location, self.message),
+            None           => write!(f, "ParseError: {}", self.message)
+        }
+    }
+}
+
+impl ParseError {
+    // The error followed by the line of source it's in, with a caret under where it is
+    pub fn describe(&self, source: &str) -> String {
+        let location = match self.location {
+            Some(location) => location,
+            None           => return self.to_string()
+        };
+        let line = match source.lines().nth(location.line - 1) {
+            Some(line) => line,
+            None       => return self.to_string()
+        };
+        // Tabs are kept in the indent so the caret lines up however wide they're shown
+        let indent: String = line.chars().take(location.col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        format!("{}\n  {}\n  {}^", self, line, indent)
+    }
+}
+
+// The characters of the program, keeping track of the location of the next one
+struct Chars<'a> {
+    text: &'a str,
+    location: Location
+}
+
+impl<'a> Chars<'a> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.text = &self.text[c.len_utf8()..];
+        if c == '\n' {
+            self.location = Location { line: self.location.line + 1, col: 1 };
+        } else {
+            self.location.col += 1;
+        }
+        Some(c)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text.chars().next()
+    }
+
+    fn next_if<F: FnOnce(char) -> bool>(&mut self, f: F) -> Option<char> {
+        match self.peek() {
+            Some(c) if f(c) => self.next(),
+            _               => None
+        }
+    }
+}
+
+// Reads the datum for a character registered with define-reader-syntax, given the source after
+// the character.  It returns the datum and how many bytes of the source that took, or None if
+// the character isn't one that's registered.
+pub type Dispatch<'a> = &'a mut dyn FnMut(char, &str) -> Result<Option<(Node, usize)>, String>;
+
+fn no_dispatch(_: char, _: &str) -> Result<Option<(Node, usize)>, String> {
+    Ok(None)
+}
+
+// Split the program into tokens, one character at a time.  Atoms run until whitespace or a
+// delimiter, so none of ( ) ' " ; need spaces around them, and ; comments, nestable #| ... |#
+// comments and #; datum comments are recognized anywhere outside of a string.
+pub fn tokenize(program: String) -> Result<Vec<(Token, Location)>, ParseError> {
+    tokenize_with(&program, Location { line: 1, col: 1 }, &mut no_dispatch)
+}
+
+// Tokenize source that starts at location, handing the characters registered as reader syntax
+// to dispatch
+pub fn tokenize_with(program: &str, location: Location, dispatch: Dispatch) -> Result<Vec<(Token, Location)>, ParseError> {
+    let mut tokens: Vec<(Token, Location)> = Vec::new();
+    let mut chars = Chars { text: program, location };
+
+    // A #! line at the very start (as in #!/usr/bin/env rscheme) is skipped, so scripts can be
+    // made executable
+    if program.starts_with("#!") {
+        while chars.next_if(|c| c != '\n').is_some() {}
+    }
+
+    while let Some(token) = next_token(&mut chars, dispatch)? {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// The next token, skipping whitespace and comments, or None at the end of the program
+fn next_token(chars: &mut Chars, dispatch: Dispatch) -> Result<Option<(Token, Location)>, ParseError> {
+    loop {
+        let location = chars.location;
+        let c = match chars.next() {
+            Some(c) => c,
+            None    => return Ok(None)
+        };
+        let token = match c {
+            '('  => Token::OpenParen,
+            ')'  => Token::CloseParen,
+            '['  => Token::OpenBracket,
+            ']'  => Token::CloseBracket,
+            '\'' => Token::Quote,
+            '`'  => Token::Quasiquote,
+            ',' if chars.peek() == Some('@') => {
+                chars.next();
+                Token::UnquoteSplicing
+            },
+            ','  => Token::Unquote,
+            '"'  => Token::String(lex_string(chars, location)?),
+            ';'  => {
+                while chars.next_if(|c| c != '\n').is_some() {}
+                continue;
+            },
+            '#' if chars.peek() == Some('|') => {
+                chars.next();
+                skip_block_comment(chars, location)?;
+                continue;
+            },
+            // The character after #\ is part of the literal even if it's a delimiter, as in #\(
+            '#' if chars.peek() == Some('\\') => {
+                chars.next();
+                let mut atom = "#\\".to_string();
+                if let Some(c) = chars.next() {
+                    atom.push(c);
                 }
-            } else if token == "'" {
-                in_quote = true;
-                tokens.push(Token::OpenParen);
-                tokens.push(Token::NonParen("quote".to_string()));
-            } else if token == "\"" {
-                in_string = true;
-                string = Vec::new();
-            } else {
-                tokens.push(Token::NonParen(token.to_string()));
+                while let Some(c) = chars.next_if(|c| !is_delimiter(c)) {
+                    atom.push(c);
+                }
+                Token::NonParen(atom)
+            },
+            '#' if chars.peek() == Some('(') => {
+                chars.next();
+                Token::OpenVector
+            },
+            '#' if chars.peek() == Some(';') => {
+                chars.next();
+                Token::DatumComment
+            },
+            '#' if chars.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                let mut digits = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                    digits.push(c);
+                }
+                let label = digits.parse::<u32>()
+                    .map_err(|_| ParseError { message: format!("Datum label #{} is too large", digits), location: Some(location), incomplete: false })?;
+                match chars.next() {
+                    Some('=') => Token::Label(label),
+                    Some('#') => Token::LabelRef(label),
+                    _         => return Err(ParseError { message: format!("Expected '=' or '#' after #{}", digits), location: Some(location), incomplete: false })
+                }
+            },
+            c if c.is_whitespace() => continue,
+            c    => {
+                // Reader syntax takes over from the character that starts an atom
+                match dispatch(c, chars.text) {
+                    Ok(Some((node, used))) => {
+                        let end = chars.text.len().saturating_sub(used);
+                        while chars.text.len() > end {
+                            chars.next();
+                        }
+                        return Ok(Some((Token::Datum(node), location)));
+                    },
+                    Ok(None)        => (),
+                    Err(message)    => return Err(ParseError { message, location: Some(location), incomplete: false })
+                }
+                let mut atom = c.to_string();
+                while let Some(c) = chars.next_if(|c| !is_delimiter(c)) {
+                    atom.push(c);
+                }
+                Token::NonParen(atom)
             }
-        } else {
-            if token == "\"" {
-                tokens.push(Token::String(string.join(" ")));
-                string = Vec::new();
-                in_string = false;
-            } else {
-                string.push(token.replace("\\\"","\""));
+        };
+        return Ok(Some((token, location)));
+    }
+}
+
+// The inverse of the escapes lex_string decodes, for writing strings back out
+pub fn escape_string(string: &str) -> String {
+    let mut escaped = String::new();
+    for c in string.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"'  => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c    => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+// The name a character literal is written with after #\, e.g. space for #\space
+pub fn char_name(c: char) -> String {
+    match c {
+        ' '    => "space".to_string(),
+        '\n'   => "newline".to_string(),
+        '\t'   => "tab".to_string(),
+        '\0'   => "nul".to_string(),
+        c if c.is_control() => format!("x{:x}", c as u32),
+        c      => c.to_string()
+    }
+}
+
+// The character for a name written after #\: a single character, a name like newline, or a
+// hex code like x41
+fn parse_char(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => return Some(c),
+        (None, _)       => return None,
+        _               => ()
+    }
+    match name {
+        "space"   => Some(' '),
+        "newline" => Some('\n'),
+        "tab"     => Some('\t'),
+        "nul"     => Some('\0'),
+        _ if name.starts_with('x') => u32::from_str_radix(&name[1..], 16).ok().and_then(char::from_u32),
+        _         => None
+    }
+}
+
+pub fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()[]'`,\";".contains(c)
+}
+
+// Read the rest of a string whose opening quote has been consumed, decoding escapes
+fn lex_string(chars: &mut Chars, start: Location) -> Result<String, ParseError> {
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            Some('"')  => return Ok(string),
+            Some('\\') => match chars.next() {
+                Some('n')  => string.push('\n'),
+                Some('t')  => string.push('\t'),
+                Some('r')  => string.push('\r'),
+                Some('"')  => string.push('"'),
+                Some('\\') => string.push('\\'),
+                // \uXXXX is the character with that hex code
+                Some('u')  => {
+                    let digits: String = (0..4).filter_map(|_| chars.next_if(|c| c.is_ascii_hexdigit())).collect();
+                    match u32::from_str_radix(&digits, 16).ok().filter(|_| digits.len() == 4).and_then(char::from_u32) {
+                        Some(c) => string.push(c),
+                        None    => return Err(ParseError { message: format!("Invalid escape \\u{} in string", digits), location: Some(start), incomplete: false })
+                    }
+                },
+                Some(c)    => return Err(ParseError { message: format!("Unknown escape \\{} in string", c), location: Some(start), incomplete: false }),
+                None       => return Err(ParseError { message: "Unterminated string".to_string(), location: Some(start), incomplete: true })
+            },
+            Some(c)    => string.push(c),
+            None       => return Err(ParseError { message: "Unterminated string".to_string(), location: Some(start), incomplete: true })
+        }
+    }
+}
+
+// Skip a block comment whose opening #| has been consumed, along with any comments nested in it
+fn skip_block_comment(chars: &mut Chars, start: Location) -> Result<(), ParseError> {
+    let mut depth = 1;
+    while depth > 0 {
+        match (chars.next(), chars.peek()) {
+            (Some('|'), Some('#')) => { chars.next(); depth -= 1; },
+            (Some('#'), Some('|')) => { chars.next(); depth += 1; },
+            (Some(_), _)            => (),
+            (None, _)               => return Err(ParseError { message: "Unterminated block comment".to_string(), location: Some(start), incomplete: true })
+        }
+    }
+    Ok(())
+}
+
+// Tokenize and parse a whole program
+pub fn parse_source(source: String) -> Result<Vec<Node>, Vec<ParseError>> {
+    parse_source_with(&source, Location { line: 1, col: 1 }, &mut no_dispatch)
+}
+
+// Tokenize and parse a program that starts at location, with reader syntax
+pub fn parse_source_with(source: &str, location: Location, dispatch: Dispatch) -> Result<Vec<Node>, Vec<ParseError>> {
+    match tokenize_with(source, location, dispatch) {
+        Ok(tokens) => parse(tokens),
+        Err(err)   => Err(vec![err])
+    }
+}
+
+// Read just the first datum in text, which starts at location, for read and for running a
+// script a piece at a time.  Returns the datum, how many bytes of text it took and the location
+// after it, or None if there's nothing but whitespace and comments.
+pub fn read_datum(text: &str, location: Location, dispatch: Dispatch) -> Result<Option<(Node, usize, Location)>, ParseError> {
+    let mut chars = Chars { text, location };
+    let mut tokens = Vec::new();
+    let mut depth = 0;
+    // Tokens are read until they make up a whole datum, which can only be once every list in it
+    // has been closed
+    while let Some(token) = next_token(&mut chars, dispatch)? {
+        match token.0 {
+            Token::OpenParen | Token::OpenBracket | Token::OpenVector => depth += 1,
+            Token::CloseParen | Token::CloseBracket                   => depth -= 1,
+            _                                                         => ()
+        }
+        tokens.push(token);
+        if depth <= 0 {
+            let mut iter = tokens.iter();
+            match parse_node(&mut iter, None, &mut HashMap::new()) {
+                Ok(Some(node)) if iter.len() == 0 => return Ok(Some((node, text.len() - chars.text.len(), chars.location))),
+                Err(ref err) if err.incomplete    => (),
+                Err(err)                          => return Err(err),
+                _                                 => ()
             }
         }
     }
+    match tokens.first() {
+        None                   => Ok(None),
+        Some(&(_, location)) => Err(ParseError { message: "Unexpected end of input: expected a datum".to_string(), location: Some(location), incomplete: true })
+    }
+}
 
-    tokens
+// What parse_incremental makes of input that may not have been finished yet
+pub enum Incremental {
+    Complete(Vec<Node>),
+    // The input is fine so far, but ends partway through a form (or before any form)
+    NeedMoreInput,
+    Errors(Vec<ParseError>)
 }
 
-pub fn parse(tokens: Vec<Token>) -> Result<Node, ParseError> {
-    match parse_nodes(&mut tokens.iter(), 0) {
-        Ok(val) => {
-            if val.len() > 1 {
-                Err(ParseError { message: "Only one outer level permitted".to_string() })
-            } else {
-                Ok(val[0].clone())
+// Parse input as it's being entered, telling input that's just unfinished, like a list that
+// hasn't been closed yet, apart from input with mistakes in it.  The REPL reads through
+// Interpreter::read_incremental instead, for the reader syntax, so this is allowed to go unused.
+#[allow(dead_code)]
+pub fn parse_incremental(source: &str) -> Incremental {
+    incremental(parse_source(source.to_string()))
+}
+
+// What parsing input that may not be finished came to
+pub fn incremental(result: Result<Vec<Node>, Vec<ParseError>>) -> Incremental {
+    match result {
+        Ok(nodes)                                                  => Incremental::Complete(nodes),
+        Err(ref errors) if errors.iter().all(|err| err.incomplete) => Incremental::NeedMoreInput,
+        Err(errors)                                                => Incremental::Errors(errors)
+    }
+}
+
+// Parse every top-level form, in order.  After an error this carries on with the next form, so
+// that all the errors in the input are reported rather than just the first.
+pub fn parse(tokens: Vec<(Token, Location)>) -> Result<Vec<Node>, Vec<ParseError>> {
+    let mut nodes = Vec::new();
+    let mut errors = Vec::new();
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let mut iter = tokens[pos..].iter();
+        match parse_node(&mut iter, None, &mut HashMap::new()) {
+            Ok(Some(node)) => {
+                nodes.push(node);
+                pos = tokens.len() - iter.len();
+            },
+            Ok(None)       => break,
+            // Picking up at a new line can parse the rest of this form again, and find the
+            // same error again
+            Err(err)       => {
+                if !errors.iter().any(|other: &ParseError| other.location == err.location) {
+                    errors.push(err);
+                }
+                pos = next_form(&tokens, pos);
             }
-        },
-        Err(err) => Err(err)
+        }
     }
+    if nodes.is_empty() && errors.is_empty() {
+        errors.push(ParseError { message: "Unexpected end of input: expected a form".to_string(), location: None, incomplete: true });
+    }
+    if errors.is_empty() { Ok(nodes) } else { Err(errors) }
 }
 
-fn parse_nodes(mut tokens: &mut slice::Iter<Token>, depth: u32) -> Result<Vec<Node>, ParseError> {
+// Where to pick up again after an error in the form starting at start: after the close that
+// balances its first token, or at the next list opened at the start of a line if that comes
+// first, since that's most likely a new top-level form when a close paren is missing
+fn next_form(tokens: &[(Token, Location)], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, &(ref token, location)) in tokens.iter().enumerate().skip(start) {
+        match *token {
+            Token::OpenParen | Token::OpenBracket | Token::OpenVector => {
+                if depth > 0 && location.col == 1 {
+                    return i;
+                }
+                depth += 1;
+            },
+            Token::CloseParen | Token::CloseBracket => depth -= 1,
+            _                                       => ()
+        }
+        if depth <= 0 {
+            return i + 1;
+        }
+    }
+    tokens.len()
+}
+
+// Datum labels only mean something within the top-level datum that defines them
+fn parse_nodes(tokens: &mut slice::Iter<(Token, Location)>, close: Option<(char, Location)>, labels: &mut HashMap<u32, Value>) -> Result<Vec<Node>, ParseError> {
     let mut node_list = Vec::new();
     loop {
-        match try!(parse_node(&mut tokens, depth)) {
+        match parse_node(tokens, close, labels)? {
             Some(node) => node_list.push(node),
             None       => return Ok(node_list)
         }
     }
 }
 
-fn parse_node(mut tokens: &mut slice::Iter<Token>, depth: u32) -> Result<Option<Node>, ParseError> {
+// Split the #x, #o, #b, #d radix and #e, #i exactness prefixes (in either order) off a number,
+// returning the radix, whether it's exact, and the digits that follow
+fn number_prefixes(val: &str) -> Option<(Option<u32>, Option<bool>, &str)> {
+    let mut radix = None;
+    let mut exact = None;
+    let mut rest = val;
+    while let Some(prefix) = rest.get(..2).filter(|prefix| prefix.starts_with('#')) {
+        match &*prefix.to_lowercase() {
+            "#x" if radix.is_none() => radix = Some(16),
+            "#o" if radix.is_none() => radix = Some(8),
+            "#b" if radix.is_none() => radix = Some(2),
+            "#d" if radix.is_none() => radix = Some(10),
+            "#e" if exact.is_none() => exact = Some(true),
+            "#i" if exact.is_none() => exact = Some(false),
+            _                       => break
+        }
+        rest = &rest[2..];
+    }
+    if rest.len() == val.len() {
+        None
+    } else {
+        Some((radix, exact, rest))
+    }
+}
+
+// The number the digits after number_prefixes stand for.  Anything but decimal has to be an
+// integer or a fraction; #i makes any number a float, and #e makes a float with no fractional
+// part an integer.
+fn prefixed_number(radix: Option<u32>, exact: Option<bool>, digits: &str) -> Option<Node> {
+    let node = match (radix, digits.split_once('/')) {
+        (_, Some((num, den)))         => {
+            let radix = radix.unwrap_or(10);
+            if den.starts_with(['+', '-']) {
+                return None;
+            }
+            rational_node(i64::from_str_radix(num, radix).ok()?, i64::from_str_radix(den, radix).ok()?)?
+        },
+        (Some(radix), None) if radix != 10 => Node::Int(i32::from_str_radix(digits, radix).ok()?),
+        _                             => match digits.parse::<i32>() {
+            Ok(int_val) => Node::Int(int_val),
+            Err(_)      => Node::Float(digits.parse::<f64>().ok().filter(|float_val| float_val.is_finite())?)
+        }
+    };
+    match (node, exact) {
+        (Node::Int(int_val), Some(false))   => Some(Node::Float(int_val as f64)),
+        (Node::Rational(num, den), Some(false)) => Some(Node::Float(num as f64 / den as f64)),
+        (Node::Float(float_val), Some(true)) => {
+            if float_val.fract() == 0.0 && float_val >= i32::MIN as f64 && float_val <= i32::MAX as f64 {
+                Some(Node::Int(float_val as i32))
+            } else {
+                decimal_rational(digits)
+            }
+        },
+        (node, _)                           => Some(node)
+    }
+}
+
+// The numerator and denominator of a fraction like 1/3 or -7/2
+fn rational_literal(val: &str) -> Option<(i64, i64)> {
+    let (num, den) = val.split_once('/')?;
+    if den.starts_with(['+', '-']) {
+        return None;
+    }
+    Some((num.parse::<i64>().ok()?, den.parse::<i64>().ok()?))
+}
+
+// The exact value of a decimal like 1.25, which is 5/4
+fn decimal_rational(digits: &str) -> Option<Node> {
+    let (whole, frac) = digits.split_once('.')?;
+    if frac.is_empty() || !frac.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let den = 10i64.checked_pow(frac.len() as u32)?;
+    let num = format!("{}{}", whole, frac).parse::<i64>().ok()?;
+    rational_node(num, den)
+}
+
+// num/den in lowest terms, which is an integer if den divides num.  There's no rational with a
+// zero denominator, or one whose terms don't fit in an i32.
+pub fn rational_node(num: i64, den: i64) -> Option<Node> {
+    if den == 0 {
+        return None;
+    }
+    let gcd = gcd(num.abs(), den.abs());
+    let (num, den) = (num / gcd * den.signum(), den.abs() / gcd);
+    let num = i32::try_from(num).ok()?;
+    match den {
+        1   => Some(Node::Int(num)),
+        den => Some(Node::Rational(num, i32::try_from(den).ok()?))
+    }
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// A list with a '.' before its last element is a pair, unless what follows the dot is itself
+// a list, in which case it's just a longer list: (a . (b c)) is (a b c)
+fn dotted_list(mut nodes: Vec<Node>, location: Location) -> Result<Node, ParseError> {
+    let is_dot = |node: &Node| matches!(*node, Node::Symbol(ref val) if val == ".");
+    let dots = nodes.iter().filter(|node| is_dot(node)).count();
+    if dots == 0 {
+        return Ok(Node::List(nodes, Some(location)));
+    }
+
+    let len = nodes.len();
+    if dots > 1 || len < 3 || !is_dot(&nodes[len - 2]) {
+        return Err(ParseError { message: "Misplaced '.'".to_string(), location: Some(location), incomplete: false });
+    }
+
+    let tail = nodes.pop().unwrap();
+    nodes.pop();
+    match tail {
+        Node::List(rest, _)       => {
+            nodes.extend(rest);
+            Ok(Node::List(nodes, Some(location)))
+        },
+        Node::Pair(rest, tail)    => {
+            nodes.extend(rest);
+            Ok(Node::Pair(nodes, tail))
+        },
+        tail                      => Ok(Node::Pair(nodes, Box::new(tail)))
+    }
+}
+
+// Parse the next datum, or return None at the close that ends the list being read (close is the
+// character it should be, and where the list was opened) or at the end of the input
+fn parse_node(tokens: &mut slice::Iter<(Token, Location)>, close: Option<(char, Location)>, labels: &mut HashMap<u32, Value>) -> Result<Option<Node>, ParseError> {
     match tokens.next() {
-        Some(token) => {
+        Some(&(ref token, location)) => {
             match *token {
-                Token::OpenParen        => {
-                    let inner = try!(parse_nodes(&mut tokens, depth + 1));
-                    Ok(Some(Node::List(inner)))
+                Token::OpenParen | Token::OpenBracket => {
+                    let close = if let Token::OpenParen = *token { ')' } else { ']' };
+                    let inner = parse_nodes(tokens, Some((close, location)), labels)?;
+                    match inner.as_slice() {
+                        [Node::Symbol(ref head), datum] if head == "quote" => Ok(Some(Node::Quoted(Box::new(quote_node(datum))))),
+                        _                                                  => dotted_list(inner, location).map(Some)
+                    }
                 },
-                Token::CloseParen       => {
-                    if depth > 0 {
-                        Ok(None)
-                    } else {
-                        Err(ParseError { message: "Unexpected close paren".to_string() })
+                Token::OpenVector       => {
+                    let inner = parse_nodes(tokens, Some((')', location)), labels)?;
+                    Ok(Some(Node::Vector(inner)))
+                },
+                Token::CloseParen | Token::CloseBracket => {
+                    let found = if let Token::CloseParen = *token { ')' } else { ']' };
+                    match close {
+                        Some((close, _)) if close == found => Ok(None),
+                        Some((close, _))              => Err(ParseError { message: format!("Expected '{}' but found '{}'", close, found), location: Some(location), incomplete: false }),
+                        None if found == ')'          => Err(ParseError { message: "Unexpected close paren".to_string(), location: Some(location), incomplete: false }),
+                        None                          => Err(ParseError { message: "Unexpected close bracket".to_string(), location: Some(location), incomplete: false })
                     }
                 },
                 Token::String(ref val) => Ok(Some(Node::String(val.clone()))),
+                Token::Datum(ref node) => Ok(Some(node.clone())),
+                Token::Quote           => {
+                    match parse_node(tokens, close, labels)? {
+                        Some(node) => Ok(Some(Node::Quoted(Box::new(quote_node(&node))))),
+                        None       => Err(ParseError { message: "Expected a datum after '".to_string(), location: Some(location), incomplete: close.is_none() })
+                    }
+                },
+                Token::Quasiquote | Token::Unquote | Token::UnquoteSplicing => {
+                    let (name, prefix) = match *token {
+                        Token::Quasiquote => ("quasiquote", "`"),
+                        Token::Unquote    => ("unquote", ","),
+                        _                 => ("unquote-splicing", ",@")
+                    };
+                    match parse_node(tokens, close, labels)? {
+                        Some(node) => Ok(Some(Node::List(vec![Node::Symbol(name.to_string()), node], Some(location)))),
+                        None       => Err(ParseError { message: format!("Expected a datum after {}", prefix), location: Some(location), incomplete: close.is_none() })
+                    }
+                },
+                Token::DatumComment    => {
+                    match parse_node(tokens, close, labels)? {
+                        Some(_) => parse_node(tokens, close, labels),
+                        None    => Err(ParseError { message: "Expected a datum after #;".to_string(), location: Some(location), incomplete: close.is_none() })
+                    }
+                },
+                // A labelled datum is converted to its value straight away, so every #n# that
+                // refers to it shares that one value rather than building a copy of it
+                Token::Label(label)    => {
+                    match parse_node(tokens, close, labels)? {
+                        Some(node) => {
+                            let val = quote_node(&node);
+                            labels.insert(label, val.clone());
+                            Ok(Some(Node::ValueWrapper(Box::new(val))))
+                        },
+                        None       => Err(ParseError { message: format!("Expected a datum after #{}=", label), location: Some(location), incomplete: close.is_none() })
+                    }
+                },
+                // Values can't refer to themselves, so a label used inside its own datum is an error
+                Token::LabelRef(label) => {
+                    match labels.get(&label) {
+                        Some(val) => Ok(Some(Node::ValueWrapper(Box::new(val.clone())))),
+                        None      => Err(ParseError { message: format!("#{}# isn't the label of an earlier datum (cyclic data isn't supported)", label), location: Some(location), incomplete: false })
+                    }
+                },
                 Token::NonParen(ref val) => {
                     if val == "#t" {
                         Ok(Some(Node::Bool(true)))
                     } else if val == "#f" {
                         Ok(Some(Node::Bool(false)))
+                    } else if let Some((radix, exact, digits)) = number_prefixes(val) {
+                        match prefixed_number(radix, exact, digits) {
+                            Some(node) => Ok(Some(node)),
+                            None       => Err(ParseError { message: format!("Invalid number {}", val), location: Some(location), incomplete: false })
+                        }
+                    } else if let Some(name) = val.strip_prefix("#:") {
+                        if name.is_empty() {
+                            return Err(ParseError { message: "Expected a name after #:".to_string(), location: Some(location), incomplete: false });
+                        }
+                        Ok(Some(Node::Keyword(name.to_string())))
+                    } else if let Some(name) = val.strip_prefix("#\\") {
+                        match parse_char(name) {
+                            Some(c) => Ok(Some(Node::Char(c))),
+                            None    => Err(ParseError { message: format!("Unknown character {}", val), location: Some(location), incomplete: false })
+                        }
                     } else {
                         let re = Regex::new(r"^(\d*\.?\d*)([\+-]\d*\.?\d*)i$").unwrap();
                         if re.is_match(val) {
-                            for cap in re.captures_iter(val) {
+                            if let Some(cap) = re.captures_iter(val).next() {
                                 let real_part = cap.at(1).unwrap_or("0").parse::<f64>();
                                 let imaginary_part = match cap.at(2).unwrap_or("1") {
                                     "-" => Ok(-1.0),
@@ -163,10 +758,16 @@ fn parse_node(mut tokens: &mut slice::Iter<Token>, depth: u32) -> Result<Option<
                                 };
                                 match (real_part, imaginary_part) {
                                     (Ok(real), Ok(im)) => return Ok(Some(Node::Complex(real, im))),
-                                    _                  => return Err(ParseError { message: format!("Error parsing complex constant {}", val) })
+                                    _                  => return Err(ParseError { message: format!("Error parsing complex constant {}", val), location: Some(location), incomplete: false })
                                 }
                             }
                         }
+                        if let Some((num, den)) = rational_literal(val) {
+                            return match rational_node(num, den) {
+                                Some(node) => Ok(Some(node)),
+                                None       => Err(ParseError { message: format!("Invalid number {}", val), location: Some(location), incomplete: false })
+                            };
+                        }
                         match val.parse::<i32>() {
                             Ok(int_val) => Ok(Some(Node::Int(int_val))),
                             _ => match val.parse::<f64>() {
@@ -178,12 +779,9 @@ fn parse_node(mut tokens: &mut slice::Iter<Token>, depth: u32) -> Result<Option<
                 }
             }
         },
-        None => {
-            if depth == 0 {
-                Ok(None)
-            } else {
-                Err(ParseError { message: "Unexected end of input".to_string() })
-            }
+        None => match close {
+            Some((close, open)) => Err(ParseError { message: format!("Unexpected end of input: expected '{}' to close this list", close), location: Some(open), incomplete: true }),
+            None                => Ok(None)
         }
     }
 }
\ No newline at end of file
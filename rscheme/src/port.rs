@@ -0,0 +1,79 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::prelude::*;
+
+// The current output port, which everything printed by a program is written to.  It's stdout
+// unless output is being captured into a string, which capture does for the duration of a call.
+thread_local! {
+    static CAPTURES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn write_str(text: &str) {
+    CAPTURES.with(|captures| {
+        match captures.borrow_mut().last_mut() {
+            Some(capture) => capture.push_str(text),
+            None          => {
+                let mut stdout = io::stdout();
+                stdout.write_all(text.as_bytes()).expect("Could not write to stdout");
+                stdout.flush().expect("Could not flush stdout");
+            }
+        }
+    })
+}
+
+// Call f with the current output port writing to a string, and return the result along with
+// everything that was written
+pub fn capture<R, F: FnOnce() -> R>(f: F) -> (R, String) {
+    CAPTURES.with(|captures| captures.borrow_mut().push(String::new()));
+    let result = f();
+    let output = CAPTURES.with(|captures| captures.borrow_mut().pop().unwrap_or_default());
+    (result, output)
+}
+
+// The strings output is being captured into, innermost last, taken away for a generator's producer
+// to write to on its own thread
+pub fn take_captures() -> Vec<String> {
+    CAPTURES.with(|captures| captures.replace(Vec::new()))
+}
+
+pub fn set_captures(stack: Vec<String>) {
+    CAPTURES.with(|captures| *captures.borrow_mut() = stack);
+}
+
+// An input port reading from a string, which is what define-reader-syntax hands its handlers
+// (the rest of the source after the dispatch character) and what open-input-string makes
+pub struct InputPort {
+    text: String,
+    // How many bytes of the text have been read
+    pos: usize
+}
+
+impl InputPort {
+    pub fn new(text: String) -> InputPort {
+        InputPort { text, pos: 0 }
+    }
+
+    // What hasn't been read yet
+    pub fn rest(&self) -> &str {
+        &self.text[self.pos..]
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn peek_char(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    pub fn read_char(&mut self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    // Mark bytes more of the text as read, by read reading a datum from it
+    pub fn advance(&mut self, bytes: usize) {
+        self.pos = (self.pos + bytes).min(self.text.len());
+    }
+}
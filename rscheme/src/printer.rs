@@ -0,0 +1,230 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use shared::{self, Shared, Locked};
+
+use interpreter::{Value, Pair};
+use parser;
+
+// How much of a value to print: at most length elements of any list, with lists nested at most
+// depth deep.  Whatever is left out is shown as "...".  Floats are shown with precision digits
+// after the point.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub length: Option<usize>,
+    pub depth: Option<usize>,
+    pub precision: Option<usize>
+}
+
+// The limits set by print-length and print-depth, which the REPL and write print with
+thread_local! {
+    static LIMITS: Cell<Limits> = Cell::new(Limits::default());
+}
+
+pub fn limits() -> Limits {
+    LIMITS.with(|limits| limits.get())
+}
+
+pub fn set_limits(limits: Limits) {
+    LIMITS.with(|cell| cell.set(limits));
+}
+
+enum Item {
+    Text(&'static str),
+    Value(Value, usize)
+}
+
+type Labels = HashMap<*const Locked<Pair>, Option<usize>>;
+
+// The printed form of a value.  This keeps its own stack of what's left to print rather than
+// recursing, so a list nested arbitrarily deep can't overflow the Rust stack.  A pair that a
+// list comes back round to is labelled, as in write_shared, so a circular list can be printed.
+pub fn write(val: &Value, limits: Limits) -> String {
+    write_labelled(val, limits, labels(val, true))
+}
+
+// Like write, but a pair that appears more than once in the value is printed in full only the
+// first time, labelled #n=, and as #n# everywhere after that
+pub fn write_shared(val: &Value, limits: Limits) -> String {
+    write_labelled(val, limits, labels(val, false))
+}
+
+enum Visit {
+    Enter(Value),
+    Leave(*const Locked<Pair>)
+}
+
+// The pairs in a value to label: the ones that are reached again from inside themselves, and,
+// unless only_cycles, the ones that are reached more than once at all.  Each starts out
+// unlabelled, and gets its number when it's first printed.
+fn labels(val: &Value, only_cycles: bool) -> Labels {
+    // Whether each pair seen so far is still being visited
+    let mut visiting: HashMap<*const Locked<Pair>, bool> = HashMap::new();
+    let mut labels = HashMap::new();
+    let mut stack = vec![Visit::Enter(val.clone())];
+    while let Some(visit) = stack.pop() {
+        let val = match visit {
+            Visit::Enter(val) => val,
+            Visit::Leave(ptr) => { visiting.insert(ptr, false); continue; }
+        };
+        match val {
+            Value::Pair(ref pair) => {
+                let ptr = Shared::as_ptr(pair);
+                if let Some(&inside) = visiting.get(&ptr) {
+                    if inside || !only_cycles {
+                        labels.insert(ptr, None);
+                    }
+                    continue;
+                }
+                visiting.insert(ptr, true);
+                stack.push(Visit::Leave(ptr));
+                let pair = shared::lock(pair);
+                stack.push(Visit::Enter(pair.cdr.clone()));
+                stack.push(Visit::Enter(pair.car.clone()));
+            },
+            Value::Vector(ref vals) | Value::Values(ref vals) => stack.extend(vals.iter().cloned().map(Visit::Enter)),
+            _ => ()
+        }
+    }
+    labels
+}
+
+fn write_labelled(val: &Value, limits: Limits, mut labels: Labels) -> String {
+    let mut out = String::new();
+    let mut next_label = 0;
+    let mut stack = vec![Item::Value(val.clone(), 0)];
+    while let Some(item) = stack.pop() {
+        let (val, depth) = match item {
+            Item::Text(text)        => { out.push_str(text); continue; },
+            Item::Value(val, depth) => (val, depth)
+        };
+        let label = match val {
+            Value::Pair(ref pair) => labels.get_mut(&Shared::as_ptr(pair)),
+            _                     => None
+        };
+        if let Some(&mut Some(label)) = label {
+            out.push_str(&format!("#{}#", label));
+            continue;
+        }
+        let (open, elements, tail, close): (&str, Vec<Value>, Option<Value>, &str) = match val {
+            Value::Pair(_) => {
+                // The cars along the list, as far as the end, a labelled pair (which is printed
+                // as its tail), or more than can be shown
+                let mut elements = Vec::new();
+                let mut rest = val.clone();
+                while let Some((car, cdr)) = rest.uncons() {
+                    elements.push(car);
+                    rest = cdr;
+                    let labelled = match rest {
+                        Value::Pair(ref pair) => labels.contains_key(&Shared::as_ptr(pair)),
+                        _                     => false
+                    };
+                    if labelled || limits.length.is_some_and(|max| elements.len() > max) {
+                        break;
+                    }
+                }
+                let tail = match rest {
+                    Value::Nil => None,
+                    rest       => Some(rest)
+                };
+                ("(", elements, tail, ")")
+            },
+            Value::Vector(ref vals)         => ("#(", vals.clone(), None, ")"),
+            Value::Values(ref vals)         => ("", vals.clone(), None, ""),
+            Value::Partial(ref func, ref args) => ("(partial ", Some((**func).clone()).into_iter().chain(args.iter().cloned()).collect(), None, ")"),
+            Value::Curried(ref func, ref args) => ("(curry ", Some((**func).clone()).into_iter().chain(args.iter().cloned()).collect(), None, ")"),
+            Value::Composition(ref funcs)   => ("(compose ", funcs.clone(), None, ")"),
+            Value::Float(float_val)         => {
+                match limits.precision {
+                    Some(precision) => out.push_str(&format!("{:.*}", precision, float_val)),
+                    None            => out.push_str(&atom(&val))
+                }
+                continue;
+            },
+            ref val                         => { out.push_str(&atom(val)); continue; }
+        };
+        if limits.depth.is_some_and(|max| depth >= max) {
+            out.push_str("...");
+            continue;
+        }
+
+        if let Value::Pair(ref pair) = val {
+            if let Some(label) = labels.get_mut(&Shared::as_ptr(pair)) {
+                *label = Some(next_label);
+                out.push_str(&format!("#{}=", next_label));
+                next_label += 1;
+            }
+        }
+        out.push_str(open);
+        stack.push(Item::Text(close));
+        let shown = match limits.length {
+            Some(max) if elements.len() > max => {
+                stack.push(Item::Text(if max > 0 { " ..." } else { "..." }));
+                max
+            },
+            _ => {
+                if let Some(tail) = tail {
+                    stack.push(Item::Value(tail, depth + 1));
+                    stack.push(Item::Text(" . "));
+                }
+                elements.len()
+            }
+        };
+        for (i, val) in elements.into_iter().take(shown).enumerate().rev() {
+            stack.push(Item::Value(val, depth + 1));
+            if i > 0 {
+                stack.push(Item::Text(" "));
+            }
+        }
+    }
+    out
+}
+
+// The printed form of a value with no elements to print
+fn atom(val: &Value) -> String {
+    match *val {
+        Value::Int(val)          => format!("{}", val),
+        Value::Float(val)        => format!("{:?}", val),
+        Value::Complex(real, 0.0)  => format!("{}", real),
+        Value::Complex(real, im) if im < 0.0 => format!("{}-{}i", real, -im),
+        Value::Complex(real, im) => format!("{}+{}i", real, im),
+        Value::Rational(num, den) => format!("{}/{}", num, den),
+        Value::Bool(true)        => "#t".to_string(),
+        Value::Bool(false)       => "#f".to_string(),
+        Value::Char(c)           => format!("#\\{}", parser::char_name(c)),
+        Value::Literal(ref val)  => val.clone(),
+        Value::Keyword(ref name) => format!("#:{}", name),
+        Value::String(ref val)   => format!("\"{}\"", parser::escape_string(val)),
+        Value::Lambda(ref lambda) => {
+            // A procedure that's been defined is shown by its name, and an anonymous one by its source
+            if let Some(ref name) = lambda.name {
+                return format!("#<procedure {}>", name);
+            }
+            let optionals = lambda.optionals.iter().map(|(param, default)| format!("({} {})", param, default));
+            let params: Vec<String> = lambda.params.iter().map(|p| p.to_string())
+                .chain(if lambda.optionals.is_empty() { None } else { Some("#:optional".to_string()) })
+                .chain(optionals)
+                .chain(lambda.keywords.iter().map(|keyword| keyword.to_string()))
+                .collect();
+            let formals = match lambda.rest {
+                Some(ref rest) if params.is_empty() => rest.clone(),
+                Some(ref rest)                      => format!("({} . {})", params.join(" "), rest),
+                None                                => format!("({})", params.join(" "))
+            };
+            format!("(lambda {} ({}))", formals, lambda.body)
+        },
+        Value::StringBuilder(_)  => "#<string-builder>".to_string(),
+        Value::InputPort(_)      => "#<input-port>".to_string(),
+        Value::Promise(_)        => "#<promise>".to_string(),
+        Value::Parameter(_)      => "#<parameter>".to_string(),
+        Value::Generator(_)      => "#<generator>".to_string(),
+        Value::Error(ref err)    => format!("#<error {}>", err.text()),
+        Value::Environment(_)    => "#<environment>".to_string(),
+        Value::Macro(ref mac)    => format!("#<macro {}>", mac.name),
+        Value::Continuation(_)   => "#<continuation>".to_string(),
+        Value::Function(name, _) | Value::SpecialForm(name, _) => name.to_string(),
+        Value::NodeWrapper(ref node) | Value::ScopedNode(ref node, _) => node.to_string(),
+        Value::Void | Value::Nil => "()".to_string(),
+        Value::Vector(_) | Value::Pair(_) | Value::Values(_)
+            | Value::Partial(..) | Value::Curried(..) | Value::Composition(_) => write(val, Limits::default())
+    }
+}
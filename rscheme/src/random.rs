@@ -0,0 +1,42 @@
+use std::cell::Cell;
+
+// The seed every program starts with, so that programs using random numbers behave the same on
+// every run unless they pick a different seed with random-seed!
+const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+thread_local! {
+    static STATE: Cell<u64> = const { Cell::new(DEFAULT_SEED) };
+}
+
+pub fn seed(seed: u64) {
+    STATE.with(|state| state.set(seed));
+}
+
+pub fn state() -> u64 {
+    STATE.with(|state| state.get())
+}
+
+// The next number from a splitmix64 generator
+pub fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let next = state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state.set(next);
+        let mut z = next;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    })
+}
+
+// A number from 0 up to but not including n
+pub fn below(n: usize) -> usize {
+    (next_u64() % n as u64) as usize
+}
+
+// Move k randomly chosen elements to the front of vals, in random order (a partial Fisher-Yates shuffle)
+pub fn shuffle_front<T>(vals: &mut [T], k: usize) {
+    for i in 0..k.min(vals.len()) {
+        let j = i + below(vals.len() - i);
+        vals.swap(i, j);
+    }
+}
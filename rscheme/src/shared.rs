@@ -0,0 +1,36 @@
+// The reference counting that values are built on.  Normally that's Rc, and the contents of a
+// string builder are in a RefCell.  With the sync feature they're Arc and a Mutex instead, which
+// makes values Send and Sync, so an interpreter can be moved to another thread and values can
+// be shared between threads (at some cost in speed).
+
+#[cfg(not(feature = "sync"))]
+pub use std::rc::Rc as Shared;
+#[cfg(feature = "sync")]
+pub use std::sync::Arc as Shared;
+
+#[cfg(not(feature = "sync"))]
+pub type Locked<T> = std::cell::RefCell<T>;
+#[cfg(feature = "sync")]
+pub type Locked<T> = std::sync::Mutex<T>;
+
+#[cfg(not(feature = "sync"))]
+pub fn lock<T>(locked: &Locked<T>) -> std::cell::RefMut<'_, T> {
+    locked.borrow_mut()
+}
+
+// A thread that panicked while holding the lock can't have left a string half-written in a way
+// that matters, so the poisoning is ignored
+#[cfg(feature = "sync")]
+pub fn lock<T>(locked: &Locked<T>) -> std::sync::MutexGuard<'_, T> {
+    locked.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(not(feature = "sync"))]
+pub fn into_inner<T>(locked: Locked<T>) -> T {
+    locked.into_inner()
+}
+
+#[cfg(feature = "sync")]
+pub fn into_inner<T>(locked: Locked<T>) -> T {
+    locked.into_inner().unwrap_or_else(|err| err.into_inner())
+}
@@ -1,18 +1,27 @@
 #![cfg(test)]
 use interpreter::*;
+use cache;
 use parser;
+use port;
+use shared::Shared;
 
 fn run_test(source: &str, interpreter: &mut Interpreter) -> Result<Value, EvalError> {
-    let tree = parser::parse(parser::tokenize(source.to_string()));
+    let tree = interpreter.read(source, parser::Location { line: 1, col: 1 });
     match tree {
-        Ok(val)  => interpreter.eval(val),
-        Err(err) => Err(EvalError{ message: err.message })
+        Ok(nodes) => {
+            let mut val = Ok(Value::Void);
+            for node in nodes {
+                val = Ok(interpreter.eval(node)?);
+            }
+            val
+        },
+        Err(mut errors) => Err(EvalError { location: None, message: errors.remove(0).message })
     }
 }
 
 #[test]
 fn test_artihmetic() {
-let mut interpreter = Interpreter::new();
+let mut interpreter = Interpreter::new(EngineConfig::default());
     
     if let Ok(Value::Int(4)) = run_test("(+ 2 2)", &mut interpreter) {
     } else {
@@ -27,7 +36,7 @@ let mut interpreter = Interpreter::new();
 
 #[test]
 fn test_if() {
-    let mut interpreter = Interpreter::new();
+    let mut interpreter = Interpreter::new(EngineConfig::default());
     
     if let Ok(Value::Int(2)) = run_test("(if (> 6 5) (+ 1 1) (+ 2 2))", &mut interpreter) {
     } else {
@@ -42,7 +51,7 @@ fn test_if() {
 
 #[test]
 fn test_def() {
-    let mut interpreter = Interpreter::new();
+    let mut interpreter = Interpreter::new(EngineConfig::default());
     
     if let Ok(Value::Void) = run_test("(define x 3)", &mut interpreter) {
     } else {
@@ -62,7 +71,7 @@ fn test_def() {
 
 #[test]
 fn test_begin() {
-    let mut interpreter = Interpreter::new();
+    let mut interpreter = Interpreter::new(EngineConfig::default());
     
     if let Ok(Value::Int(3)) = run_test("(begin (define x 1) (set! x (+ x 1)) (+ x 1))", &mut interpreter) {
     } else {
@@ -70,9 +79,119 @@ fn test_begin() {
     }
 }
 
+#[test]
+fn test_internal_defines() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // Two mutually recursive helpers, and a define that uses the one before it
+    let source = "(define (parity n)
+                    (begin
+                      (define (ev? k) (if (= k 0) #t (od? (- k 1))))
+                      (define (od? k) (if (= k 0) #f (ev? (- k 1))))
+                      (define both (list (ev? n) (od? n)))
+                      both))
+                  (list (parity 10) (parity 7))";
+    if let Ok(val) = run_test(source, &mut interpreter) {
+        assert_eq!(val.to_string(), "((#t #f) (#f #t))");
+    } else {
+        panic!("Failed");
+    }
+    // The helpers aren't defined outside the body
+    assert!(run_test("ev?", &mut interpreter).is_err());
+    assert!(run_test("both", &mut interpreter).is_err());
+
+    // A lambda returned from the body can still call the helpers, which still see each other
+    let source = "(define (make-parity)
+                    (begin
+                      (define (ev? k) (if (= k 0) #t (od? (- k 1))))
+                      (define (od? k) (if (= k 0) #f (ev? (- k 1))))
+                      (lambda (n) (od? n))))
+                  ((make-parity) 5)";
+    if let Ok(Value::Bool(true)) = run_test(source, &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // An internal define shadows a parameter, and set! changes what it defined
+    if let Ok(val) = run_test("(define (f x) (begin (define x (* x 10)) (define (bump!) (set! x (+ x 1))) (bump!) x)) (f 3)", &mut interpreter) {
+        assert_eq!(val.to_string(), "31");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_multi_form_bodies() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // A lambda's body can have several forms, and gives the value of the last
+    if let Ok(val) = run_test("(define log '()) (define sq (lambda (x) (set! log (cons x log)) (* x x))) (list (sq 3) (sq 4) log)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(9 16 (4 3))");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(lambda (x))", &mut interpreter).is_err());
+
+    // The last form is still a tail call
+    if let Ok(Value::Int(0)) = run_test("(define (down n) (+ n 1) (if (= n 0) n (down (- n 1)))) (down 100000)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(100000)) = run_test("(let lp ((i 0)) (+ i 1) (if (< i 100000) (lp (+ i 1)) i))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    // Even in a body with an internal define
+    for source in &["(define (loop n) (define m (- n 1)) (if (= m 0) 'done (loop m))) (loop 10000)",
+                    "(define (loop n) (let () (define m (- n 1)) (if (= m 0) 'done (loop m)))) (loop 10000)"] {
+        if let Ok(val) = run_test(source, &mut interpreter) {
+            assert_eq!(val.to_string(), "done");
+        } else {
+            panic!("Failed");
+        }
+    }
+    // And in every body that can have several forms, including a call on its own
+    for source in &["(define (ping n) (if (= n 0) 'done (pong n))) (define (pong n) (ping (- n 1))) (ping 10000)",
+                    "(define loop (lambda (n) (define m (- n 1)) (if (= m 0) 'done (loop m)))) (loop 10000)",
+                    "(define (loop n) (cond ((= n 0) 'done) (else (- n 1) (loop (- n 1))))) (loop 10000)",
+                    "(define (loop n) (when #t (- n 1) (if (= n 0) 'done (loop (- n 1))))) (loop 10000)",
+                    "(define (loop n) (let ((m (- n 1))) (define k m) (let* ((j k)) (if (= j 0) 'done (loop j))))) (loop 10000)"] {
+        if let Ok(val) = run_test(source, &mut interpreter) {
+            assert_eq!(val.to_string(), "done");
+        } else {
+            panic!("Failed");
+        }
+    }
+    // An error before the last form isn't lost
+    assert!(run_test("(let () (define x 1) (car 5) x)", &mut interpreter).is_err());
+    // So is the last form of a let's body, and a named let in tail position
+    for source in &["(define (loop n) (let ((m (- n 1))) (if (= m 0) 'done (loop m)))) (loop 10000)",
+                    "(define (loop n) (let* ((m (- n 1)) (k m)) (if (= k 0) 'done (loop k)))) (loop 10000)",
+                    "(define (loop n) (letrec ((m (- n 1))) (if (= m 0) 'done (loop m)))) (loop 10000)",
+                    "(define (loop n) (let-list ((m) (list (- n 1))) (if (= m 0) 'done (loop m)))) (loop 10000)",
+                    "(let lp ((n 10000)) (let ((m (- n 1))) (if (= m 0) 'done (lp m))))",
+                    "(define (loop n) (if (= n 0) 'done (let lp ((m (- n 1))) (loop m)))) (loop 10000)"] {
+        if let Ok(val) = run_test(source, &mut interpreter) {
+            assert_eq!(val.to_string(), "done");
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    // Procedure definitions at the start of a body are hoisted, so a define can use one after it
+    if let Ok(Value::Int(6)) = run_test("(define (f) (define x (g 2)) (define (g n) (* n 3)) x) (f)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(3)) = run_test("(let ((a 1)) (define b 2) (+ a b))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+}
+
 #[test]
 fn test_lambda() {
-    let mut interpreter = Interpreter::new();
+    let mut interpreter = Interpreter::new(EngineConfig::default());
     
     if let Ok(Value::Int(10)) = run_test("((lambda (x) (+ x x)) 5)", &mut interpreter) {
     } else {
@@ -94,7 +213,7 @@ fn test_lambda() {
         panic!("Failed");
     }
     
-    if let Ok(Value::List(vals)) = run_test("((compose list twice) 5)", &mut interpreter) {
+    if let Some(vals) = run_test("((compose list twice) 5)", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
         if vals.len() != 1 {
             panic!("Failed");
         }
@@ -125,7 +244,7 @@ fn test_lambda() {
 
 #[test]
 fn test_fact() {
-    let mut interpreter = Interpreter::new();
+    let mut interpreter = Interpreter::new(EngineConfig::default());
     
     if let Ok(Value::Void) = run_test("(define fact (lambda (n) (if (<= n 1) 1 (* n (fact (- n 1))))))", &mut interpreter) {
     } else {
@@ -146,7 +265,7 @@ fn test_fact() {
 
 #[test]
 fn test_abs() {
-    let mut interpreter = Interpreter::new();
+    let mut interpreter = Interpreter::new(EngineConfig::default());
     
     if let Ok(Value::Void) = run_test("(define abs (lambda (n) ((if (> n 0) + -) 0 n)))", &mut interpreter) {
     } else {
@@ -162,7 +281,7 @@ fn test_abs() {
 
 #[test]
 fn test_combine() {
-    let mut interpreter = Interpreter::new();
+    let mut interpreter = Interpreter::new(EngineConfig::default());
     
     if let Ok(Value::Void) = run_test("(define combine (lambda (f) \
         (lambda (x y) \
@@ -227,4 +346,2449 @@ fn test_combine() {
     } else {
         panic!("Failed");
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_script_forward_references() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    
+    let tree = parser::parse_source("(begin \
+        (define result (list (even? 10) (odd? 7))) \
+        (define even? (lambda (n) (if (= n 0) #t (odd? (- n 1))))) \
+        (define odd? (lambda (n) (if (= n 0) #f (even? (- n 1))))) \
+        result)".to_string());
+    if let Ok(tree) = tree {
+        if let Ok(val) = interpreter.eval_script(tree) {
+            assert_eq!(format!("{}", val), "(#t #t)");
+        } else {
+            panic!("Failed");
+        }
+    } else {
+        panic!("Failed");
+    }
+    
+    if let Ok(Value::Int(3)) = run_test("(+ (length result) 1)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // The same, without the begin
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    let tree = parser::parse_source("(define result (even? 4)) \
+        (define (even? n) (if (= n 0) #t (odd? (- n 1)))) \
+        (define (odd? n) (if (= n 0) #f (even? (- n 1)))) \
+        result".to_string());
+    if let Ok(tree) = tree {
+        if let Ok(Value::Bool(true)) = interpreter.eval_script(tree) {
+        } else {
+            panic!("Failed");
+        }
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_multiple_forms() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(3)) = run_test("(define a 1) (define b 2) (+ a b)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_map() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    
+    if let Ok(Value::Void) = run_test("(define fact (lambda (n) (if (<= n 1) 1 (* n (fact (- n 1))))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    
+    if let Ok(val) = run_test("(map fact (list 1 2 3 4))", &mut interpreter) {
+        assert_eq!(format!("{}", val), "(1 2 6 24)");
+    } else {
+        panic!("Failed");
+    }
+    
+    if let Ok(val) = run_test("(map car (list (list 1) (list 2 3)))", &mut interpreter) {
+        assert_eq!(format!("{}", val), "(1 2)");
+    } else {
+        panic!("Failed");
+    }
+    
+    // Errors in the mapped function are reported rather than dropped
+    if let Err(err) = run_test("(map car (list (list 1) 2 (list 3)))", &mut interpreter) {
+        assert_eq!(err.message, "Invalid type for 'car'");
+    } else {
+        panic!("Failed");
+    }
+    
+    if let Ok(Value::Void) = run_test("(for-each fact (list 1 2 3))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_evaluation_order() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    
+    // Arguments are evaluated left to right, so the first failing argument is the one reported
+    if let Err(err) = run_test("(list (car 1) (cdr 2))", &mut interpreter) {
+        assert_eq!(err.message, "Invalid type for 'car'");
+    } else {
+        panic!("Failed");
+    }
+    
+    if let Err(err) = run_test("((lambda (x y) (+ x y)) (cdr 1) (car 2))", &mut interpreter) {
+        assert_eq!(err.message, "Invalid type for 'cdr'");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_string_builder() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    
+    if let Ok(Value::Void) = run_test("(define sb (make-string-builder \"n:\"))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    
+    if let Ok(Value::Void) = run_test("(for-each (lambda (n) (sb-add! sb \",\" n)) (list 1 2 3))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    
+    if let Ok(Value::String(val)) = run_test("(sb->string sb)", &mut interpreter) {
+        assert_eq!(val, "n:,1,2,3");
+    } else {
+        panic!("Failed");
+    }
+    
+    if let Err(err) = run_test("(sb-add! \"not a builder\" 1)", &mut interpreter) {
+        assert_eq!(err.message, "Invalid type for 'sb-add!'");
+    } else {
+        panic!("Failed");
+    }
+}
+
+// Printing a number and reading it back should give the same number of the same type
+#[test]
+fn test_number_round_trip() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    for &n in &[0, 1, -1, 255, 256, i32::MAX, i32::MAX - 1, i32::MIN, i32::MIN + 1] {
+        if let Ok(Value::Int(val)) = run_test(&Value::Int(n).to_string(), &mut interpreter) {
+            assert_eq!(val, n);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    let floats = [0.0, -0.0, 1.0, -1.0, 0.5, 0.1, 2.0, 1e-7, 1e21, 1e300, 2147483648.0, -2147483649.0,
+                  f64::MAX, f64::MIN, f64::MIN_POSITIVE, f64::EPSILON, f64::INFINITY, f64::NEG_INFINITY];
+    for &n in &floats {
+        if let Ok(Value::Float(val)) = run_test(&Value::Float(n).to_string(), &mut interpreter) {
+            assert_eq!(val.to_bits(), n.to_bits());
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    if let Ok(Value::Float(val)) = run_test(&Value::Float(f64::NAN).to_string(), &mut interpreter) {
+        assert!(val.is_nan());
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_comments() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Void) = run_test("(define x 1) ; meaning of life", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(3)) = run_test("; add them up\n(+ x ; the first\n   2) ; the second", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::String(val)) = run_test("\"a ; b\" ; not part of the string", &mut interpreter) {
+        assert_eq!(val, "a ; b");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_block_and_datum_comments() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(3)) = run_test("(+ 1 #| one |# 2)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(3)) = run_test("#| outer #| inner |# (still a comment) |#\n(+ 1 2)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(6)) = run_test("(+ 1 #;(* 100 100) 2 #; 50 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::String(val)) = run_test("\"#| not a comment |#\"", &mut interpreter) {
+        assert_eq!(val, "#| not a comment |#");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(+ 1 #;)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_partial_and_curry() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(6)) = run_test("((partial + 1 2) 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Some(vals) = run_test("(map (partial * 2) (list 1 2 3))", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        if let [Value::Int(2), Value::Int(4), Value::Int(6)] = vals[..] {
+        } else {
+            panic!("Failed");
+        }
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Void) = run_test("(define add3 (lambda (a b c) (+ a (* 10 b) (* 100 c))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(321)) = run_test("((curry add3 1) 2 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(321)) = run_test("((((curry add3) 1) 2) 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(321)) = run_test("(curry add3 1 2 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(321)) = run_test("((partial (curry add3 1) 2) 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_compose_identity_const() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Void) = run_test("(define twice (lambda (x) (* 2 x)))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(11)) = run_test("((compose (partial + 1) twice) 5)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(41)) = run_test("((compose (partial + 1) twice twice twice) 5)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(12)) = run_test("((compose twice +) 1 2 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(5)) = run_test("((compose) 5)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Some(vals) = run_test("(map identity (list 1 2))", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        if let [Value::Int(1), Value::Int(2)] = vals[..] {
+        } else {
+            panic!("Failed");
+        }
+    } else {
+        panic!("Failed");
+    }
+
+    if let Some(vals) = run_test("(map (const 0) (list 1 2))", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        if let [Value::Int(0), Value::Int(0)] = vals[..] {
+        } else {
+            panic!("Failed");
+        }
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_lexer() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::String(val)) = run_test("\"(not a list) ; or a comment\"", &mut interpreter) {
+        assert_eq!(val, "(not a list) ; or a comment");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::String(val)) = run_test("\"tab\\tquote\\\"  backslash\\\\ newline\\n\"", &mut interpreter) {
+        assert_eq!(val, "tab\tquote\"  backslash\\ newline\n");
+        assert_eq!(Value::String(val).to_string(), "\"tab\\tquote\\\"  backslash\\\\ newline\\n\"");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(6)) = run_test("(+(* 2 2)(- 3 1))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Some(vals) = run_test("(list '(1 2) 3)", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        assert_eq!(vals.len(), 2);
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("\"unterminated", &mut interpreter).is_err());
+    assert!(run_test("#| unterminated", &mut interpreter).is_err());
+    assert!(run_test("\"bad \\q escape\"", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_bench() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Some(stats) = run_test("(bench (lambda () (* 6 7)) 10)", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        let times: Vec<f64> = stats.iter().map(|entry| match entry.list_elements().as_deref() {
+            Some([Value::Literal(_), Value::Float(time)]) => *time,
+            _                                             => panic!("Failed")
+        }).collect();
+        assert_eq!(times.len(), 3);
+        assert!(times[0] <= times[1] && times[1] <= times[2]);
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(bench (lambda () 1) 0)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_error_locations() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Err(err) = run_test("(+ 1\n   (car 5))", &mut interpreter) {
+        assert_eq!(err.to_string(), "EvalError at line 2, col 4: Invalid type for 'car'");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Void) = run_test("(define f (lambda (x)\n  (car x)))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("(f 1)", &mut interpreter) {
+        assert_eq!(err.to_string(), "EvalError at line 2, col 3: Invalid type for 'car'");
+    } else {
+        panic!("Failed");
+    }
+
+    match parser::tokenize("(list 1\n  \"oops)".to_string()) {
+        Err(err) => assert_eq!(err.to_string(), "ParseError at line 2, col 3: Unterminated string"),
+        Ok(_)    => panic!("Failed")
+    }
+
+    match parser::parse_source("(+ 1 2))".to_string()) {
+        Err(errors) => assert_eq!(errors[0].to_string(), "ParseError at line 1, col 8: Unexpected close paren"),
+        Ok(_)       => panic!("Failed")
+    }
+}
+
+#[test]
+fn test_try() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(3)) = run_test("(try (+ 1 2) (lambda (err) 0))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::String(val)) = run_test("(try (car 5) (lambda (err) err))", &mut interpreter) {
+        assert_eq!(val, "Invalid type for 'car'");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::String(val)) = run_test("(try (error \"bad value:\" 42 \"x\") (lambda (err) err))", &mut interpreter) {
+        assert_eq!(val, "bad value: 42 \"x\"");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("(error \"uncaught\")", &mut interpreter) {
+        assert_eq!(err.message, "uncaught");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("(try (car 5) (lambda (err) (error \"handler failed\")))", &mut interpreter) {
+        assert_eq!(err.message, "handler failed");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_shuffle_and_sample() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    let ints = |val: Value| -> Vec<i32> {
+        match val.list_elements() {
+            Some(vals) => vals.iter().map(|val| if let Value::Int(n) = *val { n } else { panic!("Failed") }).collect(),
+            None       => panic!("Failed")
+        }
+    };
+
+    run_test("(random-seed! 7)", &mut interpreter).ok().expect("Failed");
+    let first = ints(run_test("(shuffle (list 1 2 3 4 5 6 7 8))", &mut interpreter).ok().expect("Failed"));
+    let mut sorted = first.clone();
+    sorted.sort();
+    assert_eq!(sorted, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+    // The same seed gives the same shuffle
+    run_test("(random-seed! 7)", &mut interpreter).ok().expect("Failed");
+    assert_eq!(ints(run_test("(shuffle (list 1 2 3 4 5 6 7 8))", &mut interpreter).ok().expect("Failed")), first);
+
+    let sample = ints(run_test("(sample (list 1 2 3 4 5 6 7 8) 3)", &mut interpreter).ok().expect("Failed"));
+    assert_eq!(sample.len(), 3);
+    assert!(sample.iter().all(|n| (1..=8).contains(n)));
+    assert!(sample[0] != sample[1] && sample[1] != sample[2] && sample[0] != sample[2]);
+
+    assert!(run_test("(sample (list 1 2) 3)", &mut interpreter).is_err());
+
+    if let Ok(Value::Int(n)) = run_test("(random 10)", &mut interpreter) {
+        assert!((0..10).contains(&n));
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_quote() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Literal(val)) = run_test("'foo", &mut interpreter) {
+        assert_eq!(val, "foo");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Some(vals) = run_test("(list 'a'b '(1 2)'())", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        assert_eq!(vals.len(), 4);
+        assert_eq!(Value::list(vals).to_string(), "(a b (1 2) ())");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(3)) = run_test("(+ '1 (car '(2 x)))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::String(val)) = run_test("'\"text\"", &mut interpreter) {
+        assert_eq!(val, "text");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Bool(true)) = run_test("'#t", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(val) = run_test("''x", &mut interpreter) {
+        assert_eq!(val.to_string(), "(quote x)");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_print() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::String(val)) = run_test("(->string (list 1 \"two\" 'three))", &mut interpreter) {
+        assert_eq!(val, "(1 \"two\" three)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::String(val)) = run_test("(->string \"plain\")", &mut interpreter) {
+        assert_eq!(val, "plain");
+    } else {
+        panic!("Failed");
+    }
+
+    let (result, output) = port::capture(|| run_test("(begin (print \"x is\" 42 (list 1 2)) (print))", &mut interpreter));
+    if let Ok(Value::Void) = result {
+        assert_eq!(output, "x is 42 (1 2)\n\n");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::String(val)) = run_test("(with-output-to-string (lambda () (print 'a \"b\")))", &mut interpreter) {
+        assert_eq!(val, "a b\n");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_chars() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    for &(source, expected) in &[("#\\a", 'a'), ("#\\space", ' '), ("#\\newline", '\n'), ("#\\x41", 'A'), ("#\\(", '('), ("#\\x", 'x')] {
+        if let Ok(Value::Char(c)) = run_test(source, &mut interpreter) {
+            assert_eq!(c, expected);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    if let Some(vals) = run_test("(list #\\a #\\space #\\) #\\newline)", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        assert_eq!(Value::list(vals).to_string(), "(#\\a #\\space #\\) #\\newline)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Bool(true)) = run_test("(equal? #\\b (car '(#\\b)))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("#\\bogus", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_void() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Void) = run_test("(void)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Some(vals) = run_test("(list (void? (void)) (void? (define x 1)) (void? '()) (void? 0))", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        if let [Value::Bool(true), Value::Bool(true), Value::Bool(false), Value::Bool(false)] = vals[..] {
+        } else {
+            panic!("Failed");
+        }
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_vectors() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Vector(vals)) = run_test("#(1 (2 3) x \"s\")", &mut interpreter) {
+        assert_eq!(vals.len(), 4);
+        assert_eq!(Value::Vector(vals).to_string(), "#(1 (2 3) x \"s\")");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(3)) = run_test("(vector-ref #(1 2 3) 2)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(2)) = run_test("(vector-length (vector (+ 1 1) #()))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Some(vals) = run_test("(vector->list (list->vector (list 1 2)))", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        assert_eq!(vals.len(), 2);
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(vector-ref #(1) 1)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_pairs() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Some((vals, tail)) = run_test("'(1 2 . 3)", &mut interpreter).ok().as_ref().and_then(Value::list_parts) {
+        assert_eq!(vals.len(), 2);
+        assert_eq!(Value::dotted(vals, tail).to_string(), "(1 2 . 3)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Some(vals) = run_test("'(1 . (2 3))", &mut interpreter).ok().as_ref().and_then(Value::list_elements) {
+        assert_eq!(vals.len(), 3);
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(2)) = run_test("(cdr (cons 1 2))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(val) = run_test("(cdr '(a b . c))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(b . c)");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("'(1 . 2 3)", &mut interpreter).is_err());
+    assert!(run_test("'(. 1)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_mutable_pairs() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // A list shares the tail it was consed onto, so changing the tail changes both
+    assert!(run_test("(define xs (list 1 2 3))", &mut interpreter).is_ok());
+    assert!(run_test("(define ys (cons 0 (cdr xs)))", &mut interpreter).is_ok());
+    assert!(run_test("(set-car! (cdr xs) 20)", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(list xs ys)", &mut interpreter) {
+        assert_eq!(val.to_string(), "((1 20 3) (0 20 3))");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Bool(true)) = run_test("(eqv? (cdr xs) (cdr ys))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(val) = run_test("(let ((p (list 1 2))) (set-cdr! (cdr p) 3) p)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 2 . 3)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(val) = run_test("(append '(1 2) 3)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 2 . 3)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Bool(false)) = run_test("(list? (cons 1 2))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // A circular list prints with a label where it comes back round, and isn't a proper list
+    assert!(run_test("(set-cdr! (cdr (cdr xs)) xs)", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("xs", &mut interpreter) {
+        assert_eq!(val.to_string(), "#0=(1 20 3 . #0#)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Bool(false)) = run_test("(list? xs)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(length xs)", &mut interpreter).is_err());
+    assert!(run_test("(set-car! '() 1)", &mut interpreter).is_err());
+    assert!(run_test("(car '())", &mut interpreter).is_err());
+
+    // A long list is dropped without recursing once per pair
+    let long = Value::list(vec![Value::Int(7); 1_000_000]);
+    drop(long);
+}
+
+#[test]
+fn test_keyword_arguments() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define (scale x #:by (factor 2) #:offset offset) (+ (* x factor) offset))", &mut interpreter).is_ok());
+
+    if let Ok(Value::Int(7)) = run_test("(scale 3 #:offset 1)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(11)) = run_test("(scale #:offset 2 3 #:by 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Keyword(name)) = run_test("#:by", &mut interpreter) {
+        assert_eq!(name, "by");
+    } else {
+        panic!("Failed");
+    }
+
+    // offset has no default, and there is no #:size
+    assert!(run_test("(scale 3)", &mut interpreter).is_err());
+    assert!(run_test("(scale 3 #:offset 1 #:size 2)", &mut interpreter).is_err());
+    assert!(run_test("(scale 3 #:offset)", &mut interpreter).is_err());
+
+    // A default is evaluated where the procedure was defined, after the parameters before it
+    let source = "(define y 10)
+                  (define (f #:x (x y)) x)
+                  (define (g) (let ((y 99)) (f)))
+                  (g)";
+    if let Ok(Value::Int(10)) = run_test(source, &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(6)) = run_test("((lambda (a #:b (b (* a 2))) b) 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_quoted_constants() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define (table) '(1 2 3))", &mut interpreter).is_ok());
+    // Every call returns the same list rather than building a new one
+    if let (Ok(Value::Pair(first)), Ok(Value::Pair(second))) = (run_test("(table)", &mut interpreter), run_test("(table)", &mut interpreter)) {
+        assert!(Shared::ptr_eq(&first, &second));
+    } else {
+        panic!("Failed");
+    }
+
+    // Parameters aren't substituted into quoted data
+    if let Ok(val) = run_test("((lambda (x) '(x y)) 5)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(x y)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(val) = run_test("(car ''a)", &mut interpreter) {
+        assert_eq!(val.to_string(), "quote");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_radix_literals() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    for &(source, n) in &[("#x1F", 31), ("#XfF", 255), ("#o17", 15), ("#b1010", 10), ("#x-1F", -31), ("#b-1", -1), ("#d42", 42)] {
+        if let Ok(Value::Int(val)) = run_test(source, &mut interpreter) {
+            assert_eq!(val, n);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    assert!(run_test("#b102", &mut interpreter).is_err());
+    assert!(run_test("#x", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_destructuring() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define-values (a b c) (values 1 2 3))", &mut interpreter).is_ok());
+    if let Ok(Value::Int(6)) = run_test("(+ a b c)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(val) = run_test("(let-list ((x y . rest) (list 1 2 3 4)) (cons (+ x y) rest))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(3 3 4)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(val) = run_test("(let-list ((x . rest) '(1)) rest)", &mut interpreter) {
+        assert_eq!(val.to_string(), "()");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(define-values (p q) (values 1 2 3))", &mut interpreter).is_err());
+    assert!(run_test("(let-list ((x y . rest) (list 1)) x)", &mut interpreter).is_err());
+
+    // The names can be a procedure's parameters too
+    if let Ok(val) = run_test("(define (f x) (let-list ((x y) (list (+ x 4) 6)) (list x y))) (f 1)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(5 6)");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(define (g x) (define-values (x y) (values (+ x 4) 6)) (list x y)) (g 1)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(5 6)");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_exponents_and_exactness() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    for &(source, n) in &[("1e-3", 0.001), ("2.5E2", 250.0), ("-1e3", -1000.0), ("#i5", 5.0), ("#i#x10", 16.0), ("#x#i10", 16.0)] {
+        if let Ok(Value::Float(val)) = run_test(source, &mut interpreter) {
+            assert_eq!(val, n);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    for &(source, n) in &[("#e1e3", 1000), ("#e2.0", 2), ("#e-4", -4), ("#e#b101", 5)] {
+        if let Ok(Value::Int(val)) = run_test(source, &mut interpreter) {
+            assert_eq!(val, n);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    if let Ok(Value::Rational(3, 2)) = run_test("#e1.5", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("#e#i1", &mut interpreter).is_err());
+    assert!(run_test("#x1.5", &mut interpreter).is_err());
+
+    // The prefixes work on fractions too
+    for &(source, expected) in &[("#i1/4", "0.25"), ("#i-3/2", "-1.5"), ("#e1/3", "1/3"), ("#e6/3", "2"), ("#x1/A", "1/10"), ("#b#e-1/10", "-1/2")] {
+        if let Ok(val) = run_test(source, &mut interpreter) {
+            assert_eq!(val.to_string(), expected);
+        } else {
+            panic!("Failed");
+        }
+    }
+    assert!(run_test("#i1/0", &mut interpreter).is_err());
+    assert!(run_test("#x1/-2", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_rational_literals() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    for &(source, expected) in &[("1/3", "1/3"), ("-7/2", "-7/2"), ("6/4", "3/2"), ("-2/4", "-1/2"), ("#e1.25", "5/4"), ("'(1/2 x)", "(1/2 x)")] {
+        if let Ok(val) = run_test(source, &mut interpreter) {
+            assert_eq!(val.to_string(), expected);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    if let Ok(Value::Int(2)) = run_test("4/2", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Bool(true)) = run_test("(= 2/6 1/3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("1/0", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_printer() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // Neither a long list nor a deeply nested one overflows the stack
+    let long = Value::list(vec![Value::Int(7); 100_000]);
+    assert_eq!(long.to_string().len(), 200_001);
+    let mut deep = Value::Int(0);
+    for _ in 0..10_000 {
+        deep = Value::list(vec![deep]);
+    }
+    assert_eq!(deep.to_string().len(), 20_001);
+    // Dropping it still recurses, once per level
+    std::mem::forget(deep);
+
+    assert!(run_test("(print-length 2)", &mut interpreter).is_ok());
+    assert!(run_test("(print-depth 2)", &mut interpreter).is_ok());
+    let (_, output) = port::capture(|| run_test("(write '(1 (2 (3)) 4) '(a . b) #(1 2 3) \"s\")", &mut interpreter));
+    assert_eq!(output, "(1 (2 ...) ...) (a . b) #(1 2 ...) \"s\"\n");
+
+    if let Ok(Value::Int(2)) = run_test("(print-length)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(print-length #f)", &mut interpreter).is_ok());
+    assert!(run_test("(print-depth 0)", &mut interpreter).is_ok());
+    let (_, output) = port::capture(|| run_test("(write '(1 2 3) 4)", &mut interpreter));
+    assert_eq!(output, "... 4\n");
+
+    // Limits don't affect anything but write and the REPL
+    if let Ok(val) = run_test("'(1 (2 3))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 (2 3))");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(print-depth #f)", &mut interpreter).is_ok());
+}
+
+#[test]
+fn test_environment_rollback() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    interpreter.env.start_journal();
+
+    assert!(run_test("(define x 1)", &mut interpreter).is_ok());
+    let mark = interpreter.env.mark();
+    assert!(run_test("(begin (define local 0) (set! x 2))", &mut interpreter).is_ok());
+    assert!(run_test("(set! x 2)", &mut interpreter).is_ok());
+    assert!(run_test("(define y 3)", &mut interpreter).is_ok());
+    interpreter.env.rollback(mark);
+
+    if let Ok(Value::Int(1)) = run_test("x", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("y", &mut interpreter) {
+        assert_eq!(err.message, "Unbound variable y");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_shebang() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(3)) = run_test("#!/usr/bin/env rscheme\n(+ 1 2)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    match parser::tokenize("#!/usr/bin/env rscheme\n(list 1\n  \"oops)".to_string()) {
+        Err(err) => assert_eq!(err.to_string(), "ParseError at line 3, col 3: Unterminated string"),
+        Ok(_)    => panic!("Failed")
+    }
+}
+
+#[test]
+fn test_unicode_escapes() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::String(val)) = run_test("\"\\u00e9t\\u00E9 \\u03bb\\r\"", &mut interpreter) {
+        assert_eq!(val, "été λ\r");
+        assert_eq!(Value::String(val).to_string(), "\"été λ\\r\"");
+    } else {
+        panic!("Failed");
+    }
+
+    // Control characters without a short escape are written back with \u
+    if let Ok(val) = run_test("\"bell\\u0007\"", &mut interpreter) {
+        assert_eq!(val.to_string(), "\"bell\\u0007\"");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("\"\\u12\"", &mut interpreter).is_err());
+    assert!(run_test("\"\\ud800\"", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_square_brackets() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(3)) = run_test("[+ 1 (car [list 2 3])]", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(val) = run_test("(let-list ([a . rest] '[1 2]) rest)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(2)");
+    } else {
+        panic!("Failed");
+    }
+
+    match parser::parse_source("(list 1 [2 3)]".to_string()) {
+        Err(errors) => assert_eq!(errors[0].to_string(), "ParseError at line 1, col 13: Expected ']' but found ')'"),
+        Ok(_)       => panic!("Failed")
+    }
+
+    assert!(run_test("(+ 1 2]", &mut interpreter).is_err());
+    assert!(run_test("1]", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_datum_labels() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(val) = run_test("'(#0=(1 2) #0# #1=x #1#)", &mut interpreter) {
+        assert_eq!(val.to_string(), "((1 2) (1 2) x x)");
+    } else {
+        panic!("Failed");
+    }
+
+    // References share the labelled list rather than copying it
+    let (_, output) = port::capture(|| run_test("(write-shared '(#0=(1 #1=(2)) #1# #0#) '(1 2))", &mut interpreter));
+    assert_eq!(output, "(#0=(1 #1=(2)) #1# #0#) (1 2)\n");
+
+    let (_, output) = port::capture(|| run_test("(begin (define x '(a)) (write-shared (list x (list x) #(x))) (write x))", &mut interpreter));
+    assert_eq!(output, "(#0=(a) (#0#) #(x))\n(a)\n");
+
+    // Labels only last until the end of the top-level datum that defines them
+    assert!(run_test("'#0=(1)\n'#0#", &mut interpreter).is_err());
+    assert!(run_test("'#0=(1 #0#)", &mut interpreter).is_err());
+    assert!(run_test("'#0(1)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_parse_errors() {
+    // A list that's never closed is reported where it was opened
+    match parser::parse_source("(list 1\n  (+ 2 3)".to_string()) {
+        Err(errors) => assert_eq!(errors[0].to_string(), "ParseError at line 1, col 1: Unexpected end of input: expected ')' to close this list"),
+        Ok(_)       => panic!("Failed")
+    }
+
+    // Parsing carries on after an error, so every mistake is reported at once
+    let source = "(define x (car [1 2)))\n(define y 'x)\n(define z (list 1 2\n(display #\\nosuch)\n)\n(car (list 1)";
+    match parser::parse_source(source.to_string()) {
+        Err(errors) => {
+            let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+            assert_eq!(messages, vec![
+                "ParseError at line 1, col 20: Expected ']' but found ')'",
+                "ParseError at line 4, col 10: Unknown character #\\nosuch",
+                "ParseError at line 5, col 1: Unexpected close paren",
+                "ParseError at line 6, col 1: Unexpected end of input: expected ')' to close this list"
+            ]);
+            assert_eq!(errors[1].describe(source), "ParseError at line 4, col 10: Unknown character #\\nosuch\n  (display #\\nosuch)\n           ^");
+        },
+        Ok(_)       => panic!("Failed")
+    }
+
+    match parser::parse_source("  ; nothing here".to_string()) {
+        Err(errors) => assert_eq!(errors[0].describe("  ; nothing here"), "ParseError: Unexpected end of input: expected a form"),
+        Ok(_)       => panic!("Failed")
+    }
+}
+
+#[test]
+fn test_run_budgeted() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    assert!(run_test("(define count (lambda (i n) (if (= i n) i (count (+ i 1) n))))", &mut interpreter).is_ok());
+
+    let form = parser::parse_source("(count 0 1000)".to_string()).ok().and_then(|mut nodes| nodes.pop());
+    let mut state = match form {
+        Some(form) => interpreter.run_budgeted(form, 100),
+        None       => panic!("Failed")
+    };
+    let mut slices = 1;
+    let val = loop {
+        match state {
+            Budgeted::Done(val)       => break val,
+            Budgeted::Pending(pending) => {
+                slices += 1;
+                state = interpreter.resume_budgeted(pending, 100);
+            }
+        }
+    };
+    if let Ok(Value::Int(1000)) = val {
+    } else {
+        panic!("Failed");
+    }
+    assert!(slices > 10);
+
+    // A form that finishes within its budget is done straight away
+    let form = parser::parse_source("(+ 1 2)".to_string()).ok().and_then(|mut nodes| nodes.pop());
+    if let Some(Budgeted::Done(Ok(Value::Int(3)))) = form.map(|form| interpreter.run_budgeted(form, 100)) {
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_parse_incremental() {
+    for source in &["(define (f x)", "(list 1\n  [2 3]", "\"abc", "#| comment", "'", "(f 1) #;", ""] {
+        if let parser::Incremental::NeedMoreInput = parser::parse_incremental(source) {
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    if let parser::Incremental::Complete(nodes) = parser::parse_incremental("(define (f x)\n  (* x 2)) (f 2)") {
+        assert_eq!(nodes.len(), 2);
+    } else {
+        panic!("Failed");
+    }
+
+    // A mistake can't be fixed by typing more, even if the input is unfinished as well
+    for source in &["(list 1 2]", "(car ')", "(list 1 #\\nosuch", "1)"] {
+        if let parser::Incremental::Errors(_) = parser::parse_incremental(source) {
+        } else {
+            panic!("Failed");
+        }
+    }
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_sync() {
+    use std::thread;
+
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    assert!(run_test("(define square (lambda (x) (* x x)))", &mut interpreter).is_ok());
+    let shared = match run_test("(list 1 2 3)", &mut interpreter) {
+        Ok(val) => Shared::new(val),
+        Err(_)  => panic!("Failed")
+    };
+
+    // Each thread gets its own interpreter with the same definitions, and they all share one list
+    let handles: Vec<_> = (0..4).map(|i| {
+        let mut interpreter = interpreter.clone();
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let result = run_test(&format!("(square {})", i), &mut interpreter).ok();
+            (result.map(|val| val.to_string()), shared.to_string())
+        })
+    }).collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        match handle.join() {
+            Ok((Some(result), list)) => {
+                assert_eq!(result, (i * i).to_string());
+                assert_eq!(list, "(1 2 3)");
+            },
+            _                        => panic!("Failed")
+        }
+    }
+}
+
+#[test]
+fn test_keywords() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(val) = run_test("(list #:sep (keyword? #:sep) (keyword? 'sep) (keyword->string #:sep))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#:sep #t #f \"sep\")");
+    } else {
+        panic!("Failed");
+    }
+
+    // Keywords are their own kind of value, not symbols with a funny name
+    if let Ok(Value::Bool(false)) = run_test("(equal? #:sep 'sep)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Bool(true)) = run_test("(equal? (string->keyword \"sep\") #:sep)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(keyword->string 'sep)", &mut interpreter).is_err());
+    assert!(run_test("(string->keyword \"\")", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_engine_config() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+    if let Ok(val) = run_test("(list (abs -3) (filter (lambda (x) (> x 1)) (list 1 2 3)) (fold + 0 (list 1 2 3)) (reverse (list 1 2 3)))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(3 (2 3) 6 (3 2 1))");
+    } else {
+        panic!("Failed");
+    }
+    // Everything but #f counts as true, unless booleans are strict
+    if let Ok(val) = run_test("(list (if 0 'yes 'no) (if #f 'yes 'no) (and 1 2) (or #f 3) (not 4) (cond ((list) 'empty)) (when 0 'when))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(yes no 2 3 #f empty when)");
+    } else {
+        panic!("Failed");
+    }
+    let mut interpreter = Interpreter::new(EngineConfig { strict_booleans: true, ..EngineConfig::default() });
+    for source in &["(if 1 2 3)", "(and 1 2)", "(or 1 2)", "(not 4)", "(cond (1 2))", "(when 0 1)"] {
+        if let Err(err) = run_test(source, &mut interpreter) {
+            assert!(err.message.ends_with("requires a boolean test"));
+        } else {
+            panic!("Failed");
+        }
+    }
+    if let Ok(Value::Int(2)) = run_test("(if #t 2 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // Each evaluation gets the full number of steps
+    let mut interpreter = Interpreter::new(EngineConfig { max_steps: Some(1000), ..EngineConfig::default() });
+    assert!(run_test("(define (loop n) (if (= n 0) 0 (loop (- n 1))))", &mut interpreter).is_ok());
+    assert!(run_test("(loop 10)", &mut interpreter).is_ok());
+    assert!(run_test("(loop 10)", &mut interpreter).is_ok());
+    if let Err(err) = run_test("(loop 1000)", &mut interpreter) {
+        assert_eq!(err.message, "Exceeded the limit of 1000 steps");
+    } else {
+        panic!("Failed");
+    }
+
+    let mut interpreter = Interpreter::new(EngineConfig { float_precision: Some(2), ..EngineConfig::default() });
+    let (_, output) = port::capture(|| run_test("(print 3.14159 (list 0.5 2))", &mut interpreter));
+    assert_eq!(output, "3.14 (0.50 2)\n");
+
+    let mut interpreter = Interpreter::new(EngineConfig { load_prelude: false, sandbox: true, ..EngineConfig::default() });
+    assert!(run_test("(reverse (list 1 2))", &mut interpreter).is_err());
+    assert!(run_test("(print 1)", &mut interpreter).is_err());
+    if let Ok(Value::Int(3)) = run_test("(+ 1 2)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_special_forms() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // A procedure's arguments are all evaluated, left to right, before it's called
+    if let Ok(val) = run_test("(with-output-to-string (lambda () (list (print 1) (print 2))))", &mut interpreter) {
+        assert_eq!(val.to_string(), "\"1\\n2\\n\"");
+    } else {
+        panic!("Failed");
+    }
+
+    // A special form only evaluates the operands it needs
+    if let Ok(Value::Int(1)) = run_test("(if #t 1 (error \"not evaluated\"))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // Special forms aren't procedures, so they can't be passed to one
+    if let Ok(val) = run_test("(list + if)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(+ if)");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(map quote (list 1 2))", &mut interpreter).is_err());
+    assert!(run_test("(partial define 'x)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_import() {
+    let mut interpreter = Interpreter::new(EngineConfig { import_all: false, ..EngineConfig::default() });
+
+    // Only (scheme base) and the prelude are there to begin with
+    if let Ok(val) = run_test("(list (+ 1 2) (abs -1))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(3 1)");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(sqrt 4)", &mut interpreter).is_err());
+
+    if let Ok(Value::Float(val)) = run_test("(import (toys math) (toys string)) (sqrt 4)", &mut interpreter) {
+        assert_eq!(val, 2.0);
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::String(val)) = run_test("(->string pi)", &mut interpreter) {
+        assert!(val.starts_with("3.14"));
+    } else {
+        panic!("Failed");
+    }
+
+    // An unknown library stops the whole import
+    if let Err(err) = run_test("(import (toys vector) (toys nothing))", &mut interpreter) {
+        assert_eq!(err.message, "Unknown library (toys nothing)");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(vector 1 2)", &mut interpreter).is_err());
+
+    // A sandboxed interpreter can import (toys io), but doesn't get print from it
+    let mut interpreter = Interpreter::new(EngineConfig { import_all: false, sandbox: true, ..EngineConfig::default() });
+    assert!(run_test("(import (toys io)) (print 1)", &mut interpreter).is_err());
+    if let Ok(Value::String(val)) = run_test("(with-output-to-string (lambda () 1))", &mut interpreter) {
+        assert_eq!(val, "");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_define_library() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // Procedures from a library see its other definitions, even ones it doesn't export and ones
+    // the importer shadows, and can change them
+    let source = "(define-library (my counter)
+                    (export next! (rename next! tick!))
+                    (begin
+                      (define count 0)
+                      (define (step n) (+ n 1))
+                      (define (next!) (begin (set! count (step count)) count))))
+                  (import (my counter))
+                  (define step 'shadowed)
+                  (list (next!) (tick!) (next!) step)";
+    if let Ok(val) = run_test(source, &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 2 3 shadowed)");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("count", &mut interpreter).is_err());
+
+    // Calls in tail position stay tail calls
+    let source = "(define-library (my loop)
+                    (export count-down)
+                    (begin (define (count-down n) (if (= n 0) 'done (count-down (- n 1))))))
+                  (import (my loop))
+                  (count-down 100000)";
+    if let Ok(val) = run_test(source, &mut interpreter) {
+        assert_eq!(val.to_string(), "done");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("(define-library (my broken) (export missing))", &mut interpreter) {
+        assert_eq!(err.message, "(my broken) exports missing, which it doesn't define");
+    } else {
+        panic!("Failed");
+    }
+
+    // A library that hasn't been defined is loaded from its file on the library path
+    let dir = std::env::temp_dir().join(format!("rscheme-libraries-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("shapes")).unwrap();
+    std::fs::write(dir.join("shapes").join("square.sld"),
+        "(define-library (shapes square) (export area) (import (toys math)) (begin (define (area s) (pow s 2))))").unwrap();
+    let config = EngineConfig { library_path: vec![dir.to_string_lossy().to_string()], ..EngineConfig::default() };
+    let mut interpreter = Interpreter::new(config);
+    if let Ok(val) = run_test("(import (shapes square)) (area 3)", &mut interpreter) {
+        assert_eq!(val.to_string(), "9");
+    } else {
+        panic!("Failed");
+    }
+    if let Err(err) = run_test("(import (shapes circle))", &mut interpreter) {
+        assert_eq!(err.message, "Unknown library (shapes circle)");
+    } else {
+        panic!("Failed");
+    }
+
+    // With a cache directory, a library file's forms are cached the first time it's read and
+    // read back from there after that, until the file changes
+    let cache_dir = dir.join("cache").to_string_lossy().to_string();
+    let config = EngineConfig { library_path: vec![dir.to_string_lossy().to_string()], cache_dir: Some(cache_dir.clone()), ..EngineConfig::default() };
+    for _ in 0..2 {
+        let mut interpreter = Interpreter::new(config.clone());
+        if let Ok(val) = run_test("(import (shapes square)) (area 4)", &mut interpreter) {
+            assert_eq!(val.to_string(), "16");
+        } else {
+            panic!("Failed");
+        }
+        assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 1);
+    }
+    std::fs::write(dir.join("shapes").join("square.sld"),
+        "(define-library (shapes square) (export area) (begin (define (area s) (* 2 s s))))").unwrap();
+    let mut interpreter = Interpreter::new(config.clone());
+    if let Ok(val) = run_test("(import (shapes square)) (area 4)", &mut interpreter) {
+        assert_eq!(val.to_string(), "32");
+    } else {
+        panic!("Failed");
+    }
+    // A cache file that's been damaged is ignored
+    for entry in std::fs::read_dir(&cache_dir).unwrap() {
+        std::fs::write(entry.unwrap().path(), "damaged").unwrap();
+    }
+    let mut interpreter = Interpreter::new(config);
+    if let Ok(val) = run_test("(import (shapes square)) (area 4)", &mut interpreter) {
+        assert_eq!(val.to_string(), "32");
+    } else {
+        panic!("Failed");
+    }
+
+    // The cached forms are the ones that were read, quoted data and all
+    let source = "(define (f x) (list x 'sym \"str\" #\\a 1.5 2/3 1+2i #t #:key '(1 (2 . 3) #(4 \"5\")) `(a ,x)))\n(f -7)";
+    let forms = interpreter.read(source, parser::Location { line: 1, col: 1 }).ok().unwrap();
+    cache::store(&cache_dir, source, &forms);
+    let cached = cache::load(&cache_dir, source).unwrap();
+    assert_eq!(cached.iter().map(|form| form.to_string()).collect::<Vec<_>>(), forms.iter().map(|form| form.to_string()).collect::<Vec<_>>());
+    if let Ok(val) = interpreter.eval_script(cached) {
+        assert_eq!(val.to_string(), "(-7 sym \"str\" #\\a 1.5 2/3 1+2i #t #:key (1 (2 . 3) #(4 \"5\")) (a -7))");
+    } else {
+        panic!("Failed");
+    }
+    assert!(cache::load(&cache_dir, "(f 1)").is_none());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_fixnum_arithmetic() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // Two small integers take the fast path, anything else goes to the builtin
+    if let Ok(val) = run_test("(list (+ 2 3) (- 2 3) (* 2 3) (/ 7 2) (< 2 3) (>= 2 3) (= 2 2) (+ 2 0.5) (+ 1 2 3))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(5 -1 6 3 #t #f #t 2.5 6)");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(10000)) = run_test("(define (count i n) (if (< i n) (count (+ i 1) n) i)) (count 0 10000)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // Integer results that don't fit in an i32 are errors, on the fast path or not
+    for source in &["(+ 2147483647 1)", "(- -2147483648 1)", "(* 65536 65536)", "(+ 1 2 2147483647)", "(- 0 -2147483648 1)", "(/ -2147483648 -1)",
+                    "(let loop ((i 0) (acc 0)) (if (= i 100000) acc (loop (+ i 1) (+ acc i))))"] {
+        match run_test(source, &mut interpreter) {
+            Err(err) => assert!(err.message.starts_with("Integer overflow in"), "{}", err.message),
+            Ok(val)  => panic!("{} gave {}", source, val)
+        }
+    }
+}
+
+#[test]
+fn test_set() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // set! changes the existing binding, even from inside a procedure
+    if let Ok(Value::Int(2)) = run_test("(define x 0) (define (inc!) (set! x (+ x 1))) (inc!) (inc!) x", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(5)) = run_test("(begin (set! x 5)) x", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // A parameter can be set! too
+    if let Ok(Value::Int(11)) = run_test("((lambda (n) (begin (set! n (+ n 1)) n)) 10)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // It never makes a new binding
+    if let Err(err) = run_test("(set! never-defined 1)", &mut interpreter) {
+        assert_eq!(err.message, "Can't set! never-defined, which isn't defined");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("never-defined", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_unbound_variables() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // A typo is reported where it's made, by name
+    if let Err(err) = run_test("(+ 1 (* 2 lenght))", &mut interpreter) {
+        assert_eq!(err.message, "Unbound variable lenght");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("(no-such-procedure 1)", &mut interpreter) {
+        assert_eq!(err.message, "Unbound variable no-such-procedure");
+    } else {
+        panic!("Failed");
+    }
+
+    // Quoted symbols are just data
+    if let Ok(val) = run_test("(list 'lenght (quote no-such-procedure))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(lenght no-such-procedure)");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_env_depth() {
+    let mut interpreter = Interpreter::new(EngineConfig { max_env_depth: Some(50), ..EngineConfig::default() });
+
+    // Loops don't nest environments, but nested scopes like generated code can make do
+    if let Ok(Value::Int(1000)) = run_test("(define (count i n) (if (< i n) (count (+ i 1) n) i)) (count 0 1000)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    let nested = |n: usize| "(let () ".repeat(n) + "1" + &")".repeat(n);
+    if let Ok(Value::Int(1)) = run_test(&nested(20), &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    assert!(deepest_env() >= 20);
+
+    if let Err(err) = run_test(&nested(100), &mut interpreter) {
+        assert_eq!(err.message, "Exceeded the limit of 50 nested environments");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_eval_config() {
+    if let Ok(val) = Interpreter::eval_config("; server settings\n(let-list ((host port) '(\"localhost\" 8080)) (list #:host host #:port (+ port 1) #:debug (if (> port 8000) #t #f)))") {
+        assert_eq!(val.to_string(), "(#:host \"localhost\" #:port 8081 #:debug #t)");
+    } else {
+        panic!("Failed");
+    }
+
+    // Nothing that defines, prints or loops is there
+    assert!(Interpreter::eval_config("(define x 1)").is_err());
+    assert!(Interpreter::eval_config("(print 1)").is_err());
+    assert!(Interpreter::eval_config("((lambda (x) x) 1)").is_err());
+    assert!(Interpreter::eval_config("(import (toys io))").is_err());
+    assert!(Interpreter::eval_config("(list 1").is_err());
+}
+
+#[test]
+fn test_define_procedures() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(5)) = run_test("(define (add a b) (+ a b)) (add 2 3)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    // The curried form defines a procedure that returns a procedure
+    if let Ok(Value::Int(7)) = run_test("(define ((adder n) x) (+ n x)) ((adder 3) 4)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // Errors mention the procedure's name, however it's called
+    if let Err(err) = run_test("(map add (list 1 2))", &mut interpreter) {
+        assert_eq!(err.message, "add expects 2 arguments, got 1");
+    } else {
+        panic!("Failed");
+    }
+    if let Err(err) = run_test("(define sub2 (lambda (x) (- x 2))) (define other sub2) (other 1 2)", &mut interpreter) {
+        assert_eq!(err.message, "sub2 expects 1 argument, got 2");
+    } else {
+        panic!("Failed");
+    }
+    // And a defined procedure prints as its name
+    if let Ok(val) = run_test("(list other (lambda (x) x))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#<procedure sub2> (lambda (x) (x)))");
+    } else {
+        panic!("Failed");
+    }
+
+    // A define with nothing to bind is an error rather than a crash
+    for source in &["(define (f))", "(define x)", "(define)", "(define () 1)"] {
+        assert!(run_test(source, &mut interpreter).is_err());
+    }
+}
+
+#[test]
+fn test_value_accessors() {
+    let config = match Interpreter::eval_config("(list #:host \"localhost\" #:port 8080 #:limits '((rate 1/2) (burst . 10)))") {
+        Ok(val) => val,
+        Err(err) => panic!("{}", err)
+    };
+    assert_eq!(config.lookup("host").as_ref().and_then(Value::as_str), Some("localhost"));
+    assert_eq!(config.lookup("port").as_ref().and_then(Value::as_int), Some(8080));
+    assert!(config.lookup("missing").is_none());
+
+    let limits = config.lookup("limits").unwrap();
+    assert_eq!(limits.lookup("rate").as_ref().and_then(Value::as_float), Some(0.5));
+    assert_eq!(limits.lookup("burst").as_ref().and_then(Value::as_int), Some(10));
+    assert_eq!(limits.get(0).and_then(|entry| entry.get(0)).as_ref().and_then(Value::as_symbol), Some("rate"));
+    assert!(limits.get(2).is_none());
+
+    let port: Result<i32, EvalError> = value_match!(config.lookup("port").unwrap(), Value::Int(n) => n);
+    assert_eq!(port.ok(), Some(8080));
+    let host: Result<i32, EvalError> = value_match!(config.lookup("host").unwrap(), Value::Int(n) => n);
+    if let Err(err) = host {
+        assert_eq!(err.message, "Expected Value::Int(n), got \"localhost\"");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_equal() {
+    let mut interpreter = Interpreter::new(EngineConfig { max_compare: Some(100), ..EngineConfig::default() });
+
+    if let Ok(val) = run_test("(list (equal? '(1 (2 #(3 \"x\"))) '(1 (2 #(3 \"x\")))) (equal? '(1 2) '(1 2 3)) (equal? '(1 . 2) '(1 2)) (equal? 1 \"1\") (equal? 'a 'a))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#t #f #f #f #t)");
+    } else {
+        panic!("Failed");
+    }
+
+    // Lists that share structure are only compared once
+    if let Ok(Value::Bool(true)) = run_test("(define a '(1 2 3)) (define b (list a a a a)) (equal? (list b b b b) (list b b b b))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // Past the limit, it stops with an error that try can catch
+    let nested = "'".to_string() + &"(".repeat(200) + &")".repeat(200);
+    if let Ok(Value::String(message)) = run_test(&format!("(try (equal? {} {}) (lambda (err) err))", nested, nested), &mut interpreter) {
+        assert_eq!(message, "'equal?' gave up after 100 comparisons");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_rest_arguments() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(val) = run_test("(list ((lambda args args) 1 2 3) ((lambda args args)) ((lambda (a b . rest) (list a b rest)) 1 2 3 4))", &mut interpreter) {
+        assert_eq!(val.to_string(), "((1 2 3) () (1 2 (3 4)))");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(define (tagged tag . items) (map (lambda (x) (list tag x)) items)) (define (all . xs) xs) (list (tagged 'a 1 2) (all) (all 1))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(((a 1) (a 2)) () (1))");
+    } else {
+        panic!("Failed");
+    }
+
+    // There have to be enough arguments for the parameters before the rest
+    if let Err(err) = run_test("(tagged)", &mut interpreter) {
+        assert_eq!(err.message, "tagged expects at least 1 argument, got 0");
+    } else {
+        panic!("Failed");
+    }
+
+    // A procedure with a rest parameter has no fixed arity, so a curried one is called as soon as
+    // it gets any more arguments
+    if let Ok(val) = run_test("(list ((curry all 1 2) 3) (lambda (a . rest) a))", &mut interpreter) {
+        assert_eq!(val.to_string(), "((1 2 3) (lambda (a . rest) (a)))");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_let_forms() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define x 10)", &mut interpreter).is_ok());
+    if let Ok(Value::Int(12)) = run_test("(let ((x 1) (y x)) (+ x y 1))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(3)) = run_test("(let* ((x 1) (y (+ x 1))) (+ x y))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    let source = "(letrec ((ev? (lambda (n) (if (= n 0) #t (od? (- n 1)))))
+                           (od? (lambda (n) (if (= n 0) #f (ev? (- n 1))))))
+                    (ev? 100))";
+    if let Ok(Value::Bool(true)) = run_test(source, &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // The bindings don't outlive the form
+    if let Ok(Value::Int(10)) = run_test("x", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("od?", &mut interpreter).is_err());
+
+    assert!(run_test("(let ((x)) x)", &mut interpreter).is_err());
+    assert!(run_test("(let ((x 1)))", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_closures() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // A lambda keeps the bindings of the let, let*, letrec, named let or do it was made in
+    for &(source, expected) in &[("(define f (let ((n 5)) (lambda (x) (+ x n)))) (f 1)", "6"),
+                                 ("((let* ((a 1) (b (+ a 1))) (lambda () (list a b))))", "(1 2)"),
+                                 ("((letrec ((ev? (lambda (n) (if (= n 0) #t (od? (- n 1))))) (od? (lambda (n) (if (= n 0) #f (ev? (- n 1)))))) od?) 7)", "#t"),
+                                 ("((let loop ((i 0)) (if (< i 3) (loop (+ i 1)) (lambda () i))))", "3"),
+                                 ("(map (lambda (f) (f)) (do ((i 0 (+ i 1)) (fs '() (cons (lambda () i) fs))) ((= i 3) fs)))", "(2 1 0)")] {
+        if let Ok(val) = run_test(source, &mut interpreter) {
+            assert_eq!(val.to_string(), expected);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    // ... and a set! in it changes them, for every lambda that shares them
+    let source = "(define (make-counter) (let ((n 0)) (lambda () (set! n (+ n 1)) n)))
+                  (define c (make-counter))
+                  (define d (make-counter))
+                  (c) (c)
+                  (list (c) (d))";
+    if let Ok(val) = run_test(source, &mut interpreter) {
+        assert_eq!(val.to_string(), "(3 1)");
+    } else {
+        panic!("Failed");
+    }
+
+    // A procedure sees the names where it was defined, not the caller's
+    if let Ok(Value::Int(10)) = run_test("(define y 10) (define (get-y) y) (let ((y 99)) (get-y))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // A name that's bound again inside a lambda's body isn't replaced with the argument there
+    for &(source, expected) in &[("((lambda (x) (let ((x 2)) x)) 1)", "2"),
+                                 ("((lambda (x) (let ((y x) (x 2)) (list x y))) 1)", "(2 1)"),
+                                 ("((lambda (x) (let* ((y x) (x 2) (z x)) (list y z))) 1)", "(1 2)"),
+                                 ("((lambda (x) ((lambda (x) x) 2)) 1)", "2"),
+                                 ("((lambda (x) (do ((x 0 (+ x 1))) ((= x 3) x))) 10)", "3"),
+                                 ("((lambda (x) (let x ((i x)) (if (= i 0) 'done (x (- i 1))))) 5)", "done")] {
+        if let Ok(val) = run_test(source, &mut interpreter) {
+            assert_eq!(val.to_string(), expected);
+        } else {
+            panic!("Failed");
+        }
+    }
+}
+
+#[test]
+fn test_loops() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(val) = run_test("(let loop ((i 0) (acc '())) (if (= i 3) acc (loop (+ i 1) (cons i acc))))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(2 1 0)");
+    } else {
+        panic!("Failed");
+    }
+
+    // Looping doesn't recurse
+    if let Ok(Value::Int(10000)) = run_test("(let count ((i 0)) (if (< i 10000) (count (+ i 1)) i))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(10)) = run_test("(do ((i 0 (+ i 1)) (sum 0 (+ sum i))) ((= i 5) sum))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(define squares '())", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(do ((i 0 (+ i 1))) ((= i 3) squares) (set! squares (cons (* i i) squares)))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(4 1 0)");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(do ((i 0 (+ i 1))) ())", &mut interpreter).is_err());
+
+    // while and until check their test before each pass over the body
+    if let Ok(val) = run_test("(define i 0) (define acc '()) (while (< i 4) (set! acc (cons i acc)) (set! i (+ i 1))) acc", &mut interpreter) {
+        assert_eq!(val.to_string(), "(3 2 1 0)");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(16)) = run_test("(define n 1) (until (> n 10) (set! n (* n 2))) n", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Void) = run_test("(while #f (car 5))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    // An error in the body stops the loop
+    assert!(run_test("(while #t (car 5))", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_cond() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define (sign n) (cond ((< n 0) 'negative) ((= n 0) 'zero) (else 'positive)))", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(list (sign -2) (sign 0) (sign 5))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(negative zero positive)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(3)) = run_test("(cond ((car '(2 3)) => (lambda (n) (+ n 1))) (else 0))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(7)) = run_test("(cond (#f 1) (#t (define y 3) (+ y 4)))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Void) = run_test("(cond (#f 1))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Int(2)) = run_test("(cond (1 2))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(cond (else 1) (#t 2))", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_case() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define (kind x) (case x ((1 2 3) 'small) ((a b) 'letter) ((#\\z \"z\") 'zed) (else 'other)))", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(list (kind 2) (kind 'b) (kind #\\z) (kind \"z\") (kind 2.0) (kind 'c))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(small letter zed zed other other)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Void) = run_test("(case 4 ((1) 'one))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Ok(Value::Bool(false)) = run_test("(eqv? (list 1) (list 1))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Bool(true)) = run_test("(let ((x (list 1))) (eqv? x x))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(case 1 (else 1) ((1) 2))", &mut interpreter).is_err());
+    assert!(run_test("(case 1 (1 2))", &mut interpreter).is_err());
+
+    // Datums that share a parameter's name are still datums
+    if let Ok(val) = run_test("(define (f x) (case 'x ((x) 1) (else 2))) (define (g x) (case x ((1) 'one) (else x))) (list (f 5) (g 'y))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 y)");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_reserved_words() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    for source in &["(define if 1)", "(define (lambda x) x)", "(set! quote 2)", "(lambda (else) 1)",
+                    "(lambda (a . define) a)", "(let ((cond 1)) cond)", "(let begin ((i 0)) i)",
+                    "(do ((and 0 (+ and 1))) ((= and 1) 0))", "(define-values (x or) (values 1 2))"] {
+        if let Err(err) = run_test(source, &mut interpreter) {
+            assert!(err.message.contains("reserved word"), "{}: {}", source, err.message);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    // The core syntax still means what it did
+    if let Ok(Value::Int(1)) = run_test("(if #t 1 2)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(define (f if-x) if-x)", &mut interpreter).is_ok());
+}
+
+#[test]
+fn test_and_or() {
+    let mut interpreter = Interpreter::new(EngineConfig { strict_booleans: false, ..EngineConfig::default() });
+
+    if let Ok(val) = run_test("(list (and) (or) (and 1 #f 3) (or #f #f) (and 1 '(2)) (or #f \"x\" 3))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#t #f #f #f (2) \"x\")");
+    } else {
+        panic!("Failed");
+    }
+
+    // Operands after the deciding one aren't evaluated
+    assert!(run_test("(define n 0)", &mut interpreter).is_ok());
+    assert!(run_test("(and #f (set! n 1))", &mut interpreter).is_ok());
+    assert!(run_test("(or 1 (set! n 2))", &mut interpreter).is_ok());
+    if let Ok(Value::Int(0)) = run_test("n", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // The last operand is a tail call
+    assert!(run_test("(define (down i) (or (= i 0) (down (- i 1))))", &mut interpreter).is_ok());
+    if let Ok(Value::Bool(true)) = run_test("(down 10000)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_expt() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    for &(source, n) in &[("(expt 2 10)", 1024), ("(expt -3 3)", -27), ("(expt 7 0)", 1), ("(pow 2 30)", 1073741824)] {
+        if let Ok(Value::Int(val)) = run_test(source, &mut interpreter) {
+            assert_eq!(val, n);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    // Floats only when the result isn't an integer, or is too big for one
+    for &(source, n) in &[("(expt 2 -2)", 0.25), ("(expt 2 40)", 1099511627776.0), ("(expt 2.0 3)", 8.0), ("(expt 4 0.5)", 2.0)] {
+        if let Ok(Value::Float(val)) = run_test(source, &mut interpreter) {
+            assert_eq!(val, n);
+        } else {
+            panic!("Failed");
+        }
+    }
+}
+
+#[test]
+fn test_quasiquote() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define x 5) (define xs (list 1 2))", &mut interpreter).is_ok());
+    for &(source, expected) in &[("`(a ,x ,@xs)", "(a 5 1 2)"), ("`(a (b ,(+ x 1)) c)", "(a (b 6) c)"),
+                                 ("`(a . ,x)", "(a . 5)"), ("`(,@xs . tail)", "(1 2 . tail)"), ("`(,@'() b)", "(b)"),
+                                 ("`#(1 ,x)", "#(1 5)"), ("`(a '(b ,x))", "(a (quote (b 5)))"), ("(quasiquote (x ,x))", "(x 5)"),
+                                 // Only the innermost unquote belongs to the outer quasiquote
+                                 ("`(a `(b ,(c ,x)))", "(a (quasiquote (b (unquote (c 5)))))")] {
+        if let Ok(val) = run_test(source, &mut interpreter) {
+            assert_eq!(val.to_string(), expected);
+        } else {
+            panic!("Failed");
+        }
+    }
+
+    // A parameter's name in the template is just a symbol, except where it's unquoted
+    let source = "(define (f y ys) (list `(y ,y) `(a ,@ys y . ,y) `#(y ,y) `(y `(y ,y ,,y))))
+                  (f 1 (list 2 3))";
+    if let Ok(val) = run_test(source, &mut interpreter) {
+        assert_eq!(val.to_string(), "((y 1) (a 2 3 y . 1) #(y 1) (y (quasiquote (y (unquote y) (unquote 1)))))");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("`(a ,@x)", &mut interpreter).is_err());
+    assert!(run_test("`,@xs", &mut interpreter).is_err());
+    assert!(run_test("`(a ,)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_syntax_rules() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))", &mut interpreter).is_ok());
+    // The macro's tmp doesn't capture the one passed to it
+    assert!(run_test("(define tmp 1) (define other 2) (swap! tmp other)", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(list tmp other)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(2 1)");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(define (f a b) (begin (swap! a b) (list a b))) (f 3 4)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(4 3)");
+    } else {
+        panic!("Failed");
+    }
+
+    // Recursive macros with ..., and literals
+    assert!(run_test("(define-syntax my-or (syntax-rules () ((_) #f) ((_ e) e) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))", &mut interpreter).is_ok());
+    if let Ok(Value::Int(5)) = run_test("(define t 5) (my-or #f t)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(define-syntax pairs (syntax-rules (=>) ((_ (k => v) ...) (list (cons 'k v) ...))))", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(pairs (a => 1) (b => (+ 1 1)))", &mut interpreter) {
+        assert_eq!(val.to_string(), "((a . 1) (b . 2))");
+    } else {
+        panic!("Failed");
+    }
+
+    // when and unless are macros in the prelude
+    if let Ok(val) = run_test("(list (when #t 1 2) (unless #f 3))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(2 3)");
+    } else {
+        panic!("Failed");
+    }
+
+    // An internal macro's pattern variables can share a parameter's name
+    if let Ok(val) = run_test("(define (f x) (define-syntax m (syntax-rules () ((_ x) (quote x)))) (list (m 4) x)) (f 1)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(4 1)");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("(swap! 1)", &mut interpreter) {
+        assert_eq!(err.message, "No syntax-rules pattern of 'swap!' matches (swap! 1)");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(define-syntax bad (syntax-rules () ((_ x) x ...)))", &mut interpreter).is_err());
+    assert!(run_test("(define-syntax if (syntax-rules () ((_) 1)))", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_call_cc() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(3)) = run_test("(call/cc (lambda (k) (+ 1 2)))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    // Calling the continuation abandons the rest of the procedure
+    if let Ok(Value::Int(42)) = run_test("(+ 1 (call/cc (lambda (k) (+ 1000 (k 41)))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(-3)) = run_test("(define (first-negative xs) (call-with-current-continuation (lambda (return) (begin (for-each (lambda (x) (if (< x 0) (return x) #f)) xs) #f)))) (first-negative (list 1 2 -3 4 -5))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Bool(false)) = run_test("(first-negative (list 1 2))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // An inner continuation can escape past its own call/cc to an outer one
+    if let Ok(Value::Int(1)) = run_test("(call/cc (lambda (outer) (+ 10 (call/cc (lambda (inner) (outer 1))))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(12)) = run_test("(call/cc (lambda (outer) (+ 10 (call/cc (lambda (inner) (inner 2))))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // try doesn't catch an escape
+    if let Ok(Value::Int(5)) = run_test("(call/cc (lambda (k) (try (k 5) (lambda (err) 0))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("(define saved (call/cc (lambda (k) k))) (saved 1)", &mut interpreter) {
+        assert_eq!(err.message, "A continuation can't be called after its call/cc has returned");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(call/cc 5)", &mut interpreter).is_err());
+    if let Ok(val) = run_test("saved", &mut interpreter) {
+        assert_eq!(val.to_string(), "#<continuation>");
+    } else {
+        panic!("Failed");
+    }
+}
+
+#[test]
+fn test_generators() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // Two generators, one of them endless, interleaved
+    let source = "(define (counting-from n) (make-generator (lambda () (let loop ((i n)) (yield i) (loop (+ i 1))))))
+                  (define nat (counting-from 10))
+                  (define evens (make-generator (lambda () (for-each (lambda (x) (yield (* 2 x))) (list 1 2 3)))))
+                  (list (nat) (evens) (nat) (evens) (nat) (evens) (nat))";
+    if let Ok(val) = run_test(source, &mut interpreter) {
+        assert_eq!(val.to_string(), "(10 2 11 4 12 6 13)");
+    } else {
+        panic!("Failed");
+    }
+    // Once the producer returns, there are no more values
+    if let Ok(Value::Bool(true)) = run_test("(and (void? (evens)) (void? (evens)))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // The producer is suspended at each yield, so it carries on from there, and its side effects
+    // happen once
+    let source = "(define runs '())
+                  (define letters (make-generator (lambda () (set! runs (cons 'start runs)) (yield 'a) (set! runs (cons 'after-a runs)) (yield 'b))))
+                  (list (letters) (letters) (void? (letters)) runs)";
+    if let Ok(val) = run_test(source, &mut interpreter) {
+        assert_eq!(val.to_string(), "(a b #t (after-a start))");
+    } else {
+        panic!("Failed");
+    }
+    // Producers that change what they've captured
+    let source = "(define (walk xs) (make-generator (lambda () (let loop () (unless (null? xs) (yield (car xs)) (set! xs (cdr xs)) (loop))))))
+                  (define w (walk '(1 2 3)))
+                  (define n 0)
+                  (define counter (make-generator (lambda () (let loop () (set! n (+ n 1)) (yield n) (loop)))))
+                  (list (w) (w) (w) (void? (w)) (counter) (counter) (counter) n)";
+    if let Ok(val) = run_test(source, &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 2 3 #t 1 2 3 3)");
+    } else {
+        panic!("Failed");
+    }
+    // Output from the producer goes where the caller's does, and errors come out of the call
+    if let Ok(val) = run_test("(define noisy (make-generator (lambda () (print 'x) (yield 1) (car 5)))) (with-output-to-string (lambda () (noisy)))", &mut interpreter) {
+        assert_eq!(val.to_string(), "\"x\\n\"");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(guard (e (#t (error-object-message e))) (noisy))", &mut interpreter) {
+        assert_eq!(val.to_string(), "\"Invalid type for 'car'\"");
+    } else {
+        panic!("Failed");
+    }
+    if let Err(err) = run_test("(define self (make-generator (lambda () (self)))) (self)", &mut interpreter) {
+        assert_eq!(err.message, "A generator can't be called by its own producer");
+    } else {
+        panic!("Failed");
+    }
+    // A step limit still stops a producer that never yields
+    let mut limited = Interpreter::new(EngineConfig { max_steps: Some(10000), ..EngineConfig::default() });
+    assert!(run_test("((make-generator (lambda () (let loop () (loop)))))", &mut limited).is_err());
+
+    if let Err(err) = run_test("(yield 1)", &mut interpreter) {
+        assert_eq!(err.message, "'yield' can only be called by a generator's producer");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(nat 1)", &mut interpreter).is_err());
+    assert!(run_test("(make-generator 5)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_reader_syntax() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // Ports on strings
+    if let Ok(val) = run_test("(define p (open-input-string \"ab (1 2) c\")) (list (read-char p) (peek-char p) (read-char p) (read p) (read p) (void? (read p)))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#\\a #\\b #\\b (1 2) c #t)");
+    } else {
+        panic!("Failed");
+    }
+
+    // $[1 2 3] reads as (vector 1 2 3), from the input after this one
+    assert!(run_test("(define-reader-syntax #\\$ (lambda (port) (cons 'vector (read port))))", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(list $[1 2 (+ 1 2)] '$[a])", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#(1 2 3) (vector a))");
+    } else {
+        panic!("Failed");
+    }
+    // Handlers can read a character at a time, and read reads nested reader syntax
+    assert!(run_test("(define-reader-syntax #\\! (lambda (port) (begin (read-char port) (list 'quote (read port)))))", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(list !xyz (read (open-input-string \"$(!ab)\")))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(yz (vector (quote b)))");
+    } else {
+        panic!("Failed");
+    }
+    // Only a character that starts an atom is dispatched
+    if let Ok(val) = run_test("(define a$b 1) a$b", &mut interpreter) {
+        assert_eq!(val.to_string(), "1");
+    } else {
+        panic!("Failed");
+    }
+
+    // In a script, reader syntax applies to the forms after it
+    let mut script = Interpreter::new(EngineConfig::default());
+    if let Ok(val) = script.eval_source("(define-reader-syntax #\\% (lambda (port) (list 'quote (list 'percent (read port)))))\n(define x %50)\nx") {
+        assert_eq!(val.to_string(), "(percent 50)");
+    } else {
+        panic!("Failed");
+    }
+    // Errors after it still say where they are in the whole script
+    if let Err(ScriptError::Parse(errors)) = script.eval_source("(define-reader-syntax #\\% car)\n(define y 1)\n  )") {
+        assert_eq!(errors[0].location, Some(parser::Location { line: 3, col: 3 }));
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(define-reader-syntax #\\( car)", &mut interpreter).is_err());
+    assert!(run_test("(define-reader-syntax #\\@ 5)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_promises() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // The expression is evaluated once, the first time the promise is forced
+    if let Ok(val) = run_test("(define count 0) (define p (delay (begin (set! count (+ count 1)) count))) (list count (force p) (force p) count)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(0 1 1 1)");
+    } else {
+        panic!("Failed");
+    }
+    // In the scope the promise was made in
+    if let Ok(Value::Int(10)) = run_test("(define q (let ((x 5)) (delay (* x 2)))) (force q)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(list (promise? p) (promise? 1) (force 7) (force (make-promise 8)) q)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#t #f 7 8 #<promise>)");
+    } else {
+        panic!("Failed");
+    }
+
+    // A long chain of delay-forces doesn't nest
+    if let Ok(Value::Literal(val)) = run_test("(define (countdown n) (if (= n 0) (delay 'done) (delay-force (countdown (- n 1))))) (force (countdown 20000))", &mut interpreter) {
+        assert_eq!(val, "done");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(define (ints n) (cons n (delay (ints (+ n 1))))) (define (take s k) (if (= k 0) (list) (cons (car s) (take (force (cdr s)) (- k 1))))) (take (ints 1) 5)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 2 3 4 5)");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(force (delay-force 5))", &mut interpreter).is_err());
+    assert!(run_test("(delay)", &mut interpreter).is_err());
+    assert!(run_test("(define delay 1)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_eval() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(Value::Int(7)) = run_test("(eval (list '+ 3 4))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(9)) = run_test("(eval '(* 3 3) (interaction-environment))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    // the-environment sees the local names, and a define in it stays there
+    if let Ok(val) = run_test("(define env (let ((x 5)) (the-environment))) (eval '(define y (* x 2)) env) (list (eval 'y env) (eval '(+ x y) env))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(10 15)");
+    } else {
+        panic!("Failed");
+    }
+    // A define evaluated in the interaction environment is global
+    if let Ok(Value::Int(3)) = run_test("(define (def-global) (eval '(define z 3) (interaction-environment))) (def-global) z", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(list (symbol? 'a) (symbol? 1) (number? 1/2) (number? \"1\") (list? (list 1)) (list? (cons 1 2)) (interaction-environment))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#t #f #t #f #t #f #<environment>)");
+    } else {
+        panic!("Failed");
+    }
+
+    // A small metacircular evaluator, with closures as lists and environments as lists of
+    // (name value) bindings, falling back to eval for the builtins
+    let evaluator = "
+        (define (m-lookup name env)
+          (cond ((null? env) (eval name (interaction-environment)))
+                ((eqv? (car (car env)) name) (car (cdr (car env))))
+                (else (m-lookup name (cdr env)))))
+        (define (m-bind names vals env)
+          (if (null? names) env (cons (list (car names) (car vals)) (m-bind (cdr names) (cdr vals) env))))
+        (define (m-eval x env)
+          (cond ((symbol? x) (m-lookup x env))
+                ((not (list? x)) x)
+                ((eqv? (car x) 'quote) (car (cdr x)))
+                ((eqv? (car x) 'if) (if (m-eval (car (cdr x)) env) (m-eval (car (cdr (cdr x))) env) (m-eval (car (cdr (cdr (cdr x)))) env)))
+                ((eqv? (car x) 'lambda) (list 'closure (car (cdr x)) (car (cdr (cdr x))) env))
+                (else (m-apply (m-eval (car x) env) (map (lambda (e) (m-eval e env)) (cdr x))))))
+        (define (m-apply f args)
+          (if (and (list? f) (eqv? (car f) 'closure))
+              (m-eval (car (cdr (cdr f))) (m-bind (car (cdr f)) args (car (cdr (cdr (cdr f))))))
+              (eval (cons f (map (lambda (a) (list 'quote a)) args)))))";
+    assert!(run_test(evaluator, &mut interpreter).is_ok());
+    if let Ok(Value::Int(120)) = run_test("(m-eval '(((lambda (f) (lambda (n) (f f n))) (lambda (self n) (if (= n 0) 1 (* n (self self (- n 1)))))) 5) (list))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(m-eval '((lambda (x y) (cons y (quote (x)))) 1 2) (list))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(2 x)");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(eval 1 2)", &mut interpreter).is_err());
+    assert!(run_test("(the-environment 1)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_conditions() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    // guard catches raised objects and error objects, choosing a clause like cond
+    if let Ok(Value::Int(42)) = run_test("(guard (e ((number? e) e)) (raise 42))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(guard (e ((error-object? e) (list (error-object-message e) (error-object-irritants e)))) (error \"bad value:\" 42 'x))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(\"bad value:\" (42 x))");
+    } else {
+        panic!("Failed");
+    }
+    // Errors from builtins are error objects with just a message
+    if let Ok(val) = run_test("(guard (e (#t (list (error-object? e) (error-object-irritants e)))) (car 5))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#t ())");
+    } else {
+        panic!("Failed");
+    }
+    // With no clause chosen, the object goes on to the next guard out
+    if let Ok(val) = run_test("(guard (outer (#t (list 'outer outer))) (guard (inner ((number? inner) 'inner)) (raise 'oops)))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(outer oops)");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(3)) = run_test("(guard (e (else 3)) (+ 1 2))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    // The guard's variable can be a procedure's parameter, which its body still sees
+    if let Ok(val) = run_test("(define (f e) (guard (e (#t (list e))) (raise e))) (f 'boom)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(boom)");
+    } else {
+        panic!("Failed");
+    }
+
+    // with-exception-handler calls the handler where the object is raised
+    if let Ok(Value::Int(11)) = run_test("(with-exception-handler (lambda (e) (* e 10)) (lambda () (+ 1 (raise-continuable 1))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::Int(5)) = run_test("(call/cc (lambda (k) (with-exception-handler (lambda (e) (k 5)) (lambda () (error \"failed\")))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    if let Err(err) = run_test("(with-exception-handler (lambda (e) 0) (lambda () (raise 'oops)))", &mut interpreter) {
+        assert_eq!(err.message, "The handler returned from a non-continuable raise: Uncaught raise of oops");
+    } else {
+        panic!("Failed");
+    }
+    // Inside a guard, a raise goes to the guard rather than a handler outside it
+    if let Ok(Value::Literal(val)) = run_test("(with-exception-handler (lambda (e) 'handler) (lambda () (guard (e (#t 'guard)) (raise-continuable 1))))", &mut interpreter) {
+        assert_eq!(val, "guard");
+    } else {
+        panic!("Failed");
+    }
+    // The handler sees errors from builtins after they've unwound to it
+    if let Ok(val) = run_test("(define seen 0) (guard (e (#t (list seen (error-object-message e)))) (with-exception-handler (lambda (e) (set! seen (+ seen 1))) (lambda () (car 5))))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 \"Invalid type for 'car'\")");
+    } else {
+        panic!("Failed");
+    }
+
+    // Uncaught, an error is still reported as its message
+    if let Err(err) = run_test("(error \"bad value:\" 42)", &mut interpreter) {
+        assert_eq!(err.message, "bad value: 42");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(Value::String(val)) = run_test("(try (raise 'oops) (lambda (err) err))", &mut interpreter) {
+        assert_eq!(val, "Uncaught raise of oops");
+    } else {
+        panic!("Failed");
+    }
+    // Escaping with a continuation isn't caught
+    if let Ok(Value::Int(1)) = run_test("(call/cc (lambda (k) (guard (e (#t 0)) (k 1))))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(guard (e) 1 2)", &mut interpreter).is_ok());
+    assert!(run_test("(guard e (raise 1))", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_parameters() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define width (make-parameter 10)) (define (show) (list 'width (width)))", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(list (show) (parameterize ((width 20)) (show)) (show))", &mut interpreter) {
+        assert_eq!(val.to_string(), "((width 10) (width 20) (width 10))");
+    } else {
+        panic!("Failed");
+    }
+    // Nested bindings, and bindings undone by an error or an escape
+    if let Ok(val) = run_test("(parameterize ((width 1)) (list (width) (parameterize ((width 2)) (width)) (width)))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(1 2 1)");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(try (parameterize ((width 30)) (car 5)) (lambda (e) 0))", &mut interpreter).is_ok());
+    assert!(run_test("(call/cc (lambda (k) (parameterize ((width 40)) (k 0))))", &mut interpreter).is_ok());
+    if let Ok(Value::Int(10)) = run_test("(width)", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+
+    // The converter applies to the initial value and to each value bound
+    if let Ok(val) = run_test("(define label (make-parameter 1 (lambda (x) (list 'n x)))) (list (label) (parameterize ((label 2)) (label)))", &mut interpreter) {
+        assert_eq!(val.to_string(), "((n 1) (n 2))");
+    } else {
+        panic!("Failed");
+    }
+
+    assert!(run_test("(width 1)", &mut interpreter).is_err());
+    assert!(run_test("(parameterize ((car 1)) 1)", &mut interpreter).is_err());
+    assert!(run_test("(parameterize ((width 1)))", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_optional_params() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    assert!(run_test("(define (greet name #:optional (greeting \"hello\") punct) (list greeting name punct))", &mut interpreter).is_ok());
+    if let Ok(val) = run_test("(list (greet 'bob) (greet 'bob \"hi\") (greet 'bob \"hi\" 1))", &mut interpreter) {
+        assert_eq!(val.to_string(), "((\"hello\" bob #f) (\"hi\" bob #f) (\"hi\" bob 1))");
+    } else {
+        panic!("Failed");
+    }
+    // Defaults are evaluated in the lambda's scope, after the parameters before them
+    if let Ok(val) = run_test("(define (range-of lo #:optional (hi (+ lo 10))) (list lo hi)) (list (range-of 1) (range-of 1 2))", &mut interpreter) {
+        assert_eq!(val.to_string(), "((1 11) (1 2))");
+    } else {
+        panic!("Failed");
+    }
+    // With keyword and rest parameters
+    if let Ok(val) = run_test("((lambda (a #:optional (b 2) #:scale (s 10)) (* s (+ a b))) 1 #:scale 100)", &mut interpreter) {
+        assert_eq!(val.to_string(), "300");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(define (f a #:optional (b 0) . more) (list a b more)) (list (f 1) (f 1 2 3 4))", &mut interpreter) {
+        assert_eq!(val.to_string(), "((1 0 ()) (1 2 (3 4)))");
+    } else {
+        panic!("Failed");
+    }
+    if let Ok(val) = run_test("(lambda (x #:optional (y 10)) x)", &mut interpreter) {
+        assert_eq!(val.to_string(), "(lambda (x #:optional (y 10)) (x))");
+    } else {
+        panic!("Failed");
+    }
+
+    if let Err(err) = run_test("(greet)", &mut interpreter) {
+        assert_eq!(err.message, "greet expects 1 to 3 arguments, got 0");
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(greet 'a \"b\" 1 2)", &mut interpreter).is_err());
+    assert!(run_test("(lambda (#:optional a #:optional b) a)", &mut interpreter).is_err());
+    assert!(run_test("(lambda (#:optional (if 1)) 1)", &mut interpreter).is_err());
+}
+
+#[test]
+fn test_gensym() {
+    let mut interpreter = Interpreter::new(EngineConfig::default());
+
+    if let Ok(val) = run_test("(define a (gensym)) (list (symbol? a) (eqv? a a) (eqv? a (gensym)) (equal? (gensym \"x\") (gensym \"x\")))", &mut interpreter) {
+        assert_eq!(val.to_string(), "(#t #t #f #f)");
+    } else {
+        panic!("Failed");
+    }
+    // They print so they can't be mistaken for (or read back as) an ordinary symbol
+    if let Ok(Value::Literal(name)) = run_test("(gensym 'tmp)", &mut interpreter) {
+        assert!(name.starts_with("#[tmp") && name.ends_with(']'));
+        if let Ok(nodes) = parser::parse_source(name.clone()) {
+            assert!(nodes.len() > 1);
+        } else {
+            panic!("Failed");
+        }
+    } else {
+        panic!("Failed");
+    }
+    // Generated code can bind them
+    if let Ok(Value::Int(10)) = run_test("(define s (gensym)) (eval (list 'let (list (list s 5)) (list '* s 2)))", &mut interpreter) {
+    } else {
+        panic!("Failed");
+    }
+    assert!(run_test("(gensym 1)", &mut interpreter).is_err());
+}